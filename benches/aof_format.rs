@@ -0,0 +1,89 @@
+//! compares the JSON and binary AOF formats: encoding cost, write-path
+//! throughput through the AOF writer, file size and replay time
+
+use std::time::{Duration, Instant};
+
+use kvstore::aof::{manifest_path, segments, Aof, AofFormat, AppendFsync, LogEntry};
+
+const ENTRIES: usize = 200_000;
+
+/// a mix like a real workload: plain sets, sets with a TTL and list pushes
+fn workload() -> Vec<LogEntry> {
+    (0..ENTRIES)
+        .map(|i| {
+            let key = format!("key:{:08}", i % 10_000);
+            match i % 3 {
+                0 => LogEntry { op: "set".into(), key, value: Some(format!("value-{i}")), expires_at_ms: None, values: None },
+                1 => LogEntry {
+                    op: "set".into(),
+                    key,
+                    value: Some(format!("value-{i}")),
+                    expires_at_ms: Some(1_800_000_000_000 + i as i64),
+                    values: None,
+                },
+                _ => LogEntry {
+                    op: "rpush".into(),
+                    key,
+                    value: None,
+                    expires_at_ms: None,
+                    values: Some(vec![format!("a{i}"), format!("b{i}"), format!("c{i}")]),
+                },
+            }
+        })
+        .collect()
+}
+
+fn per_sec(n: usize, d: Duration) -> f64 {
+    n as f64 / d.as_secs_f64()
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let entries = workload();
+    println!("{ENTRIES} entries per run\n");
+    println!("{:<8} {:>14} {:>14} {:>12} {:>14}", "format", "encode/s", "write/s", "file bytes", "replay/s");
+    for format in [AofFormat::Json, AofFormat::Binary] {
+        let started = Instant::now();
+        let mut encoded = 0;
+        for entry in &entries {
+            encoded += match format {
+                AofFormat::Json => entry.to_line()?.len() + 1,
+                AofFormat::Binary => entry.to_frame().len(),
+            };
+        }
+        let encode = started.elapsed();
+        std::hint::black_box(encoded);
+
+        let path = std::env::temp_dir().join(format!("kv-bench-aof-{}-{}", format.as_str(), std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let aof = Aof::new(&path).await?;
+        aof.set_fsync(AppendFsync::No);
+        aof.set_format(format);
+        let started = Instant::now();
+        for entry in &entries {
+            aof.log(entry.clone());
+        }
+        aof.flush().await?;
+        let write = started.elapsed();
+        let files = segments(&path);
+        let size = files.iter().map(|file| std::fs::metadata(file).map_or(0, |m| m.len())).sum::<u64>();
+
+        let started = Instant::now();
+        let replayed = Aof::replay(&path)?.entries.len();
+        let replay = started.elapsed();
+        assert_eq!(replayed, ENTRIES);
+        for file in files.iter().chain([&manifest_path(&path)]) {
+            let _ = std::fs::remove_file(file);
+        }
+
+        println!(
+            "{:<8} {:>14.0} {:>14.0} {:>12} {:>14.0}",
+            format.as_str(),
+            per_sec(ENTRIES, encode),
+            per_sec(ENTRIES, write),
+            size,
+            per_sec(ENTRIES, replay),
+        );
+    }
+    Ok(())
+}
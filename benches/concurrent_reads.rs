@@ -0,0 +1,48 @@
+//! read throughput as reader threads are added. reads share the keyspace
+//! lock, so throughput should grow with the threads up to the core count
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use kvstore::Store;
+
+const KEYS: usize = 10_000;
+const READS_PER_THREAD: usize = 500_000;
+
+fn per_sec(n: usize, d: Duration) -> f64 {
+    n as f64 / d.as_secs_f64()
+}
+
+#[tokio::main]
+async fn main() {
+    let store = Store::new(None);
+    for i in 0..KEYS {
+        store.set(format!("key:{i}"), format!("value-{i}"), None);
+        store.lpush(&format!("list:{i}"), vec!["a".to_string(), "b".to_string()]);
+    }
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    println!("{KEYS} strings and lists, {READS_PER_THREAD} reads per thread, {cores} cores\n");
+    println!("{:<8} {:>14} {:>10}", "threads", "reads/s", "speedup");
+    let mut base = None;
+    for threads in [1, 2, 4, 8] {
+        let started = Instant::now();
+        thread::scope(|s| {
+            for t in 0..threads {
+                let store = &store;
+                s.spawn(move || {
+                    for i in 0..READS_PER_THREAD {
+                        let n = (i * 31 + t * 7) % KEYS;
+                        // half GETs, half LLENs
+                        let reply = if i % 2 == 0 { store.get(&format!("key:{n}")) } else { store.llen(&format!("list:{n}")) };
+                        std::hint::black_box(reply);
+                    }
+                });
+            }
+        });
+        let rate = per_sec(threads * READS_PER_THREAD, started.elapsed());
+        let base = *base.get_or_insert(rate);
+        println!("{threads:<8} {rate:>14.0} {:>9.2}x", rate / base);
+    }
+}
@@ -0,0 +1,59 @@
+//! GETs of large values from several threads, with a writer on the same
+//! shard. a GET hands out the stored buffer rather than copying it, so read
+//! latency shouldn't grow with the value and the writer shouldn't wait on
+//! readers copying under the lock
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use kvstore::Store;
+
+const READS_PER_THREAD: usize = 2_000;
+const WRITES: usize = 2_000;
+
+fn micros(d: Duration, n: usize) -> f64 {
+    d.as_secs_f64() * 1e6 / n as f64
+}
+
+fn main() {
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    let threads = cores.clamp(2, 8);
+    println!("{threads} reader threads, {READS_PER_THREAD} GETs each, one writer doing {WRITES} SETs, {cores} cores\n");
+    println!("{:<10} {:>14} {:>16}", "value", "GET us/op", "writer SET us/op");
+    for size in [1 << 10, 64 << 10, 1 << 20, 5 << 20] {
+        // one shard, so the writer shares its lock with every read
+        let store = Store::with_shards(None, 1);
+        store.set("page".to_string(), "x".repeat(size), None);
+        let done = AtomicBool::new(false);
+        let (reads, writes) = thread::scope(|s| {
+            let readers: Vec<_> = (0..threads).map(|_| s.spawn(|| {
+                let started = Instant::now();
+                for _ in 0..READS_PER_THREAD {
+                    std::hint::black_box(store.get("page"));
+                }
+                started.elapsed()
+            })).collect();
+            let writer = s.spawn(|| {
+                let started = Instant::now();
+                let mut n = 0;
+                while n < WRITES && !done.load(Ordering::Relaxed) {
+                    store.set("counter".to_string(), n.to_string(), None);
+                    n += 1;
+                }
+                (started.elapsed(), n.max(1))
+            });
+            let reads: Duration = readers.into_iter().map(|r| r.join().unwrap()).sum();
+            done.store(true, Ordering::Relaxed);
+            (reads, writer.join().unwrap())
+        });
+        println!(
+            "{:<10} {:>14.2} {:>16.2}",
+            format!("{}KB", size >> 10),
+            micros(reads, threads * READS_PER_THREAD),
+            micros(writes.0, writes.1),
+        );
+    }
+}
@@ -0,0 +1,47 @@
+//! the argument path of a big SADD: parsing the RESP request and running it,
+//! once with members the set doesn't have and once with members it already has
+
+use std::time::{Duration, Instant};
+
+use kvstore::{
+    protocol::{encode_request, execute, read_request},
+    Store,
+};
+
+const MEMBERS: usize = 1000;
+const RUNS: usize = 2000;
+
+fn per_sec(n: usize, d: Duration) -> f64 {
+    n as f64 / d.as_secs_f64()
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut args = vec!["SADD".to_string(), "set".to_string()];
+    args.extend((0..MEMBERS).map(|i| format!("member-{i:06}")));
+    let request = encode_request(&args);
+    let store = Store::new(None);
+
+    println!("SADD with {MEMBERS} members, {RUNS} runs\n");
+    println!("{:<10} {:>14} {:>14}", "members", "commands/s", "members/s");
+    for existing in [false, true] {
+        let mut took = Duration::ZERO;
+        for _ in 0..RUNS {
+            if !existing {
+                store.del("set");
+            }
+            let started = Instant::now();
+            let parsed = read_request(&mut &request[..]).await?.expect("a request");
+            let reply = execute(&store, &parsed.args);
+            took += started.elapsed();
+            std::hint::black_box(reply);
+        }
+        println!(
+            "{:<10} {:>14.0} {:>14.0}",
+            if existing { "existing" } else { "new" },
+            per_sec(RUNS, took),
+            per_sec(RUNS * MEMBERS, took),
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,51 @@
+//! a mixed GET/SET workload from 16 threads standing in for connections,
+//! against one lock over the whole keyspace and against the default shards
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use kvstore::{store::DEFAULT_SHARDS, Store};
+
+const CONNECTIONS: usize = 16;
+const KEYS: usize = 10_000;
+const OPS_PER_CONNECTION: usize = 200_000;
+
+fn per_sec(n: usize, d: Duration) -> f64 {
+    n as f64 / d.as_secs_f64()
+}
+
+fn run(store: &Store) -> Duration {
+    let started = Instant::now();
+    thread::scope(|s| {
+        for c in 0..CONNECTIONS {
+            s.spawn(move || {
+                for i in 0..OPS_PER_CONNECTION {
+                    let key = format!("key:{}", (i * 31 + c * 7) % KEYS);
+                    // one write to every three reads
+                    let reply = if i % 4 == 0 { store.set(key, format!("value-{i}"), None) } else { store.get(&key) };
+                    std::hint::black_box(reply);
+                }
+            });
+        }
+    });
+    started.elapsed()
+}
+
+#[tokio::main]
+async fn main() {
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    println!("{CONNECTIONS} connections, {OPS_PER_CONNECTION} ops each (25% SET), {cores} cores\n");
+    println!("{:<8} {:>14} {:>10}", "shards", "ops/s", "speedup");
+    let mut base = None;
+    for shards in [1, DEFAULT_SHARDS] {
+        let store = Store::with_shards(None, shards);
+        for i in 0..KEYS {
+            store.set(format!("key:{i}"), "value".to_string(), None);
+        }
+        let rate = per_sec(CONNECTIONS * OPS_PER_CONNECTION, run(&store));
+        let base = *base.get_or_insert(rate);
+        println!("{shards:<8} {rate:>14.0} {:>9.2}x", rate / base);
+    }
+}
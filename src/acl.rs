@@ -0,0 +1,267 @@
+//! named users and what they may run. each connection runs as a user,
+//! picked by `AUTH user password`. a user is allowed categories of commands
+//! (read, write, admin) and key patterns, and anything outside them is
+//! refused with NOPERM.
+//!
+//! users come from the aclfile, one per line:
+//!
+//! ```text
+//! # a read-only user limited to the cache: keys
+//! user reader >secret +@read ~cache:*
+//! user ops >hunter2 +@all allkeys
+//! user batch >s3cret +@all allkeys ratelimit-cps=50 ratelimit-burst=100
+//! ```
+//!
+//! `>password` sets the password and `nopass` accepts any, `+@category`
+//! allows a category (`+@all` for every one) and `~pattern` a glob of keys
+//! (`allkeys` for `~*`). `ratelimit-cps=n` and `ratelimit-burst=n` give the
+//! user their own rate limit in place of the server's, 0 cps for none, which
+//! starts from a full bucket on AUTH. without an aclfile entry for it,
+//! `default` is allowed everything and needs `requirepass`, if set
+
+use std::sync::Arc;
+use crate::{
+    config::ServerConfig,
+    error::RedisError,
+    glob,
+    protocol::{command_id, is_write_command, tokenize_inline, COMMANDS, CONNECTION_COMMANDS},
+};
+
+/// server scoped commands that change or inspect the server rather than the dataset
+const ADMIN_COMMANDS: &[&str] = &[
+    "AOFOFFSET", "BGREWRITEAOF", "BGSAVE", "CLIENT", "CONFIG", "DEBUG", "LATENCY", "PSYNC", "REPLICAOF", "SAVE",
+    "SHUTDOWN", "SLAVEOF", "SLOWLOG", "SYNC",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Read,
+    Write,
+    Admin,
+}
+
+impl Category {
+    pub const ALL: [Category; 3] = [Category::Read, Category::Write, Category::Admin];
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "read" => Some(Category::Read),
+            "write" => Some(Category::Write),
+            "admin" => Some(Category::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Category::Read => "read",
+            Category::Write => "write",
+            Category::Admin => "admin",
+        }
+    }
+
+    /// the category of an uppercased command, None for the connection
+    /// commands every user may run
+    pub fn of(cmd: &str) -> Option<Self> {
+        if CONNECTION_COMMANDS.contains(&cmd) {
+            None
+        } else if ADMIN_COMMANDS.contains(&cmd) {
+            Some(Category::Admin)
+        } else if is_write_command(cmd) {
+            Some(Category::Write)
+        } else {
+            Some(Category::Read)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub name: String,
+    /// None for `nopass`, where any password is accepted
+    pub password: Option<String>,
+    pub categories: Vec<Category>,
+    /// globs of the keys the user may touch
+    pub key_patterns: Vec<String>,
+    /// the user's own `ratelimit-cps` and `ratelimit-burst`, None for the server's
+    pub ratelimit_cps: Option<u64>,
+    pub ratelimit_burst: Option<u64>,
+}
+
+impl User {
+    /// a user allowed every command on every key
+    pub fn unrestricted(name: &str, password: Option<String>) -> Self {
+        User {
+            name: name.to_string(),
+            password,
+            categories: Category::ALL.to_vec(),
+            key_patterns: vec!["*".to_string()],
+            ratelimit_cps: None,
+            ratelimit_burst: None,
+        }
+    }
+
+    /// the commands per second and burst the user is limited to, their own
+    /// where the aclfile gave them and the server's otherwise
+    pub fn rate_limit(&self, config: &ServerConfig) -> (u64, u64) {
+        (
+            self.ratelimit_cps.unwrap_or(config.ratelimit_cps),
+            self.ratelimit_burst.unwrap_or(config.ratelimit_burst),
+        )
+    }
+
+    /// parses the rules after `user <name>` in an aclfile line
+    fn parse(name: &str, rules: &[String]) -> anyhow::Result<Self> {
+        let mut user = User {
+            name: name.to_string(),
+            password: None,
+            categories: Vec::new(),
+            key_patterns: Vec::new(),
+            ratelimit_cps: None,
+            ratelimit_burst: None,
+        };
+        let number = |setting: &str, value: &str| {
+            value.parse::<u64>().map_err(|_| anyhow::anyhow!("invalid {setting} '{value}' for user '{name}'"))
+        };
+        let mut has_password = false;
+        for rule in rules {
+            if let Some(password) = rule.strip_prefix('>') {
+                user.password = Some(password.to_string());
+                has_password = true;
+            } else if rule.eq_ignore_ascii_case("nopass") {
+                user.password = None;
+                has_password = true;
+            } else if let Some(category) = rule.strip_prefix("+@") {
+                if category.eq_ignore_ascii_case("all") {
+                    user.categories = Category::ALL.to_vec();
+                } else {
+                    let category = Category::parse(category)
+                        .ok_or_else(|| anyhow::anyhow!("unknown category '{category}', must be read, write, admin or all"))?;
+                    if !user.categories.contains(&category) {
+                        user.categories.push(category);
+                    }
+                }
+            } else if let Some(pattern) = rule.strip_prefix('~') {
+                user.key_patterns.push(pattern.to_string());
+            } else if rule.eq_ignore_ascii_case("allkeys") {
+                user.key_patterns.push("*".to_string());
+            } else if let Some(cps) = rule.strip_prefix("ratelimit-cps=") {
+                user.ratelimit_cps = Some(number("ratelimit-cps", cps)?);
+            } else if let Some(burst) = rule.strip_prefix("ratelimit-burst=") {
+                user.ratelimit_burst = Some(number("ratelimit-burst", burst)?);
+            } else {
+                anyhow::bail!("unknown rule '{rule}' for user '{name}'");
+            }
+        }
+        // a user nobody could log in as is more likely a mistake than intended
+        if !has_password {
+            anyhow::bail!("user '{name}' needs a >password or nopass");
+        }
+        Ok(user)
+    }
+
+    /// Ok if the user may run `args`, `cmd` being its first element
+    /// uppercased, otherwise the NOPERM error to reply with. unknown
+    /// commands are let through to fail as unknown
+    pub fn check(&self, cmd: &str, args: &[&str]) -> Result<(), RedisError> {
+        if command_id(cmd).is_none() {
+            return Ok(());
+        }
+        let Some(category) = Category::of(cmd) else { return Ok(()) };
+        if !self.categories.contains(&category) {
+            return Err(RedisError::NoPerm(format!(
+                "User {} has no permissions to run the '{}' command",
+                self.name,
+                cmd.to_lowercase(),
+            )));
+        }
+        let allowed = match touched_keys(cmd, args) {
+            Some(keys) => keys.iter().all(|key| self.key_patterns.iter().any(|p| glob::matches(p, key))),
+            // any key at all, so only for users allowed every key
+            None => self.key_patterns.iter().any(|p| p == "*"),
+        };
+        if !allowed {
+            return Err(RedisError::NoPerm("No permissions to access a key".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// the keys a command touches, going by its usage in COMMANDS. None for
+/// DELPATTERN, which may touch any key
+fn touched_keys<'a>(cmd: &str, args: &'a [&'a str]) -> Option<&'a [&'a str]> {
+    let from = |start: usize, end: usize| &args[start.min(args.len())..end.min(args.len())];
+    match cmd {
+        "DELPATTERN" => None,
+        "BITOP" => Some(from(2, args.len())),
+        "LCS" | "SMOVE" => Some(from(1, 3)),
+        "MEMORY" | "OBJECT" => Some(from(2, 3)),
+        _ => {
+            let usage = command_id(cmd).map_or("", |id| COMMANDS[id].1);
+            Some(if usage.starts_with("key") { from(1, 2) } else { &[] })
+        }
+    }
+}
+
+/// the users a server knows
+#[derive(Debug, Clone)]
+pub struct Acl {
+    users: Vec<Arc<User>>,
+}
+
+impl Acl {
+    /// `default` from `requirepass`, then the users in `aclfile` if set. the
+    /// file may redefine `default`
+    pub fn load(config: &ServerConfig) -> anyhow::Result<Self> {
+        let mut acl = Acl { users: vec![Arc::new(User::unrestricted("default", config.requirepass.clone()))] };
+        if let Some(path) = &config.aclfile {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| anyhow::anyhow!("reading aclfile {path}: {e}"))?;
+            acl.apply(&text).map_err(|e| anyhow::anyhow!("{path}:{e}"))?;
+        }
+        Ok(acl)
+    }
+
+    /// adds the users in aclfile `text`, errors are prefixed with their line number
+    pub fn apply(&mut self, text: &str) -> anyhow::Result<()> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let at = |e: String| anyhow::anyhow!("{}: {e}", i + 1);
+            let user = match tokenize_inline(line).map_err(at)?.as_slice() {
+                [keyword, name, rules @ ..] if keyword.eq_ignore_ascii_case("user") => {
+                    User::parse(name, rules).map_err(|e| at(e.to_string()))?
+                }
+                _ => return Err(at(format!("expected `user <name> [rule ...]`, got '{line}'"))),
+            };
+            self.users.retain(|u| u.name != user.name);
+            self.users.push(Arc::new(user));
+        }
+        Ok(())
+    }
+
+    pub fn user(&self, name: &str) -> Option<&Arc<User>> {
+        self.users.iter().find(|u| u.name == name)
+    }
+
+    /// the user a new connection runs as, None when it has to AUTH first
+    pub fn initial_user(&self) -> Option<Arc<User>> {
+        self.user("default").filter(|u| u.password.is_none()).cloned()
+    }
+
+    /// the user named `name` if `password` is theirs
+    pub fn authenticate(&self, name: &str, password: &str) -> Option<Arc<User>> {
+        let user = self.user(name)?;
+        match &user.password {
+            None => Some(user.clone()),
+            Some(expected) => constant_time_eq(password.as_bytes(), expected.as_bytes()).then(|| user.clone()),
+        }
+    }
+}
+
+/// compares without returning early, so timing doesn't leak how much of a guess was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -1,6 +1,13 @@
 use serde::{Deserialize, Serialize};
-use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::mpsc};
-use std::{fs, io::{BufRead, BufReader}, path::Path};
+use tokio::{fs::OpenOptions, io::{AsyncWriteExt, BufWriter}, sync::{mpsc, oneshot, Notify}, time::MissedTickBehavior};
+use tracing::{error, info, warn};
+use std::{
+    fs,
+    path::Path,
+    sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use parking_lot::Mutex;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogEntry {
@@ -8,76 +15,1572 @@ pub struct LogEntry {
     pub key: String,
     pub value: Option<String>,
     pub expires_at_ms: Option<i64>,
+    /// members for collection ops, omitted for plain string entries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<String>>,
+}
+
+impl LogEntry {
+    /// marks where snapshot `id` was taken: everything logged before it is in
+    /// the snapshot, so replay can start from the snapshot and skip to here
+    pub fn snapshot_marker(id: u64) -> Self {
+        LogEntry { op: "snapshot".into(), key: id.to_string(), value: None, expires_at_ms: None, values: None }
+    }
+
+    /// the snapshot this entry marks, if it is a marker
+    pub fn snapshot_id(&self) -> Option<u64> {
+        (self.op == "snapshot").then(|| self.key.parse().ok()).flatten()
+    }
+
+    /// the entry as written to a JSON AOF, without the newline: its JSON
+    /// prefixed with the JSON's CRC-32 in hex
+    pub fn to_line(&self) -> serde_json::Result<String> {
+        let json = serde_json::to_string(self)?;
+        Ok(format!("{:08x} {json}", crc32(json.as_bytes())))
+    }
+
+    /// the entry as written to a binary AOF: the payload's length and CRC-32
+    /// (u32 each), then the payload
+    ///
+    /// ```text
+    /// payload: op (u8 length, bytes), flags u8, key
+    ///          then, per flag: value, expires_at_ms i64, values (u32 count, each)
+    /// strings: u32 length, then the bytes
+    /// ```
+    ///
+    /// integers are little-endian
+    pub fn to_frame(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(16 + self.op.len() + self.key.len());
+        payload.push(self.op.len() as u8);
+        payload.extend(self.op.as_bytes());
+        let flag = |present: bool, bit: u8| if present { bit } else { 0 };
+        payload.push(
+            flag(self.value.is_some(), HAS_VALUE)
+                | flag(self.expires_at_ms.is_some(), HAS_EXPIRY)
+                | flag(self.values.is_some(), HAS_VALUES),
+        );
+        put_str(&mut payload, &self.key);
+        if let Some(value) = &self.value {
+            put_str(&mut payload, value);
+        }
+        if let Some(ms) = self.expires_at_ms {
+            payload.extend(ms.to_le_bytes());
+        }
+        if let Some(values) = &self.values {
+            payload.extend((values.len() as u32).to_le_bytes());
+            for value in values {
+                put_str(&mut payload, value);
+            }
+        }
+        let mut frame = Vec::with_capacity(8 + payload.len());
+        frame.extend((payload.len() as u32).to_le_bytes());
+        frame.extend(crc32(&payload).to_le_bytes());
+        frame.extend(payload);
+        frame
+    }
+
+    /// the entry as written to an AOF in `format`, with its line ending
+    fn encode(&self, format: AofFormat) -> serde_json::Result<Vec<u8>> {
+        match format {
+            AofFormat::Json => self.to_line().map(|line| (line + "\n").into_bytes()),
+            AofFormat::Binary => Ok(self.to_frame()),
+        }
+    }
+}
+
+/// how entries are written to new AOF files. each file starts in one format
+/// for good, replay tells them apart by their headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AofFormat {
+    /// one JSON entry per line, readable with standard tools
+    Json,
+    /// length-prefixed binary frames after a header, smaller and cheaper to write
+    Binary,
+}
+
+impl AofFormat {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "json" => Some(AofFormat::Json),
+            "binary" => Some(AofFormat::Binary),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AofFormat::Json => "json",
+            AofFormat::Binary => "binary",
+        }
+    }
+
+    /// the format of an AOF file starting with `bytes`
+    fn detect(bytes: &[u8]) -> Self {
+        if bytes.starts_with(BINARY_MAGIC) { AofFormat::Binary } else { AofFormat::Json }
+    }
+}
+
+/// the writer stops adding queued entries to a batch past this many bytes
+const BATCH_BYTES: usize = 1024 * 1024;
+
+/// starts a binary AOF file, followed by the format version
+const BINARY_MAGIC: &[u8] = b"KVAOF";
+/// bumped whenever the binary layout changes, older versions are refused
+pub const BINARY_VERSION: u8 = 1;
+const HAS_VALUE: u8 = 1;
+const HAS_EXPIRY: u8 = 2;
+const HAS_VALUES: u8 = 4;
+
+fn binary_header() -> Vec<u8> {
+    [BINARY_MAGIC, &[BINARY_VERSION]].concat()
+}
+
+/// starts the header line of a JSON AOF file, followed by the format version
+/// and the unix time in milliseconds the file was created
+const JSON_MAGIC: &[u8] = b"#KVAOF-JSON ";
+/// bumped whenever the JSON layout changes. files from before the header
+/// line are version 0, and newer versions are refused
+pub const JSON_VERSION: u8 = 1;
+
+/// what a new AOF file in `format` starts with
+fn file_header(format: AofFormat) -> Vec<u8> {
+    match format {
+        AofFormat::Binary => binary_header(),
+        AofFormat::Json => {
+            let created_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+            [JSON_MAGIC, format!("{JSON_VERSION} {created_ms}\n").as_bytes()].concat()
+        }
+    }
+}
+
+/// the format version and creation time in a JSON AOF's header `line`,
+/// None if it isn't a complete one
+fn parse_json_header(line: &[u8]) -> Option<(u8, u64)> {
+    let rest = std::str::from_utf8(line.strip_prefix(JSON_MAGIC)?).ok()?.strip_suffix('\n')?;
+    let (version, created_ms) = rest.trim_end().split_once(' ')?;
+    Some((version.parse().ok()?, created_ms.parse().ok()?))
+}
+
+/// the format version of the AOF file at `path`, for refusing to append to
+/// one newer than this build writes. an empty or missing file is None
+fn version_of(path: &str) -> std::io::Result<Option<(AofFormat, u8)>> {
+    use std::io::{BufRead, Read};
+
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut head = Vec::new();
+    std::io::BufReader::new(file.take(256)).read_until(b'\n', &mut head)?;
+    Ok(match AofFormat::detect(&head) {
+        _ if head.is_empty() => None,
+        AofFormat::Binary => head.get(BINARY_MAGIC.len()).map(|&version| (AofFormat::Binary, version)),
+        AofFormat::Json => match parse_json_header(&head) {
+            Some((version, _)) => Some((AofFormat::Json, version)),
+            None => Some((AofFormat::Json, 0)),
+        },
+    })
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    out.extend((s.len() as u32).to_le_bytes());
+    out.extend(s.as_bytes());
+}
+
+/// parses a frame's payload, see `LogEntry::to_frame`
+fn decode_payload(mut p: &[u8]) -> Option<LogEntry> {
+    fn take<'a>(p: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+        let (taken, rest) = p.split_at_checked(n)?;
+        *p = rest;
+        Some(taken)
+    }
+    fn u32(p: &mut &[u8]) -> Option<u32> {
+        Some(u32::from_le_bytes(take(p, 4)?.try_into().ok()?))
+    }
+    fn string(p: &mut &[u8], len: usize) -> Option<String> {
+        String::from_utf8(take(p, len)?.to_vec()).ok()
+    }
+    let op_len = take(&mut p, 1)?[0] as usize;
+    let op = string(&mut p, op_len)?;
+    let flags = take(&mut p, 1)?[0];
+    let key_len = u32(&mut p)? as usize;
+    let key = string(&mut p, key_len)?;
+    let value = match flags & HAS_VALUE {
+        0 => None,
+        _ => {
+            let len = u32(&mut p)? as usize;
+            Some(string(&mut p, len)?)
+        }
+    };
+    let expires_at_ms = match flags & HAS_EXPIRY {
+        0 => None,
+        _ => Some(i64::from_le_bytes(take(&mut p, 8)?.try_into().ok()?)),
+    };
+    let values = match flags & HAS_VALUES {
+        0 => None,
+        _ => {
+            let count = u32(&mut p)?;
+            let mut values = Vec::new();
+            for _ in 0..count {
+                let len = u32(&mut p)? as usize;
+                values.push(string(&mut p, len)?);
+            }
+            Some(values)
+        }
+    };
+    p.is_empty().then_some(LogEntry { op, key, value, expires_at_ms, values })
+}
+
+/// why an AOF line didn't load
+enum BadLine {
+    Parse(serde_json::Error),
+    Checksum,
+}
+
+/// parses a line written by `to_line`, or a bare JSON line from before
+/// checksums, returning the entry and whether a checksum vouched for it
+fn parse_line(line: &[u8]) -> Result<(LogEntry, bool), BadLine> {
+    let line = line.trim_ascii();
+    if line.starts_with(b"{") {
+        return serde_json::from_slice(line).map(|entry| (entry, false)).map_err(BadLine::Parse);
+    }
+    let (crc, json) = line.split_at(line.iter().position(|&b| b == b' ').ok_or(BadLine::Checksum)?);
+    let crc = std::str::from_utf8(crc).ok().and_then(|crc| u32::from_str_radix(crc, 16).ok());
+    let json = &json[1..];
+    if crc != Some(crc32(json)) {
+        return Err(BadLine::Checksum);
+    }
+    serde_json::from_slice(json).map(|entry| (entry, true)).map_err(BadLine::Parse)
+}
+
+/// CRC-32 with the IEEE polynomial, as in zlib and gzip
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut c = i as u32;
+            let mut k = 0;
+            while k < 8 {
+                c = if c & 1 == 1 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+                k += 1;
+            }
+            table[i] = c;
+            i += 1;
+        }
+        table
+    };
+    !bytes.iter().fold(!0u32, |c, &b| TABLE[((c ^ b as u32) & 0xff) as usize] ^ (c >> 8))
+}
+
+/// what replay does with an entry whose checksum doesn't match
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumPolicy {
+    /// refuse to start
+    Abort,
+    /// leave the entry out and carry on
+    Skip,
+}
+
+impl ChecksumPolicy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "abort" => Some(ChecksumPolicy::Abort),
+            "skip" => Some(ChecksumPolicy::Skip),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChecksumPolicy::Abort => "abort",
+            ChecksumPolicy::Skip => "skip",
+        }
+    }
+}
+
+/// what `Aof::replay` read
+#[derive(Debug, Default)]
+pub struct ReplaySummary {
+    pub entries: Vec<LogEntry>,
+    /// size of a half-written last entry that was left out, 0 if the AOF ended cleanly
+    pub truncated_bytes: u64,
+    /// entries left out because their checksum didn't match
+    pub checksum_failures: u64,
+    /// entries loaded from before checksums, which nothing could verify
+    pub unverified: u64,
+    /// the file holding that entry and where it starts
+    truncate_at: Option<(String, u64)>,
+}
+
+impl ReplaySummary {
+    /// cuts the half-written last entry off its file, so new entries are
+    /// appended on a line of their own
+    pub fn repair(&self) -> std::io::Result<()> {
+        if let Some((file, len)) = &self.truncate_at {
+            let file = fs::OpenOptions::new().write(true).open(file)?;
+            file.set_len(*len)?;
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// messages handled by the writer task
+enum AofMsg {
+    Entry(LogEntry),
+    /// flush + fsync everything queued so far, then ack
+    Flush(oneshot::Sender<()>),
+    /// close the live segment and start the next one, then ack
+    Rotate(oneshot::Sender<()>),
+    /// keep a copy of every entry from now on for the rewritten file
+    StartRewrite,
+    /// append the kept entries to the rewritten file at the given path and
+    /// swap it in for every segment, then ack
+    FinishRewrite(String, AofFormat, oneshot::Sender<std::io::Result<()>>),
+    /// stop keeping entries, the rewrite failed
+    AbortRewrite,
 }
 
 #[derive(Clone)]
 pub struct Aof {
-    tx: mpsc::UnboundedSender<LogEntry>,
+    tx: mpsc::UnboundedSender<AofMsg>,
+    /// live file size in bytes that triggers a rotation, 0 disables
+    rotate_size: Arc<AtomicU64>,
+    /// entries logged but not yet written, how far the disk is behind
+    pending: Arc<AtomicU64>,
+    /// highest `pending` has been
+    pending_peak: Arc<AtomicU64>,
+    queue: Arc<Queue>,
+    /// false while the writer can't write, or once it has stopped
+    healthy: Arc<AtomicBool>,
+    fsync: Arc<Mutex<AppendFsync>>,
+    /// format for new files, a file already started keeps its own
+    format: Arc<Mutex<AofFormat>>,
+    /// what the writer has done so far, updated by the writer task
+    progress: Arc<Progress>,
+    path: Arc<str>,
+    growth: Arc<Growth>,
+    rewriting: Arc<AtomicBool>,
+    /// unix time of the last finished rewrite, 0 before the first
+    last_rewrite: Arc<AtomicU64>,
+    last_rewrite_ok: Arc<AtomicBool>,
+}
+
+/// counters the writer task keeps for `Aof::stats`
+#[derive(Default)]
+struct Progress {
+    entries: AtomicU64,
+    bytes: AtomicU64,
+    /// unix time of the last write, 0 before the first
+    last_write: AtomicU64,
+    /// unix time of the last fsync, 0 before the first
+    last_fsync: AtomicU64,
+    /// bytes across the segments as of the last fsync, see `Aof::current_offset`
+    durable: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl Progress {
+    fn failed(&self, e: &impl std::fmt::Display) {
+        *self.last_error.lock() = Some(e.to_string());
+    }
+}
+
+/// how many entries may wait for the writer before writes are held back
+struct Queue {
+    /// 0 means no limit
+    capacity: AtomicU64,
+    policy: Mutex<QueueFullPolicy>,
+    /// notified whenever the writer takes entries off the queue
+    room: Notify,
+}
+
+/// what a write command does when the AOF queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueFullPolicy {
+    /// wait for the writer to catch up
+    Block,
+    /// fail right away, the client can retry
+    Error,
+}
+
+impl QueueFullPolicy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "block" => Some(QueueFullPolicy::Block),
+            "error" => Some(QueueFullPolicy::Error),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QueueFullPolicy::Block => "block",
+            QueueFullPolicy::Error => "error",
+        }
+    }
+}
+
+/// clears `healthy` when the writer task ends, by returning or by panicking
+struct WriterAlive(Arc<AtomicBool>);
+
+impl Drop for WriterAlive {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+/// how big the AOF has grown since it was last rewritten, and how big it may
+/// get before an automatic rewrite, like redis' auto-aof-rewrite-*
+struct Growth {
+    /// bytes across the AOF's segments
+    size: AtomicU64,
+    /// `size` after the last rewrite, or at startup
+    base: AtomicU64,
+    /// rewrite once `size` is this many percent over `base`, 0 disables
+    percentage: AtomicU64,
+    /// but not while `size` is below this many bytes
+    min_size: AtomicU64,
+    /// woken by the writer once a rewrite is due
+    due: Notify,
+}
+
+impl Growth {
+    fn is_due(&self) -> bool {
+        let percentage = self.percentage.load(Ordering::Relaxed);
+        let size = self.size.load(Ordering::Relaxed);
+        // an AOF that started out empty has grown without bound
+        let base = self.base.load(Ordering::Relaxed).max(1);
+        percentage > 0
+            && size >= self.min_size.load(Ordering::Relaxed)
+            && u128::from(size) * 100 >= u128::from(base) * u128::from(100 + percentage)
+    }
+}
+
+/// when the writer fsyncs the AOF, redis' appendfsync
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppendFsync {
+    /// after every entry
+    Always,
+    /// once a second, at most a second of writes is lost on power failure
+    EverySec,
+    /// never, the OS decides when data reaches the disk
+    No,
+}
+
+impl AppendFsync {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "always" => Some(AppendFsync::Always),
+            "everysec" => Some(AppendFsync::EverySec),
+            "no" => Some(AppendFsync::No),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AppendFsync::Always => "always",
+            AppendFsync::EverySec => "everysec",
+            AppendFsync::No => "no",
+        }
+    }
+}
+
+/// what the writer has been doing, for INFO and for embedders
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AofStats {
+    pub fsync: AppendFsync,
+    /// entries written since startup, not counting rewrites
+    pub entries_written: u64,
+    pub bytes_written: u64,
+    /// unix time of the last write, 0 before the first
+    pub last_write: u64,
+    /// unix time of the last fsync, 0 before the first
+    pub last_fsync: u64,
+    /// the most recent write, fsync or rotate failure, kept after recovering
+    pub last_error: Option<String>,
+    /// entries logged but not yet written
+    pub pending: u64,
+    /// the most entries ever waiting at once, a sign of the writer falling behind
+    pub pending_peak: u64,
+    /// see `Aof::is_healthy`
+    pub healthy: bool,
+    pub rewrite_in_progress: bool,
+    /// unix time of the last finished rewrite, 0 before the first
+    pub last_rewrite: u64,
+    /// whether the last rewrite succeeded, true before the first
+    pub last_rewrite_ok: bool,
+    /// bytes across the AOF's segments
+    pub current_size: u64,
+    /// size after the last rewrite, or at startup
+    pub base_size: u64,
+}
+
+impl AofStats {
+    /// the stats as INFO's persistence fields, in order. anything else that
+    /// reports them renders from this, so the two can't disagree
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let status = |ok: bool| if ok { "ok" } else { "err" }.to_string();
+        let last_rewrite = if self.last_rewrite == 0 { -1 } else { self.last_rewrite as i64 };
+        vec![
+            ("aof_pending_entries", self.pending.to_string()),
+            ("aof_pending_peak", self.pending_peak.to_string()),
+            ("aof_entries_written", self.entries_written.to_string()),
+            ("aof_bytes_written", self.bytes_written.to_string()),
+            ("aof_last_write_time", self.last_write.to_string()),
+            ("aof_last_write_status", status(self.healthy)),
+            ("aof_last_error", self.last_error.clone().unwrap_or_default()),
+            ("aof_fsync", self.fsync.as_str().to_string()),
+            ("aof_last_fsync_time", self.last_fsync.to_string()),
+            ("aof_rewrite_in_progress", (self.rewrite_in_progress as u8).to_string()),
+            ("aof_last_rewrite_time", last_rewrite.to_string()),
+            ("aof_last_bgrewrite_status", status(self.last_rewrite_ok)),
+            ("aof_current_size", self.current_size.to_string()),
+            ("aof_base_size", self.base_size.to_string()),
+        ]
+    }
+}
+
+/// first line of a manifest, naming its format
+const MANIFEST_HEADER: &str = "kvstore-aof-manifest 1";
+/// last line of a manifest, so one cut short can't pass for a shorter list
+const MANIFEST_END: &str = "end";
+
+/// path of segment `n` of the AOF at `path`
+fn segment_path(path: &str, n: u64) -> String {
+    format!("{path}.{n}")
+}
+
+/// which segment of the AOF at `path` the file at `file` is, if any
+fn segment_number(path: &str, file: &str) -> Option<u64> {
+    file.strip_prefix(path)?.strip_prefix('.')?.parse().ok()
+}
+
+/// path of the manifest listing the live segments of the AOF at `path`
+pub fn manifest_path(path: &str) -> String {
+    format!("{path}.manifest")
+}
+
+/// the files making up the AOF at `path`, oldest first, the last being the
+/// one appended to. that's what the manifest lists, or, when the manifest is
+/// missing or damaged, every numbered segment on disk in order. an AOF from
+/// before segments, a single `path` file, comes after those
+pub fn segments(path: &str) -> Vec<String> {
+    match read_manifest(path) {
+        Ok(Some(files)) => return files,
+        Ok(None) => {}
+        Err(e) => warn!(manifest = %manifest_path(path), error = %e, "ignoring the AOF manifest, using the segments on disk"),
+    }
+    // a rewrite's base segment is numbered after the ones it replaced and
+    // starts with a flushall, so leftovers from before it replay harmlessly
+    scan_segments(path).into_iter()
+        .map(|n| segment_path(path, n))
+        .chain(Path::new(path).is_file().then(|| path.to_string()))
+        .collect()
+}
+
+/// numbers of the segments of the AOF at `path` found on disk, ascending
+fn scan_segments(path: &str) -> Vec<u64> {
+    let file = Path::new(path);
+    let dir = match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = file_name(path);
+    let mut numbers: Vec<u64> = fs::read_dir(dir).into_iter().flatten()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| segment_number(&name, &entry.file_name().to_string_lossy()))
+        .collect();
+    numbers.sort_unstable();
+    numbers
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path).file_name().unwrap_or_default().to_string_lossy().into_owned()
+}
+
+/// the segments listed in the manifest of the AOF at `path`, None if there's
+/// no manifest. a manifest that is cut short, lists anything but this AOF's
+/// segments or lists one that is gone is an error
+fn read_manifest(path: &str) -> anyhow::Result<Option<Vec<String>>> {
+    let text = match fs::read_to_string(manifest_path(path)) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut lines = text.lines();
+    if lines.next() != Some(MANIFEST_HEADER) {
+        anyhow::bail!("not an AOF manifest");
+    }
+    let name = file_name(path);
+    let mut files = Vec::new();
+    for line in lines.by_ref() {
+        if line == MANIFEST_END {
+            break;
+        }
+        let n = segment_number(&name, line).ok_or_else(|| anyhow::anyhow!("'{line}' is not a segment of {path}"))?;
+        let file = segment_path(path, n);
+        if !Path::new(&file).exists() {
+            anyhow::bail!("segment {file} is missing");
+        }
+        files.push(file);
+    }
+    if text.lines().last() != Some(MANIFEST_END) || files.is_empty() {
+        anyhow::bail!("manifest is incomplete");
+    }
+    Ok(Some(files))
+}
+
+/// replaces the manifest of the AOF at `path` with one listing `files`,
+/// through a synced temp file and a rename, so it's either the old list or the new
+async fn write_manifest(path: &str, files: &[String]) -> std::io::Result<()> {
+    let mut text = format!("{MANIFEST_HEADER}\n");
+    for file in files {
+        text.push_str(&format!("{}\n", file_name(file)));
+    }
+    text.push_str(&format!("{MANIFEST_END}\n"));
+    let manifest = manifest_path(path);
+    let tmp = format!("{manifest}.tmp");
+    let mut file = tokio::fs::File::create(&tmp).await?;
+    file.write_all(text.as_bytes()).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp, &manifest).await
 }
 
 impl Aof {
+    /// opens the AOF at `path` for appending. a live segment in a format
+    /// version newer than this build writes is refused
     pub async fn new(path: &str) -> anyhow::Result<Self> {
-        // check that the file exists
-        if !Path::new(path).exists() {
-            tokio::fs::File::create(path).await?;
+        let (mut files, mut next_segment) = open_segments(path).await?;
+        let newer = match version_of(live(&files))? {
+            Some((AofFormat::Json, version)) => (version > JSON_VERSION).then_some(("JSON", version, JSON_VERSION)),
+            Some((AofFormat::Binary, version)) => (version > BINARY_VERSION).then_some(("binary", version, BINARY_VERSION)),
+            None => None,
+        };
+        if let Some((format, version, known)) = newer {
+            anyhow::bail!(
+                "{} is a {format} AOF in format version {version}, newer than this build writes ({known}), refusing to append to it",
+                live(&files),
+            );
         }
-        let (tx, mut rx) = mpsc::unbounded_channel::<LogEntry>();
+        let (tx, mut rx) = mpsc::unbounded_channel::<AofMsg>();
+        let rotate_size = Arc::new(AtomicU64::new(0));
+        let threshold = rotate_size.clone();
+        let pending = Arc::new(AtomicU64::new(0));
+        let queued = pending.clone();
+        let queue = Arc::new(Queue {
+            capacity: AtomicU64::new(0),
+            policy: Mutex::new(QueueFullPolicy::Block),
+            room: Notify::new(),
+        });
+        let drained = queue.clone();
+        let healthy = Arc::new(AtomicBool::new(true));
+        let alive = WriterAlive(healthy.clone());
+        let fsync = Arc::new(Mutex::new(AppendFsync::EverySec));
+        let policy = fsync.clone();
+        let format = Arc::new(Mutex::new(AofFormat::Json));
+        let new_format = format.clone();
+        let progress = Arc::new(Progress::default());
+        let synced = progress.clone();
+        let size: u64 = files.iter()
+            .filter_map(|p| fs::metadata(p).ok())
+            .map(|m| m.len())
+            .sum();
+        // what's already there survived whatever came before this process
+        progress.durable.store(size, Ordering::Relaxed);
+        let growth = Arc::new(Growth {
+            size: AtomicU64::new(size),
+            base: AtomicU64::new(size),
+            percentage: AtomicU64::new(100),
+            min_size: AtomicU64::new(64 * 1024 * 1024),
+            due: Notify::new(),
+        });
+        let grown = growth.clone();
+        let rewriting = Arc::new(AtomicBool::new(false));
+        let in_rewrite = rewriting.clone();
+        let aof_path: Arc<str> = path.into();
         let path = path.to_string();
 
         tokio::spawn(async move {
+            let alive = alive;
             let file_res = OpenOptions::new()
                 .create(true)
                 .append(true)
-                .open(&path)
+                .open(files.last().expect("open_segments leaves one"))
                 .await;
 
             let mut file = match file_res {
                 Ok(f) => f,
                 Err(e) => {
-                    eprintln!("AOF open error: {e}");
+                    error!(path = %path, error = %e, "AOF open failed");
                     return;
                 }
             };
+            let mut written = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+            // the live file's format, None until its first entry picks one
+            let mut live_format = match written {
+                0 => None,
+                _ => Some(AofFormat::detect(&read_start(live(&files)).await)),
+            };
+            // entries written since the last fsync
+            let mut dirty = false;
+            let mut tick = tokio::time::interval(Duration::from_secs(1));
+            tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            // entries logged since a rewrite took its snapshot
+            let mut rewrite: Option<Vec<LogEntry>> = None;
+            // a message that ended a batch of entries, handled next
+            let mut deferred = None;
 
-            while let Some(entry) = rx.recv().await {
-                if let Ok(line) = serde_json::to_string(&entry) {
-                    if let Err(e) = file.write_all(line.as_bytes()).await {
-                        eprintln!("AOF write error: {e}");
-                        break;
+            loop {
+                let everysec = dirty && *policy.lock() == AppendFsync::EverySec;
+                let msg = match deferred.take() {
+                    Some(msg) => msg,
+                    None => tokio::select! {
+                        msg = rx.recv() => match msg {
+                            Some(msg) => msg,
+                            None => break,
+                        },
+                        _ = tick.tick(), if everysec => {
+                            sync(&mut file, live(&files), &synced, grown.size.load(Ordering::Relaxed)).await;
+                            dirty = false;
+                            continue;
+                        }
+                    },
+                };
+                match msg {
+                    AofMsg::Entry(entry) => {
+                        // take whatever else is queued along, so a burst of
+                        // entries is one write and at most one fsync
+                        let format = *live_format.get_or_insert_with(|| *new_format.lock());
+                        let mut batch = match written {
+                            0 => file_header(format),
+                            _ => Vec::new(),
+                        };
+                        // a batch also ends where the file is due to rotate
+                        let limit = threshold.load(Ordering::Relaxed);
+                        let full = |len: usize| len >= BATCH_BYTES || (limit > 0 && written + len as u64 >= limit);
+                        let mut count = 0;
+                        let mut next = Some(entry);
+                        while let Some(entry) = next.take() {
+                            count += 1;
+                            if let Ok(bytes) = entry.encode(format) {
+                                batch.extend(bytes);
+                                if let Some(kept) = &mut rewrite {
+                                    kept.push(entry);
+                                }
+                            }
+                            if !full(batch.len()) {
+                                match rx.try_recv() {
+                                    Ok(AofMsg::Entry(entry)) => next = Some(entry),
+                                    Ok(other) => deferred = Some(other),
+                                    Err(_) => {}
+                                }
+                            }
+                        }
+                        // flushed so a failed write shows up here, with its batch
+                        let res = async {
+                            file.write_all(&batch).await?;
+                            file.flush().await
+                        }.await;
+                        if let Err(e) = res {
+                            error!(path = %live(&files), error = %e, "AOF write failed, retrying");
+                            synced.failed(&e);
+                            alive.0.store(false, Ordering::Relaxed);
+                            file = rewrite_batch(live(&files), written, &batch).await;
+                            alive.0.store(true, Ordering::Relaxed);
+                        }
+                        written += batch.len() as u64;
+                        grown.size.fetch_add(batch.len() as u64, Ordering::Relaxed);
+                        synced.entries.fetch_add(count, Ordering::Relaxed);
+                        synced.bytes.fetch_add(batch.len() as u64, Ordering::Relaxed);
+                        synced.last_write.store(unix_now(), Ordering::Relaxed);
+                        dirty = true;
+                        if !in_rewrite.load(Ordering::Relaxed) && grown.is_due() {
+                            grown.due.notify_one();
+                        }
+                        if *policy.lock() == AppendFsync::Always {
+                            sync(&mut file, live(&files), &synced, grown.size.load(Ordering::Relaxed)).await;
+                            dirty = false;
+                        }
+                        queued.fetch_sub(count, Ordering::Relaxed);
+                        drained.room.notify_waiters();
+                        if limit > 0 && written >= limit {
+                            match rotate(&path, &mut files, next_segment, &mut file).await {
+                                Ok(fresh) => {
+                                    file = fresh;
+                                    next_segment += 1;
+                                    written = 0;
+                                    live_format = None;
+                                    dirty = false;
+                                    synced.durable.store(grown.size.load(Ordering::Relaxed), Ordering::Relaxed);
+                                }
+                                Err(e) => {
+                                    error!(path = %path, error = %e, "AOF rotate failed, staying on the current segment");
+                                    synced.failed(&e);
+                                }
+                            }
+                        }
                     }
-                    if let Err(e) = file.write_all(b"\n").await {
-                        eprintln!("AOF write error: {e}");
-                        break;
+                    AofMsg::Rotate(ack) => {
+                        match rotate(&path, &mut files, next_segment, &mut file).await {
+                            Ok(fresh) => {
+                                file = fresh;
+                                next_segment += 1;
+                                written = 0;
+                                live_format = None;
+                                dirty = false;
+                                synced.durable.store(grown.size.load(Ordering::Relaxed), Ordering::Relaxed);
+                            }
+                            Err(e) => {
+                                error!(path = %path, error = %e, "AOF rotate failed, staying on the current segment");
+                                synced.failed(&e);
+                            }
+                        }
+                        let _ = ack.send(());
+                    }
+                    AofMsg::Flush(ack) => {
+                        sync(&mut file, live(&files), &synced, grown.size.load(Ordering::Relaxed)).await;
+                        dirty = false;
+                        let _ = ack.send(());
+                    }
+                    AofMsg::StartRewrite => rewrite = Some(Vec::new()),
+                    AofMsg::AbortRewrite => rewrite = None,
+                    AofMsg::FinishRewrite(tmp, format, ack) => {
+                        let kept = rewrite.take().unwrap_or_default();
+                        match swap_in(&path, &tmp, format, &kept, next_segment, &mut files).await {
+                            Ok(fresh) => {
+                                file = fresh;
+                                next_segment += 1;
+                                written = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+                                live_format = (written > 0).then_some(format);
+                                grown.size.store(written, Ordering::Relaxed);
+                                grown.base.store(written, Ordering::Relaxed);
+                                synced.durable.store(written, Ordering::Relaxed);
+                                dirty = false;
+                                let _ = ack.send(Ok(()));
+                            }
+                            // the live file is untouched, carry on with it
+                            Err(e) => {
+                                let _ = ack.send(Err(e));
+                            }
+                        }
                     }
-                    // fsync could be added; omitted for perf
                 }
             }
+            // every handle is gone, don't leave the tail to the OS unless asked to
+            if dirty && *policy.lock() != AppendFsync::No {
+                sync(&mut file, live(&files), &synced, grown.size.load(Ordering::Relaxed)).await;
+            }
         });
 
-        Ok(Self { tx })
+        Ok(Self {
+            tx,
+            rotate_size,
+            pending,
+            pending_peak: Arc::new(AtomicU64::new(0)),
+            queue,
+            healthy,
+            fsync,
+            format,
+            progress,
+            path: aof_path,
+            growth,
+            rewriting,
+            last_rewrite: Arc::new(AtomicU64::new(0)),
+            last_rewrite_ok: Arc::new(AtomicBool::new(true)),
+        })
+    }
+
+    pub fn rotate_size(&self) -> u64 {
+        self.rotate_size.load(Ordering::Relaxed)
+    }
+
+    /// rotate once the live file reaches `bytes`, 0 turns rotation off
+    pub fn set_rotate_size(&self, bytes: u64) {
+        self.rotate_size.store(bytes, Ordering::Relaxed);
+    }
+
+    /// closes the live segment and continues in a fresh one after it, e.g.
+    /// to hand the closed ones to a backup
+    pub async fn rotate(&self) -> anyhow::Result<()> {
+        let (ack, done) = oneshot::channel();
+        self.tx
+            .send(AofMsg::Rotate(ack))
+            .map_err(|_| anyhow::anyhow!("AOF writer is not running"))?;
+        done.await.map_err(|_| anyhow::anyhow!("AOF writer stopped before rotating"))?;
+        Ok(())
     }
 
     pub fn log(&self, entry: LogEntry) {
         // fire n forget
-        let _ = self.tx.send(entry);
+        let depth = self.pending.fetch_add(1, Ordering::Relaxed) + 1;
+        self.pending_peak.fetch_max(depth, Ordering::Relaxed);
+        if self.tx.send(AofMsg::Entry(entry)).is_err() {
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+            self.healthy.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// false while the writer is failing to write (it retries, and recovers
+    /// when the file can be written again) or after it stopped for good
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// (capacity, policy) of the queue of entries waiting for the writer.
+    /// the limit is applied to write commands before they run, `log` itself
+    /// never waits since it's called with the keyspace locked
+    pub fn queue_limit(&self) -> (u64, QueueFullPolicy) {
+        (self.queue.capacity.load(Ordering::Relaxed), *self.queue.policy.lock())
     }
 
-    pub fn replay(path: &str) -> anyhow::Result<Vec<LogEntry>> {
-        if !Path::new(path).exists() {
-            return Ok(vec![]);
+    /// a capacity of 0 leaves the queue unbounded
+    pub fn set_queue_limit(&self, capacity: u64, policy: QueueFullPolicy) {
+        self.queue.capacity.store(capacity, Ordering::Relaxed);
+        *self.queue.policy.lock() = policy;
+        self.queue.room.notify_waiters();
+    }
+
+    /// whether the queue is under its capacity
+    pub fn has_room(&self) -> bool {
+        let capacity = self.queue.capacity.load(Ordering::Relaxed);
+        capacity == 0 || self.pending() < capacity
+    }
+
+    /// waits until the queue has room, or the writer is unhealthy and won't
+    /// be taking entries off it
+    pub async fn wait_for_room(&self) {
+        loop {
+            let notified = self.queue.room.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.has_room() || !self.is_healthy() {
+                return;
+            }
+            // unhealthy doesn't notify, so look again now and then
+            let _ = tokio::time::timeout(Duration::from_millis(100), notified).await;
+        }
+    }
+
+    /// entries logged but not yet written to the file
+    pub fn pending(&self) -> u64 {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    pub fn fsync(&self) -> AppendFsync {
+        *self.fsync.lock()
+    }
+
+    pub fn set_fsync(&self, policy: AppendFsync) {
+        *self.fsync.lock() = policy;
+    }
+
+    pub fn format(&self) -> AofFormat {
+        *self.format.lock()
+    }
+
+    /// the format for files started from now on: after a rotation or
+    /// rewrite, or an empty live file's first entry
+    pub fn set_format(&self, format: AofFormat) {
+        *self.format.lock() = format;
+    }
+
+    pub fn stats(&self) -> AofStats {
+        AofStats {
+            fsync: self.fsync(),
+            entries_written: self.progress.entries.load(Ordering::Relaxed),
+            bytes_written: self.progress.bytes.load(Ordering::Relaxed),
+            last_write: self.progress.last_write.load(Ordering::Relaxed),
+            last_fsync: self.progress.last_fsync.load(Ordering::Relaxed),
+            last_error: self.progress.last_error.lock().clone(),
+            pending: self.pending(),
+            pending_peak: self.pending_peak.load(Ordering::Relaxed),
+            healthy: self.is_healthy(),
+            rewrite_in_progress: self.rewriting.load(Ordering::Relaxed),
+            last_rewrite: self.last_rewrite.load(Ordering::Relaxed),
+            last_rewrite_ok: self.last_rewrite_ok.load(Ordering::Relaxed),
+            current_size: self.growth.size.load(Ordering::Relaxed),
+            base_size: self.growth.base.load(Ordering::Relaxed),
+        }
+    }
+
+    /// (percentage, min size) of the automatic rewrite thresholds
+    pub fn auto_rewrite(&self) -> (u64, u64) {
+        (self.growth.percentage.load(Ordering::Relaxed), self.growth.min_size.load(Ordering::Relaxed))
+    }
+
+    /// rewrite once the AOF has grown `percentage` percent since the last
+    /// rewrite and is at least `min_size` bytes. a percentage of 0 disables it
+    pub fn set_auto_rewrite(&self, percentage: u64, min_size: u64) {
+        self.growth.percentage.store(percentage, Ordering::Relaxed);
+        self.growth.min_size.store(min_size, Ordering::Relaxed);
+    }
+
+    /// whether the AOF has outgrown the automatic rewrite thresholds, with no
+    /// rewrite already running
+    pub fn rewrite_due(&self) -> bool {
+        !self.rewriting.load(Ordering::Relaxed) && self.growth.is_due()
+    }
+
+    /// resolves once the writer finds a rewrite due. check `rewrite_due`
+    /// afterwards, another rewrite may have got there first
+    pub async fn wait_rewrite_due(&self) {
+        self.growth.due.notified().await;
+    }
+
+    /// starts a rewrite: every entry logged from here on is kept for the
+    /// rewritten file. the caller must take its snapshot of the dataset in the
+    /// same critical section, so each write is either in it or logged after
+    pub fn begin_rewrite(&self) -> anyhow::Result<()> {
+        if self.rewriting.swap(true, Ordering::Relaxed) {
+            anyhow::bail!("Background append only file rewriting already in progress");
+        }
+        if self.tx.send(AofMsg::StartRewrite).is_err() {
+            self.rewriting.store(false, Ordering::Relaxed);
+            anyhow::bail!("AOF writer is not running");
+        }
+        Ok(())
+    }
+
+    /// writes the snapshot taken with `begin_rewrite` to a temp file, then has
+    /// the writer append what was logged since and swap it in for the
+    /// segments as a single base segment
+    pub async fn finish_rewrite(&self, snapshot: Vec<LogEntry>) -> anyhow::Result<()> {
+        let res = self.swap(snapshot).await;
+        let now = unix_now();
+        self.last_rewrite.store(now, Ordering::Relaxed);
+        self.last_rewrite_ok.store(res.is_ok(), Ordering::Relaxed);
+        self.rewriting.store(false, Ordering::Relaxed);
+        res
+    }
+
+    /// starts the AOF over once a snapshot taken with `begin_rewrite` is on
+    /// disk. the new file holds only what was logged since, starting with the
+    /// snapshot's marker
+    pub async fn finish_snapshot(&self) -> anyhow::Result<()> {
+        let res = self.swap(Vec::new()).await;
+        self.rewriting.store(false, Ordering::Relaxed);
+        res
+    }
+
+    /// gives up on a rewrite started with `begin_rewrite`, the live file carries on
+    pub fn abort_rewrite(&self) {
+        let _ = self.tx.send(AofMsg::AbortRewrite);
+        self.rewriting.store(false, Ordering::Relaxed);
+    }
+
+    async fn swap(&self, entries: Vec<LogEntry>) -> anyhow::Result<()> {
+        let tmp = format!("{}.rewrite", self.path);
+        let format = self.format();
+        let res = async {
+            write_entries(&tmp, format, &entries).await?;
+            let (ack, done) = oneshot::channel();
+            self.tx
+                .send(AofMsg::FinishRewrite(tmp.clone(), format, ack))
+                .map_err(|_| anyhow::anyhow!("AOF writer is not running"))?;
+            done.await.map_err(|_| anyhow::anyhow!("AOF writer stopped before the rewrite finished"))??;
+            anyhow::Ok(())
+        }.await;
+        if res.is_err() {
+            let _ = self.tx.send(AofMsg::AbortRewrite);
+            let _ = tokio::fs::remove_file(&tmp).await;
+        }
+        res
+    }
+
+    /// the AOF's size in bytes as of the last fsync: the live segments, taken
+    /// in order, are on disk up to this offset. it grows with writes and only
+    /// drops when a rewrite replaces the segments, so a backup that copied up
+    /// to an earlier offset can copy on from there unless the offset went down
+    pub fn current_offset(&self) -> u64 {
+        self.progress.durable.load(Ordering::Relaxed)
+    }
+
+    /// waits until every entry logged before this call is written and fsynced
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        let (ack, done) = oneshot::channel();
+        self.tx
+            .send(AofMsg::Flush(ack))
+            .map_err(|_| anyhow::anyhow!("AOF writer is not running"))?;
+        done.await.map_err(|_| anyhow::anyhow!("AOF writer stopped before flushing"))?;
+        Ok(())
+    }
+
+    /// reads every segment in order, see `segments`, one entry at a time, so
+    /// replaying never holds more of the AOF than the entry being applied. a
+    /// last entry cut short by a crash is left out and counted in the
+    /// summary, as are entries failing their checksum, for the caller to
+    /// decide on. any other bad entry is an error, since replaying past it
+    /// would apply later writes on top of a hole
+    pub fn replay_iter(path: &str) -> Replay {
+        let mut files = segments(path);
+        files.reverse();
+        Replay { files, ..Replay::default() }
+    }
+
+    /// every entry `replay_iter` yields, collected into the summary
+    pub fn replay(path: &str) -> anyhow::Result<ReplaySummary> {
+        let mut replay = Self::replay_iter(path);
+        let entries = replay.by_ref().collect::<Result<Vec<_>, _>>()?;
+        Ok(ReplaySummary { entries, ..replay.into_summary() })
+    }
+}
+
+/// why `Aof::replay_iter` stopped early
+#[derive(Debug)]
+pub enum ReplayError {
+    /// reading a segment failed
+    Io { file: String, error: std::io::Error },
+    /// an entry is damaged other than as a half-written tail, or the file is
+    /// in a format this version can't read
+    Corrupt(String),
+}
+
+impl std::fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplayError::Io { file, error } => write!(f, "reading {file}: {error}"),
+            ReplayError::Corrupt(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+/// the entries of an AOF as they're read, from `Aof::replay_iter`. nothing
+/// more comes after an error. the summary is complete once it returns None
+#[derive(Default)]
+pub struct Replay {
+    /// segments not opened yet, newest first, so the next one is popped off the end
+    files: Vec<String>,
+    current: Option<Segment>,
+    /// the counts, with `entries` left empty
+    summary: ReplaySummary,
+    entries_read: u64,
+    bytes_read: u64,
+    log_every: u64,
+    failed: bool,
+}
+
+impl Replay {
+    /// logs the entries and bytes read so far every `n` entries, 0 never does
+    pub fn log_every(mut self, n: u64) -> Self {
+        self.log_every = n;
+        self
+    }
+
+    pub fn entries_read(&self) -> u64 {
+        self.entries_read
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// the truncated tail and checksum failures found so far
+    pub fn summary(&self) -> &ReplaySummary {
+        &self.summary
+    }
+
+    pub fn into_summary(self) -> ReplaySummary {
+        self.summary
+    }
+
+    fn fail(&mut self, e: ReplayError) -> Option<Result<LogEntry, ReplayError>> {
+        self.failed = true;
+        Some(Err(e))
+    }
+}
+
+impl Iterator for Replay {
+    type Item = Result<LogEntry, ReplayError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.failed {
+            let Some(segment) = &mut self.current else {
+                let file = self.files.pop()?;
+                let last = self.files.is_empty();
+                match Segment::open(file, last, &mut self.summary) {
+                    Ok(segment) => {
+                        self.bytes_read += segment.as_ref().map_or(0, |s| s.offset);
+                        self.current = segment;
+                    }
+                    Err(e) => return self.fail(e),
+                }
+                continue;
+            };
+            let before = segment.offset;
+            let res = match segment.format {
+                AofFormat::Json => segment.next_line(&mut self.summary),
+                AofFormat::Binary => segment.next_frame(&mut self.summary),
+            };
+            self.bytes_read += segment.offset - before;
+            match res {
+                Ok(Some(entry)) => {
+                    self.entries_read += 1;
+                    if self.log_every > 0 && self.entries_read.is_multiple_of(self.log_every) {
+                        info!(entries = self.entries_read, bytes = self.bytes_read, "replaying AOF");
+                    }
+                    return Some(Ok(entry));
+                }
+                Ok(None) => self.current = None,
+                Err(e) => return self.fail(e),
+            }
+        }
+        None
+    }
+}
+
+/// bytes read from a segment at a time during replay
+const REPLAY_BUFFER: usize = 256 * 1024;
+
+/// the segment `Replay` is reading
+struct Segment {
+    file: String,
+    reader: std::io::BufReader<fs::File>,
+    format: AofFormat,
+    /// only the last segment may end in a half-written entry
+    last: bool,
+    len: u64,
+    /// bytes read so far
+    offset: u64,
+    lineno: usize,
+    /// a bad JSON line in the last segment, waiting to see whether anything
+    /// but blank lines follows it: its line number, where it starts and why
+    pending: Option<(usize, u64, BadLine)>,
+    buf: Vec<u8>,
+}
+
+impl Segment {
+    /// opens `file` and reads its header. None for a binary file whose header
+    /// was itself cut short, there's nothing in it to keep
+    fn open(file: String, last: bool, summary: &mut ReplaySummary) -> Result<Option<Self>, ReplayError> {
+        use std::io::{BufRead, Read};
+
+        let io = |error| ReplayError::Io { file: file.clone(), error };
+        let handle = fs::File::open(&file).map_err(io)?;
+        let len = handle.metadata().map_err(io)?.len();
+        let mut reader = std::io::BufReader::with_capacity(REPLAY_BUFFER, handle);
+        let format = AofFormat::detect(reader.fill_buf().map_err(io)?);
+        let mut offset = 0;
+        if format == AofFormat::Binary {
+            let mut header = [0u8; BINARY_MAGIC.len() + 1];
+            if len < header.len() as u64 {
+                if last {
+                    summary.truncated_bytes = len;
+                    summary.truncate_at = Some((file, 0));
+                }
+                return Ok(None);
+            }
+            reader.read_exact(&mut header).map_err(io)?;
+            let version = header[BINARY_MAGIC.len()];
+            if version != BINARY_VERSION {
+                return Err(ReplayError::Corrupt(format!(
+                    "{file}: binary AOF format version {version} is not supported, expected {BINARY_VERSION}"
+                )));
+            }
+            offset = header.len() as u64;
+        }
+        let mut lineno = 0;
+        if format == AofFormat::Json && reader.fill_buf().map_err(io)?.starts_with(JSON_MAGIC) {
+            let mut line = Vec::new();
+            reader.read_until(b'\n', &mut line).map_err(io)?;
+            match parse_json_header(&line) {
+                Some((version, _)) if version <= JSON_VERSION => {}
+                Some((version, _)) => {
+                    return Err(ReplayError::Corrupt(format!(
+                        "{file}: JSON AOF format version {version} is newer than this build reads ({JSON_VERSION})"
+                    )));
+                }
+                // the header itself was cut short, there's nothing to keep
+                None if last && line.len() as u64 == len => {
+                    summary.truncated_bytes = len;
+                    summary.truncate_at = Some((file, 0));
+                    return Ok(None);
+                }
+                None => return Err(ReplayError::Corrupt(format!("{file}: unreadable AOF header"))),
+            }
+            offset = line.len() as u64;
+            lineno = 1;
+        }
+        Ok(Some(Segment { file, reader, format, last, len, offset, lineno, pending: None, buf: Vec::new() }))
+    }
+
+    /// the next entry of a JSON AOF file. a bad line is tolerated as a
+    /// half-written tail only at the end of the last file
+    fn next_line(&mut self, summary: &mut ReplaySummary) -> Result<Option<LogEntry>, ReplayError> {
+        use std::io::BufRead;
+
+        loop {
+            self.buf.clear();
+            let n = self.reader.read_until(b'\n', &mut self.buf)
+                .map_err(|error| ReplayError::Io { file: self.file.clone(), error })?;
+            if n == 0 {
+                if let Some((_, start, _)) = self.pending.take() {
+                    summary.truncated_bytes = self.offset - start;
+                    summary.truncate_at = Some((self.file.clone(), start));
+                }
+                return Ok(None);
+            }
+            let start = self.offset;
+            self.offset += n as u64;
+            self.lineno += 1;
+            if self.buf.trim_ascii().is_empty() {
+                continue;
+            }
+            // more follows the bad line, so it isn't a half-written tail
+            if let Some((lineno, _, bad)) = self.pending.take() {
+                self.reject(lineno, bad, summary)?;
+            }
+            match parse_line(&self.buf) {
+                Ok((entry, verified)) => {
+                    summary.unverified += u64::from(!verified);
+                    return Ok(Some(entry));
+                }
+                Err(bad) if self.last => self.pending = Some((self.lineno, start, bad)),
+                Err(bad) => self.reject(self.lineno, bad, summary)?,
+            }
+        }
+    }
+
+    /// skips a line failing its checksum, or fails on one that doesn't parse
+    fn reject(&self, lineno: usize, bad: BadLine, summary: &mut ReplaySummary) -> Result<(), ReplayError> {
+        match bad {
+            BadLine::Checksum => {
+                warn!(file = %self.file, line = lineno, "AOF entry failed its checksum");
+                summary.checksum_failures += 1;
+                Ok(())
+            }
+            BadLine::Parse(e) => Err(ReplayError::Corrupt(format!(
+                "{} line {lineno}: corrupt AOF entry ({e}). fix or remove that line to start, \
+                 accepting the loss of whatever it held",
+                self.file,
+            ))),
         }
-        let file = fs::File::open(path)?;
-        let reader = BufReader::new(file);
+    }
+
+    /// the next entry of a binary AOF file. a frame cut short is tolerated
+    /// as a half-written tail only in the last file
+    fn next_frame(&mut self, summary: &mut ReplaySummary) -> Result<Option<LogEntry>, ReplayError> {
+        use std::io::Read;
 
-        let mut entries = Vec::new();
-        for line_res in reader.lines() {
-            let line = line_res?;
-            if line.trim().is_empty() { continue; }
-            match serde_json::from_str::<LogEntry>(&line) {
-                Ok(e) => entries.push(e),
-                Err(e) => eprintln!("AOF replay parse error: {e} (line: {line})"),
+        let io = |file: &str, error| ReplayError::Io { file: file.to_string(), error };
+        while self.offset < self.len {
+            let (start, rest) = (self.offset, self.len - self.offset);
+            let mut header = [0u8; 8];
+            let end = if rest < 8 {
+                None
+            } else {
+                self.reader.read_exact(&mut header).map_err(|e| io(&self.file, e))?;
+                let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as u64;
+                Some(start + 8 + len).filter(|&end| end <= self.len)
+            };
+            let Some(end) = end else {
+                if !self.last {
+                    return Err(ReplayError::Corrupt(format!(
+                        "{} byte {start}: AOF entry cut short. the file is not the last one, \
+                         so this is corruption rather than a half-written tail",
+                        self.file,
+                    )));
+                }
+                summary.truncated_bytes = rest;
+                summary.truncate_at = Some((self.file.clone(), start));
+                self.offset = self.len;
+                return Ok(None);
+            };
+            self.buf.resize((end - start - 8) as usize, 0);
+            self.reader.read_exact(&mut self.buf).map_err(|e| io(&self.file, e))?;
+            self.offset = end;
+            let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+            if crc != crc32(&self.buf) {
+                if self.last && end == self.len {
+                    summary.truncated_bytes = rest;
+                    summary.truncate_at = Some((self.file.clone(), start));
+                    return Ok(None);
+                }
+                // the length still frames the entry, so the ones after it are intact
+                warn!(file = %self.file, byte = start, "AOF entry failed its checksum");
+                summary.checksum_failures += 1;
+                continue;
             }
+            return match decode_payload(&self.buf) {
+                Some(entry) => Ok(Some(entry)),
+                None => Err(ReplayError::Corrupt(format!(
+                    "{} byte {start}: AOF entry passed its checksum but could not be decoded",
+                    self.file,
+                ))),
+            };
         }
-        Ok(entries)
+        Ok(None)
     }
-}
\ No newline at end of file
+}
+
+/// flushes and fsyncs `file`, recording when (or why not) in `progress`.
+/// failures are logged, the writer carries on
+async fn sync(file: &mut tokio::fs::File, path: &str, progress: &Progress, size: u64) {
+    if let Err(e) = file.flush().await {
+        error!(path = %path, error = %e, "AOF flush failed");
+        progress.failed(&e);
+    }
+    match file.sync_data().await {
+        Ok(()) => {
+            progress.last_fsync.store(unix_now(), Ordering::Relaxed);
+            progress.durable.store(size, Ordering::Relaxed);
+        }
+        Err(e) => {
+            error!(path = %path, error = %e, "AOF fsync failed");
+            progress.failed(&e);
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// writes `entries` to a new file at `path` in `format`, synced
+async fn write_entries(path: &str, format: AofFormat, entries: &[LogEntry]) -> anyhow::Result<()> {
+    let mut out = BufWriter::new(tokio::fs::File::create(path).await?);
+    out.write_all(&file_header(format)).await?;
+    for entry in entries {
+        out.write_all(&entry.encode(format)?).await?;
+    }
+    out.flush().await?;
+    out.get_ref().sync_data().await?;
+    Ok(())
+}
+
+/// appends the entries `kept` during a rewrite to the rewritten file at `tmp`,
+/// written in `format`, and makes it segment `n`, the new base. the manifest
+/// is then switched over to list only the base, which is the moment the
+/// rewrite takes effect, and the segments it replaces are removed. returns the
+/// base, open for appending
+async fn swap_in(
+    path: &str,
+    tmp: &str,
+    format: AofFormat,
+    kept: &[LogEntry],
+    n: u64,
+    files: &mut Vec<String>,
+) -> std::io::Result<tokio::fs::File> {
+    let mut fresh = OpenOptions::new().append(true).open(tmp).await?;
+    let mut fresh_len = fresh.metadata().await?.len();
+    for entry in kept {
+        let mut bytes = entry.encode(format).map_err(std::io::Error::other)?;
+        if fresh_len == 0 {
+            bytes.splice(0..0, file_header(format));
+        }
+        fresh_len += bytes.len() as u64;
+        fresh.write_all(&bytes).await?;
+    }
+    fresh.flush().await?;
+    fresh.sync_data().await?;
+    let base = segment_path(path, n);
+    tokio::fs::rename(tmp, &base).await?;
+    if let Err(e) = write_manifest(path, std::slice::from_ref(&base)).await {
+        let _ = tokio::fs::remove_file(&base).await;
+        return Err(e);
+    }
+    // a segment left behind here is removed on the next startup, as the
+    // manifest no longer lists it
+    for old in std::mem::replace(files, vec![base]) {
+        if let Err(e) = tokio::fs::remove_file(&old).await {
+            warn!(segment = %old, error = %e, "could not remove a rewritten AOF segment");
+        }
+    }
+    Ok(fresh)
+}
+
+/// after a failed write: reopens the live file, cuts off whatever part of
+/// `batch` made it past the `written` bytes before it, and writes `batch`
+/// again, retrying every second until it succeeds. returns the reopened file
+async fn rewrite_batch(path: &str, written: u64, batch: &[u8]) -> tokio::fs::File {
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let res = async {
+            let mut file = OpenOptions::new().create(true).append(true).open(path).await?;
+            if file.metadata().await?.len() > written {
+                file.set_len(written).await?;
+            }
+            file.write_all(batch).await?;
+            file.flush().await?;
+            std::io::Result::Ok(file)
+        }.await;
+        match res {
+            Ok(file) => {
+                warn!(path = %path, "AOF writes recovered");
+                return file;
+            }
+            Err(e) => error!(path = %path, error = %e, "AOF write failed, retrying"),
+        }
+    }
+}
+
+/// the first bytes of the file at `path`, enough to tell its format
+async fn read_start(path: &str) -> Vec<u8> {
+    use tokio::io::AsyncReadExt;
+    let mut start = Vec::new();
+    if let Ok(file) = tokio::fs::File::open(path).await {
+        let _ = file.take(BINARY_MAGIC.len() as u64).read_to_end(&mut start).await;
+    }
+    start
+}
+
+/// syncs the live segment `file` and starts segment `n` after it, adding it
+/// to `files` and the manifest. returns the new segment, open for appending
+async fn rotate(path: &str, files: &mut Vec<String>, n: u64, file: &mut tokio::fs::File) -> std::io::Result<tokio::fs::File> {
+    file.flush().await?;
+    file.sync_data().await?;
+    let segment = segment_path(path, n);
+    let fresh = OpenOptions::new().create(true).append(true).open(&segment).await?;
+    files.push(segment);
+    if let Err(e) = write_manifest(path, files).await {
+        if let Some(segment) = files.pop() {
+            let _ = tokio::fs::remove_file(segment).await;
+        }
+        return Err(e);
+    }
+    Ok(fresh)
+}
+
+/// the segment appended to, the last of `files`
+fn live(files: &[String]) -> &str {
+    files.last().map_or("", String::as_str)
+}
+
+/// settles the segments of the AOF at `path` before appending to it: an AOF
+/// from before segments becomes the newest segment, a new AOF gets its first,
+/// the manifest is written to match, and segments it doesn't list, left by an
+/// interrupted rewrite, are removed. returns the segments and the next number
+async fn open_segments(path: &str) -> anyhow::Result<(Vec<String>, u64)> {
+    let mut files = segments(path);
+    let on_disk = scan_segments(path);
+    let mut next = on_disk.last().map_or(1, |n| n + 1);
+    if files.last().is_some_and(|file| file == path) {
+        let segment = segment_path(path, next);
+        tokio::fs::rename(path, &segment).await?;
+        info!(from = %path, to = %segment, "moved the AOF into a segment");
+        if let Some(last) = files.last_mut() {
+            *last = segment;
+        }
+        next += 1;
+    }
+    if files.is_empty() {
+        let segment = segment_path(path, next);
+        tokio::fs::File::create(&segment).await?;
+        files.push(segment);
+        next += 1;
+    }
+    write_manifest(path, &files).await?;
+    for n in on_disk {
+        let segment = segment_path(path, n);
+        if !files.contains(&segment) {
+            warn!(segment = %segment, "removing an AOF segment the manifest doesn't list");
+            let _ = tokio::fs::remove_file(&segment).await;
+        }
+    }
+    Ok((files, next))
+}
@@ -0,0 +1,235 @@
+//! load generator, in the spirit of redis-benchmark. it talks to the server
+//! with the same RESP encoding and reply parsing as kv-cli
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use kvstore::{
+    protocol::{encode_request, read_reply},
+    Response,
+};
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::TcpStream,
+    task::JoinSet,
+};
+
+const TESTS: &[&str] = &["set", "get", "incr", "lpush", "sadd"];
+
+struct Options {
+    addr: String,
+    password: Option<String>,
+    clients: usize,
+    requests: u64,
+    pipeline: u64,
+    keyspace: u64,
+    value: String,
+    csv: bool,
+}
+
+/// what one test run measured
+struct Report {
+    test: String,
+    elapsed: Duration,
+    /// per request, sorted
+    latencies: Vec<Duration>,
+    errors: u64,
+}
+
+fn cli() -> Command {
+    let number = |name: &'static str, short: char, default: &'static str, help: &'static str| {
+        Arg::new(name).short(short).default_value(default).value_parser(clap::value_parser!(u64)).help(help)
+    };
+    Command::new("kv-bench")
+        .about("Benchmark tool for kvstore")
+        // -h is the host, like redis-benchmark
+        .disable_help_flag(true)
+        .arg(Arg::new("help").long("help").action(ArgAction::Help).help("Print help"))
+        .arg(Arg::new("host").short('h').default_value("127.0.0.1").help("Server hostname"))
+        .arg(Arg::new("port").short('p').default_value("6379").value_parser(clap::value_parser!(u16)).help("Server port"))
+        .arg(Arg::new("password").short('a').env("KVCLI_AUTH").help("Password to AUTH with"))
+        .arg(number("clients", 'c', "50", "Number of parallel connections"))
+        .arg(number("requests", 'n', "100000", "Total number of requests per test"))
+        .arg(number("pipeline", 'P', "1", "Requests sent at once on each connection"))
+        .arg(number("keyspace", 'r', "0", "Use random keys from 0 to this, 0 uses one key"))
+        .arg(number("datasize", 'd', "3", "Value size in bytes for SET, LPUSH and SADD"))
+        .arg(Arg::new("tests").short('t').default_value("set,get,incr,lpush,sadd").help("Comma separated tests to run"))
+        .arg(Arg::new("csv").long("csv").action(ArgAction::SetTrue).help("Output in CSV format"))
+}
+
+fn options(matches: &ArgMatches) -> Result<(Options, Vec<String>)> {
+    let number = |name: &str| *matches.get_one::<u64>(name).unwrap();
+    let tests: Vec<String> = matches.get_one::<String>("tests").unwrap()
+        .split(',')
+        .map(|t| t.trim().to_lowercase())
+        .filter(|t| !t.is_empty())
+        .collect();
+    if let Some(unknown) = tests.iter().find(|t| !TESTS.contains(&t.as_str())) {
+        anyhow::bail!("unknown test '{unknown}', expected some of {}", TESTS.join(","));
+    }
+    if number("clients") == 0 || number("pipeline") == 0 {
+        anyhow::bail!("-c and -P must be at least 1");
+    }
+    let options = Options {
+        addr: format!("{}:{}", matches.get_one::<String>("host").unwrap(), matches.get_one::<u16>("port").unwrap()),
+        password: matches.get_one::<String>("password").cloned(),
+        clients: number("clients") as usize,
+        requests: number("requests"),
+        pipeline: number("pipeline"),
+        keyspace: number("keyspace"),
+        value: "x".repeat(number("datasize") as usize),
+        csv: matches.get_flag("csv"),
+    };
+    Ok((options, tests))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let (options, tests) = options(&cli().get_matches())?;
+    let options = Arc::new(options);
+    if options.csv {
+        println!("\"test\",\"rps\",\"avg_latency_ms\",\"p50_latency_ms\",\"p95_latency_ms\",\"p99_latency_ms\",\"max_latency_ms\"");
+    }
+    for test in tests {
+        let report = run(&options, test).await?;
+        if options.csv {
+            print_csv(&report);
+        } else {
+            print_report(&report, &options);
+        }
+    }
+    Ok(())
+}
+
+/// runs one test to completion over `clients` connections sharing the request budget
+async fn run(options: &Arc<Options>, test: String) -> Result<Report> {
+    let issued = Arc::new(AtomicU64::new(0));
+    let started = Instant::now();
+    let mut tasks = JoinSet::new();
+    for _ in 0..options.clients {
+        let (options, test, issued) = (options.clone(), test.clone(), issued.clone());
+        tasks.spawn(async move { client(&options, &test, &issued).await });
+    }
+
+    let mut latencies = Vec::with_capacity(options.requests as usize);
+    let mut errors = 0;
+    while let Some(res) = tasks.join_next().await {
+        let (l, e) = res??;
+        latencies.extend(l);
+        errors += e;
+    }
+    let elapsed = started.elapsed();
+    latencies.sort_unstable();
+    Ok(Report { test, elapsed, latencies, errors })
+}
+
+/// one connection: claims up to `pipeline` requests at a time until the budget
+/// is spent, returning each request's latency and the number of error replies
+async fn client(options: &Options, test: &str, issued: &AtomicU64) -> Result<(Vec<Duration>, u64)> {
+    let stream = TcpStream::connect(&options.addr).await?;
+    // a pipelined batch is one write, there's nothing to coalesce
+    stream.set_nodelay(true)?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    if let Some(password) = &options.password {
+        writer.write_all(&encode_request(&["AUTH".to_string(), password.clone()])).await?;
+        if let Some(Response::Error(e)) = read_reply(&mut reader).await? {
+            anyhow::bail!("AUTH failed: {e}");
+        }
+    }
+
+    let mut latencies = Vec::new();
+    let mut errors = 0;
+    let mut batch = Vec::new();
+    loop {
+        let first = issued.fetch_add(options.pipeline, Ordering::Relaxed);
+        if first >= options.requests {
+            break;
+        }
+        let count = options.pipeline.min(options.requests - first);
+        batch.clear();
+        for _ in 0..count {
+            batch.extend(encode_request(&command(test, options)));
+        }
+        let sent = Instant::now();
+        writer.write_all(&batch).await?;
+        for _ in 0..count {
+            match read_reply(&mut reader).await? {
+                // a misconfigured run fails every request the same way, so stop at the first one
+                Some(Response::Error(e)) if first == 0 && latencies.is_empty() => {
+                    anyhow::bail!("{test}: {e}");
+                }
+                Some(Response::Error(_)) => errors += 1,
+                Some(_) => {}
+                None => anyhow::bail!("server closed the connection"),
+            }
+            latencies.push(sent.elapsed());
+        }
+    }
+    Ok((latencies, errors))
+}
+
+/// the command for one request of `test`, with a random key when `-r` is set
+fn command(test: &str, options: &Options) -> Vec<String> {
+    let key = |prefix: &str| match options.keyspace {
+        0 => format!("{prefix}:__rand_int__"),
+        n => format!("{prefix}:{:012}", rand::random_range(0..n)),
+    };
+    match test {
+        "set" => vec!["SET".into(), key("key"), options.value.clone()],
+        "get" => vec!["GET".into(), key("key")],
+        "incr" => vec!["INCR".into(), key("counter")],
+        "lpush" => vec!["LPUSH".into(), "mylist".into(), options.value.clone()],
+        "sadd" => vec!["SADD".into(), "myset".into(), key("element")],
+        _ => unreachable!("tests are validated up front"),
+    }
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+fn stats(report: &Report) -> (f64, [f64; 5]) {
+    let l = &report.latencies;
+    let rps = l.len() as f64 / report.elapsed.as_secs_f64();
+    let avg = if l.is_empty() { 0.0 } else { ms(l.iter().sum::<Duration>()) / l.len() as f64 };
+    let max = l.last().copied().unwrap_or_default();
+    (rps, [avg, ms(percentile(l, 50.0)), ms(percentile(l, 95.0)), ms(percentile(l, 99.0)), ms(max)])
+}
+
+fn print_report(report: &Report, options: &Options) {
+    let (rps, [avg, p50, p95, p99, max]) = stats(report);
+    println!("====== {} ======", report.test.to_uppercase());
+    println!("  {} requests completed in {:.2} seconds", report.latencies.len(), report.elapsed.as_secs_f64());
+    println!("  {} parallel clients, pipeline {}", options.clients, options.pipeline);
+    println!("  {} bytes payload", options.value.len());
+    if report.errors > 0 {
+        println!("  {} error replies", report.errors);
+    }
+    println!("  throughput: {rps:.2} requests per second");
+    println!("  latency (msec): avg={avg:.3} p50={p50:.3} p95={p95:.3} p99={p99:.3} max={max:.3}");
+    println!();
+}
+
+fn print_csv(report: &Report) {
+    let (rps, [avg, p50, p95, p99, max]) = stats(report);
+    println!(
+        "\"{}\",\"{rps:.2}\",\"{avg:.3}\",\"{p50:.3}\",\"{p95:.3}\",\"{p99:.3}\",\"{max:.3}\"",
+        report.test.to_uppercase(),
+    );
+}
@@ -0,0 +1,310 @@
+//! interactive client, in the spirit of redis-cli
+
+use std::{
+    io::{BufRead, IsTerminal, Write},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use clap::{Arg, ArgAction, Command};
+use kvstore::{
+    export::Record,
+    protocol::{encode_request, read_reply, tokenize_inline},
+    snapshot, Response,
+};
+use rustyline::{error::ReadlineError, DefaultEditor};
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::mpsc,
+};
+
+/// history file in the home directory, like ~/.rediscli_history
+const HISTORY_FILE: &str = ".kvcli_history";
+
+struct Conn {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+impl Conn {
+    async fn open(addr: &str, password: Option<&str>) -> Result<Self> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        let mut conn = Conn { reader: BufReader::new(reader), writer };
+        if let Some(password) = password {
+            if let Response::Error(e) = conn.call(&["AUTH".to_string(), password.to_string()]).await? {
+                eprintln!("AUTH failed: {e}");
+            }
+        }
+        Ok(conn)
+    }
+
+    async fn call(&mut self, args: &[String]) -> Result<Response> {
+        self.writer.write_all(&encode_request(args)).await?;
+        read_reply(&mut self.reader)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Server closed the connection"))
+    }
+}
+
+fn cli() -> Command {
+    Command::new("kv-cli")
+        .about("Command line client for kvstore")
+        // -h is the host, like redis-cli
+        .disable_help_flag(true)
+        .arg(Arg::new("help").long("help").action(ArgAction::Help).help("Print help"))
+        .arg(Arg::new("host").short('h').default_value("127.0.0.1").help("Server hostname"))
+        .arg(Arg::new("port").short('p').default_value("6379").value_parser(clap::value_parser!(u16)).help("Server port"))
+        .arg(Arg::new("password").short('a').env("KVCLI_AUTH").help("Password to AUTH with"))
+        .arg(Arg::new("pipe").long("pipe").action(ArgAction::SetTrue).help("Send commands from stdin as fast as possible"))
+        .arg(Arg::new("dump").long("dump").action(ArgAction::SetTrue).help("Write every key to stdout as JSON lines"))
+        .arg(Arg::new("restore").long("restore").action(ArgAction::SetTrue).conflicts_with("dump").help("Load keys written by --dump from stdin"))
+        .arg(Arg::new("replace").long("replace").action(ArgAction::SetTrue).requires("restore").help("With --restore, delete every other key first"))
+        .arg(Arg::new("command").num_args(1..).trailing_var_arg(true).help("Run one command and exit"))
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    let matches = cli().get_matches();
+    let addr = format!("{}:{}", matches.get_one::<String>("host").unwrap(), matches.get_one::<u16>("port").unwrap());
+    let password = matches.get_one::<String>("password").map(String::as_str);
+
+    let conn = match Conn::open(&addr, password).await {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("Could not connect to {addr}: {e}");
+            std::process::exit(1);
+        }
+    };
+    if matches.get_flag("pipe") {
+        return pipe(conn).await;
+    }
+    if matches.get_flag("dump") {
+        return dump(conn).await;
+    }
+    if matches.get_flag("restore") {
+        return restore(conn, matches.get_flag("replace")).await;
+    }
+    if let Some(args) = matches.get_many::<String>("command") {
+        return one_shot(conn, args.cloned().collect()).await;
+    }
+    repl(conn, &addr, password).await
+}
+
+/// `kv-cli SET foo bar`: formatted like the REPL on a terminal, raw when piped
+async fn one_shot(mut conn: Conn, args: Vec<String>) -> Result<()> {
+    let reply = conn.call(&args).await?;
+    if std::io::stdout().is_terminal() {
+        println!("{}", format_reply(&reply, 0));
+    } else {
+        println!("{}", format_raw(&reply));
+    }
+    if matches!(reply, Response::Error(_)) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+async fn repl(conn: Conn, addr: &str, password: Option<&str>) -> Result<()> {
+    let mut editor = DefaultEditor::new()?;
+    let history = std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(HISTORY_FILE));
+    if let Some(history) = &history {
+        // a missing history file just means a first run
+        let _ = editor.load_history(history);
+    }
+
+    let mut conn = Some(conn);
+    loop {
+        let prompt = if conn.is_some() { format!("{addr}> ") } else { "not connected> ".to_string() };
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let args = match tokenize_inline(&line) {
+            Ok(args) if args.is_empty() => continue,
+            Ok(args) => args,
+            Err(_) => {
+                println!("Invalid argument(s)");
+                continue;
+            }
+        };
+        let _ = editor.add_history_entry(line.as_str());
+        if args[0].eq_ignore_ascii_case("quit") || args[0].eq_ignore_ascii_case("exit") {
+            break;
+        }
+
+        // reconnect lazily after the server went away
+        if conn.is_none() {
+            conn = Conn::open(addr, password).await.ok();
+        }
+        let Some(c) = &mut conn else {
+            println!("Could not connect to {addr}");
+            continue;
+        };
+        match c.call(&args).await {
+            Ok(reply) => println!("{}", format_reply(&reply, 0)),
+            Err(e) => {
+                println!("Error: {e}");
+                conn = None;
+            }
+        }
+    }
+
+    if let Some(history) = &history {
+        let _ = editor.save_history(history);
+    }
+    Ok(())
+}
+
+/// streams stdin to the server while reading replies concurrently, so a
+/// large load never stalls on a full socket buffer
+async fn pipe(conn: Conn) -> Result<()> {
+    let Conn { mut reader, mut writer } = conn;
+    let (sent, mut pending) = mpsc::unbounded_channel();
+    let send = tokio::spawn(async move {
+        let (lines, mut rx) = mpsc::channel::<String>(1024);
+        // stdin is blocking, so read it off the runtime
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines().map_while(|line| line.ok()) {
+                if lines.blocking_send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        while let Some(line) = rx.recv().await {
+            let args = match tokenize_inline(&line) {
+                Ok(args) if args.is_empty() => continue,
+                Ok(args) => args,
+                Err(e) => {
+                    eprintln!("skipping '{line}': {e}");
+                    continue;
+                }
+            };
+            writer.write_all(&encode_request(&args)).await?;
+            let _ = sent.send(());
+        }
+        writer.flush().await?;
+        anyhow::Ok(())
+    });
+
+    let (mut replies, mut errors) = (0u64, 0u64);
+    while pending.recv().await.is_some() {
+        match read_reply(&mut reader).await? {
+            Some(Response::Error(e)) => {
+                errors += 1;
+                eprintln!("{e}");
+            }
+            Some(_) => {}
+            None => anyhow::bail!("Server closed the connection after {replies} replies"),
+        }
+        replies += 1;
+    }
+    send.await??;
+    println!("All data transferred. errors: {errors}, replies: {replies}");
+    std::io::stdout().flush()?;
+    Ok(())
+}
+
+/// `kv-cli --dump`: every key in the format of `Store::export_json`, read
+/// with KEYS, DUMP and PTTL. keys deleted meanwhile are left out
+async fn dump(mut conn: Conn) -> Result<()> {
+    let call = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+    let mut keys = match conn.call(&call(&["KEYS", ""])).await? {
+        Response::Array(keys) => keys.iter().map(format_raw).collect::<Vec<_>>(),
+        other => anyhow::bail!("KEYS failed: {other}"),
+    };
+    keys.sort();
+    let mut out = std::io::stdout().lock();
+    for key in &keys {
+        let payload = match conn.call(&call(&["DUMP", key])).await? {
+            Response::BulkString(Some(payload)) => payload,
+            Response::Error(e) => anyhow::bail!("DUMP {key} failed: {e}"),
+            _ => continue,
+        };
+        let expires_at = match conn.call(&call(&["PTTL", key])).await? {
+            Response::Integer(ms) if ms >= 0 => Some(SystemTime::now() + Duration::from_millis(ms as u64)),
+            _ => None,
+        };
+        let value = snapshot::restore(&payload)?;
+        serde_json::to_writer(&mut out, &Record::new(key, &value, expires_at))?;
+        writeln!(out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// `kv-cli --restore`: loads `--dump` output from stdin with RESTORE ...
+/// REPLACE, after checking all of it parses. keys that have expired since are
+/// skipped
+async fn restore(mut conn: Conn, replace: bool) -> Result<()> {
+    let mut records = Vec::new();
+    for (n, line) in std::io::stdin().lock().lines().enumerate() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            records.push(serde_json::from_str::<Record>(&line).map_err(|e| anyhow::anyhow!("line {}: {e}", n + 1))?);
+        }
+    }
+    if replace {
+        if let Response::Error(e) = conn.call(&["DELPATTERN".to_string(), "*".to_string(), "CONFIRM".to_string()]).await? {
+            anyhow::bail!("clearing the dataset failed: {e}");
+        }
+    }
+    let mut restored = 0;
+    for record in records.iter().filter(|r| r.is_live()) {
+        let ttl = match record.expires_at() {
+            Some(at) => match at.duration_since(SystemTime::now()) {
+                Ok(left) => left.as_millis().max(1),
+                Err(_) => continue,
+            },
+            None => 0,
+        };
+        let payload = snapshot::dump(&record.to_value());
+        let args = ["RESTORE".to_string(), record.key.clone(), ttl.to_string(), payload, "REPLACE".to_string()];
+        if let Response::Error(e) = conn.call(&args).await? {
+            anyhow::bail!("RESTORE {} failed: {e}", record.key);
+        }
+        restored += 1;
+    }
+    eprintln!("Restored {restored} keys");
+    Ok(())
+}
+
+/// redis-cli's human readable form: quoted strings, typed integers and
+/// numbered, indented array items
+fn format_reply(reply: &Response, indent: usize) -> String {
+    match reply {
+        Response::SimpleString(s) => s.clone(),
+        Response::Error(e) => format!("(error) {e}"),
+        Response::Integer(i) => format!("(integer) {i}"),
+        Response::BulkString(Some(s)) => format!("{s:?}"),
+        Response::BulkShared(s) => format!("{s:?}"),
+        // escaped like redis-cli does, e.g. "\xc3"
+        Response::BulkBytes(b) => format!("\"{}\"", b.escape_ascii()),
+        Response::BulkString(None) | Response::Nil => "(nil)".to_string(),
+        Response::Array(items) if items.is_empty() => "(empty array)".to_string(),
+        Response::Array(items) => {
+            let width = items.len().to_string().len();
+            items.iter().enumerate()
+                .map(|(i, item)| {
+                    let prefix = format!("{:>width$}) ", i + 1);
+                    let pad = if i == 0 { String::new() } else { " ".repeat(indent) };
+                    format!("{pad}{prefix}{}", format_reply(item, indent + prefix.len()))
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+    }
+}
+
+/// the plain form used when stdout isn't a terminal: one value per line
+fn format_raw(reply: &Response) -> String {
+    match reply {
+        Response::BulkString(None) | Response::Nil => String::new(),
+        Response::Array(items) => items.iter().map(format_raw).collect::<Vec<_>>().join("\n"),
+        other => other.to_string(),
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+use crate::{error::{RedisError, Response}, protocol::help_reply};
+
+/// metadata tracked for each live connection
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub id: u64,
+    pub addr: SocketAddr,
+    pub name: Option<String>,
+    pub connected_at: Instant,
+    pub last_active: Instant,
+    pub last_cmd: String,
+    kill: Arc<Notify>,
+}
+
+/// registry of connected clients shared between the accept loop and handlers
+#[derive(Clone, Default)]
+pub struct ClientRegistry {
+    inner: Arc<Mutex<HashMap<u64, ClientInfo>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl ClientRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// registers a new connection, returning its id and the signal fired by CLIENT KILL
+    pub fn register(&self, addr: SocketAddr) -> (u64, Arc<Notify>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let kill = Arc::new(Notify::new());
+        let now = Instant::now();
+        self.inner.lock().insert(id, ClientInfo {
+            id,
+            addr,
+            name: None,
+            connected_at: now,
+            last_active: now,
+            last_cmd: "NULL".to_string(),
+            kill: kill.clone(),
+        });
+        (id, kill)
+    }
+
+    /// like `register`, but the client is removed again when the guard drops
+    pub fn register_guarded(&self, addr: SocketAddr) -> (Registration, Arc<Notify>) {
+        let (id, kill) = self.register(addr);
+        (Registration { clients: self.clone(), id }, kill)
+    }
+
+    pub fn unregister(&self, id: u64) {
+        self.inner.lock().remove(&id);
+    }
+
+    /// records the command a client just issued
+    pub fn touch(&self, id: u64, cmd: &str) {
+        if let Some(info) = self.inner.lock().get_mut(&id) {
+            info.last_active = Instant::now();
+            info.last_cmd = cmd.to_lowercase();
+        }
+    }
+
+    pub fn set_name(&self, id: u64, name: Option<String>) {
+        if let Some(info) = self.inner.lock().get_mut(&id) {
+            info.name = name;
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<ClientInfo> {
+        self.inner.lock().get(&id).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// renders every client in the redis CLIENT LIST text format
+    pub fn list(&self) -> String {
+        let map = self.inner.lock();
+        let mut clients: Vec<&ClientInfo> = map.values().collect();
+        clients.sort_by_key(|c| c.id);
+        clients.iter()
+            .map(|c| format!(
+                "id={} addr={} name={} age={} idle={} cmd={}\n",
+                c.id,
+                c.addr,
+                c.name.as_deref().unwrap_or(""),
+                c.connected_at.elapsed().as_secs(),
+                c.last_active.elapsed().as_secs(),
+                c.last_cmd,
+            ))
+            .collect()
+    }
+
+    pub fn kill_by_id(&self, id: u64) -> bool {
+        match self.inner.lock().get(&id) {
+            Some(info) => {
+                info.kill.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// kills every client connected from `addr`, returning how many were signalled
+    pub fn kill_by_addr(&self, addr: &str) -> usize {
+        let map = self.inner.lock();
+        let mut killed = 0;
+        for info in map.values().filter(|c| c.addr.to_string() == addr) {
+            info.kill.notify_one();
+            killed += 1;
+        }
+        killed
+    }
+}
+
+/// keeps a client registered for as long as it's alive, so every exit path of
+/// a connection handler (errors, kills, panics) releases its slot
+pub struct Registration {
+    clients: ClientRegistry,
+    id: u64,
+}
+
+impl Registration {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.clients.unregister(self.id);
+    }
+}
+
+/// handles `CLIENT <subcommand>` for the connection identified by `id`
+pub fn handle_client_command(clients: &ClientRegistry, id: u64, parts: &[&str]) -> Response {
+    if parts.len() < 2 {
+        return RedisError::WrongArguments {
+            command: "CLIENT".to_string(),
+            expected: "at least 1".to_string(),
+            got: parts.len().saturating_sub(1),
+        }.into();
+    }
+
+    let sub = parts[1].to_uppercase();
+    match (sub.as_str(), parts.len()) {
+        ("LIST", 2) => Response::BulkString(Some(clients.list())),
+        ("ID", 2) => Response::Integer(id as i64),
+        ("GETNAME", 2) => Response::BulkString(clients.get(id).and_then(|c| c.name)),
+        ("SETNAME", 3) => {
+            if parts[2].is_empty() {
+                clients.set_name(id, None);
+            } else {
+                clients.set_name(id, Some(parts[2].to_string()));
+            }
+            "OK".into()
+        }
+        ("KILL", 4) => {
+            let filter = parts[2].to_uppercase();
+            match filter.as_str() {
+                "ID" => match parts[3].parse::<u64>() {
+                    Ok(target) => Response::Integer(clients.kill_by_id(target) as i64),
+                    Err(_) => RedisError::NotInteger(parts[3].to_string()).into(),
+                },
+                "ADDR" => Response::Integer(clients.kill_by_addr(parts[3]) as i64),
+                _ => RedisError::InvalidType("syntax error".to_string()).into(),
+            }
+        }
+        ("HELP", 2) => help_reply("CLIENT", &[
+            ("ID", "Return the ID of the current connection."),
+            ("LIST", "Return information about client connections."),
+            ("GETNAME", "Return the name of the current connection."),
+            ("SETNAME <name>", "Assign the name <name> to the current connection."),
+            ("KILL ID <id> | ADDR <ip:port>", "Kill connections by ID or address."),
+        ]),
+        _ => RedisError::InvalidType(format!("unknown subcommand or wrong number of arguments for 'CLIENT|{}'", sub)).into(),
+    }
+}
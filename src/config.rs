@@ -0,0 +1,415 @@
+use std::{ffi::OsString, path::Path, str::FromStr};
+use clap::{parser::ValueSource, Arg, Command};
+use crate::{aof::{AofFormat, AppendFsync, ChecksumPolicy, QueueFullPolicy}, protocol::tokenize_inline, ratelimit::RateLimitMode, store::MAX_STRING_LEN, types::{KeysLimitPolicy, MaxMemoryPolicy}};
+
+/// settings for a server instance, see `load_from` for where they come from
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// addresses to listen on, port 0 picks a free port (see `Server::local_addrs`)
+    pub addrs: Vec<String>,
+    /// path of the append-only file, its segments are `<aof_path>.N`
+    pub aof_path: String,
+    /// directory for the AOF segments and manifest, named after `aof_path`'s
+    /// file name. empty keeps them next to `aof_path`
+    pub aof_dir: String,
+    /// path of the snapshot written by SAVE and BGSAVE
+    pub dbfilename: String,
+    /// start a new AOF segment once the live one reaches this many bytes, 0 disables
+    pub aof_rotate_size: u64,
+    /// when the AOF is fsynced: after every write, once a second, or never
+    pub appendfsync: AppendFsync,
+    /// whether writes are logged to the AOF. off skips the AOF at startup too,
+    /// loading only the snapshot
+    pub appendonly: bool,
+    /// how new AOF files are written, JSON lines or binary frames
+    pub aof_format: AofFormat,
+    /// entries that may wait for the AOF writer before writes are held back, 0 for no limit
+    pub aof_queue_capacity: u64,
+    /// whether a write finding the AOF queue full waits or fails
+    pub aof_queue_full_policy: QueueFullPolicy,
+    /// whether startup drops a last AOF entry cut short by a crash (and cuts
+    /// it from the file) rather than refusing to start
+    pub aof_load_truncated: bool,
+    /// whether startup refuses an AOF with entries failing their checksum or
+    /// leaves those entries out
+    pub aof_checksum_policy: ChecksumPolicy,
+    /// rewrite the AOF once it has grown this many percent since the last rewrite, 0 disables
+    pub auto_aof_rewrite_percentage: u64,
+    /// smallest AOF size in bytes that is rewritten automatically
+    pub auto_aof_rewrite_min_size: u64,
+    /// seconds between background sweeps for expired keys
+    pub sweep_interval: u64,
+    /// close connections idle for this many seconds, 0 disables
+    pub timeout: u64,
+    /// max simultaneous connections, further clients are rejected
+    pub maxclients: usize,
+    /// length of the queue of connections waiting to be accepted
+    pub tcp_backlog: u32,
+    /// seconds of silence before TCP keepalive probes a client, 0 disables
+    pub tcp_keepalive: u64,
+    /// when set, clients must AUTH with this password before running commands
+    pub requirepass: Option<String>,
+    /// file of ACL users, see `acl` for its format
+    pub aclfile: Option<String>,
+    /// commands per second allowed on each connection, 0 disables rate limiting
+    pub ratelimit_cps: u64,
+    /// commands a connection may send at once before the rate applies
+    pub ratelimit_burst: u64,
+    /// whether commands over the limit are delayed or rejected with BUSY
+    pub ratelimit_mode: RateLimitMode,
+    /// start in read-only mode, rejecting writes
+    pub readonly: bool,
+    /// longest accepted key name in bytes
+    pub max_key_len: usize,
+    /// largest accepted value in bytes, like redis' proto-max-bulk-len
+    pub max_value_len: usize,
+    /// most elements in one list, set or hash, 0 for no limit
+    pub max_collection_len: usize,
+    /// percent by which TTLs are randomly moved either way, so keys set
+    /// together don't all expire together. 0 disables it
+    pub ttl_jitter_pct: u64,
+    /// estimated dataset size in bytes before `maxmemory_policy` kicks in, 0 disables
+    pub maxmemory: usize,
+    pub maxmemory_policy: MaxMemoryPolicy,
+    /// most keys one KEYS reply may hold, 0 disables the limit
+    pub keys_limit: usize,
+    pub keys_limit_policy: KeysLimitPolicy,
+    /// log commands slower than this many microseconds, negative disables the slowlog
+    pub slowlog_log_slower_than: i64,
+    /// slowlog entries kept before the oldest are dropped
+    pub slowlog_max_len: usize,
+    /// keys a KEYS call may walk before it's slowlogged regardless of time, 0 disables
+    pub slowlog_keys_scanned: usize,
+    /// seconds to wait for open connections to finish during shutdown
+    pub shutdown_timeout: u64,
+    /// PEM certificate chain, enables TLS together with `tls_key_file`
+    pub tls_cert_file: Option<String>,
+    /// PEM private key for `tls_cert_file`
+    pub tls_key_file: Option<String>,
+    /// PEM CA bundle, when set clients must present a certificate signed by it
+    pub tls_ca_cert_file: Option<String>,
+    /// `host:port` of a primary to replicate from at startup
+    pub replicaof: Option<String>,
+    /// allow the DEBUG command, meant for tests rather than production
+    pub enable_debug_command: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            addrs: vec!["127.0.0.1:6379".to_string()],
+            aof_path: "kvstore.aof".to_string(),
+            aof_dir: String::new(),
+            dbfilename: "dump.kvs".to_string(),
+            aof_rotate_size: 0,
+            appendfsync: AppendFsync::EverySec,
+            appendonly: true,
+            aof_format: AofFormat::Json,
+            aof_queue_capacity: 100_000,
+            aof_queue_full_policy: QueueFullPolicy::Block,
+            aof_load_truncated: true,
+            aof_checksum_policy: ChecksumPolicy::Abort,
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 64 * 1024 * 1024,
+            sweep_interval: 2,
+            timeout: 0,
+            maxclients: 10000,
+            tcp_backlog: 511,
+            tcp_keepalive: 300,
+            requirepass: None,
+            aclfile: None,
+            ratelimit_cps: 0,
+            ratelimit_burst: 0,
+            ratelimit_mode: RateLimitMode::Delay,
+            readonly: false,
+            max_key_len: MAX_STRING_LEN,
+            max_value_len: MAX_STRING_LEN,
+            max_collection_len: 0,
+            ttl_jitter_pct: 0,
+            maxmemory: 0,
+            maxmemory_policy: MaxMemoryPolicy::NoEviction,
+            keys_limit: 0,
+            keys_limit_policy: KeysLimitPolicy::RequirePrefix,
+            slowlog_log_slower_than: 10_000,
+            slowlog_max_len: 128,
+            slowlog_keys_scanned: 100_000,
+            shutdown_timeout: 10,
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_ca_cert_file: None,
+            replicaof: None,
+            enable_debug_command: false,
+        }
+    }
+}
+
+/// every setting as (name, env var, help). the name is the same as a
+/// `--name` flag and as a `name value` line in a config file
+const SETTINGS: &[(&str, &str, &str)] = &[
+    ("addr", "KV_ADDR", "addresses to listen on, comma separated host:port"),
+    ("aof-path", "KV_AOF", "path of the append-only file"),
+    ("aof-dir", "KV_AOF_DIR", "directory for the AOF segments and manifest, empty uses aof-path's"),
+    ("dbfilename", "KV_DBFILENAME", "path of the snapshot written by SAVE and BGSAVE"),
+    ("aof-rotate-size", "KV_AOF_ROTATE_SIZE", "rotate the AOF at this many bytes, 0 disables"),
+    ("appendfsync", "KV_APPENDFSYNC", "fsync the AOF always, everysec or no"),
+    ("appendonly", "KV_APPENDONLY", "log writes to the AOF, yes or no"),
+    ("aof-format", "KV_AOF_FORMAT", "write new AOF files as json or binary"),
+    ("aof-queue-capacity", "KV_AOF_QUEUE_CAPACITY", "entries waiting for the AOF writer before writes are held back, 0 disables"),
+    ("aof-queue-full-policy", "KV_AOF_QUEUE_FULL_POLICY", "when the AOF queue is full, block writes or fail them with an error"),
+    ("aof-load-truncated", "KV_AOF_LOAD_TRUNCATED", "drop a partly written last AOF entry on startup, yes or no"),
+    ("aof-checksum-policy", "KV_AOF_CHECKSUM_POLICY", "on AOF entries failing their checksum at startup, abort or skip"),
+    ("auto-aof-rewrite-percentage", "KV_AUTO_AOF_REWRITE_PERCENTAGE", "rewrite the AOF after this much growth in percent, 0 disables"),
+    ("auto-aof-rewrite-min-size", "KV_AUTO_AOF_REWRITE_MIN_SIZE", "smallest AOF in bytes to rewrite automatically"),
+    ("sweep-interval", "KV_SWEEP_INTERVAL", "seconds between sweeps for expired keys"),
+    ("timeout", "KV_TIMEOUT", "close clients idle for this many seconds, 0 disables"),
+    ("maxclients", "KV_MAXCLIENTS", "max simultaneous connections"),
+    ("tcp-backlog", "KV_TCP_BACKLOG", "connections queued for accept on each listener"),
+    ("tcp-keepalive", "KV_TCP_KEEPALIVE", "seconds idle before keepalive probes, 0 disables"),
+    ("requirepass", "KV_REQUIREPASS", "password clients must AUTH with"),
+    ("aclfile", "KV_ACLFILE", "file of ACL users, one `user <name> [rule ...]` per line"),
+    ("ratelimit-cps", "KV_RATELIMIT_CPS", "commands per second per connection, 0 disables"),
+    ("ratelimit-burst", "KV_RATELIMIT_BURST", "commands a connection may send at once"),
+    ("ratelimit-mode", "KV_RATELIMIT_MODE", "delay or reject commands over the rate limit"),
+    ("readonly", "KV_READONLY", "start read-only, yes or no"),
+    ("max-key-len", "KV_MAX_KEY_LEN", "longest accepted key in bytes"),
+    ("proto-max-bulk-len", "KV_MAX_VALUE_LEN", "largest accepted value in bytes"),
+    ("max-collection-len", "KV_MAX_COLLECTION_LEN", "most elements in one list, set or hash, 0 disables"),
+    ("ttl-jitter-pct", "KV_TTL_JITTER_PCT", "percent TTLs are randomly moved either way, 0 disables"),
+    ("maxmemory", "KV_MAXMEMORY", "dataset size in bytes before eviction, 0 disables"),
+    ("maxmemory-policy", "KV_MAXMEMORY_POLICY", "noeviction, allkeys-lfu, volatile-lfu, allkeys-lru or volatile-lru"),
+    ("keys-limit", "KV_KEYS_LIMIT", "most keys one KEYS reply may hold, 0 disables"),
+    ("keys-limit-policy", "KV_KEYS_LIMIT_POLICY", "past keys-limit, require-prefix or truncate"),
+    ("slowlog-log-slower-than", "KV_SLOWLOG_SLOWER_THAN", "slowlog threshold in microseconds, negative disables"),
+    ("slowlog-max-len", "KV_SLOWLOG_MAX_LEN", "slowlog entries kept"),
+    ("slowlog-keys-scanned", "KV_SLOWLOG_KEYS_SCANNED", "slowlog KEYS calls walking more keys than this, 0 disables"),
+    ("shutdown-timeout", "KV_SHUTDOWN_TIMEOUT", "seconds to let connections finish on shutdown"),
+    ("tls-cert-file", "KV_TLS_CERT", "PEM certificate chain, enables TLS"),
+    ("tls-key-file", "KV_TLS_KEY", "PEM private key for the certificate"),
+    ("tls-ca-cert-file", "KV_TLS_CA", "PEM CA bundle, requires client certificates"),
+    ("replicaof", "KV_REPLICAOF", "host:port of a primary to replicate from"),
+    ("enable-debug-command", "KV_ENABLE_DEBUG_COMMAND", "allow DEBUG, yes or no"),
+];
+
+impl ServerConfig {
+    /// the path the AOF's segments are named after: `aof_path`, or its file
+    /// name inside `aof_dir`
+    pub fn aof_base(&self) -> String {
+        if self.aof_dir.is_empty() {
+            return self.aof_path.clone();
+        }
+        let name = Path::new(&self.aof_path).file_name().unwrap_or(self.aof_path.as_ref());
+        Path::new(&self.aof_dir).join(name).to_string_lossy().into_owned()
+    }
+
+    /// builds a config from `KV_*` env vars (and the `KV_CONFIG` file), ignoring the command line
+    pub fn from_env() -> anyhow::Result<Self> {
+        Self::load_from(["kvstore"])
+    }
+
+    /// builds a config from a command line. later sources win: defaults, then
+    /// the `--config` file, then `KV_*` env vars, then flags. the result is
+    /// validated, so a bad value fails here rather than once the server is up
+    pub fn load_from<I, T>(args: I) -> anyhow::Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = command().try_get_matches_from(args)?;
+        let mut config = Self::default();
+        if let Some(path) = matches.get_one::<String>("config") {
+            config.apply_file(path)?;
+        }
+        for &(name, env, _) in SETTINGS {
+            let Some(value) = matches.get_one::<String>(name) else { continue };
+            config.set(name, value).map_err(|e| match matches.value_source(name) {
+                Some(ValueSource::EnvVariable) => anyhow::anyhow!("{env}: {e}"),
+                _ => anyhow::anyhow!("--{name}: {e}"),
+            })?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// applies a redis.conf-style file: one `name value` per line, `#` starts
+    /// a comment line, values with spaces can be quoted
+    pub fn apply_file(&mut self, path: &str) -> anyhow::Result<()> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("reading config file {path}: {e}"))?;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let at = |e: String| anyhow::anyhow!("{path}:{}: {e}", i + 1);
+            match tokenize_inline(line).map_err(at)?.as_slice() {
+                [name, value] => self.set(&name.to_lowercase(), value).map_err(|e| at(e.to_string()))?,
+                _ => return Err(at(format!("expected `name value`, got '{line}'"))),
+            }
+        }
+        Ok(())
+    }
+
+    /// sets one setting by name, parsing `value`
+    pub fn set(&mut self, name: &str, value: &str) -> anyhow::Result<()> {
+        // empty means unset for the optional ones
+        let optional = || (!value.is_empty()).then(|| value.to_string());
+        match name {
+            "addr" => self.addrs = value.split(',').map(|a| a.trim().to_string()).filter(|a| !a.is_empty()).collect(),
+            "aof-path" => self.aof_path = value.to_string(),
+            "aof-dir" => self.aof_dir = value.to_string(),
+            "dbfilename" => self.dbfilename = value.to_string(),
+            "aof-rotate-size" => self.aof_rotate_size = number(value, "a number of bytes")?,
+            "appendfsync" => {
+                self.appendfsync = AppendFsync::parse(value)
+                    .ok_or_else(|| anyhow::anyhow!("must be always, everysec or no, got '{value}'"))?;
+            }
+            "aof-format" => {
+                self.aof_format = AofFormat::parse(value)
+                    .ok_or_else(|| anyhow::anyhow!("must be json or binary, got '{value}'"))?;
+            }
+            "aof-queue-capacity" => self.aof_queue_capacity = number(value, "a number of entries")?,
+            "aof-queue-full-policy" => {
+                self.aof_queue_full_policy = QueueFullPolicy::parse(value)
+                    .ok_or_else(|| anyhow::anyhow!("must be block or error, got '{value}'"))?;
+            }
+            "auto-aof-rewrite-percentage" => self.auto_aof_rewrite_percentage = number(value, "a percentage")?,
+            "auto-aof-rewrite-min-size" => self.auto_aof_rewrite_min_size = number(value, "a number of bytes")?,
+            "sweep-interval" => self.sweep_interval = number(value, "a number of seconds")?,
+            "timeout" => self.timeout = number(value, "a number of seconds")?,
+            "maxclients" => self.maxclients = number(value, "a number")?,
+            "tcp-backlog" => self.tcp_backlog = number(value, "a number")?,
+            "tcp-keepalive" => self.tcp_keepalive = number(value, "a number of seconds")?,
+            "requirepass" => self.requirepass = optional(),
+            "aclfile" => self.aclfile = optional(),
+            "ratelimit-cps" => self.ratelimit_cps = number(value, "a number")?,
+            "ratelimit-burst" => self.ratelimit_burst = number(value, "a number")?,
+            "ratelimit-mode" => {
+                self.ratelimit_mode = RateLimitMode::parse(value)
+                    .ok_or_else(|| anyhow::anyhow!("must be delay or reject, got '{value}'"))?;
+            }
+            "readonly" => {
+                self.readonly = parse_bool(value)
+                    .ok_or_else(|| anyhow::anyhow!("must be yes or no, got '{value}'"))?;
+            }
+            "max-key-len" => self.max_key_len = number(value, "a number of bytes")?,
+            "proto-max-bulk-len" => self.max_value_len = number(value, "a number of bytes")?,
+            "max-collection-len" => self.max_collection_len = number(value, "a number of elements")?,
+            "ttl-jitter-pct" => self.ttl_jitter_pct = number(value, "a percentage")?,
+            "maxmemory" => self.maxmemory = number(value, "a number of bytes")?,
+            "maxmemory-policy" => {
+                self.maxmemory_policy = MaxMemoryPolicy::parse(value)
+                    .ok_or_else(|| anyhow::anyhow!("must be noeviction, allkeys-lfu, volatile-lfu, allkeys-lru or volatile-lru, got '{value}'"))?;
+            }
+            "keys-limit" => self.keys_limit = number(value, "a number of keys")?,
+            "keys-limit-policy" => {
+                self.keys_limit_policy = KeysLimitPolicy::parse(value)
+                    .ok_or_else(|| anyhow::anyhow!("must be require-prefix or truncate, got '{value}'"))?;
+            }
+            "slowlog-log-slower-than" => self.slowlog_log_slower_than = number(value, "a number of microseconds")?,
+            "slowlog-max-len" => self.slowlog_max_len = number(value, "a number")?,
+            "slowlog-keys-scanned" => self.slowlog_keys_scanned = number(value, "a number of keys")?,
+            "shutdown-timeout" => self.shutdown_timeout = number(value, "a number of seconds")?,
+            "tls-cert-file" => self.tls_cert_file = optional(),
+            "tls-key-file" => self.tls_key_file = optional(),
+            "tls-ca-cert-file" => self.tls_ca_cert_file = optional(),
+            "replicaof" => self.replicaof = optional(),
+            "aof-checksum-policy" => {
+                self.aof_checksum_policy = ChecksumPolicy::parse(value)
+                    .ok_or_else(|| anyhow::anyhow!("must be abort or skip, got '{value}'"))?;
+            }
+            "appendonly" => {
+                self.appendonly = parse_bool(value)
+                    .ok_or_else(|| anyhow::anyhow!("must be yes or no, got '{value}'"))?;
+            }
+            "aof-load-truncated" => {
+                self.aof_load_truncated = parse_bool(value)
+                    .ok_or_else(|| anyhow::anyhow!("must be yes or no, got '{value}'"))?;
+            }
+            "enable-debug-command" => {
+                self.enable_debug_command = parse_bool(value)
+                    .ok_or_else(|| anyhow::anyhow!("must be yes or no, got '{value}'"))?;
+            }
+            _ => anyhow::bail!("unknown setting '{name}'"),
+        }
+        Ok(())
+    }
+
+    /// checks settings that are valid on their own but not in combination, or
+    /// that would otherwise only fail once the server is running
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.addrs.is_empty() {
+            anyhow::bail!("addr needs at least one address to listen on");
+        }
+        for addr in &self.addrs {
+            parse_host_port(addr).map_err(|e| anyhow::anyhow!("addr: {e}"))?;
+        }
+        if self.aof_path.is_empty() || self.dbfilename.is_empty() {
+            anyhow::bail!("aof-path and dbfilename must not be empty");
+        }
+        if self.sweep_interval == 0 {
+            anyhow::bail!("sweep-interval must be at least 1 second");
+        }
+        if self.maxclients == 0 {
+            anyhow::bail!("maxclients must be at least 1");
+        }
+        if self.tcp_backlog == 0 {
+            anyhow::bail!("tcp-backlog must be at least 1");
+        }
+        if self.max_key_len == 0 || self.max_value_len == 0 {
+            anyhow::bail!("max-key-len and proto-max-bulk-len must be at least 1");
+        }
+        if self.ttl_jitter_pct > 100 {
+            anyhow::bail!("ttl-jitter-pct must be at most 100");
+        }
+        if self.tls_cert_file.is_some() != self.tls_key_file.is_some() {
+            anyhow::bail!("tls-cert-file and tls-key-file must be set together");
+        }
+        if self.tls_ca_cert_file.is_some() && self.tls_cert_file.is_none() {
+            anyhow::bail!("tls-ca-cert-file needs tls-cert-file and tls-key-file");
+        }
+        if let Some(primary) = &self.replicaof {
+            parse_host_port(primary).map_err(|e| anyhow::anyhow!("replicaof: {e}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// the command line parser, one `--name` flag (with its env var) per setting
+fn command() -> Command {
+    let config = Arg::new("config")
+        .long("config")
+        .env("KV_CONFIG")
+        .value_name("FILE")
+        .help("redis.conf-style file of `name value` lines");
+    SETTINGS.iter().fold(
+        Command::new("kvstore")
+            .version(env!("CARGO_PKG_VERSION"))
+            .about("A Redis-like key-value server")
+            .arg(config),
+        |cmd, &(name, env, help)| cmd.arg(Arg::new(name).long(name).env(env).value_name("VALUE").help(help)),
+    )
+}
+
+fn number<T: FromStr>(value: &str, what: &str) -> anyhow::Result<T> {
+    value.parse().map_err(|_| anyhow::anyhow!("must be {what}, got '{value}'"))
+}
+
+/// splits a `host:port` address
+pub fn parse_host_port(addr: &str) -> anyhow::Result<(String, u16)> {
+    let (host, port) = addr
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow::anyhow!("expected host:port, got '{addr}'"))?;
+    let port = port
+        .parse()
+        .map_err(|_| anyhow::anyhow!("port must be a number, got '{port}'"))?;
+    Ok((host.to_string(), port))
+}
+
+/// parses redis-style booleans (`yes`/`no`), also accepting `true`/`false` and `1`/`0`
+pub fn parse_bool(s: &str) -> Option<bool> {
+    match s.to_lowercase().as_str() {
+        "yes" | "true" | "1" => Some(true),
+        "no" | "false" | "0" => Some(false),
+        _ => None,
+    }
+}
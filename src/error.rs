@@ -1,4 +1,9 @@
-use std::fmt;
+use std::{fmt, io::{self, Write}, sync::Arc};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// bulk strings at least this big are written from their own buffer rather
+/// than copied into the encoded reply
+pub const STREAM_THRESHOLD: usize = 64 * 1024;
 
 #[derive(Debug, Clone)]
 pub enum RedisError {
@@ -8,12 +13,34 @@ pub enum RedisError {
     WrongArguments { command: String, expected: String, got: usize },
     /// invalid data type for operation
     InvalidType(String),
+    /// command run against a key holding another type, which it names
+    WrongType(&'static str),
     /// key not found
     KeyNotFound(String),
     /// value cannot be parsed as integer
     NotInteger(String),
     /// internal server error
     Internal(String),
+    /// write attempted while the server is read-only
+    ReadOnly,
+    /// client went over its command rate limit
+    RateLimited,
+    /// write refused because the dataset is over maxmemory and nothing can be evicted
+    OutOfMemory,
+    /// write refused because the AOF writer can't write, or has stopped
+    PersistenceFailed,
+    /// write refused because the AOF queue is full, under aof-queue-full-policy error
+    AofQueueFull,
+    /// command sent before AUTH on a server with requirepass
+    NoAuth,
+    /// AUTH with the wrong password
+    WrongPass,
+    /// command or key outside what the connection's ACL user is allowed
+    NoPerm(String),
+    /// EXEC of a transaction that had a command rejected while queueing
+    ExecAbort,
+    /// an error reply read back from a server, kept verbatim
+    Reply(String),
 }
 
 impl fmt::Display for RedisError {
@@ -24,9 +51,22 @@ impl fmt::Display for RedisError {
                 write!(f, "ERR wrong number of arguments for '{}' command. Expected {}, got {}", command, expected, got)
             },
             RedisError::InvalidType(msg) => write!(f, "ERR {}", msg),
+            RedisError::WrongType(actual) => {
+                write!(f, "WRONGTYPE Operation against a key holding the wrong kind of value, it holds a {}", actual)
+            },
             RedisError::KeyNotFound(key) => write!(f, "ERR key '{}' not found", key),
             RedisError::NotInteger(val) => write!(f, "ERR value '{}' is not an integer or out of range", val),
             RedisError::Internal(msg) => write!(f, "ERR internal error: {}", msg),
+            RedisError::ReadOnly => write!(f, "READONLY You can't write against a read only server"),
+            RedisError::RateLimited => write!(f, "BUSY rate limit exceeded"),
+            RedisError::OutOfMemory => write!(f, "OOM command not allowed when used memory > 'maxmemory'"),
+            RedisError::PersistenceFailed => write!(f, "MISCONF AOF persistence failed, writes are refused until it recovers"),
+            RedisError::AofQueueFull => write!(f, "BUSY AOF write queue is full, try again later"),
+            RedisError::NoAuth => write!(f, "NOAUTH Authentication required."),
+            RedisError::WrongPass => write!(f, "WRONGPASS invalid username-password pair or user is disabled."),
+            RedisError::NoPerm(msg) => write!(f, "NOPERM {}", msg),
+            RedisError::ExecAbort => write!(f, "EXECABORT Transaction discarded because of previous errors."),
+            RedisError::Reply(msg) => write!(f, "{}", msg),
         }
     }
 }
@@ -41,6 +81,10 @@ pub enum Response {
     Error(RedisError),
     Integer(i64),
     BulkString(Option<String>),
+    /// a bulk string that needn't be utf-8, written to the wire as is
+    BulkBytes(Vec<u8>),
+    /// a bulk string shared with the stored value, so replying doesn't copy it
+    BulkShared(Arc<str>),
     Array(Vec<Response>),
     Nil,
 }
@@ -53,6 +97,8 @@ impl fmt::Display for Response {
             Response::Integer(i) => write!(f, "{}", i),
             Response::BulkString(Some(s)) => write!(f, "{}", s),
             Response::BulkString(None) | Response::Nil => write!(f, "(nil)"),
+            Response::BulkBytes(b) => write!(f, "{}", String::from_utf8_lossy(b)),
+            Response::BulkShared(s) => write!(f, "{}", s),
             Response::Array(arr) => {
                 if arr.is_empty() {
                     write!(f, "(empty)")
@@ -65,6 +111,96 @@ impl fmt::Display for Response {
     }
 }
 
+impl Response {
+    /// the text of a bulk string reply, shared or not
+    pub fn bulk_str(&self) -> Option<&str> {
+        match self {
+            Response::BulkString(Some(s)) => Some(s),
+            Response::BulkShared(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// encodes the response in RESP2 wire format
+    pub fn to_resp(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write_resp(&mut out);
+        out
+    }
+
+    /// writes the response in RESP2 wire format. unlike `to_resp`, large bulk
+    /// strings go to the socket straight from the response, so a big GET isn't
+    /// held in memory twice
+    pub async fn write_resp_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+        let mut out = Vec::new();
+        self.buffer_resp(&mut out, writer).await?;
+        writer.write_all(&out).await
+    }
+
+    /// appends the RESP encoding to `out`, a connection's pending output. if the
+    /// response holds large bulk strings, `out` and the response are written to
+    /// `writer` right away and `out` is left empty
+    pub async fn buffer_resp<W: AsyncWrite + Unpin>(&self, out: &mut Vec<u8>, writer: &mut W) -> io::Result<()> {
+        let mut streamed = Vec::new();
+        self.encode(out, STREAM_THRESHOLD, &mut streamed);
+        if streamed.is_empty() {
+            return Ok(());
+        }
+        let mut at = 0;
+        for (offset, bytes) in streamed {
+            writer.write_all(&out[at..offset]).await?;
+            writer.write_all(bytes).await?;
+            at = offset;
+        }
+        writer.write_all(&out[at..]).await?;
+        out.clear();
+        Ok(())
+    }
+
+    fn write_resp(&self, out: &mut Vec<u8>) {
+        self.encode(out, usize::MAX, &mut Vec::new());
+    }
+
+    /// encodes into `out`, except bulk strings of `stream_from` bytes or more,
+    /// which are left out and recorded with the offset they belong at
+    fn encode<'a>(&'a self, out: &mut Vec<u8>, stream_from: usize, streamed: &mut Vec<(usize, &'a [u8])>) {
+        // writing to a Vec can't fail, and formatting in place saves a String per reply
+        match self {
+            Response::SimpleString(s) => {
+                out.push(b'+');
+                out.extend_from_slice(s.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            Response::Error(e) => {
+                let _ = write!(out, "-{}\r\n", e);
+            }
+            Response::Integer(i) => {
+                let _ = write!(out, ":{}\r\n", i);
+            }
+            Response::BulkString(Some(s)) => encode_bulk(s.as_bytes(), out, stream_from, streamed),
+            Response::BulkBytes(b) => encode_bulk(b, out, stream_from, streamed),
+            Response::BulkShared(s) => encode_bulk(s.as_bytes(), out, stream_from, streamed),
+            Response::BulkString(None) | Response::Nil => out.extend_from_slice(b"$-1\r\n"),
+            Response::Array(arr) => {
+                let _ = write!(out, "*{}\r\n", arr.len());
+                for item in arr {
+                    item.encode(out, stream_from, streamed);
+                }
+            }
+        }
+    }
+}
+
+fn encode_bulk<'a>(bytes: &'a [u8], out: &mut Vec<u8>, stream_from: usize, streamed: &mut Vec<(usize, &'a [u8])>) {
+    let _ = write!(out, "${}\r\n", bytes.len());
+    if bytes.len() >= stream_from {
+        streamed.push((out.len(), bytes));
+    } else {
+        out.extend_from_slice(bytes);
+    }
+    out.extend_from_slice(b"\r\n");
+}
+
 impl From<RedisError> for Response {
     fn from(error: RedisError) -> Self {
         Response::Error(error)
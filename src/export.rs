@@ -0,0 +1,85 @@
+//! the dataset as JSON lines, one key per line, for debugging and test
+//! fixtures:
+//!
+//! ```text
+//! {"key":"name","type":"string","value":"kv","expires_at_ms":null}
+//! {"key":"tags","type":"set","value":["a","b"],"expires_at_ms":1767225600000}
+//! {"key":"user","type":"hash","value":{"id":"1"},"expires_at_ms":null}
+//! ```
+//!
+//! expiries are absolute unix times in milliseconds
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::SystemTime,
+};
+use serde::{Deserialize, Serialize};
+use crate::types::{from_unix_ms, unix_ms, Entry, RedisValue, SetValue};
+
+/// one exported key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    pub key: String,
+    #[serde(flatten)]
+    pub value: Value,
+    pub expires_at_ms: Option<i64>,
+}
+
+/// a value in its JSON form. set members and hash fields are sorted, so the
+/// same dataset always exports the same way
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum Value {
+    String(String),
+    List(Vec<String>),
+    Set(Vec<String>),
+    Hash(BTreeMap<String, String>),
+}
+
+impl Record {
+    pub fn new(key: &str, value: &RedisValue, expires_at: Option<SystemTime>) -> Self {
+        let value = match value {
+            RedisValue::String(s) => Value::String(s.to_string()),
+            RedisValue::List(list) => Value::List(list.iter().cloned().collect()),
+            RedisValue::Set(set) => {
+                let mut members = set.members();
+                members.sort();
+                Value::Set(members)
+            }
+            RedisValue::Hash(hash) => Value::Hash(hash.iter().map(|(f, v)| (f.clone(), v.clone())).collect()),
+        };
+        Record {
+            key: key.to_string(),
+            value,
+            expires_at_ms: expires_at.map(unix_ms),
+        }
+    }
+
+    pub fn from_entry(key: &str, entry: &Entry) -> Self {
+        Self::new(key, &entry.value, entry.expires_at)
+    }
+
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.expires_at_ms.map(from_unix_ms)
+    }
+
+    pub fn to_value(&self) -> RedisValue {
+        match &self.value {
+            Value::String(s) => RedisValue::String(s.as_str().into()),
+            Value::List(items) => RedisValue::List(items.iter().cloned().collect::<VecDeque<_>>()),
+            Value::Set(members) => {
+                let mut set = SetValue::new();
+                for member in members {
+                    set.insert(member.clone());
+                }
+                RedisValue::Set(set)
+            }
+            Value::Hash(fields) => RedisValue::Hash(fields.iter().map(|(f, v)| (f.clone(), v.clone())).collect()),
+        }
+    }
+
+    /// false once its expiry has passed
+    pub fn is_live(&self) -> bool {
+        self.expires_at().is_none_or(|at| at > SystemTime::now())
+    }
+}
@@ -0,0 +1,249 @@
+//! the keyspace split into shards, each a map behind its own lock, so writes
+//! to keys in different shards don't wait on each other. a key's shard comes
+//! from its hash. locks on several shards are always taken in shard order,
+//! so two callers can never each hold a shard the other is waiting on.
+//!
+//! next to each shard is an index of when its keys with a TTL expire, so the
+//! sweeper only looks at keys whose deadline has passed. it's only ever added
+//! to: an entry goes stale when its key is deleted or gets another TTL, and is
+//! checked against the key when it comes up. a shard's index is locked after
+//! the shard, never before
+//!
+//! shards keep their keys in an IndexMap rather than a HashMap, so eviction
+//! can pick keys at random positions in constant time. removing a key moves
+//! the shard's last one into its place, so positions mean nothing else
+//!
+//! each shard also counts the bytes its keys and values are estimated to
+//! use, for maxmemory. the count is changed by whoever holds the shard's
+//! write lock as they change the shard, so it's exact for a locked shard
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    hash::{BuildHasher, RandomState},
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::SystemTime,
+};
+use indexmap::IndexMap;
+use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use rand::Rng;
+use crate::types::Entry;
+
+pub(crate) type Shard = IndexMap<String, Entry>;
+
+/// deadlines and keys, soonest first
+type Expiries = BinaryHeap<Reverse<(SystemTime, String)>>;
+
+pub(crate) struct Keyspace {
+    shards: Box<[RwLock<Shard>]>,
+    expiries: Box<[Mutex<Expiries>]>,
+    /// estimated bytes used by each shard, see `Entry::usage`
+    used: Box<[AtomicUsize]>,
+    hasher: RandomState,
+}
+
+/// locks on some of the shards, indexed by shard, None for the ones not taken
+pub(crate) struct Locked<G> {
+    guards: Vec<Option<G>>,
+    hasher: RandomState,
+}
+
+pub(crate) type ReadLocked<'a> = Locked<RwLockReadGuard<'a, Shard>>;
+pub(crate) type WriteLocked<'a> = Locked<RwLockWriteGuard<'a, Shard>>;
+
+fn index_of(hasher: &RandomState, shards: usize, key: &str) -> usize {
+    (hasher.hash_one(key) % shards as u64) as usize
+}
+
+impl Keyspace {
+    /// `shards` is at least 1
+    pub(crate) fn new(shards: usize) -> Self {
+        let shards = shards.max(1);
+        Keyspace {
+            shards: (0..shards).map(|_| RwLock::new(IndexMap::new())).collect(),
+            expiries: (0..shards).map(|_| Mutex::new(BinaryHeap::new())).collect(),
+            used: (0..shards).map(|_| AtomicUsize::new(0)).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn index(&self, key: &str) -> usize {
+        index_of(&self.hasher, self.shards.len(), key)
+    }
+
+    pub(crate) fn read(&self, key: &str) -> RwLockReadGuard<'_, Shard> {
+        self.shards[self.index(key)].read()
+    }
+
+    pub(crate) fn write(&self, key: &str) -> RwLockWriteGuard<'_, Shard> {
+        self.shards[self.index(key)].write()
+    }
+
+    /// shard `i` alone, for work that goes shard by shard
+    pub(crate) fn read_shard(&self, i: usize) -> RwLockReadGuard<'_, Shard> {
+        self.shards[i].read()
+    }
+
+    /// shard `i` alone, for work that goes shard by shard
+    pub(crate) fn write_shard(&self, i: usize) -> RwLockWriteGuard<'_, Shard> {
+        self.shards[i].write()
+    }
+
+    /// estimated bytes used by every shard, without locking any
+    pub(crate) fn used(&self) -> usize {
+        self.used.iter().map(|used| used.load(Ordering::Relaxed)).sum()
+    }
+
+    /// adds `bytes` to the count of `key`'s shard, with the shard write locked
+    pub(crate) fn grow(&self, key: &str, bytes: usize) {
+        self.used[self.index(key)].fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// takes `bytes` off the count of `key`'s shard, with the shard write locked
+    pub(crate) fn shrink(&self, key: &str, bytes: usize) {
+        self.used[self.index(key)].fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// read locks on the shards holding `keys`
+    pub(crate) fn read_keys(&self, keys: &[&str]) -> ReadLocked<'_> {
+        self.lock(keys.iter().map(|key| self.index(key)).collect(), RwLock::read)
+    }
+
+    /// write locks on the shards holding `keys`
+    pub(crate) fn write_keys(&self, keys: &[&str]) -> WriteLocked<'_> {
+        self.lock(keys.iter().map(|key| self.index(key)).collect(), RwLock::write)
+    }
+
+    /// read locks on every shard, for a consistent view of the whole keyspace
+    pub(crate) fn read_all(&self) -> ReadLocked<'_> {
+        self.lock((0..self.shards.len()).collect(), RwLock::read)
+    }
+
+    /// write locks on every shard
+    pub(crate) fn write_all(&self) -> WriteLocked<'_> {
+        self.lock((0..self.shards.len()).collect(), RwLock::write)
+    }
+
+    /// records that `key` expires `at`. called with its shard write locked,
+    /// whenever a key gets a TTL
+    pub(crate) fn index_expiry(&self, key: &str, at: SystemTime) {
+        self.expiries[self.index(key)].lock().push(Reverse((at, key.to_string())));
+    }
+
+    /// the next entry in shard `i`'s index whose deadline is at or before
+    /// `now`, taken out of it. called with the shard write locked
+    pub(crate) fn next_expired(&self, i: usize, now: SystemTime) -> Option<(SystemTime, String)> {
+        let mut expiries = self.expiries[i].lock();
+        match expiries.peek() {
+            Some(Reverse((at, _))) if *at <= now => expiries.pop().map(|Reverse(next)| next),
+            _ => None,
+        }
+    }
+
+    /// up to `probes` different entries picked at random from shard `i`'s
+    /// index. stale ones included, so their keys may be gone or have no TTL
+    pub(crate) fn sample_expiries(&self, i: usize, probes: usize, rng: &mut impl Rng) -> Vec<String> {
+        let expiries = self.expiries[i].lock();
+        let slots = expiries.as_slice();
+        rand::seq::index::sample(rng, slots.len(), probes.min(slots.len()))
+            .into_iter()
+            .map(|at| slots[at].0 .1.clone())
+            .collect()
+    }
+
+    /// every key in shard `i`'s index, stale ones included
+    pub(crate) fn expiring_keys(&self, i: usize) -> Vec<String> {
+        self.expiries[i].lock().iter().map(|Reverse((_, key))| key.clone()).collect()
+    }
+
+    /// entries in shard `i`'s index, stale ones included
+    pub(crate) fn indexed_expiries(&self, i: usize) -> usize {
+        self.expiries[i].lock().len()
+    }
+
+    /// rebuilds shard `i`'s index from `shard`, dropping the stale entries,
+    /// and counts its bytes again
+    pub(crate) fn reindex(&self, i: usize, shard: &Shard) {
+        *self.expiries[i].lock() = shard.iter()
+            .filter_map(|(key, entry)| entry.expires_at.map(|at| Reverse((at, key.clone()))))
+            .collect();
+        let used = shard.iter().map(|(key, entry)| entry.usage(key)).sum();
+        self.used[i].store(used, Ordering::Relaxed);
+    }
+
+    /// `reindex` for every shard, after loading or replacing the dataset
+    pub(crate) fn reindex_all(&self, locked: &WriteLocked) {
+        for (i, shard) in locked.guards.iter().enumerate() {
+            if let Some(shard) = shard {
+                self.reindex(i, shard);
+            }
+        }
+    }
+
+    fn lock<'a, G>(&'a self, mut wanted: Vec<usize>, lock: impl Fn(&'a RwLock<Shard>) -> G) -> Locked<G> {
+        wanted.sort_unstable();
+        wanted.dedup();
+        let mut guards: Vec<Option<G>> = (0..self.shards.len()).map(|_| None).collect();
+        // in shard order, see the module doc
+        for i in wanted {
+            guards[i] = Some(lock(&self.shards[i]));
+        }
+        Locked { guards, hasher: self.hasher.clone() }
+    }
+}
+
+impl<G: Deref<Target = Shard>> Locked<G> {
+    /// the shard holding `key`. panics if it isn't one of the locked ones
+    pub(crate) fn shard(&self, key: &str) -> &Shard {
+        let i = index_of(&self.hasher, self.guards.len(), key);
+        self.guards[i].as_deref().expect("shard of the key is locked")
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&Entry> {
+        self.shard(key).get(key)
+    }
+
+    /// every locked shard's entries
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&String, &Entry)> {
+        self.guards.iter().flatten().flat_map(|shard| shard.iter())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.guards.iter().flatten().map(|shard| shard.len()).sum()
+    }
+}
+
+impl<G: DerefMut<Target = Shard>> Locked<G> {
+    pub(crate) fn shard_mut(&mut self, key: &str) -> &mut Shard {
+        let i = index_of(&self.hasher, self.guards.len(), key);
+        self.guards[i].as_deref_mut().expect("shard of the key is locked")
+    }
+
+    pub(crate) fn get_mut(&mut self, key: &str) -> Option<&mut Entry> {
+        self.shard_mut(key).get_mut(key)
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) -> Option<Entry> {
+        self.shard_mut(key).swap_remove(key)
+    }
+
+    pub(crate) fn insert(&mut self, key: String, entry: Entry) -> Option<Entry> {
+        self.shard_mut(&key).insert(key, entry)
+    }
+
+    /// empties every locked shard, handing back what they held
+    pub(crate) fn drain(&mut self) -> impl Iterator<Item = (String, Entry)> + '_ {
+        self.guards.iter_mut().flatten().flat_map(|shard| shard.drain(..))
+    }
+
+    pub(crate) fn clear(&mut self) {
+        for shard in self.guards.iter_mut().flatten() {
+            shard.clear();
+        }
+    }
+}
@@ -1,10 +1,23 @@
+pub mod acl;
 pub mod aof;
+pub mod client;
+pub mod config;
 pub mod error;
+pub mod export;
+pub mod glob;
+mod keyspace;
 pub mod protocol;
+pub mod ratelimit;
+pub mod replication;
 pub mod server;
+pub mod slowlog;
+pub mod snapshot;
+pub mod stats;
 pub mod store;
+pub mod tls;
 pub mod types;
 
+pub use config::ServerConfig;
 pub use error::{RedisError, Response};
-pub use store::Store;
-pub use types::{Entry, RedisValue}; 
\ No newline at end of file
+pub use store::{MemoryStats, ReplayStats, StatsSnapshot, Store};
+pub use types::{BitOp, BitRange, Entry, ExpireCondition, KeysLimitPolicy, LcsOptions, MaxMemoryPolicy, RangeUnit, RedisValue, SetCondition, SetExpiry, SetValue}; 
\ No newline at end of file
@@ -1,32 +1,46 @@
-mod server;
-mod store;
-mod protocol;
-mod aof;
-mod error;
-mod types;
+use kvstore::{server::{self, Shutdown}, ServerConfig};
 
 use anyhow::Result;
+use tracing::{error, info};
+use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let addr = std::env::var("KV_ADDR").unwrap_or_else(|_| "127.0.0.1:6379".to_string());
-    let aof_path = std::env::var("KV_AOF").unwrap_or_else(|_| "kvstore.aof".to_string());
+    // RUST_LOG picks the levels, KV_LOG_FORMAT=json switches to one JSON object per line
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    if std::env::var("KV_LOG_FORMAT").is_ok_and(|f| f.eq_ignore_ascii_case("json")) {
+        tracing_subscriber::fmt().json().with_env_filter(filter).init();
+    } else {
+        tracing_subscriber::fmt().with_env_filter(filter).init();
+    }
 
-    println!("KVStore starting on {addr} (AOF: {aof_path})");
+    let config = match ServerConfig::load_from(std::env::args_os()) {
+        Ok(config) => config,
+        // --help, --version and bad flags print clap's own message
+        Err(e) => match e.downcast::<clap::Error>() {
+            Ok(e) => e.exit(),
+            Err(e) => {
+                error!("invalid configuration: {e}");
+                std::process::exit(1);
+            }
+        },
+    };
 
-    // start server
-    let srv = server::run(&addr, &aof_path);
+    info!(addrs = ?config.addrs, aof = %config.aof_path, "KVStore starting");
 
-    tokio::select! {
-        res = srv => {
-            if let Err(e) = res {
-                eprintln!("Server error: {e:?}");
-            }
-        }
-        _ = tokio::signal::ctrl_c() => {
-            println!("\nShutting down");
+    // ctrl_c goes through the same path as the SHUTDOWN command so the AOF is flushed
+    let shutdown = Shutdown::new();
+    let on_signal = shutdown.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            info!("shutting down");
+            on_signal.trigger();
         }
+    });
+
+    if let Err(e) = server::serve(config, shutdown).await {
+        error!(error = ?e, "server error");
     }
 
     Ok(())
-}
\ No newline at end of file
+}
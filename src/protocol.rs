@@ -1,17 +1,413 @@
-use crate::{store::Store, error::{RedisError, Response}};
+use std::{fmt, future::Future, io, panic::{self, AssertUnwindSafe}, pin::Pin};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+use tracing::error;
+use crate::{store::Store, error::{RedisError, Response}, types::{BitOp, BitRange, ExpireCondition, LcsOptions, RangeUnit, SetCondition, SetExpiry}};
+
+/// commands that mutate the keyspace, rejected while the server is read-only
+const WRITE_COMMANDS: &[&str] = &[
+    "SET", "GETORSET", "DEL", "DELEQ", "DELPATTERN", "EXPIRE", "PEXPIRE", "EXPIREAT", "PEXPIREAT",
+    "INCR", "SETRANGE", "BITOP", "LPUSH", "LPUSHRET", "LPOP", "SADD", "SREM", "SMOVE", "HSET", "RESTORE",
+];
+
+pub fn is_write_command(cmd: &str) -> bool {
+    WRITE_COMMANDS.iter().any(|c| c.eq_ignore_ascii_case(cmd))
+}
+
+/// commands about the connection itself rather than the dataset, which every
+/// ACL user may run and the store doesn't count as reads or writes
+pub(crate) const CONNECTION_COMMANDS: &[&str] = &["AUTH", "DISCARD", "EXEC", "MULTI", "PING", "QUIT"];
+
+/// every command the server dispatches, including the server scoped ones, as
+/// (name, arguments, summary) for COMMAND DOCS. kept sorted: a command's
+/// position is its id for per-command stats
+pub const COMMANDS: &[(&str, &str, &str)] = &[
+    ("AOFOFFSET", "", "Returns the AOF's size in bytes once everything logged so far is fsynced."),
+    ("AUTH", "[username] password", "Authenticates the connection."),
+    ("BGET", "key timeout", "Returns a key's value, waiting up to timeout seconds for it to be set."),
+    ("BGREWRITEAOF", "", "Rewrites the append only file in the background."),
+    ("BGSAVE", "", "Writes a snapshot of the dataset to disk in the background."),
+    ("BITOP", "AND|OR|XOR|NOT destkey key [key ...]", "Stores the bitwise combination of strings in a key."),
+    ("BITPOS", "key bit [start [end [BYTE|BIT]]]", "Finds the first set or clear bit in a string."),
+    ("CLIENT", "subcommand [arg ...]", "Inspects and manages client connections."),
+    ("COMMAND", "DOCS|COUNT [command ...]", "Describes the commands the server knows."),
+    ("CONFIG", "subcommand [arg ...]", "Reads and changes settings at runtime."),
+    ("DBSIZE", "", "Returns the number of keys."),
+    ("DEBUG", "subcommand [arg ...]", "Test hooks, off unless enable-debug-command is set."),
+    ("DEL", "key", "Deletes a key."),
+    ("DELEQ", "key value", "Deletes a key only if it holds the given value."),
+    ("DELPATTERN", "pattern CONFIRM", "Deletes every key matching a glob pattern."),
+    ("DISCARD", "", "Drops the commands queued since MULTI."),
+    ("DUMP", "key", "Serializes a key's value for RESTORE."),
+    ("EXEC", "", "Runs the commands queued since MULTI."),
+    ("EXISTS", "key", "Checks whether a key exists."),
+    ("EXPIRE", "key seconds [NX|XX|GT|LT]", "Sets a key's time to live in seconds."),
+    ("EXPIREAT", "key unix-time-seconds [NX|XX|GT|LT]", "Sets the unix time in seconds a key expires at."),
+    ("EXPIRETIME", "key", "Returns the unix time in seconds a key expires at."),
+    ("GET", "key", "Returns the string value of a key."),
+    ("GETORSET", "key default [EX seconds]", "Returns a key's value, setting it to default first if it doesn't exist."),
+    ("GETRANGE", "key start end", "Returns a byte range of a string."),
+    ("HGETALL", "key [SORTED]", "Returns every field and value in a hash, sorted by field with SORTED."),
+    ("HRANDFIELD", "key [count [WITHVALUES]]", "Returns random fields from a hash."),
+    ("HSET", "key field value [field value ...]", "Sets fields in a hash."),
+    ("INCR", "key", "Increments the integer value of a key by one."),
+    ("INFO", "[section]", "Returns information and statistics about the server."),
+    ("KEYS", "prefix", "Lists the keys starting with a prefix."),
+    ("LASTSAVE", "", "Returns the unix time of the last successful save."),
+    ("LATENCY", "HISTOGRAM [command ...]", "Reports per-command latency histograms."),
+    ("LCS", "key1 key2 [LEN] [IDX] [MINMATCHLEN len] [WITHMATCHLEN]", "Finds the longest common subsequence of two strings."),
+    ("LLEN", "key", "Returns the length of a list."),
+    ("LPOP", "key", "Removes and returns the first element of a list."),
+    ("LPUSH", "key element [element ...]", "Prepends elements to a list."),
+    ("LPUSHRET", "key element [element ...]", "Prepends elements to a list and returns the list."),
+    ("MEMORY", "subcommand [key] [SAMPLES count]", "Reports the estimated memory used by a key or the dataset."),
+    ("MULTI", "", "Starts a transaction."),
+    ("OBJECT", "subcommand key", "Inspects the internals of a key."),
+    ("PEEK", "key", "Returns a string value without touching the key."),
+    ("PEXPIRE", "key milliseconds [NX|XX|GT|LT]", "Sets a key's time to live in milliseconds."),
+    ("PEXPIREAT", "key unix-time-milliseconds [NX|XX|GT|LT]", "Sets the unix time in milliseconds a key expires at."),
+    ("PEXPIRETIME", "key", "Returns the unix time in milliseconds a key expires at."),
+    ("PING", "", "Returns PONG."),
+    ("PSYNC", "replicationid offset", "Starts replication, same as SYNC."),
+    ("PTTL", "key", "Returns a key's time to live in milliseconds."),
+    ("QUIT", "", "Closes the connection."),
+    ("RANDOMKEY", "[COUNT count] [TYPE type]", "Returns a random key, or up to count distinct ones of a type."),
+    ("REPLICAOF", "host port | NO ONE", "Follows a primary, or stops following one."),
+    ("RESTORE", "key ttl serialized-value [REPLACE]", "Creates a key from a DUMP payload."),
+    ("SADD", "key member [member ...]", "Adds members to a set."),
+    ("SAVE", "", "Writes a snapshot of the dataset to disk."),
+    ("SCARD", "key", "Returns the number of members in a set."),
+    ("SET", "key value [NX|XX] [GET] [EX seconds|PX ms|EXAT unix-time|PXAT unix-time-ms]", "Sets the string value of a key, optionally returning the old one."),
+    ("SETRANGE", "key offset value", "Overwrites part of a string at an offset."),
+    ("SHUTDOWN", "[NOSAVE|SAVE]", "Stops the server."),
+    ("SLAVEOF", "host port | NO ONE", "Same as REPLICAOF."),
+    ("SLOWLOG", "subcommand [arg]", "Reads and resets the slow command log."),
+    ("SMEMBERS", "key [SORTED]", "Returns every member of a set, sorted with SORTED."),
+    ("SMOVE", "source destination member", "Moves a member from one set to another."),
+    ("SREM", "key member [member ...]", "Removes members from a set."),
+    ("SYNC", "", "Starts replication from this server."),
+    ("TTL", "key", "Returns a key's time to live in seconds."),
+    ("VERSION", "key", "Returns a number that changes whenever the key is written."),
+    ("WAIT", "numreplicas timeout", "Waits for replicas to acknowledge this client's writes."),
+    ("WAITAOF", "numlocal timeout", "Waits for the AOF to be written and fsynced up to this point."),
+];
+
+/// the id of an uppercased command name, if it is one we know
+pub fn command_id(cmd: &str) -> Option<usize> {
+    COMMANDS.binary_search_by_key(&cmd, |&(name, ..)| name).ok()
+}
+
+/// the reply to `<CMD> HELP`: a usage line, then each subcommand's usage
+/// followed by an indented description, like redis
+pub fn help_reply(cmd: &str, subcommands: &[(&str, &str)]) -> Response {
+    let mut lines = vec![format!("{cmd} <subcommand> [<arg> [value] [opt] ...]. Subcommands are:")];
+    for (usage, description) in subcommands.iter().chain([&("HELP", "Print this help.")]) {
+        lines.push(usage.to_string());
+        lines.push(format!("    {description}"));
+    }
+    Response::Array(lines.into_iter().map(Response::SimpleString).collect())
+}
+
+/// a parsed request, remembering whether it arrived as RESP so the reply can match
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub args: Vec<String>,
+    pub resp: bool,
+}
+
+/// upper bounds on RESP framing, so a bad header can't make us allocate unbounded memory
+const MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// malformed request framing, carried inside an `InvalidData` io::Error.
+/// remembers whether the request was RESP so the error can be sent back the
+/// way the client is speaking
+#[derive(Debug)]
+pub struct ProtocolError {
+    pub message: String,
+    pub resp: bool,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Protocol error: {}", self.message)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl ProtocolError {
+    /// the ProtocolError inside an io::Error, if that's what it is
+    pub fn of(e: &io::Error) -> Option<&ProtocolError> {
+        e.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+fn protocol_error(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, ProtocolError { message: msg.into(), resp: true })
+}
+
+/// reads the next request, either a RESP array (first byte `*`) or an inline
+/// command line, waiting for as many reads as it takes to arrive. returns None
+/// at EOF, malformed input is an `InvalidData` error and EOF partway through a
+/// request `UnexpectedEof`
+pub async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Request>> {
+    let first = match reader.fill_buf().await?.first() {
+        Some(b) => *b,
+        None => return Ok(None),
+    };
+
+    let mut line = String::new();
+    if first != b'*' {
+        read_frame_line(reader, &mut line).await?;
+        let args = tokenize_inline(&line)
+            .map_err(|message| io::Error::new(io::ErrorKind::InvalidData, ProtocolError { message, resp: false }))?;
+        return Ok(Some(Request { args, resp: false }));
+    }
+
+    read_frame_line(reader, &mut line).await?;
+    let count = parse_header(&line, '*')?;
+    if count > MAX_MULTIBULK_LEN {
+        return Err(protocol_error("invalid multibulk length"));
+    }
+
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        line.clear();
+        read_frame_line(reader, &mut line).await?;
+        let len = parse_header(&line, '$')?;
+        if len > MAX_BULK_LEN {
+            return Err(protocol_error("invalid bulk length"));
+        }
+        let buf = read_bulk(reader, len).await?;
+        args.push(utf8_arg(buf));
+    }
+    Ok(Some(Request { args, resp: true }))
+}
+
+/// how much of a bulk string is allocated up front. the rest grows as bytes
+/// actually arrive, so a `$len` header alone can't make us allocate `len`
+const BULK_PREALLOC: usize = 64 * 1024;
+
+/// reads a `len` byte bulk string and its trailing CRLF, returning the bytes
+async fn read_bulk<R: AsyncBufRead + Unpin>(reader: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity((len + 2).min(BULK_PREALLOC));
+    reader.take(len as u64 + 2).read_to_end(&mut buf).await?;
+    if buf.len() < len + 2 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if !buf.ends_with(b"\r\n") {
+        return Err(protocol_error("expected CRLF after bulk string"));
+    }
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// appends a newline terminated line of a request to `line`. a line cut off
+/// by EOF isn't a command yet, so it's `UnexpectedEof` rather than run
+async fn read_frame_line<R: AsyncBufRead + Unpin>(reader: &mut R, line: &mut String) -> io::Result<()> {
+    reader.read_line(line).await?;
+    if !line.ends_with('\n') {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// encodes a command as a RESP array of bulk strings, the way clients send them
+pub fn encode_request(args: &[String]) -> Vec<u8> {
+    Response::Array(args.iter().map(|a| Response::BulkString(Some(a.clone()))).collect()).to_resp()
+}
+
+/// reads one RESP reply, for the client side of a connection. returns None at
+/// EOF. boxed because arrays nest
+pub fn read_reply<'a, R: AsyncBufRead + Unpin + Send>(
+    reader: &'a mut R,
+) -> Pin<Box<dyn Future<Output = io::Result<Option<Response>>> + Send + 'a>> {
+    Box::pin(async move {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        let (kind, rest) = line.split_at(line.len().min(1));
+        let length = || rest.parse::<i64>().map_err(|_| protocol_error(format!("bad length in '{line}'")));
+        let reply = match kind {
+            "+" => Response::SimpleString(rest.to_string()),
+            "-" => RedisError::Reply(rest.to_string()).into(),
+            ":" => Response::Integer(length()?),
+            "$" => match usize::try_from(length()?) {
+                Err(_) => Response::BulkString(None),
+                Ok(len) if len > MAX_BULK_LEN => return Err(protocol_error("invalid bulk length")),
+                Ok(len) => {
+                    let buf = read_bulk(reader, len).await?;
+                    match String::from_utf8(buf) {
+                        Ok(s) => Response::BulkString(Some(s)),
+                        Err(e) => Response::BulkBytes(e.into_bytes()),
+                    }
+                }
+            },
+            "*" => match usize::try_from(length()?) {
+                Err(_) => Response::Nil,
+                Ok(len) if len > MAX_MULTIBULK_LEN => return Err(protocol_error("invalid multibulk length")),
+                Ok(len) => {
+                    let mut items = Vec::with_capacity(len);
+                    for _ in 0..len {
+                        match read_reply(reader).await? {
+                            Some(item) => items.push(item),
+                            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+                        }
+                    }
+                    Response::Array(items)
+                }
+            },
+            _ => return Err(protocol_error(format!("unexpected reply '{line}'"))),
+        };
+        Ok(Some(reply))
+    })
+}
+
+fn parse_header(line: &str, prefix: char) -> io::Result<usize> {
+    line.trim_end()
+        .strip_prefix(prefix)
+        .and_then(|n| n.parse::<usize>().ok())
+        .ok_or_else(|| protocol_error(format!("expected '{prefix}', got '{}'", line.trim_end())))
+}
+
+/// splits an inline command into arguments the way redis-cli does: whitespace
+/// separated, with "double quotes" (supporting \" \\ \n \r \t \xHH escapes)
+/// and 'single quotes' (supporting only \')
+pub fn tokenize_inline(line: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&c) = chars.peek() else { return Ok(args) };
+
+        // bytes rather than chars, so \xHH escapes above 0x7f are the byte
+        // itself and escaped UTF-8 sequences come out as one character
+        let mut arg = Vec::new();
+        match c {
+            '"' => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('\\') => match chars.next() {
+                            Some('n') => arg.push(b'\n'),
+                            Some('r') => arg.push(b'\r'),
+                            Some('t') => arg.push(b'\t'),
+                            Some('x') => {
+                                let hex: String = chars.clone().take(2).collect();
+                                match u8::from_str_radix(&hex, 16) {
+                                    Ok(b) if hex.len() == 2 => {
+                                        arg.push(b);
+                                        chars.nth(1);
+                                    }
+                                    _ => arg.push(b'x'),
+                                }
+                            }
+                            Some(other) => push_char(&mut arg, other),
+                            None => return Err("unbalanced quotes in request".to_string()),
+                        },
+                        Some('"') => break,
+                        Some(other) => push_char(&mut arg, other),
+                        None => return Err("unbalanced quotes in request".to_string()),
+                    }
+                }
+                if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+                    return Err("closing quote must be followed by a space".to_string());
+                }
+            }
+            '\'' => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('\\') if chars.peek() == Some(&'\'') => {
+                            chars.next();
+                            arg.push(b'\'');
+                        }
+                        Some('\'') => break,
+                        Some(other) => push_char(&mut arg, other),
+                        None => return Err("unbalanced quotes in request".to_string()),
+                    }
+                }
+                if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+                    return Err("closing quote must be followed by a space".to_string());
+                }
+            }
+            _ => {
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    push_char(&mut arg, c);
+                }
+            }
+        }
+        args.push(utf8_arg(arg));
+    }
+}
+
+/// valid UTF-8 keeps the buffer it was read into, with no copy
+fn utf8_arg(buf: Vec<u8>) -> String {
+    String::from_utf8(buf).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned())
+}
+
+fn push_char(arg: &mut Vec<u8>, c: char) {
+    arg.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+}
 
 pub fn handle_command(store: &Store, input: &str) -> Response {
-    let line = input.trim();
-    if line.is_empty() {
-        return RedisError::InvalidCommand("empty command".to_string()).into();
+    match tokenize_inline(input) {
+        Ok(args) => execute(store, &args),
+        Err(e) => RedisError::InvalidType(format!("Protocol error: {e}")).into(),
     }
-    
-    let parts: Vec<&str> = line.split_whitespace().collect();
-    if parts.is_empty() {
-        return RedisError::InvalidCommand("empty command".to_string()).into();
+}
+
+/// runs an already tokenized command against the store
+pub fn execute(store: &Store, args: &[String]) -> Response {
+    guarded(args, || {
+        if let Some(cmd) = args.first() {
+            if is_write_command(&cmd.to_uppercase()) {
+                if store.is_readonly() {
+                    return RedisError::ReadOnly.into();
+                }
+                if let Err(e) = store.check_persistence() {
+                    return e;
+                }
+                // make room before the write, like redis does
+                if let Err(e) = store.evict_if_needed() {
+                    return e;
+                }
+            }
+        }
+        dispatch(store, args)
+    })
+}
+
+/// like `execute` but skips the read-only check, so a replica can apply
+/// the write stream from its primary
+pub fn apply(store: &Store, args: &[String]) -> Response {
+    guarded(args, || dispatch(store, args))
+}
+
+/// runs `f`, turning a panic into an error reply so one bad command doesn't
+/// take the connection down. the store's locks don't poison, so the commands
+/// after it carry on with whatever the panic left behind
+pub fn guarded(args: &[String], f: impl FnOnce() -> Response) -> Response {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| {
+        error!(command = ?args.first(), "command panicked");
+        RedisError::Internal("command panicked".to_string()).into()
+    })
+}
+
+fn dispatch(store: &Store, args: &[String]) -> Response {
+    if args.is_empty() {
+        return RedisError::InvalidType("empty command".to_string()).into();
     }
-    
+    let parts: Vec<&str> = args.iter().map(String::as_str).collect();
+
     let cmd = parts[0].to_uppercase();
+    // connection commands and unknown ones aren't counted
+    if command_id(&cmd).is_some() && !CONNECTION_COMMANDS.contains(&cmd.as_str()) {
+        store.count_command(is_write_command(&cmd));
+    }
 
     match cmd.as_str() {
         "PING" => Response::SimpleString("PONG".to_string()),
@@ -28,23 +424,37 @@ pub fn handle_command(store: &Store, input: &str) -> Response {
             }
             let key = parts[1].to_string();
 
-            if parts.len() >= 5 && parts[parts.len()-2].eq_ignore_ascii_case("EX") {
-                let ttl = parts.last().and_then(|s| s.parse::<u64>().ok());
-                if ttl.is_none() { 
-                    return RedisError::InvalidType("invalid EX ttl".to_string()).into(); 
+            // options are taken off the end, whatever is left after the key is the value
+            let (mut end, mut expiry, mut cond, mut get) = (parts.len(), None, SetCondition::default(), false);
+            loop {
+                let option = (end >= 5 && expiry.is_none()).then(|| SetExpiry::parse(parts[end-2], parts[end-1])).flatten();
+                if let Some(parsed) = option {
+                    match parsed {
+                        Ok(e) => expiry = Some(e),
+                        Err(e) => return e.into(),
+                    }
+                    end -= 2;
+                } else if end >= 4 && parts[end-1].eq_ignore_ascii_case("GET") {
+                    get = true;
+                    end -= 1;
+                } else if end >= 4 && cond.add(parts[end-1]) {
+                    end -= 1;
+                } else {
+                    break;
                 }
-                // value is between parts[2..len-2]
-                let value = parts[2..parts.len()-2].join(" ");
-                if value.is_empty() {
-                    return RedisError::InvalidType("empty value".to_string()).into();
-                }
-                store.set(key, value, ttl)
-            } else {
-                let value = parts[2..].join(" ");
-                if value.is_empty() { 
-                    return RedisError::InvalidType("empty value".to_string()).into(); 
-                }
-                store.set(key, value, None)
+            }
+            if cond.nx && cond.xx {
+                return RedisError::InvalidType("syntax error, NX and XX can't be used together".to_string()).into();
+            }
+            let value = parts[2..end].join(" ");
+            if value.is_empty() {
+                return RedisError::InvalidType("empty value".to_string()).into();
+            }
+            match expiry {
+                _ if get => store.set_get(key, value, expiry, cond),
+                None if cond == SetCondition::default() => store.set(key, value, None),
+                Some(SetExpiry::Ex(secs)) if cond == SetCondition::default() => store.set(key, value, Some(secs)),
+                _ => store.set_if(key, value, expiry, cond),
             }
         }
 
@@ -59,6 +469,36 @@ pub fn handle_command(store: &Store, input: &str) -> Response {
             store.get(parts[1])
         }
 
+        "GETORSET" => {
+            let ttl = match parts.len() {
+                3 => None,
+                5 if parts[3].eq_ignore_ascii_case("EX") => match parts[4].parse::<u64>() {
+                    Ok(ttl) => Some(ttl),
+                    Err(_) => return RedisError::InvalidType("invalid EX ttl".to_string()).into(),
+                },
+                5 => return RedisError::InvalidType(format!("Unsupported option {}", parts[3])).into(),
+                _ => {
+                    return RedisError::WrongArguments {
+                        command: "GETORSET".to_string(),
+                        expected: "2 or 4".to_string(),
+                        got: parts.len() - 1
+                    }.into();
+                }
+            };
+            store.get_or_set(parts[1], parts[2].to_string(), ttl)
+        }
+
+        "PEEK" => {
+            if parts.len() != 2 {
+                return RedisError::WrongArguments {
+                    command: "PEEK".to_string(),
+                    expected: "1".to_string(),
+                    got: parts.len() - 1
+                }.into();
+            }
+            store.peek(parts[1])
+        }
+
         "DEL" => {
             if parts.len() != 2 { 
                 return RedisError::WrongArguments { 
@@ -70,6 +510,32 @@ pub fn handle_command(store: &Store, input: &str) -> Response {
             store.del(parts[1])
         }
 
+        "DELEQ" => {
+            if parts.len() != 3 {
+                return RedisError::WrongArguments {
+                    command: "DELEQ".to_string(),
+                    expected: "2".to_string(),
+                    got: parts.len() - 1
+                }.into();
+            }
+            store.del_if_equal(parts[1], parts[2])
+        }
+
+        "DELPATTERN" => {
+            if parts.len() != 2 && parts.len() != 3 {
+                return RedisError::WrongArguments {
+                    command: "DELPATTERN".to_string(),
+                    expected: "1 or 2".to_string(),
+                    got: parts.len() - 1
+                }.into();
+            }
+            // destructive enough that callers have to opt in explicitly
+            if !parts.get(2).is_some_and(|c| c.eq_ignore_ascii_case("CONFIRM")) {
+                return RedisError::InvalidType("DELPATTERN deletes every matching key, repeat it with CONFIRM to proceed".to_string()).into();
+            }
+            store.del_pattern(parts[1])
+        }
+
         "EXISTS" => {
             if parts.len() != 2 { 
                 return RedisError::WrongArguments { 
@@ -81,15 +547,78 @@ pub fn handle_command(store: &Store, input: &str) -> Response {
             store.exists(parts[1])
         }
 
-        "TTL" => {
+        "DUMP" => {
+            if parts.len() != 2 {
+                return RedisError::WrongArguments {
+                    command: "DUMP".to_string(),
+                    expected: "1".to_string(),
+                    got: parts.len() - 1
+                }.into();
+            }
+            store.dump(parts[1])
+        }
+
+        "RESTORE" => {
+            if !(4..=5).contains(&parts.len()) {
+                return RedisError::WrongArguments {
+                    command: "RESTORE".to_string(),
+                    expected: "3 or 4".to_string(),
+                    got: parts.len() - 1
+                }.into();
+            }
+            let Ok(ttl) = parts[2].parse::<i64>() else {
+                return RedisError::NotInteger(parts[2].to_string()).into();
+            };
+            let replace = match parts.get(4) {
+                None => false,
+                Some(flag) if flag.eq_ignore_ascii_case("REPLACE") => true,
+                Some(_) => return RedisError::InvalidType("syntax error".to_string()).into(),
+            };
+            store.restore(parts[1], ttl, parts[3], replace)
+        }
+
+        "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" => {
+            if parts.len() < 3 {
+                return RedisError::WrongArguments {
+                    command: cmd.clone(),
+                    expected: "at least 2".to_string(),
+                    got: parts.len() - 1
+                }.into();
+            }
+            let Ok(ttl) = parts[2].parse::<i64>() else {
+                return RedisError::NotInteger(parts[2].to_string()).into();
+            };
+            let mut cond = ExpireCondition::default();
+            for flag in &parts[3..] {
+                if !cond.add(flag) {
+                    return RedisError::InvalidType(format!("Unsupported option {flag}")).into();
+                }
+            }
+            if !cond.is_valid() {
+                return RedisError::InvalidType("NX and XX, GT or LT options at the same time are not compatible".to_string()).into();
+            }
+            match cmd.as_str() {
+                "EXPIRE" => store.expire(parts[1], ttl, cond),
+                "PEXPIRE" => store.pexpire(parts[1], ttl, cond),
+                "EXPIREAT" => store.pexpireat(parts[1], ttl.saturating_mul(1000), cond),
+                _ => store.pexpireat(parts[1], ttl, cond),
+            }
+        }
+
+        "TTL" | "PTTL" | "EXPIRETIME" | "PEXPIRETIME" => {
             if parts.len() != 2 { 
                 return RedisError::WrongArguments { 
-                    command: "TTL".to_string(), 
+                    command: cmd.clone(), 
                     expected: "1".to_string(), 
                     got: parts.len() - 1 
                 }.into(); 
             }
-            store.ttl(parts[1])
+            match cmd.as_str() {
+                "TTL" => store.ttl(parts[1]),
+                "PTTL" => store.pttl(parts[1]),
+                "EXPIRETIME" => store.expiretime(parts[1]),
+                _ => store.pexpiretime(parts[1]),
+            }
         }
 
         "KEYS" => {
@@ -103,6 +632,33 @@ pub fn handle_command(store: &Store, input: &str) -> Response {
             store.keys_with_prefix(parts[1])
         }
 
+        "DBSIZE" => {
+            if parts.len() != 1 {
+                return RedisError::WrongArguments {
+                    command: "DBSIZE".to_string(),
+                    expected: "0".to_string(),
+                    got: parts.len() - 1
+                }.into();
+            }
+            store.dbsize()
+        }
+
+        "RANDOMKEY" => {
+            let (mut count, mut kind) = (None, None);
+            for option in parts[1..].chunks(2) {
+                match option {
+                    [name, n] if name.eq_ignore_ascii_case("COUNT") => match n.parse::<i64>() {
+                        Ok(n) if n >= 0 => count = Some(n as usize),
+                        Ok(_) => return RedisError::InvalidType("value is out of range, must be positive".to_string()).into(),
+                        Err(_) => return RedisError::NotInteger(n.to_string()).into(),
+                    },
+                    [name, t] if name.eq_ignore_ascii_case("TYPE") => kind = Some(*t),
+                    _ => return RedisError::InvalidType("syntax error".to_string()).into(),
+                }
+            }
+            store.random_keys(count, kind)
+        }
+
         "INCR" => {
             if parts.len() != 2 { 
                 return RedisError::WrongArguments { 
@@ -114,6 +670,115 @@ pub fn handle_command(store: &Store, input: &str) -> Response {
             store.incr(parts[1])
         }
 
+        "SETRANGE" => {
+            if parts.len() < 4 {
+                return RedisError::WrongArguments {
+                    command: "SETRANGE".to_string(),
+                    expected: "3".to_string(),
+                    got: parts.len() - 1
+                }.into();
+            }
+            let offset = match parts[2].parse::<usize>() {
+                Ok(o) => o,
+                Err(_) => return RedisError::InvalidType("offset is out of range".to_string()).into(),
+            };
+            let value = parts[3..].join(" ");
+            store.setrange(parts[1], offset, &value)
+        }
+
+        "GETRANGE" => {
+            if parts.len() != 4 {
+                return RedisError::WrongArguments {
+                    command: "GETRANGE".to_string(),
+                    expected: "3".to_string(),
+                    got: parts.len() - 1
+                }.into();
+            }
+            let (start, end) = match (parts[2].parse::<i64>(), parts[3].parse::<i64>()) {
+                (Ok(s), Ok(e)) => (s, e),
+                (Err(_), _) => return RedisError::NotInteger(parts[2].to_string()).into(),
+                (_, Err(_)) => return RedisError::NotInteger(parts[3].to_string()).into(),
+            };
+            store.getrange(parts[1], start, end)
+        }
+
+        "BITOP" => {
+            if parts.len() < 4 {
+                return RedisError::WrongArguments {
+                    command: "BITOP".to_string(),
+                    expected: "at least 3".to_string(),
+                    got: parts.len() - 1
+                }.into();
+            }
+            let op = match BitOp::parse(parts[1]) {
+                Some(op) => op,
+                None => return RedisError::InvalidType("syntax error".to_string()).into(),
+            };
+            store.bitop(op, parts[2], &parts[3..])
+        }
+
+        "BITPOS" => {
+            if !(3..=6).contains(&parts.len()) {
+                return RedisError::WrongArguments {
+                    command: "BITPOS".to_string(),
+                    expected: "2 to 5".to_string(),
+                    got: parts.len() - 1
+                }.into();
+            }
+            let bit = match parts[2] {
+                "0" => false,
+                "1" => true,
+                _ => return RedisError::InvalidType("The bit argument must be 1 or 0.".to_string()).into(),
+            };
+            let mut offsets = Vec::new();
+            for p in parts.iter().skip(3).take(2) {
+                match p.parse::<i64>() {
+                    Ok(n) => offsets.push(n),
+                    Err(_) => return RedisError::NotInteger(p.to_string()).into(),
+                }
+            }
+            let unit = match parts.get(5).map(|u| u.to_uppercase()) {
+                None => RangeUnit::Byte,
+                Some(u) if u == "BYTE" => RangeUnit::Byte,
+                Some(u) if u == "BIT" => RangeUnit::Bit,
+                Some(_) => return RedisError::InvalidType("syntax error".to_string()).into(),
+            };
+            let range = offsets.first().map(|&start| BitRange { start, end: offsets.get(1).copied(), unit });
+            store.bitpos(parts[1], bit, range)
+        }
+
+        "LCS" => {
+            if parts.len() < 3 {
+                return RedisError::WrongArguments {
+                    command: "LCS".to_string(),
+                    expected: "at least 2".to_string(),
+                    got: parts.len() - 1,
+                }.into();
+            }
+            let mut opts = LcsOptions::default();
+            let mut rest = parts[3..].iter();
+            while let Some(opt) = rest.next() {
+                match opt.to_uppercase().as_str() {
+                    "LEN" => opts.len = true,
+                    "IDX" => opts.idx = true,
+                    "WITHMATCHLEN" => opts.with_match_len = true,
+                    "MINMATCHLEN" => match rest.next().map(|n| (n, n.parse::<i64>())) {
+                        // like redis, a negative minimum matches everything
+                        Some((_, Ok(n))) => opts.min_match_len = n.max(0) as usize,
+                        Some((n, Err(_))) => return RedisError::NotInteger(n.to_string()).into(),
+                        None => return RedisError::InvalidType("syntax error".to_string()).into(),
+                    },
+                    _ => return RedisError::InvalidType("syntax error".to_string()).into(),
+                }
+            }
+            if opts.len && opts.idx {
+                return RedisError::InvalidType(
+                    "If you want both the length and indexes, please just use IDX.".to_string(),
+                ).into();
+            }
+            store.lcs(parts[1], parts[2], opts)
+        }
+
         // list ops
         "LPUSH" => {
             if parts.len() < 3 {
@@ -123,9 +788,18 @@ pub fn handle_command(store: &Store, input: &str) -> Response {
                     got: parts.len() - 1 
                 }.into();
             }
-            let key = parts[1];
-            let values: Vec<String> = parts[2..].iter().map(|s| s.to_string()).collect();
-            store.lpush(key, values)
+            store.lpush(parts[1], &parts[2..])
+        }
+
+        "LPUSHRET" => {
+            if parts.len() < 3 {
+                return RedisError::WrongArguments {
+                    command: "LPUSHRET".to_string(),
+                    expected: "at least 2".to_string(),
+                    got: parts.len() - 1,
+                }.into();
+            }
+            store.lpush_return(parts[1], &parts[2..])
         }
 
         "LPOP" => {
@@ -159,9 +833,7 @@ pub fn handle_command(store: &Store, input: &str) -> Response {
                     got: parts.len() - 1 
                 }.into();
             }
-            let key = parts[1];
-            let members: Vec<String> = parts[2..].iter().map(|s| s.to_string()).collect();
-            store.sadd(key, members)
+            store.sadd(parts[1], &parts[2..])
         }
 
         "SREM" => {
@@ -172,9 +844,7 @@ pub fn handle_command(store: &Store, input: &str) -> Response {
                     got: parts.len() - 1 
                 }.into();
             }
-            let key = parts[1];
-            let members: Vec<String> = parts[2..].iter().map(|s| s.to_string()).collect();
-            store.srem(key, members)
+            store.srem(parts[1], &parts[2..])
         }
 
         "SCARD" => {
@@ -188,6 +858,184 @@ pub fn handle_command(store: &Store, input: &str) -> Response {
             store.scard(parts[1])
         }
 
+        "SMEMBERS" => match sorted_flag("SMEMBERS", &parts) {
+            Ok(sorted) => store.smembers(parts[1], sorted),
+            Err(e) => e,
+        },
+
+        "SMOVE" => {
+            if parts.len() != 4 {
+                return RedisError::WrongArguments {
+                    command: "SMOVE".to_string(),
+                    expected: "3".to_string(),
+                    got: parts.len() - 1
+                }.into();
+            }
+            store.smove(parts[1], parts[2], parts[3])
+        }
+
+        // hash ops
+        "HSET" => {
+            if parts.len() < 4 || !parts.len().is_multiple_of(2) {
+                return RedisError::WrongArguments {
+                    command: "HSET".to_string(),
+                    expected: "key followed by field value pairs".to_string(),
+                    got: parts.len() - 1
+                }.into();
+            }
+            let pairs = parts[2..]
+                .chunks_exact(2)
+                .map(|p| (p[0].to_string(), p[1].to_string()))
+                .collect();
+            store.hset(parts[1], pairs)
+        }
+
+        "HGETALL" => match sorted_flag("HGETALL", &parts) {
+            Ok(sorted) => store.hgetall(parts[1], sorted),
+            Err(e) => e,
+        },
+
+        "HRANDFIELD" => {
+            if !(2..=4).contains(&parts.len()) {
+                return RedisError::WrongArguments {
+                    command: "HRANDFIELD".to_string(),
+                    expected: "1 to 3".to_string(),
+                    got: parts.len() - 1
+                }.into();
+            }
+            let count = match parts.get(2).map(|c| c.parse::<i64>()) {
+                None => None,
+                Some(Ok(c)) => Some(c),
+                Some(Err(_)) => return RedisError::NotInteger(parts[2].to_string()).into(),
+            };
+            let withvalues = match parts.get(3) {
+                None => false,
+                Some(w) if w.eq_ignore_ascii_case("WITHVALUES") => true,
+                Some(_) => return RedisError::InvalidType("syntax error".to_string()).into(),
+            };
+            store.hrandfield(parts[1], count, withvalues)
+        }
+
+        // keyspace introspection
+        "OBJECT" => {
+            match (parts.get(1).map(|s| s.to_uppercase()).as_deref(), parts.len()) {
+                (Some("FREQ"), 3) => store.object_freq(parts[2]),
+                (Some("ENCODING"), 3) => store.object_encoding(parts[2]),
+                (Some("HELP"), 2) => help_reply("OBJECT", &[
+                    ("ENCODING <key>", "Return the kind of internal representation used to store the value of the key."),
+                    ("FREQ <key>", "Return the access frequency index of the key."),
+                ]),
+                (sub, _) => RedisError::InvalidType(format!(
+                    "unknown subcommand or wrong number of arguments for 'OBJECT|{}'",
+                    sub.unwrap_or_default(),
+                )).into(),
+            }
+        }
+
+        "MEMORY" => memory_command(store, &parts),
+
+        "VERSION" => {
+            if parts.len() != 2 {
+                return RedisError::WrongArguments {
+                    command: "VERSION".to_string(),
+                    expected: "1".to_string(),
+                    got: parts.len() - 1
+                }.into();
+            }
+            store.version_of(parts[1])
+        }
+
+        "COMMAND" => command_command(&parts),
+
         _ => RedisError::InvalidCommand(cmd).into(),
     }
 }
+
+/// `<cmd> key [SORTED]`: whether to sort the reply. the order is otherwise
+/// the hash table's, which changes between runs
+fn sorted_flag(cmd: &str, parts: &[&str]) -> Result<bool, Response> {
+    match parts {
+        [_, _] => Ok(false),
+        [_, _, flag] if flag.eq_ignore_ascii_case("SORTED") => Ok(true),
+        [_, _, _] => Err(RedisError::InvalidType("syntax error".to_string()).into()),
+        _ => Err(RedisError::WrongArguments {
+            command: cmd.to_string(),
+            expected: "1 or 2".to_string(),
+            got: parts.len() - 1,
+        }.into()),
+    }
+}
+
+/// `MEMORY USAGE key [SAMPLES count]` and `MEMORY STATS`. USAGE samples 5
+/// elements of a collection by default like redis, SAMPLES 0 counts them all
+fn memory_command(store: &Store, parts: &[&str]) -> Response {
+    let sub = parts.get(1).map(|s| s.to_uppercase()).unwrap_or_default();
+    match (sub.as_str(), parts.len()) {
+        ("USAGE", 3 | 5) => {
+            let samples = match parts.get(3..5) {
+                None => 5,
+                Some([option, count]) if option.eq_ignore_ascii_case("SAMPLES") => match count.parse::<usize>() {
+                    Ok(count) => count,
+                    Err(_) => return RedisError::NotInteger(count.to_string()).into(),
+                },
+                Some(_) => return RedisError::InvalidType("syntax error".to_string()).into(),
+            };
+            match store.memory_usage_sampled(parts[2], samples) {
+                Some(bytes) => Response::Integer(bytes as i64),
+                None => Response::Nil,
+            }
+        }
+        ("STATS", 2) => {
+            let stats = store.memory_stats();
+            let field = |name: &str| Response::BulkString(Some(name.to_string()));
+            Response::Array(vec![
+                field("total.estimated"), Response::Integer(stats.total as i64),
+                field("overhead.total"), Response::Integer(stats.overhead as i64),
+                field("dataset.bytes"), Response::Integer((stats.total - stats.overhead) as i64),
+                field("keys.count"), Response::Integer(stats.keys as i64),
+                // there's one database, so the breakdown matches the totals
+                field("db.0"), Response::Array(vec![
+                    field("keys"), Response::Integer(stats.keys as i64),
+                    field("bytes"), Response::Integer(stats.total as i64),
+                    field("overhead"), Response::Integer(stats.overhead as i64),
+                ]),
+            ])
+        }
+        ("HELP", 2) => help_reply("MEMORY", &[
+            ("USAGE <key> [SAMPLES <count>]", "Return the estimated bytes used by the key and its value."),
+            ("STATS", "Return the estimated memory used by the dataset."),
+        ]),
+        _ => RedisError::InvalidType(format!(
+            "unknown subcommand or wrong number of arguments for 'MEMORY|{sub}'",
+        )).into(),
+    }
+}
+
+/// `COMMAND DOCS [name ...]`, every command when no names are given, and `COMMAND COUNT`
+fn command_command(parts: &[&str]) -> Response {
+    let sub = parts.get(1).map(|s| s.to_uppercase()).unwrap_or_default();
+    match (sub.as_str(), parts.len()) {
+        ("COUNT", 2) => Response::Integer(COMMANDS.len() as i64),
+        ("DOCS", 2) => Response::Array(COMMANDS.iter().flat_map(command_doc).collect()),
+        ("DOCS", _) => Response::Array(
+            parts[2..].iter()
+                .filter_map(|name| command_id(&name.to_uppercase()))
+                .flat_map(|id| command_doc(&COMMANDS[id]))
+                .collect(),
+        ),
+        ("HELP", 2) => help_reply("COMMAND", &[
+            ("COUNT", "Return the total number of commands in this server."),
+            ("DOCS [<command-name> ...]", "Return documentation details about all commands, or the named ones."),
+        ]),
+        _ => RedisError::InvalidType(format!("unknown subcommand or wrong number of arguments for 'COMMAND|{sub}'")).into(),
+    }
+}
+
+/// one command's entry in COMMAND DOCS: its lowercase name, then its details as a flat map
+fn command_doc(&(name, args, summary): &(&str, &str, &str)) -> [Response; 2] {
+    let bulk = |s: &str| Response::BulkString(Some(s.to_string()));
+    [
+        bulk(&name.to_lowercase()),
+        Response::Array(vec![bulk("summary"), bulk(summary), bulk("arguments"), bulk(args)]),
+    ]
+}
@@ -0,0 +1,73 @@
+use std::time::{Duration, Instant};
+
+/// what to do with a command that arrives while a client is over its limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitMode {
+    /// hold the command back until the bucket has refilled
+    Delay,
+    /// answer with a BUSY error straight away
+    Reject,
+}
+
+impl RateLimitMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "delay" => Some(RateLimitMode::Delay),
+            "reject" => Some(RateLimitMode::Reject),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RateLimitMode::Delay => "delay",
+            RateLimitMode::Reject => "reject",
+        }
+    }
+}
+
+/// token bucket refilled at `rate` tokens a second, holding at most `burst`
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// a full bucket. `burst` is at least 1 so the limit can always be met
+    pub fn new(rate: u64, burst: u64, now: Instant) -> Self {
+        let burst = burst.max(1) as f64;
+        Self { rate: rate as f64, burst, tokens: burst, last: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+
+    /// takes a token if one is available
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// takes a token whether or not one is available, returning how long the
+    /// caller has to wait for it to have been earned
+    pub fn reserve(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
@@ -0,0 +1,322 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use parking_lot::Mutex;
+use tokio::{
+    io::{AsyncBufRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::{broadcast::{self, error::RecvError}, watch},
+    task::JoinHandle,
+};
+use tracing::{info, warn};
+use crate::{
+    error::{RedisError, Response},
+    protocol::{self, encode_request as encode, is_write_command, read_request},
+    store::Store,
+};
+
+/// how many write commands a replica may fall behind before it's dropped and has to resync
+const STREAM_CAPACITY: usize = 10_000;
+
+/// replication state for one server: fans writes out to connected replicas
+/// when acting as a primary, and owns the link task when acting as a replica
+#[derive(Clone)]
+pub struct Replication {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    stream: broadcast::Sender<Arc<Vec<String>>>,
+    /// held around execute + publish and around snapshot + subscribe, so every
+    /// write lands in a replica's snapshot or its stream, never both or neither
+    order: Mutex<()>,
+    /// number of writes published so far, the replication offset
+    offset: AtomicU64,
+    /// offset each attached replica has acknowledged, keyed by client id
+    replicas: Mutex<HashMap<u64, u64>>,
+    /// bumped on every ack so WAIT can re-check
+    acked: watch::Sender<()>,
+    primary: Mutex<Option<Link>>,
+}
+
+/// connection to the primary while this server is a replica
+struct Link {
+    host: String,
+    port: u16,
+    up: Arc<AtomicBool>,
+    offset: Arc<AtomicU64>,
+    task: JoinHandle<()>,
+}
+
+/// role details reported by INFO
+pub enum Role {
+    Primary { replicas: usize, offset: u64 },
+    Replica { host: String, port: u16, link_up: bool, offset: u64 },
+}
+
+impl Replication {
+    pub fn new() -> Self {
+        let (stream, _) = broadcast::channel(STREAM_CAPACITY);
+        Self {
+            inner: Arc::new(Inner {
+                stream,
+                order: Mutex::new(()),
+                offset: AtomicU64::new(0),
+                replicas: Mutex::new(HashMap::new()),
+                acked: watch::Sender::new(()),
+                primary: Mutex::new(None),
+            }),
+        }
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.inner.primary.lock().is_some()
+    }
+
+    pub fn role(&self) -> Role {
+        match &*self.inner.primary.lock() {
+            Some(link) => Role::Replica {
+                host: link.host.clone(),
+                port: link.port,
+                link_up: link.up.load(Ordering::Relaxed),
+                offset: link.offset.load(Ordering::Relaxed),
+            },
+            None => Role::Primary {
+                replicas: self.inner.replicas.lock().len(),
+                offset: self.inner.offset.load(Ordering::Relaxed),
+            },
+        }
+    }
+
+    /// runs a client command, rejecting writes on a replica and publishing
+    /// successful writes to replicas on a primary. `woff` is moved to the
+    /// offset of a published write, so WAIT knows what the client wrote
+    pub fn execute(&self, store: &Store, args: &[String], woff: &mut u64) -> Response {
+        let is_write = args.first().is_some_and(|cmd| is_write_command(&cmd.to_uppercase()));
+        if !is_write {
+            return protocol::execute(store, args);
+        }
+        if self.is_replica() {
+            return RedisError::ReadOnly.into();
+        }
+        let _order = self.inner.order.lock();
+        let resp = protocol::execute(store, args);
+        if !matches!(resp, Response::Error(_)) {
+            self.publish(args, woff);
+        }
+        resp
+    }
+
+    /// runs the commands queued by MULTI back to back, with no other client's
+    /// writes in between. like redis, a command failing at runtime puts its
+    /// error in the reply and the rest still run. a transaction that writes is
+    /// refused as a whole if the server stopped taking writes after it was queued
+    pub fn exec(&self, store: &Store, queued: &[Vec<String>], woff: &mut u64) -> Response {
+        let is_write = |args: &Vec<String>| args.first().is_some_and(|cmd| is_write_command(cmd));
+        if queued.iter().any(is_write) {
+            if self.is_replica() || store.is_readonly() {
+                return RedisError::ReadOnly.into();
+            }
+            if let Err(e) = store.check_persistence() {
+                return e;
+            }
+        }
+        let _order = self.inner.order.lock();
+        let replies = queued.iter()
+            .map(|args| {
+                let resp = protocol::execute(store, args);
+                if is_write(args) && !matches!(resp, Response::Error(_)) {
+                    self.publish(args, woff);
+                }
+                resp
+            })
+            .collect();
+        Response::Array(replies)
+    }
+
+    /// sends a successful write to the replicas. callers hold `order`
+    fn publish(&self, args: &[String], woff: &mut u64) {
+        *woff = self.inner.offset.fetch_add(1, Ordering::Relaxed) + 1;
+        // no receivers just means no replicas are attached
+        let _ = self.inner.stream.send(Arc::new(args.to_vec()));
+    }
+
+    /// serves the replica behind client `id` after it sent SYNC: a FULLRESYNC
+    /// header, a snapshot of the dataset, then every write from then on. ACKs
+    /// from the replica are read concurrently. returns when `closed` resolves,
+    /// the replica disconnects, or it lags too far behind
+    pub async fn feed<R, W>(
+        &self,
+        id: u64,
+        store: &Store,
+        reader: &mut R,
+        writer: &mut W,
+        closed: impl Future<Output = ()>,
+    ) -> io::Result<()>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let (mut rx, snapshot, start) = {
+            let _order = self.inner.order.lock();
+            let start = self.inner.offset.load(Ordering::Relaxed);
+            (self.inner.stream.subscribe(), store.snapshot_commands(), start)
+        };
+        self.inner.replicas.lock().insert(id, 0);
+        let _attached = Attached { inner: &self.inner, id };
+
+        let send = async {
+            let header = ["FULLRESYNC".to_string(), start.to_string(), snapshot.len().to_string()];
+            writer.write_all(&encode(&header)).await?;
+            for cmd in &snapshot {
+                writer.write_all(&encode(cmd)).await?;
+            }
+            loop {
+                match rx.recv().await {
+                    Ok(cmd) => writer.write_all(&encode(&cmd)).await?,
+                    Err(RecvError::Lagged(n)) => {
+                        warn!(replica = id, behind = n, "replica fell too far behind, dropping it");
+                        return Ok(());
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                }
+            }
+        };
+        let acks = async {
+            while let Some(request) = read_request(reader).await? {
+                let args = &request.args;
+                let is_ack = args.len() == 3
+                    && args[0].eq_ignore_ascii_case("REPLCONF")
+                    && args[1].eq_ignore_ascii_case("ACK");
+                if let Some(offset) = is_ack.then(|| args[2].parse::<u64>().ok()).flatten() {
+                    self.inner.replicas.lock().insert(id, offset);
+                    self.inner.acked.send_replace(());
+                }
+            }
+            Ok(())
+        };
+        tokio::select! {
+            _ = closed => Ok(()),
+            r = send => r,
+            r = acks => r,
+        }
+    }
+
+    /// waits until `numreplicas` replicas have acknowledged offset `target`, or
+    /// `timeout` passes (None waits forever). returns how many did
+    pub async fn wait(&self, target: u64, numreplicas: usize, timeout: Option<Duration>) -> usize {
+        let count = || self.inner.replicas.lock().values().filter(|&&acked| acked >= target).count();
+        let mut acked = self.inner.acked.subscribe();
+        let reached = async {
+            while count() < numreplicas {
+                if acked.changed().await.is_err() {
+                    break;
+                }
+            }
+        };
+        match timeout {
+            Some(timeout) => {
+                let _ = tokio::time::timeout(timeout, reached).await;
+            }
+            None => reached.await,
+        }
+        count()
+    }
+
+    /// starts following `host:port`, replacing any previous primary
+    pub fn replicate_from(&self, store: Store, host: String, port: u16) {
+        let up = Arc::new(AtomicBool::new(false));
+        let offset = Arc::new(AtomicU64::new(0));
+        let task = tokio::spawn(follow(format!("{host}:{port}"), store, up.clone(), offset.clone()));
+        let old = self.inner.primary.lock().replace(Link { host, port, up, offset, task });
+        if let Some(old) = old {
+            old.task.abort();
+        }
+    }
+
+    /// stops following the primary, keeping the data replicated so far
+    pub fn promote(&self) {
+        if let Some(link) = self.inner.primary.lock().take() {
+            link.task.abort();
+        }
+    }
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// detaches a replica on every exit path of `feed`
+struct Attached<'a> {
+    inner: &'a Inner,
+    id: u64,
+}
+
+impl Drop for Attached<'_> {
+    fn drop(&mut self) {
+        self.inner.replicas.lock().remove(&self.id);
+    }
+}
+
+/// keeps a replica in sync with `addr`, reconnecting (and resyncing) after failures
+async fn follow(addr: String, store: Store, up: Arc<AtomicBool>, offset: Arc<AtomicU64>) {
+    loop {
+        match sync_from(&addr, &store, &up, &offset).await {
+            Ok(()) => warn!(primary = %addr, "primary closed the replication link"),
+            Err(e) => warn!(primary = %addr, error = %e, "replication failed"),
+        }
+        up.store(false, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn sync_from(addr: &str, store: &Store, up: &AtomicBool, offset: &AtomicU64) -> anyhow::Result<()> {
+    let stream = TcpStream::connect(addr).await?;
+    let (reader, mut writer) = stream.into_split();
+    writer.write_all(&encode(&["SYNC".to_string()])).await?;
+    let mut reader = BufReader::new(reader);
+
+    let header = read_request(&mut reader).await?.map(|r| r.args).unwrap_or_default();
+    let (start, mut snapshot_left) = match header.as_slice() {
+        [cmd, start, count] if cmd == "FULLRESYNC" => (start.parse::<u64>()?, count.parse::<usize>()?),
+        _ => anyhow::bail!("expected FULLRESYNC from primary, got {header:?}"),
+    };
+    // the snapshot replaces whatever this replica had
+    store.flush_all();
+    offset.store(start, Ordering::Relaxed);
+    up.store(true, Ordering::Relaxed);
+    info!(primary = %addr, offset = start, "replicating");
+    if snapshot_left == 0 {
+        send_ack(&mut writer, offset).await?;
+    }
+
+    while let Some(request) = read_request(&mut reader).await? {
+        if let Response::Error(e) = protocol::apply(store, &request.args) {
+            warn!(command = ?request.args.first(), error = %e, "replica failed to apply write");
+        }
+        if snapshot_left > 0 {
+            snapshot_left -= 1;
+        } else {
+            offset.fetch_add(1, Ordering::Relaxed);
+        }
+        // ack once caught up with what has arrived, rather than after every command
+        if snapshot_left == 0 && reader.buffer().is_empty() {
+            send_ack(&mut writer, offset).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn send_ack<W: AsyncWrite + Unpin>(writer: &mut W, offset: &AtomicU64) -> io::Result<()> {
+    let ack = ["REPLCONF".to_string(), "ACK".to_string(), offset.load(Ordering::Relaxed).to_string()];
+    writer.write_all(&encode(&ack)).await
+}
@@ -1,49 +1,1548 @@
-use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use crate::{store::Store, protocol::handle_command, aof::Aof};
+use std::{
+    io::{self, Write},
+    net::SocketAddr,
+    sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc},
+    task::Poll,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use parking_lot::Mutex;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use crate::{
+    acl::{Acl, User},
+    store::{ReplayStats, Store},
+    protocol::{command_id, guarded, help_reply, is_write_command, read_request, ProtocolError, Request},
+    aof::{Aof, AofFormat, AppendFsync, ChecksumPolicy, LogEntry, QueueFullPolicy, Replay},
+    client::{handle_client_command, ClientRegistry},
+    config::{parse_bool, parse_host_port, ServerConfig},
+    error::{RedisError, Response, STREAM_THRESHOLD},
+    ratelimit::{RateLimitMode, TokenBucket},
+    replication::{Replication, Role},
+    slowlog::{handle_slowlog_command, SlowLog},
+    snapshot,
+    stats::{handle_latency_command, info_commandstats, CommandStats},
+    tls,
+    types::{KeysLimitPolicy, MaxMemoryPolicy},
+};
 
+/// commands handled by the connection rather than the store, which can't be queued in a MULTI
+const NOT_IN_MULTI: &[&str] = &[
+    "AUTH", "BGET", "BGREWRITEAOF", "BGSAVE", "CLIENT", "CONFIG", "DEBUG", "INFO", "LASTSAVE", "LATENCY", "PSYNC",
+    "REPLICAOF", "SAVE", "SHUTDOWN", "SLAVEOF", "SLOWLOG", "SYNC", "WAIT",
+    "WAITAOF", "AOFOFFSET",
+];
+
+/// commands that wait on purpose, for a key, replicas, the disk or a timer.
+/// how long they take says nothing about the server, so they're counted but
+/// kept out of SLOWLOG and the commandstats and LATENCY timings. DEBUG
+/// joins them for SLEEP, see `is_blocking`
+const BLOCKING: &[&str] = &["AOFOFFSET", "BGET", "WAIT", "WAITAOF"];
+
+/// longest a TLS handshake may take, or the idle timeout if that's shorter.
+/// until it's done the connection holds a maxclients slot
+#[cfg(feature = "tls")]
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// pending replies are written once they reach this many bytes, even while
+/// more pipelined requests are waiting
+const FLUSH_THRESHOLD: usize = 64 * 1024;
+
+/// how long an automatic AOF rewrite that failed waits before trying again
+const AUTO_REWRITE_RETRY: Duration = Duration::from_secs(10);
+
+/// startup logs its progress through the AOF every this many entries
+const REPLAY_PROGRESS_EVERY: u64 = 1_000_000;
+/// flushes DEBUG RELOAD tries before giving up on writes draining
+const RELOAD_ATTEMPTS: usize = 10;
+
+/// cloneable trigger used to stop the server from a client or a signal handler
+#[derive(Clone)]
+pub struct Shutdown {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (tx, _) = watch::channel(false);
+        Self { tx: Arc::new(tx) }
+    }
+
+    pub fn trigger(&self) {
+        self.tx.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    /// resolves once `trigger` has been called
+    pub async fn wait(&self) {
+        let mut rx = self.tx.subscribe();
+        let _ = rx.wait_for(|triggered| *triggered).await;
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// state shared by the accept loop and every connection
+#[derive(Clone)]
+struct Shared {
+    store: Store,
+    clients: ClientRegistry,
+    shutdown: Shutdown,
+    config: Arc<ServerConfig>,
+    acl: Arc<Acl>,
+    replication: Replication,
+    /// the automatic AOF rewrite task for the AOF in use, if any
+    rewriter: Arc<Mutex<Option<JoinHandle<()>>>>,
+    /// unix time of the last successful SAVE or BGSAVE, starts at boot like redis
+    last_save: Arc<AtomicU64>,
+    bgsave_in_progress: Arc<AtomicBool>,
+    /// held while a SAVE or BGSAVE writes `dbfilename`, so two never write its
+    /// temporary file at once
+    snapshot_lock: Arc<tokio::sync::Mutex<()>>,
+    /// whether the last BGSAVE succeeded, true before the first
+    last_bgsave_ok: Arc<AtomicBool>,
+    slowlog: SlowLog,
+    commandstats: CommandStats,
+    /// commands delayed or rejected by the per-client rate limit
+    throttled: Arc<AtomicU64>,
+    /// seconds a connection may stay idle, `timeout` as changed by CONFIG SET
+    idle_timeout: Arc<AtomicU64>,
+}
+
+/// the old entry point, kept for existing callers. `serve` takes the full config
 pub async fn run(addr: &str, aof_path: &str) -> anyhow::Result<()> {
-    let listener = TcpListener::bind(addr).await?;
-    let aof = Aof::new(aof_path).await.ok();
-    let store = Store::new(aof.clone());
+    let config = ServerConfig {
+        addrs: vec![addr.to_string()],
+        aof_path: aof_path.to_string(),
+        ..ServerConfig::default()
+    };
+    serve(config, Shutdown::new()).await
+}
+
+/// runs the server until `shutdown` is triggered, then drains open connections
+/// (bounded by `shutdown_timeout`) and flushes the AOF before returning
+pub async fn serve(config: ServerConfig, shutdown: Shutdown) -> anyhow::Result<()> {
+    Server::bind(config, shutdown).await?.run().await
+}
+
+/// a server with its listeners bound and its dataset loaded, ready to `run`.
+/// binding first lets callers learn the ports picked for `:0` addresses
+pub struct Server {
+    listeners: Vec<TcpListener>,
+    local_addrs: Vec<SocketAddr>,
+    tls: Option<tls::Acceptor>,
+    shared: Shared,
+}
+
+impl Server {
+    /// binds every configured address, failing if any of them can't be bound,
+    /// then opens the AOF and replays it. a corrupt AOF fails startup
+    pub async fn bind(config: ServerConfig, shutdown: Shutdown) -> anyhow::Result<Self> {
+        let tls = tls::acceptor(&config)?;
+        let mut listeners = Vec::with_capacity(config.addrs.len());
+        let mut local_addrs = Vec::with_capacity(config.addrs.len());
+        for addr in &config.addrs {
+            let listener = listen(addr, config.tcp_backlog)
+                .await
+                .map_err(|e| anyhow::anyhow!("binding {addr}: {e}"))?;
+            local_addrs.push(listener.local_addr()?);
+            listeners.push(listener);
+        }
 
-    // replay AOF
-    if let Ok(entries) = crate::aof::Aof::replay(aof_path) {
-        store.load_from_aof(entries);
+        if config.appendonly && !config.aof_dir.is_empty() {
+            std::fs::create_dir_all(&config.aof_dir).map_err(|e| anyhow::anyhow!("creating {}: {e}", config.aof_dir))?;
+        }
+        let aof_path = config.aof_base();
+        let store = Store::new(None);
+        store.set_readonly(config.readonly);
+        store.set_max_key_len(config.max_key_len);
+        store.set_max_value_len(config.max_value_len);
+        store.set_max_collection_len(config.max_collection_len);
+        store.set_ttl_jitter_pct(config.ttl_jitter_pct);
+        store.set_maxmemory(config.maxmemory);
+        store.set_maxmemory_policy(config.maxmemory_policy);
+        store.set_keys_limit(config.keys_limit);
+        store.set_keys_limit_policy(config.keys_limit_policy);
+
+        // read the AOF before opening it for appends, so a last entry cut
+        // short by a crash can be cut off first. entries are applied as
+        // they're read, on top of the snapshot the AOF continues from, if any
+        let started = Instant::now();
+        let mut replay = match config.appendonly {
+            true => Aof::replay_iter(&aof_path).log_every(REPLAY_PROGRESS_EVERY),
+            false => Replay::default(),
+        };
+        let loaded = load_dataset(&store, &mut replay, &config.dbfilename)?;
+        let summary = replay.summary();
+        if summary.truncated_bytes > 0 {
+            if !config.aof_load_truncated {
+                anyhow::bail!(
+                    "{} ends in a partly written entry ({} bytes), set aof-load-truncated yes to drop it and start",
+                    aof_path,
+                    summary.truncated_bytes,
+                );
+            }
+            summary.repair().map_err(|e| anyhow::anyhow!("truncating {}: {e}", aof_path))?;
+            warn!(path = %aof_path, bytes = summary.truncated_bytes, "AOF ended in a partly written entry, truncated it");
+        }
+        if summary.checksum_failures > 0 {
+            if config.aof_checksum_policy == ChecksumPolicy::Abort {
+                anyhow::bail!(
+                    "{} entries in {} failed their checksum, set aof-checksum-policy skip to start without them",
+                    summary.checksum_failures,
+                    aof_path,
+                );
+            }
+            warn!(path = %aof_path, entries = summary.checksum_failures, "skipped AOF entries that failed their checksum");
+        }
+
+        let aof = if config.appendonly { open_aof(&config).await.ok() } else { None };
+        if let Some(aof) = aof {
+            // so the next start knows the AOF builds on this snapshot
+            if let Some(id) = loaded.unmarked_snapshot {
+                aof.log(LogEntry::snapshot_marker(id));
+            }
+            store.attach_aof(aof);
+        }
+        let stats = &loaded.stats;
+        info!(
+            path = %aof_path,
+            entries = loaded.replayed,
+            covered_by_snapshot = replay.entries_read() - loaded.replayed,
+            bytes = replay.bytes_read(),
+            truncated_bytes = summary.truncated_bytes,
+            checksum_failures = summary.checksum_failures,
+            unverified = summary.unverified,
+            keys_loaded = stats.keys_loaded,
+            deletes_applied = stats.deletes_applied,
+            expired_skipped = stats.expired_skipped,
+            unknown_ops = stats.unknown_ops,
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "replayed AOF",
+        );
+
+        let slowlog = SlowLog::new(config.slowlog_log_slower_than, config.slowlog_max_len);
+        slowlog.set_keys_scanned(config.slowlog_keys_scanned);
+        let acl = Acl::load(&config)?;
+        let idle_timeout = Arc::new(AtomicU64::new(config.timeout));
+        let shared = Shared {
+            store,
+            clients: ClientRegistry::new(),
+            shutdown,
+            config: Arc::new(config),
+            acl: Arc::new(acl),
+            replication: Replication::new(),
+            rewriter: Arc::new(Mutex::new(None)),
+            last_save: Arc::new(AtomicU64::new(unix_now())),
+            bgsave_in_progress: Arc::new(AtomicBool::new(false)),
+            snapshot_lock: Arc::new(tokio::sync::Mutex::new(())),
+            last_bgsave_ok: Arc::new(AtomicBool::new(true)),
+            slowlog,
+            commandstats: CommandStats::new(),
+            throttled: Arc::new(AtomicU64::new(0)),
+            idle_timeout,
+        };
+        Ok(Self { listeners, local_addrs, tls, shared })
     }
 
-    tokio::spawn(store.clone().start_sweeper(2));
+    /// the addresses actually bound, in the order they were configured
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
 
-    println!("Listening on {addr}");
-    loop {
-        let (socket, peer) = listener.accept().await?;
-        let store = store.clone();
-        tokio::spawn(async move {
-            if let Err(e) = handle_client(socket, store).await {
-                eprintln!("client {peer:?} error: {e:?}");
+    /// serves clients until shutdown, see `serve`
+    pub async fn run(self) -> anyhow::Result<()> {
+        let Server { listeners, local_addrs, tls, shared } = self;
+        let shutdown = shared.shutdown.clone();
+        let sweeper = tokio::spawn(shared.store.clone().start_sweeper(shared.config.sweep_interval));
+        if let Some(aof) = shared.store.aof() {
+            start_auto_rewrite(&shared, aof);
+        }
+        if let Some(primary) = &shared.config.replicaof {
+            let (host, port) = parse_host_port(primary)?;
+            shared.replication.replicate_from(shared.store.clone(), host, port);
+        }
+        for addr in &local_addrs {
+            info!(addr = %addr, "listening");
+        }
+        // whichever listener has a connection ready, starting from a different
+        // one each time so a busy address can't starve the others
+        let mut turn = 0;
+        let bound = &listeners;
+        let mut accept = || {
+            turn += 1;
+            let first = turn;
+            std::future::poll_fn(move |cx| {
+                (0..bound.len())
+                    .map(|i| &bound[(first + i) % bound.len()])
+                    .find_map(|listener| match listener.poll_accept(cx) {
+                        Poll::Ready(res) => Some(Poll::Ready(res)),
+                        Poll::Pending => None,
+                    })
+                    .unwrap_or(Poll::Pending)
+            })
+        };
+
+        let mut tasks = JoinSet::new();
+        loop {
+            let (socket, peer) = tokio::select! {
+                _ = shutdown.wait() => break,
+                // reap finished connections so the set doesn't grow forever
+                Some(_) = tasks.join_next(), if !tasks.is_empty() => continue,
+                res = accept() => res?,
+            };
+            if let Err(e) = tune_socket(&socket, shared.config.tcp_keepalive) {
+                debug!(peer = %peer, error = %e, "setting socket options failed");
+            }
+            let shared = shared.clone();
+            if shared.clients.len() >= shared.config.maxclients {
+                // accept then reject, which clients handle more gracefully than a refused connect
+                tasks.spawn(async move {
+                    let mut socket = socket;
+                    let reply = Response::from(RedisError::InvalidType("max number of clients reached".to_string()));
+                    let _ = reply.write_resp_to(&mut socket).await;
+                });
+                continue;
             }
-        });
+            // register before spawning so a burst of accepts can't overshoot maxclients
+            let (registration, kill) = shared.clients.register_guarded(peer);
+            let tls = tls.clone();
+            let span = info_span!("client", id = registration.id(), peer = %peer);
+            tasks.spawn(async move {
+                let res = match tls {
+                    #[cfg(feature = "tls")]
+                    Some(acceptor) => {
+                        let limit = match shared.idle_timeout.load(Ordering::Relaxed) {
+                            0 => TLS_HANDSHAKE_TIMEOUT,
+                            secs => TLS_HANDSHAKE_TIMEOUT.min(Duration::from_secs(secs)),
+                        };
+                        match tokio::time::timeout(limit, acceptor.accept(socket)).await {
+                            Ok(Ok(stream)) => serve_client(stream, registration.id(), &kill, &shared).await,
+                            // a bad or stalled handshake only costs this connection
+                            Ok(Err(e)) => {
+                                warn!(error = %e, "TLS handshake failed");
+                                Ok(())
+                            }
+                            Err(_) => {
+                                warn!(after_secs = limit.as_secs(), "TLS handshake timed out");
+                                Ok(())
+                            }
+                        }
+                    }
+                    #[cfg(not(feature = "tls"))]
+                    Some(acceptor) => match acceptor {},
+                    None => serve_client(socket, registration.id(), &kill, &shared).await,
+                };
+                if let Err(e) = res {
+                    error!(error = ?e, "client error");
+                }
+                drop(registration);
+            }.instrument(span));
+        }
+
+        // stop accepting, let connections finish their current command, then persist
+        drop(listeners);
+        sweeper.abort();
+        if let Some(rewriter) = shared.rewriter.lock().take() {
+            rewriter.abort();
+        }
+        shared.replication.promote();
+        let drain = Duration::from_secs(shared.config.shutdown_timeout);
+        let drained = tokio::time::timeout(drain, async {
+            while tasks.join_next().await.is_some() {}
+        }).await;
+        if drained.is_err() {
+            warn!(open = tasks.len(), after_secs = drain.as_secs(), "connections still open, closing them");
+            tasks.shutdown().await;
+        }
+        if let Some(aof) = shared.store.aof() {
+            aof.flush().await?;
+        }
+        info!("shutdown complete");
+        Ok(())
     }
 }
 
-async fn handle_client(stream: TcpStream, store: Store) -> anyhow::Result<()> {
-    let (reader, mut writer) = stream.into_split();
+async fn serve_client<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    id: u64,
+    kill: &tokio::sync::Notify,
+    shared: &Shared,
+) -> anyhow::Result<()> {
+    let Shared { store, clients, shutdown, config, acl, replication, slowlog, commandstats, .. } = shared;
+    let (reader, mut writer) = tokio::io::split(stream);
     let mut reader = BufReader::new(reader);
-    let mut line = String::new();
+    // replies not yet written. pipelined replies are batched into one write
+    let mut out = Vec::with_capacity(4096);
+    // replication offset of this client's last write, what WAIT waits for
+    let mut woff = 0;
+    // the ACL user the connection runs as, None until it authenticates
+    let mut user = acl.initial_user();
+    // commands queued since MULTI, and whether one of them was rejected
+    let mut multi: Option<Vec<Vec<String>>> = None;
+    let mut multi_failed = false;
+    let mut bucket = rate_limit_bucket(config, user.as_deref());
 
     loop {
-        line.clear();
-        let n = reader.read_line(&mut line).await?;
-        if n == 0 { break; }
-
-        let resp = handle_command(&store, &line);
-        let resp_str = resp.to_string();
-        if resp_str == "BYE" {
-            writer.write_all(b"Bye!!!\n").await?;
-            break;
+        // read for every request, so CONFIG SET timeout reaches open connections
+        // from their next one. like redis, a transaction that's been started
+        // isn't cut off however long it waits between commands
+        let timeout = if multi.is_some() { 0 } else { shared.idle_timeout.load(Ordering::Relaxed) };
+        let request = tokio::select! {
+            biased;
+            _ = kill.notified() => break,
+            _ = shutdown.wait() => break,
+            r = read_request_with_timeout(&mut reader, timeout) => match r {
+                Ok(Some(request)) => request,
+                Ok(None) => {
+                    info!(timeout_secs = timeout, "closing idle client");
+                    break;
+                }
+                // malformed framing can't be resynced, so report it and hang up like redis does
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    if ProtocolError::of(&e).is_some_and(|p| p.resp) {
+                        let _ = write!(out, "-ERR {e}\r\n");
+                    } else {
+                        let _ = writeln!(out, "ERR {e}");
+                    }
+                    break;
+                }
+                // the client hung up partway through a request, nothing to answer
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    debug!("client closed the connection mid-request");
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            },
+        };
+        let Some(Request { args, resp: is_resp }) = request else { break };
+
+        let parts: Vec<&str> = args.iter().map(String::as_str).collect();
+        if let Some(cmd) = parts.first() {
+            clients.touch(id, cmd);
+        }
+
+        // delaying a command also holds back reading the next one, which is
+        // what slows a pipelining client down
+        let mut rejected = false;
+        if let Some(bucket) = &mut bucket {
+            match config.ratelimit_mode {
+                RateLimitMode::Delay => {
+                    let wait = bucket.reserve(Instant::now());
+                    if !wait.is_zero() {
+                        shared.throttled.fetch_add(1, Ordering::Relaxed);
+                        writer.write_all(&out).await?;
+                        out.clear();
+                        tokio::select! {
+                            biased;
+                            _ = kill.notified() => break,
+                            _ = shutdown.wait() => break,
+                            _ = tokio::time::sleep(wait) => {}
+                        }
+                    }
+                }
+                RateLimitMode::Reject => {
+                    rejected = !bucket.try_take(Instant::now());
+                    if rejected {
+                        shared.throttled.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+
+        // connection and server scoped commands are handled here rather than in protocol
+        let cmd = parts.first().map(|c| c.to_uppercase()).unwrap_or_default();
+
+        // a write finding the AOF queue full waits here under the block
+        // policy, which like a rate limit delay also holds back the next command
+        let writes = match &multi {
+            Some(queued) => cmd == "EXEC" && queued.iter().any(|args| is_write_command(&args[0])),
+            None => is_write_command(&cmd),
+        };
+        let aof = shared.store.aof();
+        let blocking = aof.as_ref().filter(|aof| aof.queue_limit().1 == QueueFullPolicy::Block);
+        if let Some(aof) = blocking.filter(|aof| writes && user.is_some() && !rejected && !aof.has_room()) {
+            writer.write_all(&out).await?;
+            out.clear();
+            tokio::select! {
+                biased;
+                _ = kill.notified() => break,
+                _ = shutdown.wait() => break,
+                _ = aof.wait_for_room() => {}
+            }
+        }
+        let mut denied = user.as_ref().and_then(|user| user.check(&cmd, &parts).err());
+        debug!(cmd = %cmd, args = parts.len().saturating_sub(1), "command");
+        let started = Instant::now();
+        let resp = match cmd.as_str() {
+            _ if rejected => RedisError::RateLimited.into(),
+            // a `*0` array or a blank inline line, answered the same way
+            // whether or not the client is authenticated or inside a MULTI
+            "" if parts.is_empty() => RedisError::InvalidType("empty command".to_string()).into(),
+            "AUTH" => {
+                let reply = auth_command(acl, &parts, &mut user);
+                // the new user may have a rate limit of their own
+                if !matches!(reply, Response::Error(_)) {
+                    bucket = rate_limit_bucket(config, user.as_deref());
+                }
+                reply
+            }
+            _ if user.is_none() && cmd != "QUIT" => RedisError::NoAuth.into(),
+            // before queueing, so a MULTI can't smuggle in a command the user may not run
+            _ if denied.is_some() => {
+                if multi.is_some() {
+                    multi_failed = true;
+                }
+                denied.take().unwrap().into()
+            }
+            "MULTI" if multi.is_some() => RedisError::InvalidType("MULTI calls can not be nested".to_string()).into(),
+            "MULTI" => {
+                multi = Some(Vec::new());
+                multi_failed = false;
+                "OK".into()
+            }
+            "EXEC" => match multi.take() {
+                None => RedisError::InvalidType("EXEC without MULTI".to_string()).into(),
+                Some(_) if multi_failed => RedisError::ExecAbort.into(),
+                Some(queued) => replication.exec(store, &queued, &mut woff),
+            },
+            "DISCARD" => match multi.take() {
+                None => RedisError::InvalidType("DISCARD without MULTI".to_string()).into(),
+                Some(_) => "OK".into(),
+            },
+            _ if multi.is_some() && cmd != "QUIT" => {
+                if command_id(&cmd).is_none() {
+                    multi_failed = true;
+                    RedisError::InvalidCommand(cmd.clone()).into()
+                } else if NOT_IN_MULTI.contains(&cmd.as_str()) {
+                    multi_failed = true;
+                    RedisError::InvalidType(format!("{cmd} is not allowed in a transaction")).into()
+                } else {
+                    multi.as_mut().unwrap().push(args.clone());
+                    Response::SimpleString("QUEUED".to_string())
+                }
+            }
+            "CLIENT" => handle_client_command(clients, id, &parts),
+            "SLOWLOG" => handle_slowlog_command(slowlog, &parts),
+            "LATENCY" => handle_latency_command(commandstats, &parts),
+            "CONFIG" if parts.len() == 4 && parts[1].eq_ignore_ascii_case("SET") && parts[2].eq_ignore_ascii_case("appendonly") => {
+                set_appendonly(shared, parts[3]).await
+            }
+            "CONFIG" => config_command(shared, &parts),
+            "INFO" => match parts.len() {
+                1 => Response::BulkString(Some(info(shared, None))),
+                2 => Response::BulkString(Some(info(shared, Some(parts[1])))),
+                n => RedisError::WrongArguments {
+                    command: "INFO".to_string(),
+                    expected: "0 or 1".to_string(),
+                    got: n - 1,
+                }.into(),
+            },
+            "SAVE" | "BGSAVE" | "LASTSAVE" | "BGREWRITEAOF" if parts.len() != 1 => RedisError::WrongArguments {
+                command: cmd.clone(),
+                expected: "0".to_string(),
+                got: parts.len() - 1,
+            }.into(),
+            "SAVE" => save(shared).await,
+            "BGSAVE" => bgsave(shared),
+            "LASTSAVE" => Response::Integer(shared.last_save.load(Ordering::Relaxed) as i64),
+            "BGREWRITEAOF" => bgrewriteaof(shared),
+            "REPLICAOF" | "SLAVEOF" => replicaof_command(shared, &parts),
+            "WAIT" => match wait_args(shared, &parts) {
+                Ok((numreplicas, timeout)) => tokio::select! {
+                    biased;
+                    _ = kill.notified() => break,
+                    _ = shutdown.wait() => break,
+                    acked = replication.wait(woff, numreplicas, timeout) => Response::Integer(acked as i64),
+                },
+                Err(e) => e,
+            },
+            "WAITAOF" => match waitaof_args(shared, &parts) {
+                // nothing asked for, nothing to wait for
+                Ok((0, _)) => Response::Integer(0),
+                Ok((_, timeout)) => tokio::select! {
+                    biased;
+                    _ = kill.notified() => break,
+                    _ = shutdown.wait() => break,
+                    synced = wait_aof(shared, timeout) => Response::Integer(i64::from(synced)),
+                },
+                Err(e) => e,
+            },
+            "AOFOFFSET" => match aofoffset_args(shared, &parts) {
+                Ok(()) => tokio::select! {
+                    biased;
+                    _ = kill.notified() => break,
+                    _ = shutdown.wait() => break,
+                    offset = aof_offset(shared) => offset,
+                },
+                Err(e) => e,
+            },
+            "BGET" => match bget_args(&parts) {
+                Ok(timeout) => tokio::select! {
+                    biased;
+                    _ = kill.notified() => break,
+                    _ = shutdown.wait() => break,
+                    found = shared.store.blocking_get(parts[1], timeout) => found,
+                },
+                Err(e) => e,
+            },
+            "DEBUG" if !config.enable_debug_command => RedisError::InvalidType(
+                "DEBUG command not allowed. If the enable-debug-command option is set to \"no\", you can't use it".to_string(),
+            ).into(),
+            "DEBUG" if parts.get(1).is_some_and(|sub| sub.eq_ignore_ascii_case("SLEEP")) => match debug_sleep_args(&parts) {
+                Ok(duration) => tokio::select! {
+                    biased;
+                    _ = kill.notified() => break,
+                    _ = shutdown.wait() => break,
+                    _ = tokio::time::sleep(duration) => "OK".into(),
+                },
+                Err(e) => e,
+            },
+            "DEBUG" if parts.get(1).is_some_and(|sub| sub.eq_ignore_ascii_case("RELOAD")) => match parts.len() {
+                2 => debug_reload(shared).await,
+                n => RedisError::WrongArguments { command: "DEBUG RELOAD".to_string(), expected: "0".to_string(), got: n - 2 }.into(),
+            },
+            "DEBUG" => debug_command(shared, &parts),
+            // from here on the connection belongs to a replica and only carries the write stream
+            "SYNC" | "PSYNC" => {
+                let closed = async {
+                    tokio::select! {
+                        _ = kill.notified() => {}
+                        _ = shutdown.wait() => {}
+                    }
+                };
+                writer.write_all(&out).await?;
+                out.clear();
+                replication.feed(id, store, &mut reader, &mut writer, closed).await?;
+                break;
+            }
+            "SHUTDOWN" => match shutdown_command(&parts) {
+                Ok(true) if matches!(save(shared).await, Response::Error(_)) => {
+                    RedisError::InvalidType("Errors trying to SHUTDOWN. Check logs.".to_string()).into()
+                }
+                Ok(_) => {
+                    // redis doesn't reply to a successful SHUTDOWN, the connection just closes
+                    shutdown.trigger();
+                    break;
+                }
+                Err(e) => e,
+            },
+            _ => replication.execute(store, &args, &mut woff),
+        };
+        let elapsed = started.elapsed();
+        if is_blocking(&cmd, &parts) {
+            commandstats.count(&cmd);
         } else {
-            writer.write_all(format!("{resp_str}\n").as_bytes()).await?;
+            commandstats.record(&cmd, elapsed);
+            let client = || match clients.get(id) {
+                Some(info) => (info.addr.to_string(), info.name.unwrap_or_default()),
+                None => (String::new(), String::new()),
+            };
+            // KEYS walks the whole keyspace, so a big one is logged even when it was quick
+            if cmd == "KEYS" && slowlog.is_big_scan(store.entry_count()) {
+                slowlog.log(elapsed, &args, client);
+            } else {
+                slowlog.record(elapsed, &args, client);
+            }
+        }
+
+        let quit = matches!(&resp, Response::SimpleString(s) if s == "BYE");
+        if is_resp {
+            let reply = if quit { Response::SimpleString("OK".to_string()) } else { resp };
+            reply.buffer_resp(&mut out, &mut writer).await?;
+        } else if quit {
+            out.extend_from_slice(b"Bye!!!\n");
+        } else {
+            match (&resp, resp.bulk_str()) {
+                // large values go out from the response rather than through the buffer
+                (_, Some(s)) if s.len() >= STREAM_THRESHOLD => {
+                    writer.write_all(&out).await?;
+                    out.clear();
+                    writer.write_all(s.as_bytes()).await?;
+                    writer.write_all(b"\n").await?;
+                }
+                // raw bytes as they are, Display would replace the invalid ones
+                (Response::BulkBytes(b), _) => {
+                    out.extend_from_slice(b);
+                    out.push(b'\n');
+                }
+                _ => {
+                    let _ = writeln!(out, "{resp}");
+                }
+            }
+        }
+        if quit {
+            break;
+        }
+        // anything left in the read buffer is (part of) a request the client
+        // sends without waiting for us, so hold replies back until it's handled
+        if reader.buffer().is_empty() || out.len() >= FLUSH_THRESHOLD {
+            writer.write_all(&out).await?;
+            out.clear();
         }
     }
+    // replies to commands handled before QUIT, a kill or shutdown
+    if !out.is_empty() {
+        writer.write_all(&out).await?;
+    }
     Ok(())
 }
+
+/// a full token bucket for `user`'s rate limit, or the server's before
+/// AUTH. None when there's no limit
+fn rate_limit_bucket(config: &ServerConfig, user: Option<&User>) -> Option<TokenBucket> {
+    let (cps, burst) = match user {
+        Some(user) => user.rate_limit(config),
+        None => (config.ratelimit_cps, config.ratelimit_burst),
+    };
+    (cps > 0).then(|| TokenBucket::new(cps, burst, Instant::now()))
+}
+
+/// handles `AUTH password`, for the default user, and `AUTH user password`
+fn auth_command(acl: &Acl, parts: &[&str], user: &mut Option<Arc<User>>) -> Response {
+    let (name, password) = match parts {
+        [_, password] => ("default", *password),
+        [_, name, password] => (*name, *password),
+        _ => return RedisError::WrongArguments {
+            command: "AUTH".to_string(),
+            expected: "1 or 2".to_string(),
+            got: parts.len() - 1,
+        }.into(),
+    };
+    if parts.len() == 2 && acl.user("default").is_some_and(|u| u.password.is_none()) {
+        return RedisError::InvalidType("AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?".to_string()).into();
+    }
+    match acl.authenticate(name, password) {
+        Some(authenticated) => {
+            *user = Some(authenticated);
+            "OK".into()
+        }
+        None => RedisError::WrongPass.into(),
+    }
+}
+
+/// handles `CONFIG GET param` and `CONFIG SET param value`
+fn config_command(shared: &Shared, parts: &[&str]) -> Response {
+    let sub = parts.get(1).map(|s| s.to_uppercase()).unwrap_or_default();
+    match (sub.as_str(), parts.len()) {
+        ("GET", 3) => {
+            let param = parts[2].to_lowercase();
+            let value = match param.as_str() {
+                "readonly" => if shared.store.is_readonly() { "yes" } else { "no" }.to_string(),
+                "appendonly" => if shared.store.aof().is_some() { "yes" } else { "no" }.to_string(),
+                "timeout" => shared.idle_timeout.load(Ordering::Relaxed).to_string(),
+                "maxclients" => shared.config.maxclients.to_string(),
+                "tcp-backlog" => shared.config.tcp_backlog.to_string(),
+                "tcp-keepalive" => shared.config.tcp_keepalive.to_string(),
+                "ratelimit-cps" => shared.config.ratelimit_cps.to_string(),
+                "ratelimit-burst" => shared.config.ratelimit_burst.to_string(),
+                "ratelimit-mode" => shared.config.ratelimit_mode.as_str().to_string(),
+                "max-key-len" => shared.store.max_key_len().to_string(),
+                "proto-max-bulk-len" => shared.store.max_value_len().to_string(),
+                "max-collection-len" => shared.store.max_collection_len().to_string(),
+                "ttl-jitter-pct" => shared.store.ttl_jitter_pct().to_string(),
+                "maxmemory" => shared.store.maxmemory().to_string(),
+                "maxmemory-policy" => shared.store.maxmemory_policy().as_str().to_string(),
+                "keys-limit" => shared.store.keys_limit().to_string(),
+                "keys-limit-policy" => shared.store.keys_limit_policy().as_str().to_string(),
+                "aof-rotate-size" => shared.store.aof().as_ref().map_or(0, |aof| aof.rotate_size()).to_string(),
+                "appendfsync" => shared.store.aof().as_ref().map_or(shared.config.appendfsync, Aof::fsync).as_str().to_string(),
+                "aof-format" => shared.store.aof().as_ref().map_or(shared.config.aof_format, Aof::format).as_str().to_string(),
+                "aof-queue-capacity" => shared.store.aof().as_ref().map_or(shared.config.aof_queue_capacity, |aof| aof.queue_limit().0).to_string(),
+                "aof-queue-full-policy" => shared.store.aof().as_ref().map_or(shared.config.aof_queue_full_policy, |aof| aof.queue_limit().1).as_str().to_string(),
+                "aof-load-truncated" => if shared.config.aof_load_truncated { "yes" } else { "no" }.to_string(),
+                "aof-checksum-policy" => shared.config.aof_checksum_policy.as_str().to_string(),
+                "auto-aof-rewrite-percentage" => shared.store.aof().as_ref().map_or(0, |aof| aof.auto_rewrite().0).to_string(),
+                "auto-aof-rewrite-min-size" => shared.store.aof().as_ref().map_or(0, |aof| aof.auto_rewrite().1).to_string(),
+                "aof-dir" => shared.config.aof_dir.clone(),
+                "dbfilename" => shared.config.dbfilename.clone(),
+                "aclfile" => shared.config.aclfile.clone().unwrap_or_default(),
+                "slowlog-log-slower-than" => shared.slowlog.slower_than().to_string(),
+                "slowlog-max-len" => shared.slowlog.max_len().to_string(),
+                "slowlog-keys-scanned" => shared.slowlog.keys_scanned().to_string(),
+                _ => return Response::Array(vec![]),
+            };
+            Response::Array(vec![
+                Response::BulkString(Some(param)),
+                Response::BulkString(Some(value)),
+            ])
+        }
+        ("HELP", 2) => help_reply("CONFIG", &[
+            ("GET <parameter>", "Return the value of a parameter."),
+            ("SET <parameter> <value>", "Set a parameter at runtime."),
+            ("RESETSTAT", "Reset the statistics reported by INFO and LATENCY."),
+        ]),
+        ("RESETSTAT", 2) => {
+            shared.commandstats.reset();
+            shared.store.reset_stats();
+            "OK".into()
+        }
+        ("SET", 4) => match parts[2].to_lowercase().as_str() {
+            "readonly" => match parse_bool(parts[3]) {
+                Some(readonly) => {
+                    shared.store.set_readonly(readonly);
+                    "OK".into()
+                }
+                None => RedisError::InvalidType(format!("Invalid argument '{}' for CONFIG SET 'readonly'", parts[3])).into(),
+            },
+            param @ ("max-key-len" | "proto-max-bulk-len") => match parts[3].parse::<usize>() {
+                Ok(len) if len > 0 => {
+                    if param == "max-key-len" {
+                        shared.store.set_max_key_len(len);
+                    } else {
+                        shared.store.set_max_value_len(len);
+                    }
+                    "OK".into()
+                }
+                _ => RedisError::InvalidType(format!("Invalid argument '{}' for CONFIG SET '{}'", parts[3], param)).into(),
+            },
+            "timeout" => match parts[3].parse::<u64>() {
+                Ok(secs) => {
+                    shared.idle_timeout.store(secs, Ordering::Relaxed);
+                    "OK".into()
+                }
+                Err(_) => RedisError::InvalidType(format!("Invalid argument '{}' for CONFIG SET 'timeout'", parts[3])).into(),
+            },
+            "max-collection-len" => match parts[3].parse::<usize>() {
+                Ok(len) => {
+                    shared.store.set_max_collection_len(len);
+                    "OK".into()
+                }
+                Err(_) => RedisError::InvalidType(format!("Invalid argument '{}' for CONFIG SET 'max-collection-len'", parts[3])).into(),
+            },
+            "ttl-jitter-pct" => match parts[3].parse::<u64>() {
+                Ok(pct) if pct <= 100 => {
+                    shared.store.set_ttl_jitter_pct(pct);
+                    "OK".into()
+                }
+                _ => RedisError::InvalidType(format!("Invalid argument '{}' for CONFIG SET 'ttl-jitter-pct'", parts[3])).into(),
+            },
+            "maxmemory" => match parts[3].parse::<usize>() {
+                Ok(bytes) => {
+                    shared.store.set_maxmemory(bytes);
+                    "OK".into()
+                }
+                Err(_) => RedisError::InvalidType(format!("Invalid argument '{}' for CONFIG SET 'maxmemory'", parts[3])).into(),
+            },
+            "maxmemory-policy" => match MaxMemoryPolicy::parse(parts[3]) {
+                Some(policy) => {
+                    shared.store.set_maxmemory_policy(policy);
+                    "OK".into()
+                }
+                None => RedisError::InvalidType(format!("Invalid argument '{}' for CONFIG SET 'maxmemory-policy'", parts[3])).into(),
+            },
+            "keys-limit" => match parts[3].parse::<usize>() {
+                Ok(limit) => {
+                    shared.store.set_keys_limit(limit);
+                    "OK".into()
+                }
+                Err(_) => RedisError::InvalidType(format!("Invalid argument '{}' for CONFIG SET 'keys-limit'", parts[3])).into(),
+            },
+            "keys-limit-policy" => match KeysLimitPolicy::parse(parts[3]) {
+                Some(policy) => {
+                    shared.store.set_keys_limit_policy(policy);
+                    "OK".into()
+                }
+                None => RedisError::InvalidType(format!("Invalid argument '{}' for CONFIG SET 'keys-limit-policy'", parts[3])).into(),
+            },
+            "aof-rotate-size" => match (parts[3].parse::<u64>(), shared.store.aof()) {
+                (Ok(size), Some(aof)) => {
+                    aof.set_rotate_size(size);
+                    "OK".into()
+                }
+                (Ok(_), None) => RedisError::Internal("persistence is disabled, no AOF is open".to_string()).into(),
+                (Err(_), _) => RedisError::InvalidType(format!("Invalid argument '{}' for CONFIG SET 'aof-rotate-size'", parts[3])).into(),
+            },
+            "appendfsync" => match (AppendFsync::parse(parts[3]), shared.store.aof()) {
+                (Some(policy), Some(aof)) => {
+                    aof.set_fsync(policy);
+                    "OK".into()
+                }
+                (Some(_), None) => RedisError::Internal("persistence is disabled, no AOF is open".to_string()).into(),
+                (None, _) => RedisError::InvalidType(format!("Invalid argument '{}' for CONFIG SET 'appendfsync'", parts[3])).into(),
+            },
+            "aof-format" => match (AofFormat::parse(parts[3]), shared.store.aof()) {
+                (Some(format), Some(aof)) => {
+                    aof.set_format(format);
+                    "OK".into()
+                }
+                (Some(_), None) => RedisError::Internal("persistence is disabled, no AOF is open".to_string()).into(),
+                (None, _) => RedisError::InvalidType(format!("Invalid argument '{}' for CONFIG SET 'aof-format'", parts[3])).into(),
+            },
+            "aof-queue-capacity" => match (parts[3].parse::<u64>(), shared.store.aof()) {
+                (Ok(capacity), Some(aof)) => {
+                    aof.set_queue_limit(capacity, aof.queue_limit().1);
+                    "OK".into()
+                }
+                (Ok(_), None) => RedisError::Internal("persistence is disabled, no AOF is open".to_string()).into(),
+                (Err(_), _) => RedisError::InvalidType(format!("Invalid argument '{}' for CONFIG SET 'aof-queue-capacity'", parts[3])).into(),
+            },
+            "aof-queue-full-policy" => match (QueueFullPolicy::parse(parts[3]), shared.store.aof()) {
+                (Some(policy), Some(aof)) => {
+                    aof.set_queue_limit(aof.queue_limit().0, policy);
+                    "OK".into()
+                }
+                (Some(_), None) => RedisError::Internal("persistence is disabled, no AOF is open".to_string()).into(),
+                (None, _) => RedisError::InvalidType(format!("Invalid argument '{}' for CONFIG SET 'aof-queue-full-policy'", parts[3])).into(),
+            },
+            param @ ("auto-aof-rewrite-percentage" | "auto-aof-rewrite-min-size") => match (parts[3].parse::<u64>(), shared.store.aof()) {
+                (Ok(n), Some(aof)) => {
+                    let (percentage, min_size) = aof.auto_rewrite();
+                    if param == "auto-aof-rewrite-percentage" {
+                        aof.set_auto_rewrite(n, min_size);
+                    } else {
+                        aof.set_auto_rewrite(percentage, n);
+                    }
+                    "OK".into()
+                }
+                (Ok(_), None) => RedisError::Internal("persistence is disabled, no AOF is open".to_string()).into(),
+                (Err(_), _) => RedisError::InvalidType(format!("Invalid argument '{}' for CONFIG SET '{}'", parts[3], param)).into(),
+            },
+            "slowlog-log-slower-than" => match parts[3].parse::<i64>() {
+                Ok(us) => {
+                    shared.slowlog.set_slower_than(us);
+                    "OK".into()
+                }
+                Err(_) => RedisError::InvalidType(format!("Invalid argument '{}' for CONFIG SET 'slowlog-log-slower-than'", parts[3])).into(),
+            },
+            "slowlog-max-len" => match parts[3].parse::<usize>() {
+                Ok(len) => {
+                    shared.slowlog.set_max_len(len);
+                    "OK".into()
+                }
+                Err(_) => RedisError::InvalidType(format!("Invalid argument '{}' for CONFIG SET 'slowlog-max-len'", parts[3])).into(),
+            },
+            "slowlog-keys-scanned" => match parts[3].parse::<usize>() {
+                Ok(keys) => {
+                    shared.slowlog.set_keys_scanned(keys);
+                    "OK".into()
+                }
+                Err(_) => RedisError::InvalidType(format!("Invalid argument '{}' for CONFIG SET 'slowlog-keys-scanned'", parts[3])).into(),
+            },
+            other => RedisError::InvalidType(format!("Unknown option or number of arguments for CONFIG SET - '{}'", other)).into(),
+        },
+        _ => RedisError::InvalidType(format!("unknown subcommand or wrong number of arguments for 'CONFIG|{}'", sub)).into(),
+    }
+}
+
+/// binds `addr` like `TcpListener::bind`, trying each address it resolves
+/// to, but with `backlog` rather than the default accept queue length
+async fn listen(addr: &str, backlog: u32) -> io::Result<TcpListener> {
+    let mut last = None;
+    for addr in tokio::net::lookup_host(addr).await? {
+        let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+        // as TcpListener::bind does, so a restart can rebind despite TIME_WAIT
+        #[cfg(unix)]
+        socket.set_reuseaddr(true)?;
+        match socket.bind(addr).and_then(|()| socket.listen(backlog)) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last = Some(e),
+        }
+    }
+    Err(last.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any address")))
+}
+
+/// options for an accepted connection: no Nagle delay, so small pipelined
+/// replies go out at once, and keepalive probes after `keepalive` idle
+/// seconds (0 leaves keepalive off) so dead peers are noticed
+pub fn tune_socket(socket: &TcpStream, keepalive: u64) -> io::Result<()> {
+    socket.set_nodelay(true)?;
+    if keepalive > 0 {
+        let time = Duration::from_secs(keepalive);
+        // redis probes every third of the idle time once it starts
+        let params = socket2::TcpKeepalive::new().with_time(time).with_interval((time / 3).max(Duration::from_secs(1)));
+        socket2::SockRef::from(socket).set_tcp_keepalive(&params)?;
+    }
+    Ok(())
+}
+
+/// writes a snapshot to `dbfilename`, replying once it is on disk
+async fn save(shared: &Shared) -> Response {
+    if shared.bgsave_in_progress.load(Ordering::Relaxed) {
+        return RedisError::InvalidType("Background save already in progress".to_string()).into();
+    }
+    // waits out another SAVE, or a BGSAVE that started since the check above
+    let _writing = shared.snapshot_lock.lock().await;
+    let id = rand::random::<u64>();
+    let res = match shared.store.begin_snapshot(id) {
+        Ok(entries) => write_snapshot(shared, snapshot::encode(id, &entries)).await,
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(()) => "OK".into(),
+        Err(e) => RedisError::Internal(e.to_string()).into(),
+    }
+}
+
+/// copies the dataset like SAVE, then serializes and writes it in the background
+fn bgsave(shared: &Shared) -> Response {
+    if shared.bgsave_in_progress.swap(true, Ordering::Relaxed) {
+        return RedisError::InvalidType("Background save already in progress".to_string()).into();
+    }
+    let Ok(writing) = shared.snapshot_lock.clone().try_lock_owned() else {
+        shared.bgsave_in_progress.store(false, Ordering::Relaxed);
+        return RedisError::InvalidType("Another save is already in progress".to_string()).into();
+    };
+    let id = rand::random::<u64>();
+    let entries = match shared.store.begin_snapshot(id) {
+        Ok(entries) => entries,
+        Err(e) => {
+            shared.bgsave_in_progress.store(false, Ordering::Relaxed);
+            return RedisError::Internal(e.to_string()).into();
+        }
+    };
+    let shared = shared.clone();
+    tokio::spawn(async move {
+        let _writing = writing;
+        let res = match tokio::task::spawn_blocking(move || snapshot::encode(id, &entries)).await {
+            Ok(bytes) => write_snapshot(&shared, bytes).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = &res {
+            error!(error = %e, "background save failed");
+        }
+        shared.last_bgsave_ok.store(res.is_ok(), Ordering::Relaxed);
+        shared.bgsave_in_progress.store(false, Ordering::Relaxed);
+    });
+    "Background saving started".into()
+}
+
+/// writes a snapshot begun with `Store::begin_snapshot`, then starts the AOF
+/// over from it
+async fn write_snapshot(shared: &Shared, bytes: Vec<u8>) -> anyhow::Result<()> {
+    let written = snapshot::write_atomic(&shared.config.dbfilename, &bytes).await;
+    match (shared.store.aof(), written) {
+        (Some(aof), Ok(())) => aof.finish_snapshot().await?,
+        (Some(aof), Err(e)) => {
+            aof.abort_rewrite();
+            return Err(e);
+        }
+        (None, written) => written?,
+    }
+    shared.last_save.store(unix_now(), Ordering::Relaxed);
+    Ok(())
+}
+
+/// what `load_dataset` loaded
+struct Loaded {
+    stats: ReplayStats,
+    /// entries applied after the snapshot, or all of them without one
+    replayed: u64,
+    /// a snapshot loaded without the AOF naming it, because the AOF was
+    /// empty. the AOF should be marked as continuing from it
+    unmarked_snapshot: Option<u64>,
+}
+
+/// loads the snapshot at `path` and the entries from `replay` on top of it,
+/// applying each as it's read. the snapshot is used if the AOF marks where
+/// it continues from it, the entries before that being in it already, or if
+/// the AOF is empty. otherwise the AOF is replayed alone
+fn load_dataset(store: &Store, replay: &mut Replay, path: &str) -> anyhow::Result<Loaded> {
+    let id = snapshot_id(path);
+    let (mut failed, mut found, mut since_marker, mut other_markers) = (None, false, 0, false);
+    let mut stats;
+    loop {
+        let mut marker = false;
+        let entries = replay.by_ref()
+            .map_while(|r| r.map_err(|e| failed = Some(e)).ok())
+            // the AOF may name older snapshots too, e.g. when writing this one failed
+            .take_while(|e| match e.snapshot_id() {
+                Some(named) if Some(named) == id => {
+                    marker = true;
+                    false
+                }
+                named => {
+                    other_markers |= named.is_some();
+                    since_marker += 1;
+                    true
+                }
+            });
+        stats = store.load_from_aof(entries);
+        if let Some(e) = failed {
+            return Err(e.into());
+        }
+        if !marker {
+            break;
+        }
+        // everything so far is in the snapshot. if it won't load, carrying on
+        // replays the AOF alone
+        found = true;
+        since_marker = 0;
+        load_snapshot_file(store, path);
+    }
+    let Some(id) = id else {
+        return Ok(Loaded { stats, replayed: since_marker, unmarked_snapshot: None });
+    };
+    let unmarked_snapshot = if replay.entries_read() == 0 {
+        load_snapshot_file(store, path).then_some(id)
+    } else {
+        if !found && other_markers {
+            warn!(path, "the AOF continues from a snapshot other than this one, replaying the AOF alone");
+        }
+        None
+    };
+    Ok(Loaded { stats, replayed: since_marker, unmarked_snapshot })
+}
+
+/// the id of the snapshot at `path`, reading only its header. None if
+/// there's none or it can't be read
+fn snapshot_id(path: &str) -> Option<u64> {
+    use std::io::Read;
+
+    let mut header = Vec::new();
+    let read = std::fs::File::open(path).and_then(|file| file.take(64).read_to_end(&mut header));
+    match read {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!(path, error = %e, "reading the snapshot failed, replaying the AOF alone");
+            return None;
+        }
+    }
+    match snapshot::id_of(&header) {
+        Ok(id) => Some(id),
+        Err(e) => {
+            warn!(path, error = %e, "unreadable snapshot, replaying the AOF alone");
+            None
+        }
+    }
+}
+
+/// replaces the dataset with the snapshot at `path`, false if it won't load
+fn load_snapshot_file(store: &Store, path: &str) -> bool {
+    let started = Instant::now();
+    let loaded = std::fs::read(path).map_err(anyhow::Error::from).and_then(|bytes| store.load_snapshot(&bytes));
+    match loaded {
+        Ok(id) => {
+            info!(path, id, keys = store.key_counts().0, elapsed_ms = started.elapsed().as_millis() as u64, "loaded snapshot");
+            true
+        }
+        Err(e) => {
+            warn!(path, error = %e, "loading the snapshot failed, replaying the AOF alone");
+            false
+        }
+    }
+}
+
+/// opens the AOF at `config.aof_base()` with the configured settings
+async fn open_aof(config: &ServerConfig) -> anyhow::Result<Aof> {
+    let aof = Aof::new(&config.aof_base()).await?;
+    aof.set_rotate_size(config.aof_rotate_size);
+    aof.set_fsync(config.appendfsync);
+    aof.set_format(config.aof_format);
+    aof.set_queue_limit(config.aof_queue_capacity, config.aof_queue_full_policy);
+    aof.set_auto_rewrite(config.auto_aof_rewrite_percentage, config.auto_aof_rewrite_min_size);
+    Ok(aof)
+}
+
+/// `CONFIG SET appendonly`. turning it on opens the AOF and rewrites it from
+/// the dataset before replying, so the file matches what's in memory.
+/// turning it off stops logging, then flushes what was logged and lets the
+/// writer exit
+async fn set_appendonly(shared: &Shared, value: &str) -> Response {
+    let Some(on) = parse_bool(value) else {
+        return RedisError::InvalidType(format!("Invalid argument '{value}' for CONFIG SET 'appendonly'")).into();
+    };
+    if on == shared.store.aof().is_some() {
+        return "OK".into();
+    }
+    if !on {
+        if let Some(rewriter) = shared.rewriter.lock().take() {
+            rewriter.abort();
+        }
+        if let Some(aof) = shared.store.disable_aof() {
+            if let Err(e) = aof.flush().await {
+                error!(error = %e, "flushing the AOF before disabling it failed");
+            }
+            info!("AOF disabled");
+        }
+        return "OK".into();
+    }
+    if !shared.config.aof_dir.is_empty() {
+        if let Err(e) = std::fs::create_dir_all(&shared.config.aof_dir) {
+            return RedisError::Internal(format!("creating {}: {e}", shared.config.aof_dir)).into();
+        }
+    }
+    let res = async {
+        let aof = open_aof(&shared.config).await?;
+        let snapshot = shared.store.enable_aof(aof.clone())?;
+        if let Err(e) = aof.finish_rewrite(snapshot).await {
+            shared.store.disable_aof();
+            return Err(e);
+        }
+        anyhow::Ok(aof)
+    }.await;
+    match res {
+        Ok(aof) => {
+            info!(path = %shared.config.aof_base(), "AOF enabled");
+            start_auto_rewrite(shared, aof);
+            "OK".into()
+        }
+        Err(e) => RedisError::Internal(format!("enabling the AOF failed: {e}")).into(),
+    }
+}
+
+/// snapshots the dataset and writes the compacted AOF in the background
+fn bgrewriteaof(shared: &Shared) -> Response {
+    let Some(aof) = shared.store.aof() else {
+        return RedisError::Internal("persistence is disabled, no AOF is open".to_string()).into();
+    };
+    let snapshot = match shared.store.begin_aof_rewrite() {
+        Ok(snapshot) => snapshot,
+        Err(e) => return RedisError::Internal(e.to_string()).into(),
+    };
+    tokio::spawn(async move {
+        match aof.finish_rewrite(snapshot).await {
+            Ok(()) => info!("background AOF rewrite finished"),
+            Err(e) => error!(error = %e, "background AOF rewrite failed"),
+        }
+    });
+    "Background append only file rewriting started".into()
+}
+
+/// runs `auto_rewrite` for `aof`, replacing the task for any earlier AOF
+fn start_auto_rewrite(shared: &Shared, aof: Aof) {
+    let task = tokio::spawn(auto_rewrite(aof, shared.store.clone()));
+    if let Some(old) = shared.rewriter.lock().replace(task) {
+        old.abort();
+    }
+}
+
+/// rewrites the AOF whenever the writer finds it has outgrown the
+/// auto-aof-rewrite thresholds
+async fn auto_rewrite(aof: Aof, store: Store) {
+    loop {
+        aof.wait_rewrite_due().await;
+        if !aof.rewrite_due() {
+            continue;
+        }
+        let stats = aof.stats();
+        info!(size = stats.current_size, base = stats.base_size, "starting automatic AOF rewrite");
+        let res = match store.begin_aof_rewrite() {
+            Ok(snapshot) => aof.finish_rewrite(snapshot).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            error!(error = %e, "automatic AOF rewrite failed");
+            // the AOF is still over the thresholds, don't retry on every write
+            tokio::time::sleep(AUTO_REWRITE_RETRY).await;
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// handles `REPLICAOF host port` and `REPLICAOF NO ONE`
+fn replicaof_command(shared: &Shared, parts: &[&str]) -> Response {
+    if parts.len() != 3 {
+        return RedisError::WrongArguments {
+            command: "REPLICAOF".to_string(),
+            expected: "2".to_string(),
+            got: parts.len() - 1,
+        }.into();
+    }
+    if parts[1].eq_ignore_ascii_case("NO") && parts[2].eq_ignore_ascii_case("ONE") {
+        shared.replication.promote();
+        return "OK".into();
+    }
+    match parts[2].parse::<u16>() {
+        Ok(port) => {
+            shared.replication.replicate_from(shared.store.clone(), parts[1].to_string(), port);
+            "OK".into()
+        }
+        Err(_) => RedisError::NotInteger(parts[2].to_string()).into(),
+    }
+}
+
+/// validates `WAIT numreplicas timeout`, where a timeout of 0 blocks forever
+fn wait_args(shared: &Shared, parts: &[&str]) -> Result<(usize, Option<Duration>), Response> {
+    if parts.len() != 3 {
+        return Err(RedisError::WrongArguments {
+            command: "WAIT".to_string(),
+            expected: "2".to_string(),
+            got: parts.len() - 1,
+        }.into());
+    }
+    if shared.replication.is_replica() {
+        return Err(RedisError::InvalidType("WAIT cannot be used with replica instances".to_string()).into());
+    }
+    let numreplicas = parts[1].parse::<usize>().map_err(|_| Response::from(RedisError::NotInteger(parts[1].to_string())))?;
+    let timeout = parts[2].parse::<u64>().map_err(|_| Response::from(RedisError::NotInteger(parts[2].to_string())))?;
+    Ok((numreplicas, (timeout > 0).then(|| Duration::from_millis(timeout))))
+}
+
+/// `WAITAOF numlocal timeout`: numlocal is 0 or 1, there's only the local
+/// AOF to wait for. the timeout is in milliseconds like WAIT, 0 waits forever
+fn waitaof_args(shared: &Shared, parts: &[&str]) -> Result<(u64, Option<Duration>), Response> {
+    if parts.len() != 3 {
+        return Err(RedisError::WrongArguments {
+            command: "WAITAOF".to_string(),
+            expected: "2".to_string(),
+            got: parts.len() - 1,
+        }.into());
+    }
+    let numlocal = parts[1].parse::<u64>().map_err(|_| Response::from(RedisError::NotInteger(parts[1].to_string())))?;
+    let timeout = parts[2].parse::<u64>().map_err(|_| Response::from(RedisError::NotInteger(parts[2].to_string())))?;
+    if numlocal > 1 {
+        return Err(RedisError::InvalidType("WAITAOF numlocal must be 0 or 1".to_string()).into());
+    }
+    if numlocal == 1 && shared.store.aof().is_none() {
+        return Err(RedisError::InvalidType("WAITAOF cannot be used when numlocal is set but persistence is disabled".to_string()).into());
+    }
+    Ok((numlocal, (timeout > 0).then(|| Duration::from_millis(timeout))))
+}
+
+/// whether every AOF entry logged so far was written and fsynced within `timeout`
+async fn wait_aof(shared: &Shared, timeout: Option<Duration>) -> bool {
+    let Some(aof) = shared.store.aof() else { return false };
+    match timeout {
+        Some(timeout) => matches!(tokio::time::timeout(timeout, aof.flush()).await, Ok(Ok(()))),
+        None => aof.flush().await.is_ok(),
+    }
+}
+
+/// `AOFOFFSET` takes no arguments and needs persistence
+fn aofoffset_args(shared: &Shared, parts: &[&str]) -> Result<(), Response> {
+    if parts.len() != 1 {
+        return Err(RedisError::WrongArguments {
+            command: "AOFOFFSET".to_string(),
+            expected: "0".to_string(),
+            got: parts.len() - 1,
+        }.into());
+    }
+    if shared.store.aof().is_none() {
+        return Err(RedisError::InvalidType("AOFOFFSET cannot be used when persistence is disabled".to_string()).into());
+    }
+    Ok(())
+}
+
+/// the AOF offset once every entry logged so far is fsynced, like WAITAOF,
+/// so it covers every write acknowledged before the call
+async fn aof_offset(shared: &Shared) -> Response {
+    let Some(aof) = shared.store.aof() else { return Response::Integer(0) };
+    match aof.flush().await {
+        Ok(()) => Response::Integer(aof.current_offset() as i64),
+        Err(e) => RedisError::InvalidType(format!("AOF fsync failed: {e}")).into(),
+    }
+}
+
+/// `BGET key timeout`, the timeout in seconds like BLPOP: fractions allowed, 0 waits forever
+fn bget_args(parts: &[&str]) -> Result<Option<Duration>, Response> {
+    if parts.len() != 3 {
+        return Err(RedisError::WrongArguments {
+            command: "BGET".to_string(),
+            expected: "2".to_string(),
+            got: parts.len() - 1,
+        }.into());
+    }
+    match parts[2].parse::<f64>() {
+        Ok(secs) if secs.is_finite() && secs >= 0.0 => Ok((secs > 0.0).then(|| Duration::from_secs_f64(secs))),
+        _ => Err(RedisError::InvalidType("timeout is not a float or out of range".to_string()).into()),
+    }
+}
+
+/// whether the uppercased `cmd` is one of the `BLOCKING` commands
+fn is_blocking(cmd: &str, parts: &[&str]) -> bool {
+    BLOCKING.contains(&cmd) || cmd == "DEBUG" && parts.get(1).is_some_and(|sub| sub.eq_ignore_ascii_case("SLEEP"))
+}
+
+/// `DEBUG SLEEP seconds`, fractions allowed like redis
+fn debug_sleep_args(parts: &[&str]) -> Result<Duration, Response> {
+    if parts.len() != 3 {
+        return Err(RedisError::WrongArguments {
+            command: "DEBUG SLEEP".to_string(),
+            expected: "1".to_string(),
+            got: parts.len() - 2,
+        }.into());
+    }
+    match parts[2].parse::<f64>() {
+        Ok(secs) if secs.is_finite() && secs >= 0.0 => Ok(Duration::from_secs_f64(secs)),
+        _ => Err(RedisError::InvalidType(format!("invalid number of seconds '{}'", parts[2])).into()),
+    }
+}
+
+/// DEBUG RELOAD: with an AOF, flushes it and replaces the dataset with what
+/// replaying it from disk gives, on top of the snapshot it continues from,
+/// the way a restart would. without one, saves a snapshot like SAVE and
+/// loads that back, which loses writes made in between, so it's meant for a
+/// quiet server. anything that differs afterwards wasn't persisted
+async fn debug_reload(shared: &Shared) -> Response {
+    let Some(aof) = shared.store.aof() else {
+        if let err @ Response::Error(_) = save(shared).await {
+            return err;
+        }
+        let path = &shared.config.dbfilename;
+        let loaded = async { shared.store.load_snapshot(&tokio::fs::read(path).await?) }.await;
+        return match loaded {
+            Ok(_) => "OK".into(),
+            Err(e) => RedisError::Internal(format!("reloading {path}: {e}")).into(),
+        };
+    };
+    // a rewrite swaps the files being read
+    if aof.stats().rewrite_in_progress {
+        return RedisError::InvalidType("Background AOF rewrite or save in progress, try DEBUG RELOAD later".to_string()).into();
+    }
+    let path = shared.config.aof_base();
+    let load = || {
+        let fresh = Store::new(None);
+        let mut replay = Aof::replay_iter(&path);
+        load_dataset(&fresh, &mut replay, &shared.config.dbfilename)?;
+        let summary = replay.summary();
+        if summary.truncated_bytes > 0 || summary.checksum_failures > 0 {
+            anyhow::bail!(
+                "{} partly written bytes and {} entries failing their checksum",
+                summary.truncated_bytes,
+                summary.checksum_failures,
+            );
+        }
+        Ok(fresh)
+    };
+    for _ in 0..RELOAD_ATTEMPTS {
+        if let Err(e) = aof.flush().await {
+            return RedisError::Internal(e.to_string()).into();
+        }
+        // writes made since the flush may still be queued, then try again
+        match shared.store.reload(|| aof.pending() == 0, load) {
+            Some(Ok(())) => return "OK".into(),
+            Some(Err(e)) => return RedisError::Internal(format!("reloading {path}: {e}")).into(),
+            None => {}
+        }
+    }
+    RedisError::InvalidType("writes kept arriving, DEBUG RELOAD gave up".to_string()).into()
+}
+
+/// the DEBUG subcommands other than SLEEP, hooks for tests
+fn debug_command(shared: &Shared, parts: &[&str]) -> Response {
+    let sub = parts.get(1).map(|s| s.to_uppercase()).unwrap_or_default();
+    match (sub.as_str(), parts.len()) {
+        ("HELP", 2) => help_reply("DEBUG", &[
+            ("SLEEP <seconds>", "Stop handling this connection for <seconds>, fractions allowed."),
+            ("EXPIRE-NOW <key>", "Move the key's expiry into the past without removing it."),
+            ("OBJECT-COUNT", "Return the number of keys, keys with a TTL and pending AOF entries."),
+            ("PANIC", "Panic while holding the keyspace lock, to check the server recovers."),
+            ("RELOAD", "Flush the AOF and load the dataset back from disk, or without one save a snapshot and load that."),
+        ]),
+        ("PANIC", 2) => guarded(&[parts[0].to_string()], || shared.store.debug_panic()),
+        ("EXPIRE-NOW", 3) if shared.store.expire_now(parts[2]) => "OK".into(),
+        ("EXPIRE-NOW", 3) => RedisError::InvalidType("no such key".to_string()).into(),
+        ("OBJECT-COUNT", 2) => {
+            let (keys, expires) = shared.store.key_counts();
+            let pending = shared.store.aof().as_ref().map_or(0, Aof::pending);
+            Response::Array(vec![
+                Response::BulkString(Some("keys".to_string())),
+                Response::Integer(keys as i64),
+                Response::BulkString(Some("expires".to_string())),
+                Response::Integer(expires as i64),
+                Response::BulkString(Some("aof_pending_entries".to_string())),
+                Response::Integer(pending as i64),
+            ])
+        }
+        ("EXPIRE-NOW" | "OBJECT-COUNT" | "PANIC", _) => RedisError::WrongArguments {
+            command: format!("DEBUG {sub}"),
+            expected: if sub == "EXPIRE-NOW" { "1" } else { "0" }.to_string(),
+            got: parts.len() - 2,
+        }.into(),
+        _ => RedisError::InvalidType("DEBUG subcommand must be SLEEP, EXPIRE-NOW, OBJECT-COUNT, PANIC or RELOAD".to_string()).into(),
+    }
+}
+
+/// renders INFO, optionally limited to one section
+fn info(shared: &Shared, section: Option<&str>) -> String {
+    let wanted = |name: &str| section.is_none_or(|s| s.eq_ignore_ascii_case(name));
+    let mut out = String::new();
+    if wanted("clients") {
+        out.push_str("# Clients\n");
+        out.push_str(&format!("connected_clients:{}\n", shared.clients.len()));
+        out.push_str(&format!("maxclients:{}\n", shared.config.maxclients));
+    }
+    if wanted("stats") {
+        out.push_str("# Stats\n");
+        out.push_str(&format!("throttled_commands:{}\n", shared.throttled.load(Ordering::Relaxed)));
+        for (name, value) in shared.store.stats().fields() {
+            out.push_str(&format!("{name}:{value}\n"));
+        }
+    }
+    if wanted("memory") {
+        out.push_str("# Memory\n");
+        out.push_str(&format!("used_memory:{}\n", shared.store.used_memory()));
+        out.push_str(&format!("maxmemory:{}\n", shared.store.maxmemory()));
+        out.push_str(&format!("maxmemory_policy:{}\n", shared.store.maxmemory_policy().as_str()));
+    }
+    if wanted("persistence") {
+        out.push_str("# Persistence\n");
+        out.push_str(&format!("aof_enabled:{}\n", shared.store.aof().is_some() as u8));
+        if let Some(stats) = shared.store.aof().as_ref().map(Aof::stats) {
+            for (name, value) in stats.fields() {
+                out.push_str(&format!("{name}:{value}\n"));
+            }
+        }
+        out.push_str(&format!("rdb_last_save_time:{}\n", shared.last_save.load(Ordering::Relaxed)));
+        out.push_str(&format!("rdb_bgsave_in_progress:{}\n", shared.bgsave_in_progress.load(Ordering::Relaxed) as u8));
+        out.push_str(&format!("rdb_last_bgsave_status:{}\n", if shared.last_bgsave_ok.load(Ordering::Relaxed) { "ok" } else { "err" }));
+    }
+    if wanted("commandstats") {
+        out.push_str("# Commandstats\n");
+        out.push_str(&info_commandstats(&shared.commandstats));
+    }
+    if wanted("replication") {
+        out.push_str("# Replication\n");
+        match shared.replication.role() {
+            Role::Primary { replicas, offset } => {
+                out.push_str("role:master\n");
+                out.push_str(&format!("connected_slaves:{replicas}\n"));
+                out.push_str(&format!("master_repl_offset:{offset}\n"));
+            }
+            Role::Replica { host, port, link_up, offset } => {
+                out.push_str("role:slave\n");
+                out.push_str(&format!("master_host:{host}\n"));
+                out.push_str(&format!("master_port:{port}\n"));
+                out.push_str(&format!("master_link_status:{}\n", if link_up { "up" } else { "down" }));
+                out.push_str(&format!("slave_repl_offset:{offset}\n"));
+                out.push_str("connected_slaves:0\n");
+            }
+        }
+    }
+    out
+}
+
+/// reads the next request. the outer Option is None if nothing arrived within
+/// `timeout` seconds (0 waits forever), the inner one is None at EOF
+async fn read_request_with_timeout<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    timeout: u64,
+) -> io::Result<Option<Option<Request>>> {
+    if timeout == 0 {
+        return read_request(reader).await.map(Some);
+    }
+    match tokio::time::timeout(Duration::from_secs(timeout), read_request(reader)).await {
+        Ok(r) => r.map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+/// validates `SHUTDOWN [NOSAVE|SAVE]`, returning whether to SAVE before stopping.
+/// the AOF is flushed on the way out either way
+fn shutdown_command(parts: &[&str]) -> Result<bool, Response> {
+    match parts.len() {
+        1 => Ok(false),
+        2 if parts[1].eq_ignore_ascii_case("SAVE") => Ok(true),
+        2 if parts[1].eq_ignore_ascii_case("NOSAVE") => Ok(false),
+        2 => Err(RedisError::InvalidType("syntax error".to_string()).into()),
+        n => Err(RedisError::WrongArguments {
+            command: "SHUTDOWN".to_string(),
+            expected: "0 or 1".to_string(),
+            got: n - 1,
+        }.into()),
+    }
+}
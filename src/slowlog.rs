@@ -0,0 +1,202 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicI64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use parking_lot::Mutex;
+use crate::{error::{RedisError, Response}, protocol::help_reply};
+
+/// argv entries kept per slowlog entry, like redis
+const MAX_ARGS: usize = 32;
+/// bytes kept per argument
+const MAX_ARG_LEN: usize = 128;
+
+/// one command that ran longer than the threshold
+#[derive(Debug, Clone)]
+pub struct SlowLogEntry {
+    pub id: u64,
+    /// unix time in seconds when the command ran
+    pub timestamp: u64,
+    pub duration_us: u64,
+    pub args: Vec<String>,
+    pub client_addr: String,
+    pub client_name: String,
+}
+
+/// bounded log of slow commands, newest first
+#[derive(Clone)]
+pub struct SlowLog {
+    entries: Arc<Mutex<VecDeque<SlowLogEntry>>>,
+    next_id: Arc<AtomicUsize>,
+    /// microseconds a command must exceed to be logged, negative disables
+    slower_than: Arc<AtomicI64>,
+    max_len: Arc<AtomicUsize>,
+    /// keys a KEYS call may walk before it's logged however fast it was, 0 disables
+    keys_scanned: Arc<AtomicUsize>,
+}
+
+impl SlowLog {
+    pub fn new(slower_than_us: i64, max_len: usize) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(VecDeque::new())),
+            next_id: Arc::new(AtomicUsize::new(0)),
+            slower_than: Arc::new(AtomicI64::new(slower_than_us)),
+            max_len: Arc::new(AtomicUsize::new(max_len)),
+            keys_scanned: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn slower_than(&self) -> i64 {
+        self.slower_than.load(Ordering::Relaxed)
+    }
+
+    pub fn set_slower_than(&self, us: i64) {
+        self.slower_than.store(us, Ordering::Relaxed);
+    }
+
+    pub fn max_len(&self) -> usize {
+        self.max_len.load(Ordering::Relaxed)
+    }
+
+    /// changes the capacity, dropping the oldest entries if it shrank
+    pub fn set_max_len(&self, len: usize) {
+        self.max_len.store(len, Ordering::Relaxed);
+        self.entries.lock().truncate(len);
+    }
+
+    /// whether a command that took `elapsed` belongs in the log
+    pub fn is_slow(&self, elapsed: Duration) -> bool {
+        let threshold = self.slower_than();
+        threshold >= 0 && elapsed.as_micros() >= threshold as u128
+    }
+
+    pub fn keys_scanned(&self) -> usize {
+        self.keys_scanned.load(Ordering::Relaxed)
+    }
+
+    pub fn set_keys_scanned(&self, keys: usize) {
+        self.keys_scanned.store(keys, Ordering::Relaxed);
+    }
+
+    /// whether a KEYS that walked `scanned` keys belongs in the log, even if
+    /// it was quick: it only gets slower as the keyspace grows. a negative
+    /// `slower_than` still turns the whole slowlog off
+    pub fn is_big_scan(&self, scanned: usize) -> bool {
+        let threshold = self.keys_scanned();
+        threshold > 0 && scanned > threshold && self.slower_than() >= 0
+    }
+
+    /// logs `args` if `elapsed` is over the threshold. the client details are
+    /// only looked up for slow commands, so the fast path stays a comparison
+    pub fn record(&self, elapsed: Duration, args: &[String], client: impl FnOnce() -> (String, String)) {
+        if self.is_slow(elapsed) {
+            self.log(elapsed, args, client);
+        }
+    }
+
+    /// logs `args` whatever `elapsed` is
+    pub fn log(&self, elapsed: Duration, args: &[String], client: impl FnOnce() -> (String, String)) {
+        let max_len = self.max_len();
+        if max_len == 0 {
+            return;
+        }
+        let (client_addr, client_name) = client();
+        let entry = SlowLogEntry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) as u64,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            duration_us: elapsed.as_micros() as u64,
+            args: truncate_args(args),
+            client_addr,
+            client_name,
+        };
+        let mut entries = self.entries.lock();
+        entries.push_front(entry);
+        entries.truncate(max_len);
+    }
+
+    /// the newest `count` entries, or all of them when `count` is None
+    pub fn get(&self, count: Option<usize>) -> Vec<SlowLogEntry> {
+        let entries = self.entries.lock();
+        entries.iter().take(count.unwrap_or(usize::MAX)).cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn reset(&self) {
+        self.entries.lock().clear();
+    }
+}
+
+/// keeps at most MAX_ARGS arguments of at most MAX_ARG_LEN bytes each,
+/// noting how much was cut like redis does
+fn truncate_args(args: &[String]) -> Vec<String> {
+    let mut out: Vec<String> = args.iter()
+        .take(if args.len() > MAX_ARGS { MAX_ARGS - 1 } else { MAX_ARGS })
+        .map(|arg| {
+            if arg.len() <= MAX_ARG_LEN {
+                return arg.clone();
+            }
+            let mut end = MAX_ARG_LEN;
+            while !arg.is_char_boundary(end) {
+                end -= 1;
+            }
+            format!("{}... ({} more bytes)", &arg[..end], arg.len() - end)
+        })
+        .collect();
+    if args.len() > MAX_ARGS {
+        out.push(format!("... ({} more arguments)", args.len() - MAX_ARGS + 1));
+    }
+    out
+}
+
+/// handles `SLOWLOG GET [count]`, `SLOWLOG LEN` and `SLOWLOG RESET`
+pub fn handle_slowlog_command(slowlog: &SlowLog, parts: &[&str]) -> Response {
+    if parts.len() < 2 {
+        return RedisError::WrongArguments {
+            command: "SLOWLOG".to_string(),
+            expected: "at least 1".to_string(),
+            got: parts.len().saturating_sub(1),
+        }.into();
+    }
+
+    let sub = parts[1].to_uppercase();
+    match (sub.as_str(), parts.len()) {
+        ("GET", 2 | 3) => {
+            // default of 10 like redis, a negative count returns everything
+            let count = match parts.get(2).map(|c| c.parse::<i64>()) {
+                None => Some(10),
+                Some(Ok(n)) if n < 0 => None,
+                Some(Ok(n)) => Some(n as usize),
+                Some(Err(_)) => return RedisError::NotInteger(parts[2].to_string()).into(),
+            };
+            Response::Array(slowlog.get(count).into_iter().map(|e| Response::Array(vec![
+                Response::Integer(e.id as i64),
+                Response::Integer(e.timestamp as i64),
+                Response::Integer(e.duration_us as i64),
+                Response::Array(e.args.into_iter().map(|a| Response::BulkString(Some(a))).collect()),
+                Response::BulkString(Some(e.client_addr)),
+                Response::BulkString(Some(e.client_name)),
+            ])).collect())
+        }
+        ("LEN", 2) => Response::Integer(slowlog.len() as i64),
+        ("RESET", 2) => {
+            slowlog.reset();
+            "OK".into()
+        }
+        ("HELP", 2) => help_reply("SLOWLOG", &[
+            ("GET [<count>]", "Return top <count> entries from the slowlog (default: 10, -1 means all)."),
+            ("LEN", "Return the length of the slowlog."),
+            ("RESET", "Reset the slowlog."),
+        ]),
+        _ => RedisError::InvalidType(format!("unknown subcommand or wrong number of arguments for 'SLOWLOG|{}'", sub)).into(),
+    }
+}
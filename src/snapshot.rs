@@ -0,0 +1,245 @@
+//! point-in-time dumps of the whole keyspace, in the spirit of redis' RDB.
+//! the format is a header (magic, format version and the snapshot's id), one
+//! record per key and an end marker carrying the key count:
+//!
+//! ```text
+//! record: type u8, key, expires_at_ms i64 (-1 for none), value
+//! string: bytes         list, set: u64 count, bytes each
+//! hash:   u64 count, field bytes and value bytes each
+//! bytes:  u64 length, then the bytes
+//! ```
+//!
+//! integers are little-endian. DUMP payloads reuse the value encoding: type
+//! u8 and value, then the format version u8 and a CRC-32 u32 of what's before
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::SystemTime,
+};
+use anyhow::{bail, Context};
+use tokio::io::AsyncWriteExt;
+use crate::{
+    aof::crc32,
+    types::{from_unix_ms, unix_ms, Entry, RedisValue, SetValue},
+};
+
+const MAGIC: &[u8] = b"KVSNAP";
+/// bumped whenever the layout changes, older versions are refused
+pub const FORMAT_VERSION: u8 = 1;
+
+const TYPE_STRING: u8 = 1;
+const TYPE_LIST: u8 = 2;
+const TYPE_SET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const END: u8 = 0xff;
+
+/// serializes `entries`, skipping any that have expired. `id` names the
+/// snapshot, so an AOF can say which snapshot it continues from
+pub fn encode<'a>(id: u64, entries: impl IntoIterator<Item = (&'a String, &'a Entry)>) -> Vec<u8> {
+    let mut out = Vec::from(MAGIC);
+    out.push(FORMAT_VERSION);
+    out.extend(id.to_le_bytes());
+    let mut count = 0u64;
+    for (key, entry) in entries.into_iter().filter(|(_, e)| !e.is_expired()) {
+        out.push(tag(&entry.value));
+        put_bytes(&mut out, key.as_bytes());
+        // live entries expire after now, so -1 can't be a real expiry
+        let expires_at_ms = entry.expires_at.map_or(-1, unix_ms);
+        out.extend(expires_at_ms.to_le_bytes());
+        put_value(&mut out, &entry.value);
+        count += 1;
+    }
+    out.push(END);
+    out.extend(count.to_le_bytes());
+    out
+}
+
+/// the id of the snapshot in `bytes`, reading only the header
+pub fn id_of(bytes: &[u8]) -> anyhow::Result<u64> {
+    Reader { bytes, pos: 0 }.header()
+}
+
+/// parses a dump made by `encode` into its id and entries. keys whose expiry
+/// has passed since are dropped
+pub fn decode(bytes: &[u8]) -> anyhow::Result<(u64, Vec<(String, Entry)>)> {
+    let mut r = Reader { bytes, pos: 0 };
+    let id = r.header()?;
+    let mut entries = Vec::new();
+    let mut count = 0u64;
+    loop {
+        let tag = r.u8()?;
+        if tag == END {
+            break;
+        }
+        let key = r.string().context("reading a key")?;
+        let expires_at = match r.i64()? {
+            -1 => None,
+            ms => Some(from_unix_ms(ms)),
+        };
+        let value = r.value(tag).with_context(|| format!("reading key '{key}'"))?;
+        count += 1;
+        if expires_at.is_none_or(|at| at > SystemTime::now()) {
+            entries.push((key, Entry::new(value, expires_at)));
+        }
+    }
+    let expected = r.u64()?;
+    if expected != count {
+        bail!("snapshot is truncated: {count} of {expected} keys");
+    }
+    Ok((id, entries))
+}
+
+/// `value` as a DUMP payload. arguments reach commands as text, so it's hex
+pub fn dump(value: &RedisValue) -> String {
+    let mut out = vec![tag(value)];
+    put_value(&mut out, value);
+    out.push(FORMAT_VERSION);
+    out.extend(crc32(&out).to_le_bytes());
+    out.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// the value in a payload made by `dump`, checking its version and checksum
+pub fn restore(payload: &str) -> anyhow::Result<RedisValue> {
+    let bytes = (0..payload.len())
+        .step_by(2)
+        .map(|i| payload.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect::<Option<Vec<u8>>>()
+        .context("payload is not hex")?;
+    let Some(body_len) = bytes.len().checked_sub(5) else {
+        bail!("payload is too short");
+    };
+    let (body, crc) = bytes.split_at(body_len + 1);
+    if crc32(body) != u32::from_le_bytes(crc.try_into()?) {
+        bail!("payload checksum is wrong");
+    }
+    let (body, version) = body.split_at(body_len);
+    if version[0] != FORMAT_VERSION {
+        bail!("payload format version {} is not supported, expected {FORMAT_VERSION}", version[0]);
+    }
+    let mut r = Reader { bytes: body, pos: 0 };
+    let tag = r.u8()?;
+    let value = r.value(tag)?;
+    if r.pos != body.len() {
+        bail!("payload has trailing bytes");
+    }
+    Ok(value)
+}
+
+/// writes `bytes` to a temp file next to `path`, syncs it and renames it over
+/// `path`, so a crash leaves either the old snapshot or the new one
+pub async fn write_atomic(path: &str, bytes: &[u8]) -> anyhow::Result<()> {
+    let tmp = format!("{path}.tmp");
+    let res = async {
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        file.write_all(bytes).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp, path).await?;
+        anyhow::Ok(())
+    }.await;
+    if res.is_err() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+    }
+    res.with_context(|| format!("writing snapshot {path}"))
+}
+
+fn tag(value: &RedisValue) -> u8 {
+    match value {
+        RedisValue::String(_) => TYPE_STRING,
+        RedisValue::List(_) => TYPE_LIST,
+        RedisValue::Set(_) => TYPE_SET,
+        RedisValue::Hash(_) => TYPE_HASH,
+    }
+}
+
+fn put_value(out: &mut Vec<u8>, value: &RedisValue) {
+    match value {
+        RedisValue::String(s) => put_bytes(out, s.as_bytes()),
+        RedisValue::List(list) => put_all(out, list.len(), list.iter().map(String::as_bytes)),
+        // loading rebuilds sets through `SetValue`, which picks the encoding again
+        RedisValue::Set(set) => {
+            let members = set.members();
+            put_all(out, members.len(), members.iter().map(String::as_bytes));
+        }
+        RedisValue::Hash(hash) => {
+            put_all(out, hash.len(), hash.iter().flat_map(|(f, v)| [f.as_bytes(), v.as_bytes()]));
+        }
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend((bytes.len() as u64).to_le_bytes());
+    out.extend(bytes);
+}
+
+/// a count, then each of `items`. a hash counts pairs, not items
+fn put_all<'a>(out: &mut Vec<u8>, count: usize, items: impl Iterator<Item = &'a [u8]>) {
+    out.extend((count as u64).to_le_bytes());
+    for item in items {
+        put_bytes(out, item);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// checks the magic and format version, returning the snapshot's id
+    fn header(&mut self) -> anyhow::Result<u64> {
+        if self.take(MAGIC.len()).ok() != Some(MAGIC) {
+            bail!("not a snapshot file");
+        }
+        let version = self.u8()?;
+        if version != FORMAT_VERSION {
+            bail!("snapshot format version {version} is not supported, expected {FORMAT_VERSION}");
+        }
+        self.u64()
+    }
+
+    /// a value of type `tag`, as written by `put_value`
+    fn value(&mut self, tag: u8) -> anyhow::Result<RedisValue> {
+        Ok(match tag {
+            TYPE_STRING => RedisValue::String(self.string()?.into()),
+            TYPE_LIST => RedisValue::List((0..self.u64()?).map(|_| self.string()).collect::<anyhow::Result<VecDeque<_>>>()?),
+            TYPE_SET => {
+                let mut set = SetValue::new();
+                for _ in 0..self.u64()? {
+                    set.insert(self.string()?);
+                }
+                RedisValue::Set(set)
+            }
+            TYPE_HASH => RedisValue::Hash(
+                (0..self.u64()?).map(|_| Ok((self.string()?, self.string()?))).collect::<anyhow::Result<HashMap<_, _>>>()?,
+            ),
+            other => bail!("unknown value type {other}"),
+        })
+    }
+
+    fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        let end = self.pos.checked_add(n).filter(|&end| end <= self.bytes.len());
+        let Some(end) = end else {
+            bail!("snapshot ends unexpectedly at byte {}", self.bytes.len());
+        };
+        let taken = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn i64(&mut self) -> anyhow::Result<i64> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        let len = usize::try_from(self.u64()?)?;
+        Ok(String::from_utf8(self.take(len)?.to_vec())?)
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use crate::{
+    error::{RedisError, Response},
+    protocol::{command_id, help_reply, COMMANDS},
+};
+
+/// histogram buckets are powers of two microseconds, the last one catches
+/// everything from ~1s up
+const BUCKETS: usize = 21;
+
+/// counters for one command
+struct Counters {
+    calls: AtomicU64,
+    /// summed in nanoseconds, so calls under a microsecond still add up
+    nsec: AtomicU64,
+    buckets: [AtomicU64; BUCKETS],
+}
+
+impl Counters {
+    fn new() -> Self {
+        Self {
+            calls: AtomicU64::new(0),
+            nsec: AtomicU64::new(0),
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+/// call counts, total time and latency histograms per command, preallocated
+/// for every entry in `protocol::COMMANDS`
+#[derive(Clone)]
+pub struct CommandStats {
+    commands: Arc<Vec<Counters>>,
+}
+
+/// a snapshot of one command's counters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandStat {
+    pub name: &'static str,
+    pub calls: u64,
+    pub usec: u64,
+    /// (upper bound in usec, calls at or below it), cumulative, empty buckets left out
+    pub histogram: Vec<(u64, u64)>,
+}
+
+impl CommandStats {
+    pub fn new() -> Self {
+        Self { commands: Arc::new(COMMANDS.iter().map(|_| Counters::new()).collect()) }
+    }
+
+    /// counts one call of the uppercased `cmd`. unknown commands aren't tracked
+    pub fn record(&self, cmd: &str, elapsed: Duration) {
+        let Some(id) = command_id(cmd) else { return };
+        let usec = elapsed.as_micros() as u64;
+        let counters = &self.commands[id];
+        counters.calls.fetch_add(1, Ordering::Relaxed);
+        counters.nsec.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        // smallest power of two >= usec
+        let bucket = (u64::BITS - usec.saturating_sub(1).leading_zeros()) as usize;
+        counters.buckets[bucket.min(BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// counts one call of the uppercased `cmd` without timing it, for
+    /// commands that block on purpose
+    pub fn count(&self, cmd: &str) {
+        if let Some(id) = command_id(cmd) {
+            self.commands[id].calls.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// stats for `cmd`, or None if it isn't a known command
+    pub fn get(&self, cmd: &str) -> Option<CommandStat> {
+        command_id(&cmd.to_uppercase()).map(|id| self.stat(id))
+    }
+
+    /// stats for every command that has been called
+    pub fn all(&self) -> Vec<CommandStat> {
+        (0..COMMANDS.len())
+            .map(|id| self.stat(id))
+            .filter(|s| s.calls > 0)
+            .collect()
+    }
+
+    /// zeroes every counter, for CONFIG RESETSTAT
+    pub fn reset(&self) {
+        for counters in self.commands.iter() {
+            counters.calls.store(0, Ordering::Relaxed);
+            counters.nsec.store(0, Ordering::Relaxed);
+            for bucket in &counters.buckets {
+                bucket.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn stat(&self, id: usize) -> CommandStat {
+        let counters = &self.commands[id];
+        let mut total = 0;
+        let mut histogram = Vec::new();
+        for (i, bucket) in counters.buckets.iter().enumerate() {
+            let n = bucket.load(Ordering::Relaxed);
+            if n > 0 {
+                total += n;
+                histogram.push((1u64 << i, total));
+            }
+        }
+        CommandStat {
+            name: COMMANDS[id].0,
+            calls: counters.calls.load(Ordering::Relaxed),
+            usec: counters.nsec.load(Ordering::Relaxed) / 1000,
+            histogram,
+        }
+    }
+}
+
+impl Default for CommandStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// renders the INFO commandstats lines
+pub fn info_commandstats(stats: &CommandStats) -> String {
+    stats.all().iter()
+        .map(|s| format!(
+            "cmdstat_{}:calls={},usec={},usec_per_call={:.2}\n",
+            s.name.to_lowercase(),
+            s.calls,
+            s.usec,
+            s.usec as f64 / s.calls as f64,
+        ))
+        .collect()
+}
+
+/// handles `LATENCY HISTOGRAM [command ...]`
+pub fn handle_latency_command(stats: &CommandStats, parts: &[&str]) -> Response {
+    let sub = parts.get(1).map(|s| s.to_uppercase()).unwrap_or_default();
+    if sub == "HELP" && parts.len() == 2 {
+        return help_reply("LATENCY", &[
+            ("HISTOGRAM [<command> ...]", "Return a cumulative latency histogram for the named commands, or all of them."),
+        ]);
+    }
+    if sub != "HISTOGRAM" {
+        return RedisError::InvalidType(format!("unknown subcommand or wrong number of arguments for 'LATENCY|{}'", sub)).into();
+    }
+    let picked = if parts.len() == 2 {
+        stats.all()
+    } else {
+        // unknown or never called commands are left out, like redis
+        parts[2..].iter()
+            .filter_map(|cmd| stats.get(cmd))
+            .filter(|s| s.calls > 0)
+            .collect()
+    };
+    let mut out = Vec::with_capacity(picked.len() * 2);
+    for s in picked {
+        out.push(Response::BulkString(Some(s.name.to_lowercase())));
+        out.push(Response::Array(vec![
+            Response::BulkString(Some("calls".to_string())),
+            Response::Integer(s.calls as i64),
+            Response::BulkString(Some("histogram_usec".to_string())),
+            Response::Array(s.histogram.iter()
+                .flat_map(|&(bound, count)| [Response::Integer(bound as i64), Response::Integer(count as i64)])
+                .collect()),
+        ]));
+    }
+    Response::Array(out)
+}
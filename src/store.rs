@@ -1,336 +1,2282 @@
 use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock},
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    collections::{HashMap, HashSet},
+    io::{self, BufRead, Write},
+    sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, Arc},
+    time::{Duration, SystemTime},
 };
+use parking_lot::{Mutex, RwLock};
+use rand::{seq::{IndexedRandom, SliceRandom}, Rng};
+use tokio::sync::{mpsc, Notify};
 use crate::{
-    aof::{Aof, LogEntry},
+    aof::{Aof, LogEntry, QueueFullPolicy},
     error::{RedisError, Response},
-    types::{Entry, RedisValue},
+    export::Record,
+    glob,
+    keyspace::{Keyspace, ReadLocked, Shard},
+    snapshot,
+    types::{element_usage, BitOp, BitRange, Entry, ExpireCondition, KeysLimitPolicy, LcsOptions, MaxMemoryPolicy, RangeUnit, RedisValue, SetCondition, SetExpiry, SetValue, from_unix_ms, unix_ms},
 };
 
+/// upper bound for strings grown via SETRANGE, same as redis
+pub const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+/// expired keys read paths can hand the sweeper before they're left for the
+/// next periodic sweep
+const PURGE_QUEUE: usize = 1024;
+
+/// keys the sweeper deletes from a shard before letting writers in
+const SWEEP_BATCH: usize = 1000;
+
+/// shards `Store::new` splits the keyspace into
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// keys looked at for each eviction under the LRU policies, like redis'
+/// maxmemory-samples
+const EVICTION_SAMPLES: usize = 5;
+
+/// most fields a negative HRANDFIELD count can ask for. repeats are allowed
+/// there, so without a bound the reply is as big as the client says
+pub const MAX_RANDOM_COUNT: u64 = 1024 * 1024;
+
 #[derive(Clone)]
 pub struct Store {
-    inner: Arc<RwLock<HashMap<String, Entry>>>,
-    aof: Option<Aof>,
+    inner: Arc<Keyspace>,
+    /// swapped at runtime by `enable_aof` and `disable_aof`. taken after the
+    /// keyspace locks, never before
+    aof: Arc<RwLock<Option<Aof>>>,
+    readonly: Arc<AtomicBool>,
+    limits: Arc<Limits>,
+    stats: Arc<StoreStats>,
+    /// last version handed to a mutated entry, see `Entry::version`
+    version: Arc<AtomicU64>,
+    /// keys blocked clients are waiting on, woken when the key is set
+    waiters: Arc<Mutex<HashMap<String, Arc<Notify>>>>,
+    /// TTLs set from now on are moved by up to this percent either way
+    ttl_jitter_pct: Arc<AtomicU64>,
+    /// expired keys found under a read lock, for the sweeper to delete
+    purge_tx: mpsc::Sender<String>,
+    /// taken by the first `start_sweeper`
+    purge_rx: Arc<Mutex<Option<mpsc::Receiver<String>>>>,
+}
+
+/// what `load_from_aof` did with the entries it was given
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    /// entries that set or extended a key
+    pub keys_loaded: usize,
+    /// deletes (and flushes) applied
+    pub deletes_applied: usize,
+    /// entries skipped because their expiry had already passed
+    pub expired_skipped: usize,
+    /// entries with an op this version doesn't know
+    pub unknown_ops: usize,
+}
+
+/// what MEMORY STATS reports, in estimated bytes like `Store::used_memory`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// every live key and value
+    pub total: usize,
+    /// per-key and per-element bookkeeping, included in `total`
+    pub overhead: usize,
+    pub keys: usize,
+}
+
+/// counters for `Store::stats`, relaxed atomics bumped on the hot paths
+#[derive(Default)]
+struct StoreStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    expired: AtomicU64,
+    evicted: AtomicU64,
+    reads: AtomicU64,
+    writes: AtomicU64,
+}
+
+/// what `Store::stats` reports, and INFO's stats section with it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// reads of a value that found the key
+    pub keyspace_hits: u64,
+    /// reads of a value that found no key, or an expired one
+    pub keyspace_misses: u64,
+    /// keys deleted because their TTL passed. a key a read finds expired
+    /// counts once the sweeper deletes it
+    pub expired_keys: u64,
+    pub evicted_keys: u64,
+    /// commands run through `protocol::execute` or `apply`, by category
+    pub read_commands: u64,
+    pub write_commands: u64,
+}
+
+impl StatsSnapshot {
+    /// the stats as INFO's fields, in order
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("keyspace_hits", self.keyspace_hits.to_string()),
+            ("keyspace_misses", self.keyspace_misses.to_string()),
+            ("expired_keys", self.expired_keys.to_string()),
+            ("evicted_keys", self.evicted_keys.to_string()),
+            ("read_commands_processed", self.read_commands.to_string()),
+            ("write_commands_processed", self.write_commands.to_string()),
+        ]
+    }
+}
+
+/// size limits for incoming keys and values and for the whole dataset, in bytes
+struct Limits {
+    max_key_len: AtomicUsize,
+    max_value_len: AtomicUsize,
+    /// elements in one list, set or hash. 0 means no limit
+    max_collection_len: AtomicUsize,
+    /// 0 means no limit
+    maxmemory: AtomicUsize,
+    policy: Mutex<MaxMemoryPolicy>,
+    /// most keys one KEYS reply may hold. 0 means no limit
+    keys_limit: AtomicUsize,
+    keys_policy: Mutex<KeysLimitPolicy>,
+}
+
+/// a blocked client's registration for wakeups on one key, removed from
+/// `Store::waiters` when the last client waiting on the key is done
+struct Waiter<'a> {
+    store: &'a Store,
+    key: &'a str,
+    notify: Arc<Notify>,
+}
+
+impl<'a> Waiter<'a> {
+    fn new(store: &'a Store, key: &'a str) -> Self {
+        let notify = store.waiters.lock().entry(key.to_string()).or_default().clone();
+        Waiter { store, key, notify }
+    }
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        let mut waiters = self.store.waiters.lock();
+        // ours and the map's, clones are only taken under this lock
+        if Arc::strong_count(&self.notify) == 2 {
+            waiters.remove(self.key);
+        }
+    }
+}
+
+/// least frequently used first, for the LFU policies
+fn lfu_rank(entry: &Entry) -> u64 {
+    u64::from(u8::MAX - entry.lfu.freq())
+}
+
+/// least recently used first, for the LRU policies
+fn lru_rank(entry: &Entry) -> u64 {
+    entry.lru.idle().as_millis() as u64
+}
+
+/// SETRANGE and BITOP write bytes, but values are UTF-8 strings, so a
+/// result that isn't valid UTF-8 is refused rather than stored lossily
+fn utf8_result(bytes: Vec<u8>) -> Result<String, Response> {
+    String::from_utf8(bytes)
+        .map_err(|_| RedisError::InvalidType("result is not valid UTF-8, string values must be text".to_string()).into())
 }
 
 impl Store {
     pub fn new(aof: Option<Aof>) -> Self {
+        Self::with_shards(aof, DEFAULT_SHARDS)
+    }
+
+    /// a store whose keyspace is split into `shards` locks, 1 for a single
+    /// lock over every key
+    pub fn with_shards(aof: Option<Aof>, shards: usize) -> Self {
+        let (purge_tx, purge_rx) = mpsc::channel(PURGE_QUEUE);
         Store {
-            inner: Arc::new(RwLock::new(HashMap::new())),
-            aof,
+            inner: Arc::new(Keyspace::new(shards)),
+            aof: Arc::new(RwLock::new(aof)),
+            readonly: Arc::new(AtomicBool::new(false)),
+            limits: Arc::new(Limits {
+                max_key_len: AtomicUsize::new(MAX_STRING_LEN),
+                max_value_len: AtomicUsize::new(MAX_STRING_LEN),
+                max_collection_len: AtomicUsize::new(0),
+                maxmemory: AtomicUsize::new(0),
+                policy: Mutex::new(MaxMemoryPolicy::NoEviction),
+                keys_limit: AtomicUsize::new(0),
+                keys_policy: Mutex::new(KeysLimitPolicy::RequirePrefix),
+            }),
+            stats: Arc::new(StoreStats::default()),
+            version: Arc::new(AtomicU64::new(0)),
+            waiters: Arc::new(Mutex::new(HashMap::new())),
+            ttl_jitter_pct: Arc::new(AtomicU64::new(0)),
+            purge_tx,
+            purge_rx: Arc::new(Mutex::new(Some(purge_rx))),
+        }
+    }
+
+    fn next_version(&self) -> u64 {
+        self.version.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn stamped(&self, mut entry: Entry) -> Entry {
+        entry.version = self.next_version();
+        entry
+    }
+
+    /// when set, the command dispatcher rejects writes with READONLY
+    pub fn set_readonly(&self, readonly: bool) {
+        self.readonly.store(readonly, Ordering::Relaxed);
+    }
+
+    pub fn is_readonly(&self) -> bool {
+        self.readonly.load(Ordering::Relaxed)
+    }
+
+    /// longest key name writes will accept
+    pub fn set_max_key_len(&self, len: usize) {
+        self.limits.max_key_len.store(len, Ordering::Relaxed);
+    }
+
+    pub fn max_key_len(&self) -> usize {
+        self.limits.max_key_len.load(Ordering::Relaxed)
+    }
+
+    /// largest single value (string, element, member or field) writes will accept
+    pub fn set_max_value_len(&self, len: usize) {
+        self.limits.max_value_len.store(len, Ordering::Relaxed);
+    }
+
+    pub fn max_value_len(&self) -> usize {
+        self.limits.max_value_len.load(Ordering::Relaxed)
+    }
+
+    /// most elements a write may leave in one list, set or hash, 0 for no
+    /// limit. collections already past it keep what they have
+    pub fn set_max_collection_len(&self, len: usize) {
+        self.limits.max_collection_len.store(len, Ordering::Relaxed);
+    }
+
+    pub fn max_collection_len(&self) -> usize {
+        self.limits.max_collection_len.load(Ordering::Relaxed)
+    }
+
+    /// spreads TTLs set from now on by up to `pct` percent either way, so keys
+    /// written together with one TTL don't all expire at once. 0 disables it
+    pub fn set_ttl_jitter_pct(&self, pct: u64) {
+        self.ttl_jitter_pct.store(pct.min(100), Ordering::Relaxed);
+    }
+
+    pub fn ttl_jitter_pct(&self) -> u64 {
+        self.ttl_jitter_pct.load(Ordering::Relaxed)
+    }
+
+    /// `ttl` moved by a random amount within the configured jitter
+    fn jittered(&self, ttl: Duration) -> Duration {
+        let pct = self.ttl_jitter_pct();
+        if pct == 0 {
+            return ttl;
+        }
+        let factor = 1.0 + rand::random_range(-(pct as f64)..=pct as f64) / 100.0;
+        Duration::try_from_secs_f64(ttl.as_secs_f64() * factor).unwrap_or(Duration::MAX)
+    }
+
+    /// when a TTL of `ttl` set now runs out, jittered. TTLs too long for the
+    /// clock never run out
+    fn expires_in(&self, ttl: Duration) -> SystemTime {
+        let now = SystemTime::now();
+        now.checked_add(self.jittered(ttl)).unwrap_or_else(|| from_unix_ms(i64::MAX))
+    }
+
+    /// when a key SET with `expiry` expires. the absolute forms aren't jittered
+    fn set_expiry(&self, expiry: SetExpiry) -> SystemTime {
+        let at_ms = |ms: u64| from_unix_ms(i64::try_from(ms).unwrap_or(i64::MAX));
+        match expiry {
+            SetExpiry::Ex(secs) => self.expires_in(Duration::from_secs(secs)),
+            SetExpiry::Px(ms) => self.expires_in(Duration::from_millis(ms)),
+            SetExpiry::ExAt(secs) => at_ms(secs.saturating_mul(1000)),
+            SetExpiry::PxAt(ms) => at_ms(ms),
+        }
+    }
+
+    /// estimated memory for the dataset to stay under, 0 disables the limit
+    pub fn set_maxmemory(&self, bytes: usize) {
+        self.limits.maxmemory.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn maxmemory(&self) -> usize {
+        self.limits.maxmemory.load(Ordering::Relaxed)
+    }
+
+    pub fn set_maxmemory_policy(&self, policy: MaxMemoryPolicy) {
+        *self.limits.policy.lock() = policy;
+    }
+
+    pub fn maxmemory_policy(&self) -> MaxMemoryPolicy {
+        *self.limits.policy.lock()
+    }
+
+    /// most keys one KEYS reply may hold, 0 for no limit. what happens past
+    /// it is up to the `KeysLimitPolicy`
+    pub fn set_keys_limit(&self, limit: usize) {
+        self.limits.keys_limit.store(limit, Ordering::Relaxed);
+    }
+
+    pub fn keys_limit(&self) -> usize {
+        self.limits.keys_limit.load(Ordering::Relaxed)
+    }
+
+    pub fn set_keys_limit_policy(&self, policy: KeysLimitPolicy) {
+        *self.limits.keys_policy.lock() = policy;
+    }
+
+    pub fn keys_limit_policy(&self) -> KeysLimitPolicy {
+        *self.limits.keys_policy.lock()
+    }
+
+    /// keys removed to stay under maxmemory since startup
+    pub fn evicted_keys(&self) -> u64 {
+        self.stats.evicted.load(Ordering::Relaxed)
+    }
+
+    /// hit, miss, expiry, eviction and command counts since the store was
+    /// created or `reset_stats`
+    pub fn stats(&self) -> StatsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        StatsSnapshot {
+            keyspace_hits: load(&self.stats.hits),
+            keyspace_misses: load(&self.stats.misses),
+            expired_keys: load(&self.stats.expired),
+            evicted_keys: load(&self.stats.evicted),
+            read_commands: load(&self.stats.reads),
+            write_commands: load(&self.stats.writes),
+        }
+    }
+
+    /// zeroes every counter in `stats`, for CONFIG RESETSTAT and tests
+    pub fn reset_stats(&self) {
+        let StoreStats { hits, misses, expired, evicted, reads, writes } = &*self.stats;
+        for counter in [hits, misses, expired, evicted, reads, writes] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// counts a command by category, called as it's dispatched
+    pub(crate) fn count_command(&self, write: bool) {
+        let counter = if write { &self.stats.writes } else { &self.stats.reads };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// estimated bytes used by all keys and values, kept up to date by every
+    /// write rather than counted, so it's cheap to ask
+    pub fn used_memory(&self) -> usize {
+        self.inner.used()
+    }
+
+    /// called before a write: evicts keys per the maxmemory policy until the
+    /// dataset fits, or returns the OOM error if it can't
+    pub fn evict_if_needed(&self) -> Result<(), Response> {
+        let max = self.maxmemory();
+        if max == 0 {
+            return Ok(());
+        }
+        let policy = self.maxmemory_policy();
+        while self.inner.used() > max {
+            let evicted = match policy {
+                MaxMemoryPolicy::NoEviction => false,
+                MaxMemoryPolicy::AllKeysLfu => self.evict_sampled(false, lfu_rank),
+                MaxMemoryPolicy::VolatileLfu => self.evict_sampled(true, lfu_rank),
+                MaxMemoryPolicy::AllKeysLru => self.evict_sampled(false, lru_rank),
+                MaxMemoryPolicy::VolatileLru => self.evict_sampled(true, lru_rank),
+            };
+            if !evicted {
+                return Err(RedisError::OutOfMemory.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// samples `EVICTION_SAMPLES` keys from every shard, only keys with a TTL
+    /// if `volatile`, and evicts the one `rank` puts highest, like redis'
+    /// approximate LRU and LFU. shards are sampled under read locks and only
+    /// the victim's is write locked. false if there was no key to evict
+    fn evict_sampled(&self, volatile: bool, rank: fn(&Entry) -> u64) -> bool {
+        let mut rng = rand::rng();
+        let mut victim: Option<(u64, String)> = None;
+        let consider = |victim: &mut Option<(u64, String)>, key: &String, entry: &Entry| {
+            let score = rank(entry);
+            if victim.as_ref().is_none_or(|(top, _)| score > *top) {
+                *victim = Some((score, key.clone()));
+            }
+        };
+        for i in 0..self.inner.len() {
+            let map = self.inner.read_shard(i);
+            if map.is_empty() {
+                continue;
+            }
+            if volatile {
+                // keys with a TTL come from the expiry index, a few probes
+                // more than the samples wanted to get past its stale entries
+                let live = self.inner.sample_expiries(i, 4 * EVICTION_SAMPLES, &mut rng).into_iter()
+                    .filter_map(|key| map.get_key_value(&key).filter(|(_, e)| e.expires_at.is_some()))
+                    .take(EVICTION_SAMPLES);
+                live.for_each(|(key, entry)| consider(&mut victim, key, entry));
+            } else {
+                let picked = rand::seq::index::sample(&mut rng, map.len(), EVICTION_SAMPLES.min(map.len()));
+                for (key, entry) in picked.into_iter().filter_map(|at| map.get_index(at)) {
+                    consider(&mut victim, key, entry);
+                }
+            }
+        }
+        if victim.is_none() && volatile {
+            // every probe hit a stale index entry, which takes TTLs being
+            // rare. then walking the index to find one is cheap enough
+            for i in 0..self.inner.len() {
+                let map = self.inner.read_shard(i);
+                let found = self.inner.expiring_keys(i).into_iter()
+                    .find_map(|key| map.get_key_value(&key).filter(|(_, e)| e.expires_at.is_some()));
+                if let Some((key, entry)) = found {
+                    consider(&mut victim, key, entry);
+                    break;
+                }
+            }
+        }
+        let Some((_, key)) = victim else { return false };
+        let mut map = self.inner.write(&key);
+        // it may have gone between the locks, then the caller samples again
+        if map.get(&key).is_some_and(|e| !volatile || e.expires_at.is_some()) {
+            self.evict(&mut map, key);
+        }
+        true
+    }
+
+    fn evict(&self, map: &mut Shard, key: String) {
+        self.take(map, &key);
+        self.stats.evicted.fetch_add(1, Ordering::Relaxed);
+        self.log_del(key);
+    }
+
+    /// called before a write: refuses it while the AOF can't be written, and
+    /// when the AOF queue is full under the error policy. under the block
+    /// policy the server waits for room before getting here
+    pub fn check_persistence(&self) -> Result<(), Response> {
+        let aof = self.aof.read();
+        let Some(aof) = &*aof else { return Ok(()) };
+        if !aof.is_healthy() {
+            return Err(RedisError::PersistenceFailed.into());
+        }
+        if aof.queue_limit().1 == QueueFullPolicy::Error && !aof.has_room() {
+            return Err(RedisError::AofQueueFull.into());
+        }
+        Ok(())
+    }
+
+    /// estimated bytes used by `key` and its value, as counted toward
+    /// maxmemory. None if it doesn't exist
+    pub fn memory_usage(&self, key: &str) -> Option<usize> {
+        self.memory_usage_sampled(key, 0)
+    }
+
+    /// `memory_usage`, estimating collections longer than `samples` from that
+    /// many elements. 0 counts every element
+    pub fn memory_usage_sampled(&self, key: &str, samples: usize) -> Option<usize> {
+        let map = self.inner.read(key);
+        map.get(key)
+            .filter(|e| !e.is_expired())
+            .map(|e| key.len() + e.memory_usage(samples))
+    }
+
+    /// totals over the live keys, walking the keyspace
+    pub fn memory_stats(&self) -> MemoryStats {
+        let map = self.inner.read_all();
+        let mut stats = MemoryStats::default();
+        for (key, entry) in map.iter().filter(|(_, e)| !e.is_expired()) {
+            stats.total += entry.usage(key);
+            stats.overhead += entry.overhead();
+            stats.keys += 1;
+        }
+        stats
+    }
+
+    /// the LFU counter of `key`, without counting this as an access
+    pub fn object_freq(&self, key: &str) -> Response {
+        let map = self.inner.read(key);
+        match map.get(key) {
+            Some(entry) if !entry.is_expired() => Response::Integer(entry.lfu.freq() as i64),
+            _ => Response::Nil,
+        }
+    }
+
+    /// how the value at `key` is stored, named like redis' OBJECT ENCODING
+    pub fn object_encoding(&self, key: &str) -> Response {
+        let map = self.inner.read(key);
+        let encoding = match map.get(key) {
+            Some(entry) if !entry.is_expired() => match &entry.value {
+                RedisValue::String(_) => "raw",
+                RedisValue::List(_) => "quicklist",
+                RedisValue::Set(set) => set.encoding(),
+                RedisValue::Hash(_) => "hashtable",
+            },
+            _ => return Response::Nil,
+        };
+        Response::BulkString(Some(encoding.to_string()))
+    }
+
+    /// records an access to `key` for LFU and LRU eviction
+    fn touch_locked(map: &Shard, key: &str) {
+        if let Some(entry) = map.get(key) {
+            entry.touch();
+        }
+    }
+
+    /// the entry at `key` for a read under the read lock, counting an access
+    /// and a keyspace hit or miss. an expired one reads as missing, counts as
+    /// expired and is handed to the sweeper with `purge_later`, so reads never
+    /// wait for the write lock
+    fn live<'a>(&self, map: &'a Shard, key: &str) -> Option<&'a Entry> {
+        let found = match map.get(key) {
+            Some(entry) if entry.is_expired() => {
+                self.count_expired(entry);
+                self.purge_later(key);
+                None
+            }
+            Some(entry) => {
+                entry.touch();
+                Some(entry)
+            }
+            None => None,
+        };
+        let counter = if found.is_some() { &self.stats.hits } else { &self.stats.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    /// drops `key` after finding it expired. the removal is logged like a DEL,
+    /// so replay can't bring the key back when the clock reads earlier then
+    fn remove_expired(&self, map: &mut Shard, key: &str) {
+        if let Some(old) = self.take(map, key) {
+            self.count_expired(&old);
+            self.log_del(key.to_string());
+        }
+    }
+
+    /// counts `entry` in `expired_keys`, unless a read already did
+    fn count_expired(&self, entry: &Entry) {
+        if entry.expiry_counted.claim() {
+            self.stats.expired.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// inserts `entry` at `key`, keeping the shard's memory count
+    fn put(&self, map: &mut Shard, key: &str, entry: Entry) -> Option<Entry> {
+        self.inner.grow(key, entry.usage(key));
+        let old = map.insert(key.to_string(), entry);
+        if let Some(old) = &old {
+            self.inner.shrink(key, old.usage(key));
+        }
+        old
+    }
+
+    /// removes `key`, keeping the shard's memory count
+    fn take(&self, map: &mut Shard, key: &str) -> Option<Entry> {
+        let old = map.swap_remove(key);
+        if let Some(old) = &old {
+            self.inner.shrink(key, old.usage(key));
+        }
+        old
+    }
+
+    /// counts a new `entry` for `key` toward the shard's memory, for one
+    /// inserted through the map's entry API
+    fn counted(&self, key: &str, entry: Entry) -> Entry {
+        self.inner.grow(key, entry.usage(key));
+        entry
+    }
+
+    /// moves `key`'s part of the shard's memory count from `before` to
+    /// `after` bytes, for a value changed in place
+    fn resized(&self, key: &str, before: usize, after: usize) {
+        self.inner.grow(key, after);
+        self.inner.shrink(key, before);
+    }
+
+    /// the error to return if `key` or any of `values` is over the configured limits
+    fn oversized<'a>(&self, key: &str, values: impl IntoIterator<Item = &'a str>) -> Option<Response> {
+        let max_key = self.max_key_len();
+        if key.len() > max_key {
+            return Some(RedisError::InvalidType(format!("key is too long ({} bytes, max-key-len is {max_key})", key.len())).into());
+        }
+        let max_value = self.max_value_len();
+        values.into_iter().find(|v| v.len() > max_value).map(|v| {
+            RedisError::InvalidType(format!("value is too large ({} bytes, proto-max-bulk-len is {max_value})", v.len())).into()
+        })
+    }
+
+    /// the error to return if a write would leave `len` elements in one
+    /// collection, over max-collection-len
+    fn overfull(&self, len: usize) -> Option<Response> {
+        let max = self.max_collection_len();
+        (max > 0 && len > max).then(|| {
+            RedisError::InvalidType(format!("collection would have {len} elements, max-collection-len is {max}")).into()
+        })
+    }
+
+    /// applies AOF entries in order as they come, so `entries` can stream
+    /// them straight from `Aof::replay_iter`
+    pub fn load_from_aof(&self, entries: impl IntoIterator<Item = LogEntry>) -> ReplayStats {
+        let mut map = self.inner.write_all();
+        let mut stats = ReplayStats::default();
+        let now_ms = unix_ms(SystemTime::now());
+        for e in entries {
+            // one version per entry, even skipped ones, keeps every version at
+            // or above what it was before the restart
+            let version = self.next_version();
+            // a write that has already expired leaves the key gone, whatever came before
+            if e.expires_at_ms.is_some_and(|ms| ms <= now_ms) {
+                map.remove(&e.key);
+                stats.expired_skipped += 1;
+                continue;
+            }
+            let expires_at = e.expires_at_ms.map(from_unix_ms);
+            let key = e.key.clone();
+            match e.op.as_str() {
+                "set" => {
+                    if let Some(val) = e.value {
+                        map.insert(e.key, Entry::string(val, expires_at));
+                        stats.keys_loaded += 1;
+                    }
+                }
+                "sset" => {
+                    let mut entry = Entry::set(expires_at);
+                    if let Some(set) = entry.value.as_set_mut() {
+                        set.extend(e.values.unwrap_or_default());
+                    }
+                    map.insert(e.key, entry);
+                    stats.keys_loaded += 1;
+                }
+                "hset" => {
+                    let entry = map.shard_mut(&key).entry(e.key).or_insert_with(|| Entry::hash(expires_at));
+                    if let Some(hash) = entry.value.as_hash_mut() {
+                        let values = e.values.unwrap_or_default();
+                        for pair in values.chunks_exact(2) {
+                            hash.insert(pair[0].clone(), pair[1].clone());
+                        }
+                    }
+                    stats.keys_loaded += 1;
+                }
+                "expire" => {
+                    if let Some(entry) = map.get_mut(&e.key) {
+                        entry.expires_at = expires_at;
+                    }
+                }
+                "lpush" => {
+                    let entry = map.shard_mut(&key).entry(e.key).or_insert_with(|| Entry::list(expires_at));
+                    if let Some(list) = entry.value.as_list_mut() {
+                        for value in e.values.unwrap_or_default().into_iter().rev() {
+                            list.push_front(value);
+                        }
+                    }
+                    stats.keys_loaded += 1;
+                }
+                "lpop" => {
+                    if let Some(list) = map.get_mut(&e.key).and_then(|entry| entry.value.as_list_mut()) {
+                        list.pop_front();
+                        if list.is_empty() {
+                            map.remove(&e.key);
+                        }
+                    }
+                    stats.deletes_applied += 1;
+                }
+                "sadd" => {
+                    let entry = map.shard_mut(&key).entry(e.key).or_insert_with(|| Entry::set(expires_at));
+                    if let Some(set) = entry.value.as_set_mut() {
+                        set.extend(e.values.unwrap_or_default());
+                    }
+                    stats.keys_loaded += 1;
+                }
+                "srem" => {
+                    if let Some(set) = map.get_mut(&e.key).and_then(|entry| entry.value.as_set_mut()) {
+                        for member in e.values.unwrap_or_default() {
+                            set.remove(&member);
+                        }
+                        if set.is_empty() {
+                            map.remove(&e.key);
+                        }
+                    }
+                    stats.deletes_applied += 1;
+                }
+                "del" => {
+                    map.remove(&e.key);
+                    stats.deletes_applied += 1;
+                }
+                "flushall" => {
+                    map.clear();
+                    stats.deletes_applied += 1;
+                }
+                // where the AOF picks up from a snapshot, which is loaded before replay
+                "snapshot" => {}
+                _ => stats.unknown_ops += 1,
+            }
+            if let Some(entry) = map.get_mut(&key) {
+                entry.version = version;
+            }
+        }
+        self.inner.reindex_all(&map);
+        stats
+    }
+
+    pub fn set(&self, key: String, value: String, ttl_secs: Option<u64>) -> Response {
+        if let Some(err) = self.oversized(&key, [value.as_str()]) {
+            return err;
+        }
+        let expires_at = ttl_secs.map(|s| self.expires_in(Duration::from_secs(s)));
+        // copied into the shared buffer before locking, the lock is held for pointers
+        let shared: Arc<str> = value.as_str().into();
+        // logged under the lock, so the AOF has racing SETs in the order they
+        // took effect
+        let mut map = self.inner.write(&key);
+        self.put(&mut map, &key, self.stamped(Entry::string(shared, expires_at)));
+        self.index_expiry(&key, expires_at);
+        self.log_set(key.clone(), value, expires_at);
+        drop(map);
+        self.wake(&key);
+        "OK".into()
+    }
+
+    /// SET with NX or XX, or an expiry other than seconds: OK if the key was
+    /// set, Nil if `cond` ruled it out
+    pub fn set_if(&self, key: String, value: String, expiry: Option<SetExpiry>, cond: SetCondition) -> Response {
+        match self.set_with(key, value, expiry, cond, false) {
+            Ok((_, true)) => "OK".into(),
+            Ok((_, false)) => Response::Nil,
+            Err(e) => e,
+        }
+    }
+
+    /// SET with GET: sets the key as `set_if` would and returns its old value,
+    /// Nil if it had none. an old value that isn't a string is WRONGTYPE, and
+    /// the key is left alone
+    pub fn set_get(&self, key: String, value: String, expiry: Option<SetExpiry>, cond: SetCondition) -> Response {
+        match self.set_with(key, value, expiry, cond, true) {
+            Ok((Some(old), _)) => Response::BulkShared(old),
+            Ok((None, _)) => Response::BulkString(None),
+            Err(e) => e,
+        }
+    }
+
+    /// the old string value when `get` is set, and whether the key was written
+    fn set_with(
+        &self,
+        key: String,
+        value: String,
+        expiry: Option<SetExpiry>,
+        cond: SetCondition,
+        get: bool,
+    ) -> Result<(Option<Arc<str>>, bool), Response> {
+        if let Some(err) = self.oversized(&key, [value.as_str()]) {
+            return Err(err);
+        }
+        let shared: Arc<str> = value.as_str().into();
+        let mut map = self.inner.write(&key);
+        if map.get(&key).is_some_and(|e| e.is_expired()) {
+            self.remove_expired(&mut map, &key);
+        }
+        let old = match map.get(&key) {
+            Some(entry) if get => match entry.require_string() {
+                Ok(old) => Some(old.clone()),
+                Err(e) => return Err(e.into()),
+            },
+            _ => None,
+        };
+        if !cond.allows(map.contains_key(&key)) {
+            return Ok((old, false));
+        }
+        let expires_at = expiry.map(|e| self.set_expiry(e));
+        self.put(&mut map, &key, self.stamped(Entry::string(shared, expires_at)));
+        self.index_expiry(&key, expires_at);
+        self.log_set(key.clone(), value, expires_at);
+        drop(map);
+        self.wake(&key);
+        Ok((old, true))
+    }
+
+    /// SET that returns once the write has reached the AOF and been fsynced,
+    /// whatever `appendfsync` says, for writes that must survive a crash once
+    /// acknowledged. without persistence it's a plain SET
+    pub async fn set_durable(&self, key: String, value: String, ttl_secs: Option<u64>) -> Response {
+        let resp = self.set(key, value, ttl_secs);
+        if let (Response::SimpleString(_), Some(aof)) = (&resp, self.aof()) {
+            if let Err(e) = aof.flush().await {
+                return RedisError::Internal(e.to_string()).into();
+            }
+        }
+        resp
+    }
+
+    /// returns the string at `key`, or sets it to `default` (with a TTL of
+    /// `ttl_secs`) and returns that if the key doesn't exist, under one lock,
+    /// so of several racing callers only the first writes
+    pub fn get_or_set(&self, key: &str, default: String, ttl_secs: Option<u64>) -> Response {
+        if let Some(err) = self.oversized(key, [default.as_str()]) {
+            return err;
+        }
+        let shared: Arc<str> = default.as_str().into();
+        let mut map = self.inner.write(key);
+        Self::touch_locked(&map, key);
+        if map.get(key).is_some_and(|e| e.is_expired()) {
+            self.remove_expired(&mut map, key);
+        }
+        if let Some(entry) = map.get(key) {
+            return match entry.require_string() {
+                Ok(value) => Response::BulkShared(value.clone()),
+                Err(e) => e.into(),
+            };
+        }
+        let expires_at = ttl_secs.map(|s| self.expires_in(Duration::from_secs(s)));
+        self.put(&mut map, key, self.stamped(Entry::string(shared.clone(), expires_at)));
+        self.index_expiry(key, expires_at);
+        self.log_set(key.to_string(), default, expires_at);
+        drop(map);
+        self.wake(key);
+        Response::BulkShared(shared)
+    }
+
+    /// GET that waits for the key to be set when it doesn't exist yet, for up
+    /// to `timeout` (None waits forever). Nil if it times out
+    pub async fn blocking_get(&self, key: &str, timeout: Option<Duration>) -> Response {
+        let waiter = Waiter::new(self, key);
+        let wait = async {
+            loop {
+                // registered before the check, so a SET in between still wakes us
+                let notified = waiter.notify.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+                match self.get(key) {
+                    Response::Nil => notified.await,
+                    found => return found,
+                }
+            }
+        };
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, wait).await.unwrap_or(Response::Nil),
+            None => wait.await,
+        }
+    }
+
+    /// wakes the clients blocked on `key`
+    fn wake(&self, key: &str) {
+        if let Some(notify) = self.waiters.lock().get(key) {
+            notify.notify_waiters();
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Response {
+        let map = self.inner.read(key);
+        match self.live(&map, key).map(Entry::require_string) {
+            Some(Ok(string_val)) => Response::BulkShared(string_val.clone()),
+            Some(Err(e)) => e.into(),
+            None => Response::Nil,
+        }
+    }
+
+    /// like GET, but without side effects: an expired key reads as Nil
+    /// without being queued for deletion, and no access is recorded for eviction
+    pub fn peek(&self, key: &str) -> Response {
+        let map = self.inner.read(key);
+        match map.get(key) {
+            Some(entry) if entry.is_expired() => Response::Nil,
+            Some(entry) => match entry.require_string() {
+                Ok(string_val) => Response::BulkShared(string_val.clone()),
+                Err(e) => e.into(),
+            },
+            None => Response::Nil,
+        }
+    }
+
+    pub fn del(&self, key: &str) -> Response {
+        let mut map = self.inner.write(key);
+        let removed = if let Some(entry) = map.get(key) {
+            if entry.is_expired() {
+                self.remove_expired(&mut map, key);
+                0
+            } else {
+                self.take(&mut map, key);
+                1
+            }
+        } else { 0 };
+
+        if removed == 1 {
+            self.log_del(key.to_string());
+        }
+        Response::Integer(removed)
+    }
+
+    /// deletes `key` only if it holds the string `expected`, checked and removed
+    /// under one write lock. releases a lock only while its owner still holds it
+    pub fn del_if_equal(&self, key: &str, expected: &str) -> Response {
+        let mut map = self.inner.write(key);
+        let Some(entry) = map.get(key) else { return Response::Integer(0) };
+        if entry.is_expired() {
+            self.remove_expired(&mut map, key);
+            return Response::Integer(0);
+        }
+        match entry.require_string() {
+            Ok(current) if **current == *expected => {
+                self.take(&mut map, key);
+                self.log_del(key.to_string());
+                Response::Integer(1)
+            }
+            Ok(_) => Response::Integer(0),
+            Err(e) => e.into(),
+        }
+    }
+
+    /// deletes every key matching the glob `pattern`, returning how many live keys were removed
+    pub fn del_pattern(&self, pattern: &str) -> Response {
+        let mut map = self.inner.write_all();
+        // snapshot the matches first so we never mutate while iterating
+        let matching: Vec<String> = map.iter()
+            .map(|(k, _)| k)
+            .filter(|k| glob::matches(pattern, k))
+            .cloned()
+            .collect();
+
+        let mut removed = 0;
+        for key in matching {
+            if let Some(entry) = self.take(map.shard_mut(&key), &key) {
+                removed += i64::from(!entry.is_expired());
+                self.log_del(key);
+            }
+        }
+        Response::Integer(removed)
+    }
+
+    /// under a read lock, so health checks don't queue behind each other. an
+    /// expired key is left to the sweeper, see `purge_later`
+    pub fn exists(&self, key: &str) -> Response {
+        let map = self.inner.read(key);
+        match map.get(key) {
+            Some(entry) if entry.is_expired() => {
+                self.purge_later(key);
+                Response::Integer(0)
+            }
+            Some(_) => Response::Integer(1),
+            None => Response::Integer(0),
+        }
+    }
+
+    /// DUMP: the value at `key` as a payload RESTORE takes, Nil if there's none
+    pub fn dump(&self, key: &str) -> Response {
+        let map = self.inner.read(key);
+        match map.get(key) {
+            Some(entry) if entry.is_expired() => {
+                self.purge_later(key);
+                Response::Nil
+            }
+            Some(entry) => Response::BulkString(Some(snapshot::dump(&entry.value))),
+            None => Response::Nil,
+        }
+    }
+
+    /// RESTORE: recreates `key` from a DUMP payload with a TTL of `ttl_ms`, 0
+    /// for none. an existing key is only overwritten with `replace`
+    pub fn restore(&self, key: &str, ttl_ms: i64, payload: &str, replace: bool) -> Response {
+        if ttl_ms < 0 {
+            return RedisError::InvalidType("Invalid TTL value, must be >= 0".to_string()).into();
+        }
+        if let Some(err) = self.oversized(key, []) {
+            return err;
+        }
+        let value = match snapshot::restore(payload) {
+            Ok(value) => value,
+            Err(_) => return RedisError::InvalidType("DUMP payload version or checksum are wrong".to_string()).into(),
+        };
+        let len = match &value {
+            RedisValue::String(_) => 0,
+            RedisValue::List(list) => list.len(),
+            RedisValue::Set(set) => set.len(),
+            RedisValue::Hash(hash) => hash.len(),
+        };
+        if let Some(err) = self.overfull(len) {
+            return err;
+        }
+        let mut map = self.inner.write(key);
+        if map.get(key).is_some_and(|e| e.is_expired()) {
+            self.remove_expired(&mut map, key);
+        }
+        if map.contains_key(key) && !replace {
+            return RedisError::Reply("BUSYKEY Target key name already exists.".to_string()).into();
+        }
+        let expires_at = (ttl_ms > 0).then(|| {
+            let now = SystemTime::now();
+            now.checked_add(Duration::from_millis(ttl_ms as u64)).unwrap_or_else(|| from_unix_ms(i64::MAX))
+        });
+        self.replace_logged(&mut map, key, Entry::new(value, expires_at));
+        drop(map);
+        self.wake(key);
+        "OK".into()
+    }
+
+    /// puts `entry` at `key` whatever was there, logging it as a whole
+    fn replace_logged(&self, map: &mut Shard, key: &str, entry: Entry) {
+        let entry = self.stamped(entry);
+        self.index_expiry(key, entry.expires_at);
+        // replay appends to lists and hashes, so clear whatever was there first
+        self.log_del(key.to_string());
+        if let Some(aof) = &*self.aof.read() {
+            aof.log(log_entry(key, &entry));
+        }
+        self.put(map, key, entry);
+    }
+
+    /// writes every live key to `out` as JSON lines, see `export`, in key
+    /// order. returns how many were written
+    pub fn export_json<W: Write>(&self, mut out: W) -> io::Result<usize> {
+        let mut records = Vec::new();
+        self.for_each(|key, entry| records.push(Record::from_entry(key, entry)));
+        records.sort_by(|a, b| a.key.cmp(&b.key));
+        for record in &records {
+            serde_json::to_writer(&mut out, record)?;
+            out.write_all(b"\n")?;
+        }
+        out.flush()?;
+        Ok(records.len())
+    }
+
+    /// loads keys written by `export_json`, overwriting keys of the same name.
+    /// with `replace` every other key goes too. the whole input is parsed
+    /// before anything changes, and the keys are logged to the AOF. returns
+    /// how many keys were loaded, leaving out ones that have since expired
+    pub fn import_json<R: BufRead>(&self, input: R, replace: bool) -> anyhow::Result<usize> {
+        let mut records = Vec::new();
+        for (n, line) in input.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: Record = serde_json::from_str(&line)
+                .map_err(|e| anyhow::anyhow!("line {}: {e}", n + 1))?;
+            records.push(record);
+        }
+        records.retain(Record::is_live);
+        let mut map = self.inner.write_all();
+        if replace {
+            map.clear();
+            self.inner.reindex_all(&map);
+            if let Some(aof) = &*self.aof.read() {
+                aof.log(LogEntry { op: "flushall".into(), key: String::new(), value: None, expires_at_ms: None, values: None });
+            }
+        }
+        for record in &records {
+            self.replace_logged(map.shard_mut(&record.key), &record.key, Entry::new(record.to_value(), record.expires_at()));
+        }
+        drop(map);
+        for record in &records {
+            self.wake(&record.key);
+        }
+        Ok(records.len())
+    }
+
+    /// EXPIRE: sets a TTL of `secs` on a live key if `cond` allows it. 1 if it
+    /// was set, 0 if the key is missing or the condition wasn't met
+    pub fn expire(&self, key: &str, secs: i64, cond: ExpireCondition) -> Response {
+        self.pexpire(key, secs.saturating_mul(1000), cond)
+    }
+
+    /// PEXPIRE: like `expire`, in milliseconds. a TTL that isn't positive deletes the key
+    pub fn pexpire(&self, key: &str, ms: i64, cond: ExpireCondition) -> Response {
+        let now = SystemTime::now();
+        let at = match ms {
+            ms if ms > 0 => self.expires_in(Duration::from_millis(ms as u64)),
+            ms => now - Duration::from_millis(ms.unsigned_abs()),
+        };
+        self.expire_at(key, at, cond)
+    }
+
+    /// PEXPIREAT: like `pexpire`, at a unix time in milliseconds. a time that
+    /// has passed deletes the key. TTL jitter doesn't apply
+    pub fn pexpireat(&self, key: &str, unix_ms: i64, cond: ExpireCondition) -> Response {
+        self.expire_at(key, from_unix_ms(unix_ms), cond)
+    }
+
+    fn expire_at(&self, key: &str, at: SystemTime, cond: ExpireCondition) -> Response {
+        let mut map = self.inner.write(key);
+        let Some(entry) = map.get_mut(key) else { return Response::Integer(0) };
+        if entry.is_expired() {
+            self.remove_expired(&mut map, key);
+            return Response::Integer(0);
+        }
+        if !cond.allows(entry.expires_at, at) {
+            return Response::Integer(0);
+        }
+        if at <= SystemTime::now() {
+            self.take(&mut map, key);
+            self.log_del(key.to_string());
+        } else {
+            entry.expires_at = Some(at);
+            entry.version = self.next_version();
+            self.inner.index_expiry(key, at);
+            self.log_expire(key.to_string(), at);
+        }
+        Response::Integer(1)
+    }
+
+    /// panics with every shard write locked, for DEBUG PANIC
+    pub fn debug_panic(&self) -> Response {
+        let _map = self.inner.write_all();
+        panic!("DEBUG PANIC");
+    }
+
+    /// moves a key's expiry into the past without removing it, so the next
+    /// access or sweep finds it expired. false if there is no such key
+    pub fn expire_now(&self, key: &str) -> bool {
+        let mut map = self.inner.write(key);
+        let Some(entry) = map.get_mut(key) else { return false };
+        let at = SystemTime::now() - Duration::from_secs(1);
+        entry.expires_at = Some(at);
+        self.inner.index_expiry(key, at);
+        true
+    }
+
+    /// DBSIZE: the number of live keys
+    pub fn dbsize(&self) -> Response {
+        let map = self.inner.read_all();
+        Response::Integer(map.iter().filter(|(_, e)| !e.is_expired()).count() as i64)
+    }
+
+    /// calls `f` with every live key and its entry, for exports or audits
+    /// without copying the keyspace. `f` runs with every shard read locked, so
+    /// it must not call back into the store: a write deadlocks, and a read can
+    /// too once a writer is waiting. writes from other threads wait until it returns
+    pub fn for_each<F: FnMut(&str, &Entry)>(&self, mut f: F) {
+        let map = self.inner.read_all();
+        for (key, entry) in map.iter().filter(|(_, e)| !e.is_expired()) {
+            f(key, entry);
+        }
+    }
+
+    /// entries held, and how many of them have a TTL, expired or not
+    pub fn key_counts(&self) -> (usize, usize) {
+        let map = self.inner.read_all();
+        (map.len(), map.iter().filter(|(_, e)| e.expires_at.is_some()).count())
+    }
+
+    /// entries held, expired or not, like `key_counts().0` but locking one
+    /// shard at a time and without walking them
+    pub fn entry_count(&self) -> usize {
+        (0..self.inner.len()).map(|i| self.inner.read_shard(i).len()).sum()
+    }
+
+    /// VERSION: the key's version, 0 if it doesn't exist. it changes whenever
+    /// the key is written, so a cache can tell whether its copy is stale
+    pub fn version_of(&self, key: &str) -> Response {
+        let map = self.inner.read(key);
+        match map.get(key) {
+            Some(entry) if !entry.is_expired() => Response::Integer(entry.version as i64),
+            _ => Response::Integer(0),
+        }
+    }
+
+    pub fn ttl(&self, key: &str) -> Response {
+        match self.pttl(key) {
+            Response::Integer(ms) if ms >= 0 => Response::Integer(ms / 1000),
+            other => other,
+        }
+    }
+
+    /// PTTL: like `ttl`, in milliseconds. a read lock, like `exists`
+    pub fn pttl(&self, key: &str) -> Response {
+        let map = self.inner.read(key);
+        if let Some(entry) = map.get(key) {
+            if entry.is_expired() {
+                self.purge_later(key);
+                return Response::Integer(-2); // not found
+            }
+            match entry.expires_at {
+                Some(exp) => {
+                    let now = SystemTime::now();
+                    let rem = exp.duration_since(now).unwrap_or_default().as_millis();
+                    let rem = i64::try_from(rem).unwrap_or(i64::MAX);
+                    Response::Integer(rem)
+                }
+                None => Response::Integer(-1), // no TTL
+            }
+        } else { 
+            Response::Integer(-2) 
+        }
+    }
+
+    pub fn expiretime(&self, key: &str) -> Response {
+        match self.pexpiretime(key) {
+            Response::Integer(ms) if ms >= 0 => Response::Integer(ms / 1000),
+            other => other,
+        }
+    }
+
+    /// PEXPIRETIME: the unix time in milliseconds `key` expires at, -1 if it
+    /// has no TTL and -2 if it doesn't exist. a read lock, like `exists`
+    pub fn pexpiretime(&self, key: &str) -> Response {
+        let map = self.inner.read(key);
+        match map.get(key) {
+            Some(entry) if entry.is_expired() => {
+                self.purge_later(key);
+                Response::Integer(-2)
+            }
+            Some(Entry { expires_at: Some(at), .. }) => {
+                Response::Integer(unix_ms(*at).max(0))
+            }
+            Some(_) => Response::Integer(-1),
+            None => Response::Integer(-2),
+        }
+    }
+
+    pub fn keys_with_prefix(&self, prefix: &str) -> Response {
+        let map = self.inner.read_all();
+        let mut keys = Vec::new();
+        for (key, entry) in map.iter().filter(|(k, _)| k.starts_with(prefix)) {
+            if entry.is_expired() {
+                self.purge_later(key);
+            } else {
+                keys.push(key.clone());
+            }
+        }
+
+        let limit = self.keys_limit();
+        if limit > 0 && keys.len() > limit {
+            match self.keys_limit_policy() {
+                KeysLimitPolicy::RequirePrefix if prefix.is_empty() => {
+                    return RedisError::InvalidType(format!(
+                        "KEYS would return {} keys, more than keys-limit {limit}. Use a prefix",
+                        keys.len()
+                    )).into();
+                }
+                KeysLimitPolicy::RequirePrefix => {}
+                KeysLimitPolicy::Truncate => {
+                    tracing::warn!(prefix, matched = keys.len(), limit, "KEYS reply truncated to keys-limit");
+                    keys.truncate(limit);
+                }
+            }
+        }
+
+        if keys.is_empty() {
+            Response::Array(vec![])
+        } else {
+            Response::Array(keys.into_iter().map(|k| Response::BulkString(Some(k))).collect())
+        }
+    }
+
+    /// RANDOMKEY: up to `count` distinct live keys, holding a `kind` value
+    /// if given, by reservoir sampling under the read locks, so every match
+    /// is as likely. without `count` one key, Nil if none matches
+    pub fn random_keys(&self, count: Option<usize>, kind: Option<&str>) -> Response {
+        let map = self.inner.read_all();
+        let want = count.unwrap_or(1);
+        let mut rng = rand::rng();
+        let (mut picked, mut seen) = (Vec::new(), 0usize);
+        for (key, entry) in map.iter() {
+            if entry.is_expired() {
+                self.purge_later(key);
+                continue;
+            }
+            if kind.is_some_and(|kind| !entry.value.type_name().eq_ignore_ascii_case(kind)) {
+                continue;
+            }
+            seen += 1;
+            if picked.len() < want {
+                picked.push(key);
+            } else {
+                let at = rng.random_range(0..seen);
+                if at < want {
+                    picked[at] = key;
+                }
+            }
+        }
+        // the reservoir keeps the keys it filled up with in map order
+        picked.shuffle(&mut rng);
+        let mut keys = picked.into_iter().map(|key| Response::BulkString(Some(key.clone())));
+        match count {
+            Some(_) => Response::Array(keys.collect()),
+            None => keys.next().unwrap_or(Response::Nil),
+        }
+    }
+
+    pub fn incr(&self, key: &str) -> Response {
+        if let Some(err) = self.oversized(key, []) {
+            return err;
+        }
+        let mut map = self.inner.write(key);
+        Self::touch_locked(&map, key);
+        let resp = if let Some(entry) = map.get_mut(key) {
+            if entry.is_expired() {
+                self.remove_expired(&mut map, key);
+                let new = 1i64;
+                self.put(&mut map, key, self.stamped(Entry::string(new.to_string(), None)));
+                self.log_set(key.to_string(), new.to_string(), None);
+                Response::Integer(new)
+            } else {
+                let parsed = entry.require_string()
+                    .and_then(|s| s.parse::<i64>().map_err(|_| RedisError::NotInteger(s.to_string())));
+                match parsed {
+                    Ok(cur) => {
+                        let new = cur + 1;
+                        let before = entry.memory_usage(0);
+                        entry.value = RedisValue::String(new.to_string().into());
+                        self.resized(key, before, entry.memory_usage(0));
+                        entry.version = self.next_version();
+                        self.log_set(key.to_string(), new.to_string(), entry.expires_at);
+                        Response::Integer(new)
+                    }
+                    Err(e) => e.into(),
+                }
+            }
+        } else {
+            let new = 1i64;
+            self.put(&mut map, key, self.stamped(Entry::string(new.to_string(), None)));
+            self.log_set(key.to_string(), new.to_string(), None);
+            Response::Integer(new)
+        };
+        drop(map);
+        if matches!(resp, Response::Integer(_)) {
+            self.wake(key);
+        }
+        resp
+    }
+
+    pub fn setrange(&self, key: &str, offset: usize, value: &str) -> Response {
+        if let Some(err) = self.oversized(key, []) {
+            return err;
+        }
+        let mut map = self.inner.write(key);
+        Self::touch_locked(&map, key);
+        if map.get(key).is_some_and(|e| e.is_expired()) {
+            self.remove_expired(&mut map, key);
+        }
+
+        let (mut bytes, expires_at) = match map.get(key) {
+            Some(entry) => match entry.require_string() {
+                Ok(s) => (s.as_bytes().to_vec(), entry.expires_at),
+                Err(e) => return e.into(),
+            },
+            None => (Vec::new(), None),
+        };
+
+        // setting an empty value on a missing key is a no-op
+        if value.is_empty() {
+            return Response::Integer(bytes.len() as i64);
+        }
+
+        let end = offset.saturating_add(value.len());
+        if end > MAX_STRING_LEN {
+            return RedisError::InvalidType("string exceeds maximum allowed size (512MB)".to_string()).into();
+        }
+        if end > self.max_value_len() {
+            return RedisError::InvalidType(format!("string exceeds maximum allowed size (proto-max-bulk-len is {})", self.max_value_len())).into();
         }
+        if bytes.len() < end {
+            bytes.resize(end, 0);
+        }
+        bytes[offset..end].copy_from_slice(value.as_bytes());
+
+        let new = match utf8_result(bytes) {
+            Ok(new) => new,
+            Err(e) => return e,
+        };
+        let len = new.len() as i64;
+        self.put(&mut map, key, self.stamped(Entry::string(new.clone(), expires_at)));
+        self.log_set(key.to_string(), new, expires_at);
+        drop(map);
+        self.wake(key);
+        Response::Integer(len)
     }
 
-    pub fn load_from_aof(&self, entries: Vec<LogEntry>) {
-        let mut map = self.inner.write().unwrap();
-        for e in entries {
-            match e.op.as_str() {
-                "set" => {
-                    let expires_at = e.expires_at_ms.map(|ms| UNIX_EPOCH + Duration::from_millis(ms as u64));
-                    if let Some(val) = e.value {
-                        map.insert(e.key, Entry::string(val, expires_at));
-                    }
-                }
-                "del" => { map.remove(&e.key); }
-                _ => {}
+    pub fn getrange(&self, key: &str, start: i64, end: i64) -> Response {
+        let map = self.inner.read(key);
+        if let Some(entry) = self.live(&map, key) {
+            let string_val = match entry.require_string() {
+                Ok(string_val) => string_val,
+                Err(e) => return e.into(),
+            };
+            let bytes = string_val.as_bytes();
+            let len = bytes.len() as i64;
+            // negative offsets count back from the end of the string
+            let start = if start < 0 { (len + start).max(0) } else { start };
+            let end = if end < 0 { len + end } else { end.min(len - 1) };
+            if len == 0 || start > end || start >= len {
+                return Response::BulkString(Some(String::new()));
             }
+            // a byte range can split a character, so the reply is raw bytes
+            return Response::BulkBytes(bytes[start as usize..=end as usize].to_vec());
         }
+        Response::BulkString(Some(String::new()))
     }
 
-    pub fn set(&self, key: String, value: String, ttl_secs: Option<u64>) -> Response {
-        let expires_at = ttl_secs.map(|s| SystemTime::now() + Duration::from_secs(s));
-        {
-            let mut map = self.inner.write().unwrap();
-            map.insert(key.clone(), Entry::string(value.clone(), expires_at));
+    /// applies a bitwise op across string values treated as byte buffers,
+    /// zero-extending shorter operands. the result is stored at `dest`
+    pub fn bitop(&self, op: BitOp, dest: &str, srcs: &[&str]) -> Response {
+        if srcs.is_empty() || (op == BitOp::Not && srcs.len() != 1) {
+            return RedisError::InvalidType("BITOP NOT must be called with a single source key".to_string()).into();
         }
-        
-        if let Some(aof) = &self.aof {
-            aof.log(LogEntry {
-                op: "set".into(),
-                key,
-                value: Some(value),
-                expires_at_ms: expires_at.map(|t| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64),
-            });
+        if let Some(err) = self.oversized(dest, []) {
+            return err;
         }
-        "OK".into()
-    }
 
-    pub fn get(&self, key: &str) -> Response {
-        let mut map = self.inner.write().unwrap();
-        if let Some(entry) = map.get(key) {
-            if entry.is_expired() {
-                map.remove(key);
-                return Response::Nil;
+        let mut map = self.inner.write_keys(&[&[dest], srcs].concat());
+        let mut operands: Vec<Vec<u8>> = Vec::with_capacity(srcs.len());
+        for src in srcs {
+            match map.get(src) {
+                Some(entry) if entry.is_expired() => operands.push(Vec::new()),
+                Some(entry) => match entry.require_string() {
+                    Ok(s) => operands.push(s.as_bytes().to_vec()),
+                    Err(e) => return e.into(),
+                },
+                None => operands.push(Vec::new()),
             }
-            if let Some(string_val) = entry.value.as_string() {
-                return Response::BulkString(Some(string_val.clone()));
+        }
+
+        let len = operands.iter().map(|o| o.len()).max().unwrap_or(0);
+        let mut result = vec![0u8; len];
+        for (i, byte) in result.iter_mut().enumerate() {
+            let at = |o: &Vec<u8>| o.get(i).copied().unwrap_or(0);
+            *byte = match op {
+                BitOp::And => operands.iter().map(at).fold(0xff, |acc, b| acc & b),
+                BitOp::Or => operands.iter().map(at).fold(0, |acc, b| acc | b),
+                BitOp::Xor => operands.iter().map(at).fold(0, |acc, b| acc ^ b),
+                BitOp::Not => !at(&operands[0]),
+            };
+        }
+
+        if result.is_empty() {
+            if self.take(map.shard_mut(dest), dest).is_some() {
+                self.log_del(dest.to_string());
             }
-            return RedisError::InvalidType(format!("WRONGTYPE Operation against a key holding the wrong kind of value")).into();
+            return Response::Integer(0);
         }
-        Response::Nil
+
+        let value = match utf8_result(result) {
+            Ok(value) => value,
+            Err(e) => return e,
+        };
+        self.put(map.shard_mut(dest), dest, self.stamped(Entry::string(value.clone(), None)));
+        self.log_set(dest.to_string(), value, None);
+        drop(map);
+        self.wake(dest);
+        Response::Integer(len as i64)
     }
 
-    pub fn del(&self, key: &str) -> Response {
-        let mut map = self.inner.write().unwrap();
-        let removed = if let Some(entry) = map.get(key) {
-            if entry.is_expired() {
-                map.remove(key);
-                0
-            } else {
-                map.remove(key);
-                1
-            }
-        } else { 0 };
+    /// returns the position of the first bit set to `bit`, or -1 if there is none
+    pub fn bitpos(&self, key: &str, bit: bool, range: Option<BitRange>) -> Response {
+        let map = self.inner.read(key);
+        let bytes = match self.live(&map, key).map(Entry::require_string) {
+            Some(Ok(s)) => s.as_bytes().to_vec(),
+            Some(Err(e)) => return e.into(),
+            None => Vec::new(),
+        };
+        drop(map);
 
-        if removed == 1 {
-            if let Some(aof) = &self.aof {
-                aof.log(LogEntry {
-                    op: "del".into(),
-                    key: key.to_string(),
-                    value: None,
-                    expires_at_ms: None,
-                });
+        // a missing key behaves like an empty string
+        if bytes.is_empty() {
+            return Response::Integer(if bit { -1 } else { 0 });
+        }
+
+        let unit = range.map(|r| r.unit).unwrap_or(RangeUnit::Byte);
+        let total = match unit {
+            RangeUnit::Byte => bytes.len() as i64,
+            RangeUnit::Bit => bytes.len() as i64 * 8,
+        };
+        let mut start = range.map(|r| r.start).unwrap_or(0);
+        let mut end = range.and_then(|r| r.end).unwrap_or(total - 1);
+        if start < 0 { start += total; }
+        if end < 0 { end += total; }
+        let start = start.max(0);
+        let end = end.max(0).min(total - 1);
+        if start > end {
+            return Response::Integer(-1);
+        }
+
+        let (first_bit, last_bit) = match unit {
+            RangeUnit::Byte => (start * 8, end * 8 + 7),
+            RangeUnit::Bit => (start, end),
+        };
+        for pos in first_bit..=last_bit {
+            let byte = bytes[(pos / 8) as usize];
+            let is_set = (byte >> (7 - (pos % 8))) & 1 == 1;
+            if is_set == bit {
+                return Response::Integer(pos);
             }
         }
-        Response::Integer(removed)
+
+        // without an explicit end the string is treated as padded with zeros on the right
+        let explicit_end = range.is_some_and(|r| r.end.is_some());
+        if !bit && !explicit_end {
+            return Response::Integer(last_bit + 1);
+        }
+        Response::Integer(-1)
     }
 
-    pub fn exists(&self, key: &str) -> Response {
-        let mut map = self.inner.write().unwrap();
-        if let Some(entry) = map.get(key) {
-            if entry.is_expired() {
-                map.remove(key);
-                Response::Integer(0)
-            } else {
-                Response::Integer(1)
+    /// the longest common subsequence of two strings, compared byte by byte.
+    /// missing keys count as empty strings
+    pub fn lcs(&self, key1: &str, key2: &str, opts: LcsOptions) -> Response {
+        let map = self.inner.read_keys(&[key1, key2]);
+        let mut values = Vec::with_capacity(2);
+        for key in [key1, key2] {
+            match self.live(map.shard(key), key).map(Entry::require_string) {
+                Some(Ok(s)) => values.push(s.as_bytes().to_vec()),
+                Some(Err(e)) => return e.into(),
+                None => values.push(Vec::new()),
             }
-        } else { 
-            Response::Integer(0) 
         }
-    }
+        drop(map);
+        let (a, b) = (&values[0], &values[1]);
 
-    pub fn ttl(&self, key: &str) -> Response {
-        let mut map = self.inner.write().unwrap();
-        if let Some(entry) = map.get(key) {
-            if entry.is_expired() {
-                map.remove(key);
-                return Response::Integer(-2); // not found
+        // table[i * width + j] is the LCS length of a[..i] and b[..j]
+        let width = b.len() + 1;
+        let cells = (a.len() + 1).checked_mul(width).filter(|&n| n < u32::MAX as usize / 4);
+        let Some(cells) = cells else {
+            return RedisError::InvalidType("String too long for LCS".to_string()).into();
+        };
+        let mut table = vec![0u32; cells];
+        for i in 1..=a.len() {
+            for j in 1..=b.len() {
+                table[i * width + j] = if a[i - 1] == b[j - 1] {
+                    table[(i - 1) * width + j - 1] + 1
+                } else {
+                    table[(i - 1) * width + j].max(table[i * width + j - 1])
+                };
             }
-            match entry.expires_at {
-                Some(exp) => {
-                    let now = SystemTime::now();
-                    let rem = exp.duration_since(now).unwrap_or_default().as_secs() as i64;
-                    Response::Integer(rem)
+        }
+        let len = table[cells - 1] as usize;
+        if opts.len {
+            return Response::Integer(len as i64);
+        }
+
+        // walk back from the end, collecting the subsequence and the runs
+        // that are contiguous in both strings, last run first like redis
+        let mut subsequence = vec![0u8; len];
+        let mut matches = Vec::new();
+        let mut run: Option<([usize; 2], [usize; 2])> = None;
+        let (mut i, mut j, mut k) = (a.len(), b.len(), len);
+        while i > 0 && j > 0 {
+            if a[i - 1] == b[j - 1] {
+                k -= 1;
+                subsequence[k] = a[i - 1];
+                match &mut run {
+                    Some((ra, rb)) if ra[0] == i && rb[0] == j => {
+                        ra[0] -= 1;
+                        rb[0] -= 1;
+                    }
+                    _ => matches.extend(run.replace(([i - 1, i - 1], [j - 1, j - 1]))),
                 }
-                None => Response::Integer(-1), // no TTL
+                i -= 1;
+                j -= 1;
+            } else if table[(i - 1) * width + j] > table[i * width + j - 1] {
+                i -= 1;
+            } else {
+                j -= 1;
             }
-        } else { 
-            Response::Integer(-2) 
         }
-    }
+        matches.extend(run);
+        if !opts.idx {
+            return Response::BulkBytes(subsequence);
+        }
 
-    pub fn keys_with_prefix(&self, prefix: &str) -> Response {
-        let mut map = self.inner.write().unwrap();
-        Self::sweep_locked(&mut map);
-        let keys: Vec<String> = map.keys()
-            .filter(|k| k.starts_with(prefix))
-            .cloned()
+        let range = |r: [usize; 2]| Response::Array(vec![Response::Integer(r[0] as i64), Response::Integer(r[1] as i64)]);
+        let matches = matches.into_iter()
+            .filter(|(ra, _)| ra[1] - ra[0] + 1 >= opts.min_match_len)
+            .map(|(ra, rb)| {
+                let mut item = vec![range(ra), range(rb)];
+                if opts.with_match_len {
+                    item.push(Response::Integer((ra[1] - ra[0] + 1) as i64));
+                }
+                Response::Array(item)
+            })
             .collect();
-        
-        if keys.is_empty() {
-            Response::Array(vec![])
-        } else {
-            Response::Array(keys.into_iter().map(|k| Response::BulkString(Some(k))).collect())
+        Response::Array(vec![
+            Response::BulkString(Some("matches".to_string())),
+            Response::Array(matches),
+            Response::BulkString(Some("len".to_string())),
+            Response::Integer(len as i64),
+        ])
+    }
+
+    // list ops
+    pub fn lpush<S: AsRef<str>>(&self, key: &str, values: impl AsRef<[S]>) -> Response {
+        match self.push_front(key, values) {
+            Ok((len, _)) => Response::Integer(len),
+            Err(e) => e,
         }
     }
 
-    pub fn incr(&self, key: &str) -> Response {
-        let mut map = self.inner.write().unwrap();
-        if let Some(entry) = map.get_mut(key) {
-            if entry.is_expired() {
-                map.remove(key);
-                let new = 1i64;
-                map.insert(key.to_string(), Entry::string(new.to_string(), None));
-                self.log_set(key.to_string(), new.to_string(), None);
-                return Response::Integer(new);
-            } else {
-                if let Some(string_val) = entry.value.as_string() {
-                    match string_val.parse::<i64>() {
-                        Ok(cur) => {
-                            let new = cur + 1;
-                            entry.value = RedisValue::String(new.to_string());
-                            self.log_set(key.to_string(), new.to_string(), entry.expires_at);
-                            return Response::Integer(new);
-                        }
-                        Err(_) => {
-                            return RedisError::NotInteger(string_val.clone()).into();
-                        }
-                    }
-                } else {
-                    return RedisError::InvalidType("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()).into();
-                }
-            }
-        } else {
-            let new = 1i64;
-            map.insert(key.to_string(), Entry::string(new.to_string(), None));
-            self.log_set(key.to_string(), new.to_string(), None);
-            Response::Integer(new)
+    /// like `lpush`, but also returns the new head, so a client can push and
+    /// peek in one atomic round trip
+    pub fn lpush_return<S: AsRef<str>>(&self, key: &str, values: impl AsRef<[S]>) -> Response {
+        match self.push_front(key, values) {
+            Ok((len, head)) => Response::Array(vec![Response::Integer(len), Response::BulkString(head)]),
+            Err(e) => e,
         }
     }
 
-    // list ops
-    pub fn lpush(&self, key: &str, values: Vec<String>) -> Response {
-        let mut map = self.inner.write().unwrap();
-        let entry = map.entry(key.to_string()).or_insert_with(|| Entry::list(None));
+    /// pushes `values` onto the head of the list at `key`, keeping their order,
+    /// and returns the new length and head
+    fn push_front<S: AsRef<str>>(&self, key: &str, values: impl AsRef<[S]>) -> Result<(i64, Option<String>), Response> {
+        let values = values.as_ref();
+        if let Some(err) = self.oversized(key, values.iter().map(S::as_ref)) {
+            return Err(err);
+        }
+        let mut map = self.inner.write(key);
+        Self::touch_locked(&map, key);
+        let entry = map.entry(key.to_string()).or_insert_with(|| self.counted(key, Entry::list(None)));
         
         if entry.is_expired() {
-            *entry = Entry::list(None);
+            let expired = std::mem::replace(entry, self.counted(key, Entry::list(None)));
+            self.inner.shrink(key, expired.usage(key));
+            self.log_del(key.to_string());
         }
         
-        if let Some(list) = entry.value.as_list_mut() {
-            for value in values.iter().rev() {
-                list.push_front(value.clone());
+        let expires_at = entry.expires_at;
+        let list = entry.require_list_mut()?;
+        if let Some(err) = self.overfull(list.len() + values.len()) {
+            // don't leave behind the empty list made for the push
+            if list.is_empty() {
+                self.take(&mut map, key);
             }
-            Response::Integer(list.len() as i64)
-        } else {
-            RedisError::InvalidType("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()).into()
+            return Err(err);
         }
+        for value in values.iter().rev() {
+            list.push_front(value.as_ref().to_string());
+        }
+        self.inner.grow(key, values.iter().map(|v| element_usage(v.as_ref().len())).sum());
+        let reply = (list.len() as i64, list.front().cloned());
+        entry.version = self.next_version();
+        self.log_values("lpush", key.to_string(), values.iter().map(|v| v.as_ref().to_string()), expires_at);
+        Ok(reply)
     }
 
     pub fn lpop(&self, key: &str) -> Response {
-        let mut map = self.inner.write().unwrap();
+        let mut map = self.inner.write(key);
+        Self::touch_locked(&map, key);
         if let Some(entry) = map.get_mut(key) {
             if entry.is_expired() {
-                map.remove(key);
+                self.remove_expired(&mut map, key);
                 return Response::Nil;
             }
-            if let Some(list) = entry.value.as_list_mut() {
-                if let Some(value) = list.pop_front() {
-                    if list.is_empty() {
-                        map.remove(key);
-                    }
-                    Response::BulkString(Some(value))
-                } else {
-                    Response::Nil
-                }
+            let expires_at = entry.expires_at;
+            let list = match entry.require_list_mut() {
+                Ok(list) => list,
+                Err(e) => return e.into(),
+            };
+            let Some(value) = list.pop_front() else { return Response::Nil };
+            self.inner.shrink(key, element_usage(value.len()));
+            let emptied = list.is_empty();
+            entry.version = self.next_version();
+            if emptied {
+                self.take(&mut map, key);
+                self.log_del(key.to_string());
             } else {
-                RedisError::InvalidType("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()).into()
+                self.log_values("lpop", key.to_string(), Vec::new(), expires_at);
             }
+            Response::BulkString(Some(value))
         } else {
             Response::Nil
         }
     }
 
     pub fn llen(&self, key: &str) -> Response {
-        let mut map = self.inner.write().unwrap();
-        if let Some(entry) = map.get(key) {
-            if entry.is_expired() {
-                map.remove(key);
-                return Response::Integer(0);
-            }
-            if let RedisValue::List(list) = &entry.value {
-                Response::Integer(list.len() as i64)
-            } else {
-                RedisError::InvalidType("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()).into()
-            }
-        } else {
-            Response::Integer(0)
+        let map = self.inner.read(key);
+        match self.live(&map, key).map(Entry::require_list) {
+            Some(Ok(list)) => Response::Integer(list.len() as i64),
+            Some(Err(e)) => e.into(),
+            None => Response::Integer(0),
         }
     }
 
     // set ops  
-    pub fn sadd(&self, key: &str, members: Vec<String>) -> Response {
-        let mut map = self.inner.write().unwrap();
-        let entry = map.entry(key.to_string()).or_insert_with(|| Entry::set(None));
+    /// adds `members`, copying only those the set doesn't have yet
+    pub fn sadd<S: AsRef<str>>(&self, key: &str, members: impl AsRef<[S]>) -> Response {
+        let members = members.as_ref();
+        if let Some(err) = self.oversized(key, members.iter().map(S::as_ref)) {
+            return err;
+        }
+        let mut map = self.inner.write(key);
+        Self::touch_locked(&map, key);
+        let entry = map.entry(key.to_string()).or_insert_with(|| self.counted(key, Entry::set(None)));
         
         if entry.is_expired() {
-            *entry = Entry::set(None);
+            let expired = std::mem::replace(entry, self.counted(key, Entry::set(None)));
+            self.inner.shrink(key, expired.usage(key));
+            self.log_del(key.to_string());
         }
         
-        if let Some(set) = entry.value.as_set_mut() {
-            let mut added = 0;
-            for member in members {
-                if set.insert(member) {
-                    added += 1;
-                }
+        let expires_at = entry.expires_at;
+        let set = match entry.require_set_mut() {
+            Ok(set) => set,
+            Err(e) => return e.into(),
+        };
+        let fresh: HashSet<&str> = members.iter().map(S::as_ref).filter(|m| !set.contains(m)).collect();
+        if let Some(err) = self.overfull(set.len() + fresh.len()) {
+            if set.is_empty() {
+                self.take(&mut map, key);
             }
-            Response::Integer(added)
-        } else {
-            RedisError::InvalidType("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()).into()
+            return err;
         }
+        let mut grown = 0;
+        let added: Vec<&str> = members.iter()
+            .map(S::as_ref)
+            .filter(|&m| set.insert_sized(m).inspect(|bytes| grown += bytes).is_some())
+            .collect();
+        self.inner.grow(key, grown);
+        let count = added.len() as i64;
+        if count > 0 {
+            entry.version = self.next_version();
+            self.log_values("sadd", key.to_string(), added.into_iter().map(str::to_string), expires_at);
+        }
+        Response::Integer(count)
     }
 
-    pub fn srem(&self, key: &str, members: Vec<String>) -> Response {
-        let mut map = self.inner.write().unwrap();
+    pub fn srem<S: AsRef<str>>(&self, key: &str, members: impl AsRef<[S]>) -> Response {
+        let mut map = self.inner.write(key);
+        Self::touch_locked(&map, key);
         if let Some(entry) = map.get_mut(key) {
             if entry.is_expired() {
-                map.remove(key);
+                self.remove_expired(&mut map, key);
                 return Response::Integer(0);
             }
-            if let Some(set) = entry.value.as_set_mut() {
-                let mut removed = 0;
-                for member in members {
-                    if set.remove(&member) {
-                        removed += 1;
-                    }
-                }
-                if set.is_empty() {
-                    map.remove(key);
-                }
-                Response::Integer(removed)
-            } else {
-                RedisError::InvalidType("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()).into()
+            let expires_at = entry.expires_at;
+            let set = match entry.require_set_mut() {
+                Ok(set) => set,
+                Err(e) => return e.into(),
+            };
+            let removed: Vec<&str> = members.as_ref().iter().map(S::as_ref).filter(|m| set.remove(m)).collect();
+            // removing never changes the encoding, so members cost what they did
+            self.inner.shrink(key, removed.iter().map(|m| set.member_usage(m)).sum());
+            let emptied = set.is_empty();
+            let count = removed.len() as i64;
+            if count > 0 {
+                entry.version = self.next_version();
             }
+            if emptied {
+                self.take(&mut map, key);
+                self.log_del(key.to_string());
+            } else if count > 0 {
+                self.log_values("srem", key.to_string(), removed.into_iter().map(str::to_string), expires_at);
+            }
+            Response::Integer(count)
         } else {
             Response::Integer(0)
         }
     }
 
     pub fn scard(&self, key: &str) -> Response {
-        let mut map = self.inner.write().unwrap();
-        if let Some(entry) = map.get(key) {
-            if entry.is_expired() {
-                map.remove(key);
-                return Response::Integer(0);
+        let map = self.inner.read(key);
+        match self.live(&map, key).map(Entry::require_set) {
+            Some(Ok(set)) => Response::Integer(set.len() as i64),
+            Some(Err(e)) => e.into(),
+            None => Response::Integer(0),
+        }
+    }
+
+    /// every member of the set at `key`, in no particular order unless
+    /// `sorted`, when they're sorted bytewise at read time
+    pub fn smembers(&self, key: &str, sorted: bool) -> Response {
+        let map = self.inner.read(key);
+        let mut members = match self.live(&map, key).map(Entry::require_set) {
+            Some(Ok(set)) => set.members(),
+            Some(Err(e)) => return e.into(),
+            None => Vec::new(),
+        };
+        if sorted {
+            members.sort_unstable();
+        }
+        Response::Array(members.into_iter().map(|m| Response::BulkString(Some(m))).collect())
+    }
+
+    /// atomically moves `member` from the set at `src` to the set at `dst`
+    pub fn smove(&self, src: &str, dst: &str, member: &str) -> Response {
+        if let Some(err) = self.oversized(dst, [member]) {
+            return err;
+        }
+        let mut map = self.inner.write_keys(&[src, dst]);
+        for key in [src, dst] {
+            if map.get(key).is_some_and(|e| e.is_expired()) {
+                self.remove_expired(map.shard_mut(key), key);
             }
-            if let RedisValue::Set(set) = &entry.value {
-                Response::Integer(set.len() as i64)
-            } else {
-                RedisError::InvalidType("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()).into()
+        }
+
+        // both keys have to be sets (or absent) before anything moves
+        for key in [src, dst] {
+            if let Some(Err(e)) = map.get_mut(key).map(Entry::require_set_mut) {
+                return e.into();
             }
+        }
+
+        if let Some(RedisValue::Set(set)) = map.get(dst).map(|e| &e.value) {
+            if src != dst && !set.contains(member) {
+                if let Some(err) = self.overfull(set.len() + 1) {
+                    return err;
+                }
+            }
+        }
+
+        let removed = map.get_mut(src)
+            .and_then(|e| e.value.as_set_mut())
+            .and_then(|set| set.remove(member).then(|| set.member_usage(member)));
+        let Some(removed) = removed else {
+            return Response::Integer(0);
+        };
+        self.inner.shrink(src, removed);
+
+        let grown = if src != dst {
+            let entry = map.shard_mut(dst).entry(dst.to_string()).or_insert_with(|| self.counted(dst, Entry::set(None)));
+            entry.value.as_set_mut().and_then(|set| set.insert_sized(member))
         } else {
-            Response::Integer(0)
+            // moving a member onto its own set is a no-op
+            map.get_mut(src).and_then(|e| e.value.as_set_mut()).and_then(|set| set.insert_sized(member))
+        };
+        self.inner.grow(dst, grown.unwrap_or(0));
+
+        let moved = if src == dst { vec![src] } else { vec![src, dst] };
+        for key in moved {
+            if let Some(entry) = map.get_mut(key) {
+                entry.version = self.next_version();
+            }
+        }
+        let src_entry = &map.shard(src)[src];
+        match &src_entry.value {
+            RedisValue::Set(set) if set.is_empty() => {
+                self.take(map.shard_mut(src), src);
+                self.log_del(src.to_string());
+            }
+            RedisValue::Set(set) => self.log_set_members(src.to_string(), set, src_entry.expires_at),
+            _ => {}
+        }
+        if src != dst {
+            let dst_entry = &map.shard(dst)[dst];
+            if let RedisValue::Set(set) = &dst_entry.value {
+                self.log_set_members(dst.to_string(), set, dst_entry.expires_at);
+            }
+        }
+        Response::Integer(1)
+    }
+
+    // hash ops
+    /// sets field/value pairs on the hash at `key`, returning how many fields were new
+    pub fn hset(&self, key: &str, pairs: Vec<(String, String)>) -> Response {
+        if let Some(err) = self.oversized(key, pairs.iter().flat_map(|(f, v)| [f.as_str(), v.as_str()])) {
+            return err;
+        }
+        let mut map = self.inner.write(key);
+        Self::touch_locked(&map, key);
+        let entry = map.entry(key.to_string()).or_insert_with(|| self.counted(key, Entry::hash(None)));
+
+        if entry.is_expired() {
+            let expired = std::mem::replace(entry, self.counted(key, Entry::hash(None)));
+            self.inner.shrink(key, expired.usage(key));
+            self.log_del(key.to_string());
+        }
+
+        let hash = match entry.require_hash_mut() {
+            Ok(hash) => hash,
+            Err(e) => return e.into(),
+        };
+        let fresh: HashSet<&str> = pairs.iter().map(|(f, _)| f.as_str()).filter(|f| !hash.contains_key(*f)).collect();
+        if let Some(err) = self.overfull(hash.len() + fresh.len()) {
+            if hash.is_empty() {
+                self.take(&mut map, key);
+            }
+            return err;
+        }
+        let mut added = 0;
+        let mut logged = Vec::with_capacity(pairs.len() * 2);
+        for (field, value) in pairs {
+            logged.push(field.clone());
+            logged.push(value.clone());
+            let (field_len, value_len) = (field.len(), value.len());
+            match hash.insert(field, value) {
+                Some(old) => self.resized(key, old.len(), value_len),
+                None => {
+                    self.inner.grow(key, element_usage(field_len + value_len));
+                    added += 1;
+                }
+            }
+        }
+        let expires_at = entry.expires_at;
+        entry.version = self.next_version();
+        self.log_values("hset", key.to_string(), logged, expires_at);
+        Response::Integer(added)
+    }
+
+    /// every field and value of the hash at `key` as a flat array, fields
+    /// in no particular order unless `sorted`, like `smembers`
+    pub fn hgetall(&self, key: &str, sorted: bool) -> Response {
+        let map = self.inner.read(key);
+        let mut pairs: Vec<(&String, &String)> = match self.live(&map, key).map(Entry::require_hash) {
+            Some(Ok(hash)) => hash.iter().collect(),
+            Some(Err(e)) => return e.into(),
+            None => Vec::new(),
+        };
+        if sorted {
+            pairs.sort_unstable();
+        }
+        Response::Array(pairs.into_iter()
+            .flat_map(|(f, v)| [f, v])
+            .map(|s| Response::BulkString(Some(s.clone())))
+            .collect())
+    }
+
+    /// returns random fields from a hash. a positive `count` yields distinct fields,
+    /// a negative one may repeat fields. without a count a single field is returned
+    pub fn hrandfield(&self, key: &str, count: Option<i64>, withvalues: bool) -> Response {
+        if count.is_some_and(|n| n < 0 && n.unsigned_abs() > MAX_RANDOM_COUNT) {
+            return RedisError::InvalidType("value is out of range".to_string()).into();
+        }
+        let map = self.inner.read(key);
+        let empty = || match count {
+            Some(_) => Response::Array(vec![]),
+            None => Response::Nil,
+        };
+        let hash = match self.live(&map, key).map(Entry::require_hash) {
+            Some(Ok(hash)) => hash,
+            Some(Err(e)) => return e.into(),
+            None => return empty(),
+        };
+
+        let fields: Vec<(&String, &String)> = hash.iter().collect();
+        let mut rng = rand::rng();
+        let picked: Vec<(&String, &String)> = match count {
+            None => match fields.choose(&mut rng) {
+                Some((field, _)) => return Response::BulkString(Some((*field).clone())),
+                None => return Response::Nil,
+            },
+            Some(n) if n >= 0 => {
+                let amount = (n as usize).min(fields.len());
+                rand::seq::index::sample(&mut rng, fields.len(), amount)
+                    .into_iter()
+                    .map(|i| fields[i])
+                    .collect()
+            }
+            Some(n) => (0..n.unsigned_abs())
+                .filter_map(|_| fields.choose(&mut rng).copied())
+                .collect(),
+        };
+
+        let mut out = Vec::with_capacity(picked.len() * if withvalues { 2 } else { 1 });
+        for (field, value) in picked {
+            out.push(Response::BulkString(Some(field.clone())));
+            if withvalues {
+                out.push(Response::BulkString(Some(value.clone())));
+            }
+        }
+        Response::Array(out)
+    }
+
+    /// removes every key, used by a replica before it loads the primary's snapshot
+    pub fn flush_all(&self) {
+        let mut map = self.inner.write_all();
+        map.clear();
+        self.inner.reindex_all(&map);
+        if let Some(aof) = &*self.aof.read() {
+            aof.log(LogEntry {
+                op: "flushall".into(),
+                key: String::new(),
+                value: None,
+                expires_at_ms: None,
+                values: None,
+            });
+        }
+    }
+
+    /// starts an AOF rewrite, returning the entries that rebuild the current
+    /// dataset: one per live key, after a flushall. the AOF starts keeping new
+    /// entries under the same lock, so every write is in one or the other
+    pub fn begin_aof_rewrite(&self) -> anyhow::Result<Vec<LogEntry>> {
+        let map = self.inner.read_all();
+        let aof = self.aof.read();
+        let Some(aof) = &*aof else {
+            anyhow::bail!("append only file is disabled");
+        };
+        aof.begin_rewrite()?;
+        Ok(rewrite_entries(&map))
+    }
+
+    /// the AOF writes are logged to, if persistence is on
+    pub fn aof(&self) -> Option<Aof> {
+        self.aof.read().clone()
+    }
+
+    /// starts logging to `aof` as it is, for an AOF that already holds the
+    /// dataset, as after loading the store from it
+    pub fn attach_aof(&self, aof: Aof) {
+        *self.aof.write() = Some(aof);
+    }
+
+    /// starts logging to `aof`, returning the entries that rebuild the current
+    /// dataset for `Aof::finish_rewrite`, as `begin_aof_rewrite` does
+    pub fn enable_aof(&self, aof: Aof) -> anyhow::Result<Vec<LogEntry>> {
+        let map = self.inner.read_all();
+        let mut current = self.aof.write();
+        if current.is_some() {
+            anyhow::bail!("append only file is already enabled");
+        }
+        aof.begin_rewrite()?;
+        *current = Some(aof);
+        Ok(rewrite_entries(&map))
+    }
+
+    /// stops logging, between two writes, returning the AOF that was in use
+    /// so the caller can flush it
+    pub fn disable_aof(&self) -> Option<Aof> {
+        let _map = self.inner.write_all();
+        self.aof.write().take()
+    }
+
+    /// the whole keyspace in the `snapshot` format, under `id`
+    pub fn serialize_snapshot(&self, id: u64) -> Vec<u8> {
+        snapshot::encode(id, self.inner.read_all().iter())
+    }
+
+    /// replaces the keyspace with a snapshot made by `serialize_snapshot`,
+    /// returning its id. nothing is logged to the AOF
+    pub fn load_snapshot(&self, bytes: &[u8]) -> anyhow::Result<u64> {
+        let (id, entries) = snapshot::decode(bytes)?;
+        let mut map = self.inner.write_all();
+        map.clear();
+        for (key, entry) in entries {
+            map.insert(key, self.stamped(entry));
+        }
+        self.inner.reindex_all(&map);
+        Ok(id)
+    }
+
+    /// DEBUG RELOAD's swap: write locks every shard from checking
+    /// `ready` until the dataset `load` builds in a fresh store has replaced
+    /// this one, so clients see one pause and no write falls in between.
+    /// None, with nothing changed, if `ready` says no. nothing is logged
+    pub fn reload(
+        &self,
+        ready: impl FnOnce() -> bool,
+        load: impl FnOnce() -> anyhow::Result<Store>,
+    ) -> Option<anyhow::Result<()>> {
+        let mut map = self.inner.write_all();
+        if !ready() {
+            return None;
+        }
+        Some(load().map(|fresh| {
+            map.clear();
+            for (key, entry) in fresh.inner.write_all().drain() {
+                map.insert(key, self.stamped(entry));
+            }
+            self.inner.reindex_all(&map);
+        }))
+    }
+
+    /// a copy of the live keyspace for snapshot `id`, to serialize without
+    /// holding the lock. with an AOF this also starts a rewrite and logs the
+    /// snapshot's marker under the same lock, so every write is either in the
+    /// copy or logged after the marker
+    pub fn begin_snapshot(&self, id: u64) -> anyhow::Result<HashMap<String, Entry>> {
+        let map = self.inner.read_all();
+        if let Some(aof) = &*self.aof.read() {
+            aof.begin_rewrite()?;
+            aof.log(LogEntry::snapshot_marker(id));
+        }
+        Ok(map.iter().filter(|(_, e)| !e.is_expired()).map(|(k, e)| (k.clone(), e.clone())).collect())
+    }
+
+    /// the commands that rebuild the current dataset from scratch, used for
+    /// replica full syncs. TTLs are rounded up to whole seconds
+    pub fn snapshot_commands(&self) -> Vec<Vec<String>> {
+        let map = self.inner.read_all();
+        let now = SystemTime::now();
+        let mut cmds = Vec::with_capacity(map.len());
+        for (key, entry) in map.iter().filter(|(_, e)| !e.is_expired()) {
+            let mut cmd = match &entry.value {
+                RedisValue::String(s) => vec!["SET".to_string(), key.clone(), s.to_string()],
+                // lpush keeps argument order, so the list can be sent front to back
+                RedisValue::List(list) => ["LPUSH".to_string(), key.clone()].into_iter().chain(list.iter().cloned()).collect(),
+                RedisValue::Set(set) => ["SADD".to_string(), key.clone()].into_iter().chain(set.members()).collect(),
+                RedisValue::Hash(hash) => ["HSET".to_string(), key.clone()].into_iter()
+                    .chain(hash.iter().flat_map(|(f, v)| [f.clone(), v.clone()]))
+                    .collect(),
+            };
+            // strings carry their TTL on the SET, anything else gets a PEXPIRE after it
+            let mut expire = None;
+            if let Some(exp) = entry.expires_at {
+                let left = exp.duration_since(now).unwrap_or_default();
+                if let RedisValue::String(_) = &entry.value {
+                    let secs = left.as_secs() + u64::from(left.subsec_nanos() > 0);
+                    cmd.extend(["EX".to_string(), secs.max(1).to_string()]);
+                } else {
+                    let ms = left.as_millis().max(1);
+                    expire = Some(vec!["PEXPIRE".to_string(), key.clone(), ms.to_string()]);
+                }
+            }
+            cmds.push(cmd);
+            cmds.extend(expire);
         }
+        cmds
     }
 
     fn log_set(&self, key: String, value: String, exp: Option<SystemTime>) {
-        if let Some(aof) = &self.aof {
+        if let Some(aof) = &*self.aof.read() {
             aof.log(LogEntry {
                 op: "set".into(),
                 key,
                 value: Some(value),
-                expires_at_ms: exp.map(|t| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64),
+                expires_at_ms: exp.map(unix_ms),
+                values: None,
             });
         }
     }
 
-    fn sweep_locked(map: &mut HashMap<String, Entry>) {
-        let keys_to_remove: Vec<String> = map.iter()
-            .filter_map(|(k, v)| if v.is_expired() { Some(k.clone()) } else { None })
-            .collect();
-        for k in keys_to_remove {
-            map.remove(&k);
+    fn log_del(&self, key: String) {
+        if let Some(aof) = &*self.aof.read() {
+            aof.log(LogEntry {
+                op: "del".into(),
+                key,
+                value: None,
+                expires_at_ms: None,
+                values: None,
+            });
+        }
+    }
+
+    fn log_expire(&self, key: String, at: SystemTime) {
+        if let Some(aof) = &*self.aof.read() {
+            aof.log(LogEntry {
+                op: "expire".into(),
+                key,
+                value: None,
+                expires_at_ms: Some(unix_ms(at)),
+                values: None,
+            });
+        }
+    }
+
+    /// logs a collection op that carries a list of values. `values` is only
+    /// collected if there's an AOF to log to
+    fn log_values(&self, op: &str, key: String, values: impl IntoIterator<Item = String>, exp: Option<SystemTime>) {
+        if let Some(aof) = &*self.aof.read() {
+            aof.log(LogEntry {
+                op: op.into(),
+                key,
+                value: None,
+                expires_at_ms: exp.map(unix_ms),
+                values: Some(values.into_iter().collect()),
+            });
         }
     }
 
+    /// logs the full membership of a set, replacing whatever replay had for `key`
+    fn log_set_members(&self, key: String, set: &SetValue, exp: Option<SystemTime>) {
+        if let Some(aof) = &*self.aof.read() {
+            aof.log(LogEntry {
+                op: "sset".into(),
+                key,
+                value: None,
+                expires_at_ms: exp.map(unix_ms),
+                values: Some(set.members()),
+            });
+        }
+    }
+
+    /// asks the sweeper to delete `key`, found expired without the write lock.
+    /// if the queue is full the next periodic sweep gets it instead
+    fn purge_later(&self, key: &str) {
+        let _ = self.purge_tx.try_send(key.to_string());
+    }
+
+    /// drops the keys in shard `i` whose deadline in the expiry index has
+    /// passed, `SWEEP_BATCH` at a time so writers get the shard in between.
+    /// returns how many went
+    fn sweep_shard(&self, i: usize) -> usize {
+        let now = SystemTime::now();
+        let mut removed = 0;
+        loop {
+            let mut map = self.inner.write_shard(i);
+            for _ in 0..SWEEP_BATCH {
+                let Some((at, key)) = self.inner.next_expired(i, now) else {
+                    // stale entries pile up when TTLs keep being moved, so
+                    // start over once they outnumber the keys
+                    if self.inner.indexed_expiries(i) > 2 * map.len() + SWEEP_BATCH {
+                        self.inner.reindex(i, &map);
+                    }
+                    return removed;
+                };
+                // deleted, or given another TTL, since it was indexed
+                if map.get(&key).is_some_and(|e| e.expires_at == Some(at)) {
+                    self.remove_expired(&mut map, &key);
+                    removed += 1;
+                }
+            }
+        }
+    }
+
+    /// adds `key`'s deadline, if it has one, to the expiry index
+    fn index_expiry(&self, key: &str, at: Option<SystemTime>) {
+        if let Some(at) = at {
+            self.inner.index_expiry(key, at);
+        }
+    }
+
+    /// sweeps every `period_secs`, and deletes the keys read paths queue with
+    /// `purge_later` as they come. only the first sweeper gets the queue
     pub async fn start_sweeper(self, period_secs: u64) {
         let mut interval = tokio::time::interval(std::time::Duration::from_secs(period_secs));
+        let mut purges = self.purge_rx.lock().take();
         loop {
-            interval.tick().await;
-            let mut map = self.inner.write().unwrap();
-            Self::sweep_locked(&mut map);
+            tokio::select! {
+                _ = interval.tick() => {
+                    // a shard at a time, so the rest of the keyspace stays writable
+                    let removed: usize = (0..self.inner.len()).map(|i| self.sweep_shard(i)).sum();
+                    if removed > 0 {
+                        tracing::debug!(removed, "swept expired keys");
+                    }
+                }
+                Some(key) = next_purge(&mut purges) => {
+                    let mut next = Some(key);
+                    while let Some(key) = next {
+                        let mut map = self.inner.write(&key);
+                        // it may have been set again since it was queued
+                        if map.get(&key).is_some_and(Entry::is_expired) {
+                            self.remove_expired(&mut map, &key);
+                        }
+                        drop(map);
+                        next = purges.as_mut().and_then(|rx| rx.try_recv().ok());
+                    }
+                }
+            }
         }
     }
 }
+
+/// the next key queued by `purge_later`, never for a sweeper without the queue
+async fn next_purge(purges: &mut Option<mpsc::Receiver<String>>) -> Option<String> {
+    match purges {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// a flushall, then one entry per live key
+fn rewrite_entries(map: &ReadLocked) -> Vec<LogEntry> {
+    let mut entries = vec![LogEntry {
+        op: "flushall".into(),
+        key: String::new(),
+        value: None,
+        expires_at_ms: None,
+        values: None,
+    }];
+    entries.extend(map.iter().filter(|(_, e)| !e.is_expired()).map(|(key, entry)| log_entry(key, entry)));
+    entries
+}
+
+/// the AOF entry that recreates `entry` at `key` on an empty key
+fn log_entry(key: &str, entry: &Entry) -> LogEntry {
+    let (op, value, values) = match &entry.value {
+        RedisValue::String(s) => ("set", Some(s.to_string()), None),
+        RedisValue::List(list) => ("lpush", None, Some(list.iter().cloned().collect())),
+        RedisValue::Set(set) => ("sset", None, Some(set.members())),
+        RedisValue::Hash(hash) => ("hset", None, Some(hash.iter().flat_map(|(f, v)| [f.clone(), v.clone()]).collect())),
+    };
+    LogEntry {
+        op: op.into(),
+        key: key.to_string(),
+        value,
+        expires_at_ms: entry.expires_at.map(unix_ms),
+        values,
+    }
+}
@@ -0,0 +1,59 @@
+use crate::config::ServerConfig;
+
+#[cfg(feature = "tls")]
+pub type Acceptor = tokio_rustls::TlsAcceptor;
+
+/// stand-in so the server compiles the same way without the `tls` feature;
+/// it has no values, so the TLS branch is statically unreachable
+#[cfg(not(feature = "tls"))]
+#[derive(Clone)]
+pub enum Acceptor {}
+
+/// builds a TLS acceptor when a certificate is configured
+#[cfg(feature = "tls")]
+pub fn acceptor(config: &ServerConfig) -> anyhow::Result<Option<Acceptor>> {
+    use std::sync::Arc;
+    use tokio_rustls::rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore,
+    };
+
+    let (cert_path, key_path) = match (&config.tls_cert_file, &config.tls_key_file) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) => return Ok(None),
+        _ => anyhow::bail!("TLS needs both a certificate and a private key"),
+    };
+
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow::anyhow!("reading TLS certificate {cert_path}: {e}"))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| anyhow::anyhow!("reading TLS key {key_path}: {e}"))?;
+
+    let builder = tokio_rustls::rustls::ServerConfig::builder();
+    let builder = match &config.tls_ca_cert_file {
+        // mutual TLS, clients must present a cert signed by this CA
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in CertificateDer::pem_file_iter(ca_path)
+                .map_err(|e| anyhow::anyhow!("reading TLS CA {ca_path}: {e}"))?
+            {
+                roots.add(ca.map_err(|e| anyhow::anyhow!("reading TLS CA {ca_path}: {e}"))?)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let tls_config = builder.with_single_cert(certs, key)?;
+    Ok(Some(Acceptor::from(Arc::new(tls_config))))
+}
+
+#[cfg(not(feature = "tls"))]
+pub fn acceptor(config: &ServerConfig) -> anyhow::Result<Option<Acceptor>> {
+    if config.tls_cert_file.is_some() || config.tls_key_file.is_some() {
+        anyhow::bail!("TLS is configured but kvstore was built without the `tls` feature");
+    }
+    Ok(None)
+}
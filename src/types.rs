@@ -1,16 +1,185 @@
 use std::collections::{HashMap, HashSet, VecDeque};
-use std::time::SystemTime;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use serde::{Deserialize, Serialize};
+use crate::error::{RedisError, RedisResult};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum RedisValue {
-    String(String),
+    /// shared, so reads hand out the value without copying it
+    String(Arc<str>),
     List(VecDeque<String>),
-    Set(HashSet<String>),
+    Set(SetValue),
     Hash(HashMap<String, String>),
 }
 
+/// a set with more members than this is a hashtable even if they are all
+/// integers, like redis' set-max-intset-entries
+pub const MAX_INTSET_ENTRIES: usize = 512;
+
+/// a set's members. sets of integers are kept sorted in a vec, like redis'
+/// intset, until a member that isn't one arrives
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SetValue {
+    IntSet(Vec<i64>),
+    Hashtable(HashSet<String>),
+}
+
+impl SetValue {
+    pub fn new() -> Self {
+        SetValue::IntSet(Vec::new())
+    }
+
+    /// OBJECT ENCODING's name for the representation
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            SetValue::IntSet(_) => "intset",
+            SetValue::Hashtable(_) => "hashtable",
+        }
+    }
+
+    /// adds `member`, converting to a hashtable if it doesn't fit an intset.
+    /// false if it was already there. a borrowed member is only copied if it's
+    /// added as a string
+    pub fn insert(&mut self, member: impl AsRef<str> + Into<String>) -> bool {
+        if let SetValue::IntSet(ints) = self {
+            match as_int(member.as_ref()).map(|n| (n, ints.binary_search(&n))) {
+                Some((_, Ok(_))) => return false,
+                Some((n, Err(at))) if ints.len() < MAX_INTSET_ENTRIES => {
+                    ints.insert(at, n);
+                    return true;
+                }
+                _ => {}
+            }
+            *self = SetValue::Hashtable(ints.iter().map(i64::to_string).collect());
+        }
+        match self {
+            SetValue::Hashtable(set) => !set.contains(member.as_ref()) && set.insert(member.into()),
+            SetValue::IntSet(_) => unreachable!("converted above"),
+        }
+    }
+
+    /// adds `member` like `insert`, returning how many bytes the set grew by,
+    /// None if it was already there
+    pub fn insert_sized(&mut self, member: impl AsRef<str> + Into<String>) -> Option<usize> {
+        // an intset's size is its length, and converting one only walks a bounded set
+        let before = matches!(self, SetValue::IntSet(_)).then(|| self.memory_usage(0));
+        let len = member.as_ref().len();
+        if !self.insert(member) {
+            return None;
+        }
+        Some(match before {
+            Some(before) => self.memory_usage(0) - before,
+            None => element_usage(len),
+        })
+    }
+
+    /// estimated bytes used by the members, see `RedisValue::memory_usage`
+    pub fn memory_usage(&self, samples: usize) -> usize {
+        match self {
+            SetValue::IntSet(ints) => ints.len() * std::mem::size_of::<i64>(),
+            SetValue::Hashtable(set) => sampled(set.iter(), samples, |m| element_usage(m.len())),
+        }
+    }
+
+    /// estimated bytes `member` takes up in the set as it's encoded now
+    pub fn member_usage(&self, member: &str) -> usize {
+        match self {
+            SetValue::IntSet(_) => std::mem::size_of::<i64>(),
+            SetValue::Hashtable(_) => element_usage(member.len()),
+        }
+    }
+
+    /// false if `member` wasn't there
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self {
+            SetValue::IntSet(ints) => match as_int(member).map(|n| ints.binary_search(&n)) {
+                Some(Ok(at)) => {
+                    ints.remove(at);
+                    true
+                }
+                _ => false,
+            },
+            SetValue::Hashtable(set) => set.remove(member),
+        }
+    }
+
+    pub fn contains(&self, member: &str) -> bool {
+        match self {
+            SetValue::IntSet(ints) => as_int(member).is_some_and(|n| ints.binary_search(&n).is_ok()),
+            SetValue::Hashtable(set) => set.contains(member),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            SetValue::IntSet(ints) => ints.len(),
+            SetValue::Hashtable(set) => set.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn members(&self) -> Vec<String> {
+        match self {
+            SetValue::IntSet(ints) => ints.iter().map(i64::to_string).collect(),
+            SetValue::Hashtable(set) => set.iter().cloned().collect(),
+        }
+    }
+}
+
+impl Default for SetValue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Extend<String> for SetValue {
+    fn extend<I: IntoIterator<Item = String>>(&mut self, members: I) {
+        for member in members {
+            self.insert(member);
+        }
+    }
+}
+
+/// `s` as an integer, if it reads back exactly the same, so "01" and "+1" stay strings
+fn as_int(s: &str) -> Option<i64> {
+    let n = s.parse::<i64>().ok()?;
+    // formatted on the stack, this runs for every member added
+    let mut buf = [0u8; 20];
+    let mut out = &mut buf[..];
+    write!(out, "{n}").ok()?;
+    let len = 20 - out.len();
+    (buf[..len] == *s.as_bytes()).then_some(n)
+}
+
 impl RedisValue {
+    /// estimated bytes used by the value. collections longer than `samples`
+    /// are estimated from their first `samples` elements, 0 counts them all
+    /// as maxmemory accounting does
+    pub fn memory_usage(&self, samples: usize) -> usize {
+        match self {
+            RedisValue::String(s) => s.len(),
+            RedisValue::List(list) => sampled(list.iter(), samples, |v| element_usage(v.len())),
+            RedisValue::Set(set) => set.memory_usage(samples),
+            RedisValue::Hash(hash) => sampled(hash.iter(), samples, |(f, v)| element_usage(f.len() + v.len())),
+        }
+    }
+
+    /// how many list elements, set members or hash fields the value has, 0 for a string
+    pub fn elements(&self) -> usize {
+        match self {
+            RedisValue::String(_) => 0,
+            RedisValue::List(list) => list.len(),
+            RedisValue::Set(set) => set.len(),
+            RedisValue::Hash(hash) => hash.len(),
+        }
+    }
+
     pub fn type_name(&self) -> &'static str {
         match self {
             RedisValue::String(_) => "string",
@@ -20,7 +189,7 @@ impl RedisValue {
         }
     }
 
-    pub fn as_string(&self) -> Option<&String> {
+    pub fn as_string(&self) -> Option<&Arc<str>> {
         match self {
             RedisValue::String(s) => Some(s),
             _ => None,
@@ -34,7 +203,7 @@ impl RedisValue {
         }
     }
 
-    pub fn as_set_mut(&mut self) -> Option<&mut HashSet<String>> {
+    pub fn as_set_mut(&mut self) -> Option<&mut SetValue> {
         match self {
             RedisValue::Set(set) => Some(set),
             _ => None,
@@ -56,6 +225,365 @@ impl RedisValue {
             RedisValue::Hash(hash) => hash.len(),
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// bitwise operator for BITOP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitOp {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+impl BitOp {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_uppercase().as_str() {
+            "AND" => Some(BitOp::And),
+            "OR" => Some(BitOp::Or),
+            "XOR" => Some(BitOp::Xor),
+            "NOT" => Some(BitOp::Not),
+            _ => None,
+        }
+    }
+}
+
+/// unit that BITPOS/BITCOUNT range offsets are expressed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeUnit {
+    Byte,
+    Bit,
+}
+
+/// optional start/end range for bit operations, negative offsets count from the end
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitRange {
+    pub start: i64,
+    pub end: Option<i64>,
+    pub unit: RangeUnit,
+}
+
+/// LCS's flags: LEN replies with just the length, IDX with the matching
+/// ranges, optionally filtered by MINMATCHLEN and sized with WITHMATCHLEN
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LcsOptions {
+    pub len: bool,
+    pub idx: bool,
+    pub min_match_len: usize,
+    pub with_match_len: bool,
+}
+
+/// EXPIRE's NX/XX/GT/LT flags. a key without a TTL counts as never
+/// expiring, so GT never applies to it and LT always does
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExpireCondition {
+    pub nx: bool,
+    pub xx: bool,
+    pub gt: bool,
+    pub lt: bool,
+}
+
+impl ExpireCondition {
+    /// sets the flag named `s`, returning false if it isn't one
+    pub fn add(&mut self, s: &str) -> bool {
+        match s.to_uppercase().as_str() {
+            "NX" => self.nx = true,
+            "XX" => self.xx = true,
+            "GT" => self.gt = true,
+            "LT" => self.lt = true,
+            _ => return false,
+        }
+        true
+    }
+
+    /// NX rules out every other flag, and GT and LT rule out each other
+    pub fn is_valid(&self) -> bool {
+        !(self.nx && (self.xx || self.gt || self.lt)) && !(self.gt && self.lt)
+    }
+
+    /// whether moving a key's expiry from `current` to `new` is allowed
+    pub fn allows(&self, current: Option<SystemTime>, new: SystemTime) -> bool {
+        match current {
+            None => !self.xx && !self.gt,
+            Some(current) => !self.nx && (!self.gt || new > current) && (!self.lt || new < current),
+        }
+    }
+}
+
+/// SET's NX/XX flags: NX only sets a key that doesn't exist, XX only one that does
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SetCondition {
+    pub nx: bool,
+    pub xx: bool,
+}
+
+impl SetCondition {
+    /// sets the flag named `s`, returning false if it isn't one
+    pub fn add(&mut self, s: &str) -> bool {
+        match s.to_uppercase().as_str() {
+            "NX" => self.nx = true,
+            "XX" => self.xx = true,
+            _ => return false,
+        }
+        true
+    }
+
+    /// whether a key that `exists` or not may be set
+    pub fn allows(&self, exists: bool) -> bool {
+        if exists { !self.nx } else { !self.xx }
+    }
+}
+
+/// SET's expiry options
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetExpiry {
+    /// EX: seconds from now
+    Ex(u64),
+    /// PX: milliseconds from now
+    Px(u64),
+    /// EXAT: a unix time in seconds
+    ExAt(u64),
+    /// PXAT: a unix time in milliseconds
+    PxAt(u64),
+}
+
+impl SetExpiry {
+    /// parses the option named `name` with its argument `arg`. None if `name`
+    /// isn't one, Some(Err) if `arg` isn't a number
+    pub fn parse(name: &str, arg: &str) -> Option<Result<Self, RedisError>> {
+        let make: fn(u64) -> Self = match name.to_uppercase().as_str() {
+            "EX" => SetExpiry::Ex,
+            "PX" => SetExpiry::Px,
+            "EXAT" => SetExpiry::ExAt,
+            "PXAT" => SetExpiry::PxAt,
+            _ => return None,
+        };
+        Some(arg.parse().map(make).map_err(|_| RedisError::InvalidType(format!("invalid {} ttl", name.to_uppercase()))))
+    }
+}
+
+/// `t` as unix milliseconds: negative before 1970, and saturating rather
+/// than wrapping for times past what an i64 holds
+pub(crate) fn unix_ms(t: SystemTime) -> i64 {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => i64::try_from(d.as_millis()).unwrap_or(i64::MAX),
+        Err(e) => i64::try_from(e.duration().as_millis()).map_or(i64::MIN, |ms| -ms),
+    }
+}
+
+/// the time `ms` unix milliseconds stand for, the inverse of `unix_ms`
+pub(crate) fn from_unix_ms(ms: i64) -> SystemTime {
+    if ms >= 0 {
+        UNIX_EPOCH + Duration::from_millis(ms as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_millis(ms.unsigned_abs())
+    }
+}
+
+/// counter value for new keys, so they aren't evicted before they get a chance
+pub const LFU_INIT_VAL: u8 = 5;
+/// higher means more hits are needed to grow the counter
+const LFU_LOG_FACTOR: f64 = 10.0;
+/// minutes without access for the counter to drop by one
+const LFU_DECAY_MINUTES: u64 = 1;
+
+/// when a key was last read or written, in milliseconds since the epoch,
+/// for LRU eviction. an atomic like `Lfu`, so reads can record it under
+/// the keyspace read lock
+#[derive(Debug)]
+pub struct Lru(AtomicU64);
+
+impl Lru {
+    pub fn new() -> Self {
+        Self(AtomicU64::new(now_ms()))
+    }
+
+    pub fn touch(&self) {
+        self.0.store(now_ms(), Ordering::Relaxed);
+    }
+
+    /// how long since the last access
+    pub fn idle(&self) -> Duration {
+        Duration::from_millis(now_ms().saturating_sub(self.0.load(Ordering::Relaxed)))
+    }
+}
+
+impl Clone for Lru {
+    fn clone(&self) -> Self {
+        Self(AtomicU64::new(self.0.load(Ordering::Relaxed)))
+    }
+}
+
+impl Default for Lru {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// redis-style logarithmic access frequency counter. it saturates at 255
+/// after about a million hits and decays while the key is left alone. the
+/// counter and the minute it last decayed share one atomic, so reads can
+/// record accesses under the keyspace read lock
+#[derive(Debug)]
+pub struct Lfu(AtomicU64);
+
+impl Lfu {
+    pub fn new() -> Self {
+        Self(AtomicU64::new(Self::pack(LFU_INIT_VAL, now_minutes())))
+    }
+
+    /// the counter in the low byte, minutes since the epoch above it
+    fn pack(counter: u8, decayed_at: u64) -> u64 {
+        decayed_at << 8 | u64::from(counter)
+    }
+
+    /// the counter with decay applied, what OBJECT FREQ reports
+    pub fn freq(&self) -> u8 {
+        let packed = self.0.load(Ordering::Relaxed);
+        let periods = now_minutes().saturating_sub(packed >> 8) / LFU_DECAY_MINUTES;
+        (packed as u8).saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+
+    /// records an access: decays, then increments with a probability that
+    /// shrinks as the counter grows. racing touches may count as one
+    pub fn touch(&self) {
+        let mut counter = self.freq();
+        if counter < u8::MAX {
+            let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+            if rand::random::<f64>() < 1.0 / (base * LFU_LOG_FACTOR + 1.0) {
+                counter += 1;
+            }
+        }
+        self.0.store(Self::pack(counter, now_minutes()), Ordering::Relaxed);
+    }
+}
+
+impl Clone for Lfu {
+    fn clone(&self) -> Self {
+        Self(AtomicU64::new(self.0.load(Ordering::Relaxed)))
+    }
+}
+
+impl Default for Lfu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn now_minutes() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 60
+}
+
+/// what to do when a write would go over maxmemory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaxMemoryPolicy {
+    /// reject writes with OOM
+    NoEviction,
+    /// evict the least frequently used key
+    AllKeysLfu,
+    /// evict the least frequently used key that has a TTL
+    VolatileLfu,
+    /// evict the least recently used of a sample of keys
+    AllKeysLru,
+    /// evict the least recently used of a sample of keys with a TTL
+    VolatileLru,
+}
+
+impl MaxMemoryPolicy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "noeviction" => Some(MaxMemoryPolicy::NoEviction),
+            "allkeys-lfu" => Some(MaxMemoryPolicy::AllKeysLfu),
+            "volatile-lfu" => Some(MaxMemoryPolicy::VolatileLfu),
+            "allkeys-lru" => Some(MaxMemoryPolicy::AllKeysLru),
+            "volatile-lru" => Some(MaxMemoryPolicy::VolatileLru),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MaxMemoryPolicy::NoEviction => "noeviction",
+            MaxMemoryPolicy::AllKeysLfu => "allkeys-lfu",
+            MaxMemoryPolicy::VolatileLfu => "volatile-lfu",
+            MaxMemoryPolicy::AllKeysLru => "allkeys-lru",
+            MaxMemoryPolicy::VolatileLru => "volatile-lru",
+        }
+    }
+}
+
+/// what KEYS does when more keys match than `keys-limit` allows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeysLimitPolicy {
+    /// refuse a KEYS over the whole keyspace, a longer prefix still answers in full
+    RequirePrefix,
+    /// return the first `keys-limit` keys found and log a warning
+    Truncate,
+}
+
+impl KeysLimitPolicy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "require-prefix" => Some(KeysLimitPolicy::RequirePrefix),
+            "truncate" => Some(KeysLimitPolicy::Truncate),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeysLimitPolicy::RequirePrefix => "require-prefix",
+            KeysLimitPolicy::Truncate => "truncate",
+        }
+    }
+}
+
+/// rough per-entry bookkeeping cost on top of the data itself
+const ENTRY_OVERHEAD: usize = 64;
+/// rough cost of each list element, set member or hash field
+const ELEMENT_OVERHEAD: usize = 16;
+
+/// estimated bytes used by a list element, set member or hash field and
+/// value of `len` bytes in all
+pub fn element_usage(len: usize) -> usize {
+    len + ELEMENT_OVERHEAD
+}
+
+/// set once an expired key has been counted toward `expired_keys`, so a key
+/// read several times before it's deleted counts once. an atomic like `Lru`,
+/// so reads can set it under the keyspace read lock
+#[derive(Debug, Default)]
+pub struct ExpiryCounted(AtomicBool);
+
+impl ExpiryCounted {
+    /// true the first time only
+    pub fn claim(&self) -> bool {
+        !self.0.swap(true, Ordering::Relaxed)
+    }
+}
+
+impl Clone for ExpiryCounted {
+    fn clone(&self) -> Self {
+        Self(AtomicBool::new(self.0.load(Ordering::Relaxed)))
+    }
+}
+
+/// sums `size` over `iter`, or over its first `samples` items scaled up to
+/// its length when it's longer and `samples` isn't 0
+fn sampled<I: ExactSizeIterator>(iter: I, samples: usize, size: impl Fn(I::Item) -> usize) -> usize {
+    let len = iter.len();
+    if samples == 0 || len <= samples {
+        return iter.map(size).sum();
+    }
+    iter.take(samples).map(size).sum::<usize>() * len / samples
 }
 
 /// entry wrapper w expiration support
@@ -63,15 +591,33 @@ impl RedisValue {
 pub struct Entry {
     pub value: RedisValue,
     pub expires_at: Option<SystemTime>,
+    /// access frequency, not persisted
+    #[serde(skip)]
+    pub lfu: Lfu,
+    /// last access, not persisted
+    #[serde(skip)]
+    pub lru: Lru,
+    /// bumped on every write from a store-wide counter, so it never repeats for
+    /// a key, even across a delete. rebuilt from the AOF order on replay
+    #[serde(skip)]
+    pub version: u64,
+    #[serde(skip)]
+    pub expiry_counted: ExpiryCounted,
 }
 
 impl Entry {
     pub fn new(value: RedisValue, expires_at: Option<SystemTime>) -> Self {
-        Self { value, expires_at }
+        Self { value, expires_at, lfu: Lfu::new(), lru: Lru::new(), version: 0, expiry_counted: ExpiryCounted::default() }
     }
 
-    pub fn string(value: String, expires_at: Option<SystemTime>) -> Self {
-        Self::new(RedisValue::String(value), expires_at)
+    /// records an access for LFU and LRU eviction
+    pub fn touch(&self) {
+        self.lfu.touch();
+        self.lru.touch();
+    }
+
+    pub fn string(value: impl Into<Arc<str>>, expires_at: Option<SystemTime>) -> Self {
+        Self::new(RedisValue::String(value.into()), expires_at)
     }
 
     pub fn list(expires_at: Option<SystemTime>) -> Self {
@@ -79,7 +625,7 @@ impl Entry {
     }
 
     pub fn set(expires_at: Option<SystemTime>) -> Self {
-        Self::new(RedisValue::Set(HashSet::new()), expires_at)
+        Self::new(RedisValue::Set(SetValue::new()), expires_at)
     }
 
     pub fn hash(expires_at: Option<SystemTime>) -> Self {
@@ -93,4 +639,74 @@ impl Entry {
             false
         }
     }
+
+    /// the value if it's a string, else WRONGTYPE naming what it is. strings
+    /// are replaced rather than edited in place, so there's no `_mut` one
+    pub fn require_string(&self) -> RedisResult<&Arc<str>> {
+        match &self.value {
+            RedisValue::String(s) => Ok(s),
+            other => Err(RedisError::WrongType(other.type_name())),
+        }
+    }
+
+    /// the value if it's a list, else WRONGTYPE naming what it is
+    pub fn require_list(&self) -> RedisResult<&VecDeque<String>> {
+        match &self.value {
+            RedisValue::List(list) => Ok(list),
+            other => Err(RedisError::WrongType(other.type_name())),
+        }
+    }
+
+    pub fn require_list_mut(&mut self) -> RedisResult<&mut VecDeque<String>> {
+        match &mut self.value {
+            RedisValue::List(list) => Ok(list),
+            other => Err(RedisError::WrongType(other.type_name())),
+        }
+    }
+
+    /// the value if it's a set, else WRONGTYPE naming what it is
+    pub fn require_set(&self) -> RedisResult<&SetValue> {
+        match &self.value {
+            RedisValue::Set(set) => Ok(set),
+            other => Err(RedisError::WrongType(other.type_name())),
+        }
+    }
+
+    pub fn require_set_mut(&mut self) -> RedisResult<&mut SetValue> {
+        match &mut self.value {
+            RedisValue::Set(set) => Ok(set),
+            other => Err(RedisError::WrongType(other.type_name())),
+        }
+    }
+
+    /// the value if it's a hash, else WRONGTYPE naming what it is
+    pub fn require_hash(&self) -> RedisResult<&HashMap<String, String>> {
+        match &self.value {
+            RedisValue::Hash(hash) => Ok(hash),
+            other => Err(RedisError::WrongType(other.type_name())),
+        }
+    }
+
+    pub fn require_hash_mut(&mut self) -> RedisResult<&mut HashMap<String, String>> {
+        match &mut self.value {
+            RedisValue::Hash(hash) => Ok(hash),
+            other => Err(RedisError::WrongType(other.type_name())),
+        }
+    }
+
+    /// estimated bytes used by the entry, sampling like `RedisValue::memory_usage`
+    pub fn memory_usage(&self, samples: usize) -> usize {
+        ENTRY_OVERHEAD + self.value.memory_usage(samples)
+    }
+
+    /// the part of `memory_usage(0)` that's bookkeeping rather than data
+    pub fn overhead(&self) -> usize {
+        ENTRY_OVERHEAD + self.value.elements() * ELEMENT_OVERHEAD
+    }
+
+    /// `memory_usage` of every element with the key stored at, what counts
+    /// toward maxmemory
+    pub fn usage(&self, key: &str) -> usize {
+        key.len() + self.memory_usage(0)
+    }
 } 
\ No newline at end of file
@@ -1,4 +1,4 @@
-use kvstore::{Store, Response, RedisValue};
+use kvstore::{Store, Response};
 use std::time::Duration;
 
 #[tokio::test]
@@ -9,10 +9,10 @@ async fn test_basic_string_operations() {
     assert!(matches!(result, Response::SimpleString(_)));
 
     let result = store.get("key1");
-    if let Response::BulkString(Some(value)) = result {
-        assert_eq!(value, "value1");
+    if let Response::BulkShared(value) = result {
+        assert_eq!(&*value, "value1");
     } else {
-        panic!("Expected BulkString with value");
+        panic!("Expected BulkShared with value");
     }
 
     let result = store.get("nonexistent");
@@ -29,18 +29,77 @@ async fn test_ttl_functionality() {
     // check TTL exists
     let result = store.ttl("temp_key");
     if let Response::Integer(ttl) = result {
-        assert!(ttl >= 0 && ttl <= 1);
+        assert!((0..=1).contains(&ttl));
     } else {
         panic!("Expected integer TTL");
     }
 
-    // wait for expiry
-    tokio::time::sleep(Duration::from_secs(2)).await;
-    
+    // expire it rather than waiting a second for it
+    assert!(store.expire_now("temp_key"));
+
     let result = store.get("temp_key");
     assert!(matches!(result, Response::Nil));
 }
 
+#[test]
+fn test_ttl_jitter() {
+    let store = Store::new(None);
+    store.set_ttl_jitter_pct(10);
+    for i in 0..1000 {
+        store.set(format!("key{i}"), "v".to_string(), Some(100));
+    }
+    let ttls: Vec<i64> = (0..1000)
+        .map(|i| match store.pttl(&format!("key{i}")) {
+            Response::Integer(ms) => ms,
+            other => panic!("Expected integer PTTL, got {other:?}"),
+        })
+        .collect();
+    let (min, max) = (*ttls.iter().min().unwrap(), *ttls.iter().max().unwrap());
+    // within ±10% of 100s, and spread across most of that window
+    assert!(min >= 89_000 && max <= 110_000, "{min}..{max}");
+    assert!(max - min > 10_000, "{min}..{max}");
+    assert!(matches!(store.ttl("key0"), Response::Integer(89..=110)));
+
+    store.set_ttl_jitter_pct(0);
+    store.set("exact".to_string(), "v".to_string(), Some(100));
+    assert!(matches!(store.pttl("exact"), Response::Integer(99_000..=100_000)));
+}
+
+#[test]
+fn test_set_get_flag() {
+    use kvstore::protocol::handle_command;
+    use kvstore::SetCondition;
+
+    let store = Store::new(None);
+    assert_eq!(handle_command(&store, "SET k v1 GET").to_string(), "(nil)");
+    assert_eq!(handle_command(&store, "SET k v2 GET").to_string(), "v1");
+    assert_eq!(handle_command(&store, "GET k").to_string(), "v2");
+
+    // NX only sets a missing key, but GET still reports what's there
+    assert_eq!(handle_command(&store, "SET k v3 NX GET").to_string(), "v2");
+    assert_eq!(handle_command(&store, "GET k").to_string(), "v2");
+    assert_eq!(handle_command(&store, "SET fresh v NX GET").to_string(), "(nil)");
+    assert_eq!(handle_command(&store, "GET fresh").to_string(), "v");
+    assert_eq!(handle_command(&store, "SET k v4 XX GET EX 100").to_string(), "v2");
+    assert!(matches!(store.ttl("k"), Response::Integer(99..=100)));
+    assert_eq!(handle_command(&store, "SET missing v XX GET").to_string(), "(nil)");
+    assert_eq!(handle_command(&store, "EXISTS missing").to_string(), "0");
+
+    // without GET, NX and XX reply OK or nil
+    assert_eq!(handle_command(&store, "SET k v5 NX").to_string(), "(nil)");
+    assert_eq!(handle_command(&store, "SET k v5 XX").to_string(), "OK");
+    assert!(handle_command(&store, "SET k v NX XX").to_string().contains("syntax error"));
+
+    // the old value must be a string, and the key is left alone if it isn't
+    store.lpush("list", vec!["a".to_string()]);
+    assert!(handle_command(&store, "SET list v GET").to_string().contains("WRONGTYPE"));
+    assert_eq!(handle_command(&store, "LLEN list").to_string(), "1");
+    assert!(matches!(
+        store.set_get("list".to_string(), "v".to_string(), None, SetCondition { nx: true, xx: false }),
+        Response::Error(_)
+    ));
+}
+
 #[tokio::test]
 async fn test_increment_operations() {
     let store = Store::new(None);
@@ -130,7 +189,7 @@ async fn test_key_expiration_cleanup() {
     let result = store.exists("key2");
     assert_eq!(result.to_string(), "1");
 
-    tokio::time::sleep(Duration::from_secs(2)).await;
+    store.expire_now("key1");
 
     let result = store.exists("key1");
     assert_eq!(result.to_string(), "0");
@@ -155,4 +214,4158 @@ fn test_protocol_parsing() {
     // test wrong argss
     let result = handle_command(&store, "GET");
     assert!(result.to_string().contains("wrong number of arguments"));
-} 
\ No newline at end of file
+} 
+#[tokio::test]
+async fn test_setrange_getrange() {
+    let store = Store::new(None);
+
+    store.set("greeting".to_string(), "Hello World".to_string(), None);
+    let result = store.setrange("greeting", 6, "Redis");
+    assert_eq!(result.to_string(), "11");
+    assert_eq!(store.get("greeting").to_string(), "Hello Redis");
+
+    // zero-pads up to the offset on a missing key
+    let result = store.setrange("padded", 3, "ab");
+    assert_eq!(result.to_string(), "5");
+    assert_eq!(store.get("padded").to_string(), "\0\0\0ab");
+
+    assert_eq!(store.getrange("greeting", 0, 4).to_string(), "Hello");
+    assert_eq!(store.getrange("greeting", -5, -1).to_string(), "Redis");
+    assert_eq!(store.getrange("greeting", 0, -1).to_string(), "Hello Redis");
+    assert_eq!(store.getrange("greeting", 5, 2).to_string(), "");
+    assert_eq!(store.getrange("missing", 0, -1).to_string(), "");
+
+    // writing into the middle of a multibyte character is refused and the
+    // value left alone
+    store.set("accent".to_string(), "café".to_string(), None);
+    assert!(store.setrange("accent", 4, "x").to_string().contains("not valid UTF-8"));
+    assert_eq!(store.get("accent").to_string(), "café");
+    assert_eq!(store.setrange("accent", 3, "e!").to_string(), "5");
+    assert_eq!(store.get("accent").to_string(), "cafe!");
+
+    store.lpush("list", vec!["a".to_string()]);
+    assert!(store.setrange("list", 0, "x").to_string().contains("WRONGTYPE"));
+    assert!(store.getrange("list", 0, 1).to_string().contains("WRONGTYPE"));
+}
+
+#[tokio::test]
+async fn test_bitop() {
+    use kvstore::BitOp;
+
+    let store = Store::new(None);
+    store.set("a".to_string(), "abc".to_string(), None);
+    store.set("b".to_string(), "a".to_string(), None);
+
+    // shorter operands are zero-extended
+    let result = store.bitop(BitOp::And, "and", &["a", "b"]);
+    assert_eq!(result.to_string(), "3");
+    assert_eq!(store.get("and").to_string(), "a\0\0");
+
+    let result = store.bitop(BitOp::Or, "or", &["a", "b"]);
+    assert_eq!(result.to_string(), "3");
+    assert_eq!(store.get("or").to_string(), "abc");
+
+    let result = store.bitop(BitOp::Xor, "xor", &["a", "b"]);
+    assert_eq!(result.to_string(), "3");
+    assert_eq!(store.get("xor").to_string(), "\0bc");
+
+    // "é" is 0xC3 0xA9, which inverts to "<V"
+    store.set("e".to_string(), "é".to_string(), None);
+    let result = store.bitop(BitOp::Not, "not", &["e"]);
+    assert_eq!(result.to_string(), "2");
+    assert_eq!(store.get("not").to_string(), "<V");
+
+    // NOT of ASCII sets the high bit of every byte, which isn't UTF-8, so
+    // it's refused and the destination kept
+    let result = store.bitop(BitOp::Not, "not", &["a"]);
+    assert!(result.to_string().contains("not valid UTF-8"));
+    assert_eq!(store.get("not").to_string(), "<V");
+
+    let result = store.bitop(BitOp::Not, "not", &["a", "b"]);
+    assert!(result.to_string().contains("ERR"));
+
+    // missing sources produce an empty result and clear the destination
+    let result = store.bitop(BitOp::Or, "or", &["missing"]);
+    assert_eq!(result.to_string(), "0");
+    assert!(matches!(store.get("or"), Response::Nil));
+}
+
+#[tokio::test]
+async fn test_client_registry() {
+    use kvstore::client::{handle_client_command, ClientRegistry};
+
+    let clients = ClientRegistry::new();
+    let (a, _) = clients.register("127.0.0.1:5001".parse().unwrap());
+    let (b, b_kill) = clients.register("127.0.0.1:5002".parse().unwrap());
+    assert_eq!(clients.len(), 2);
+
+    let result = handle_client_command(&clients, a, &["CLIENT", "ID"]);
+    assert_eq!(result.to_string(), a.to_string());
+
+    assert!(matches!(handle_client_command(&clients, a, &["CLIENT", "GETNAME"]), Response::BulkString(None)));
+    handle_client_command(&clients, a, &["CLIENT", "SETNAME", "worker-1"]);
+    assert_eq!(handle_client_command(&clients, a, &["CLIENT", "GETNAME"]).to_string(), "worker-1");
+
+    clients.touch(b, "GET");
+    let list = handle_client_command(&clients, a, &["CLIENT", "LIST"]).to_string();
+    assert!(list.contains(&format!("id={a} addr=127.0.0.1:5001 name=worker-1")));
+    assert!(list.contains(&format!("id={b} addr=127.0.0.1:5002 name= ")));
+    assert!(list.contains("cmd=get"));
+
+    let result = handle_client_command(&clients, a, &["CLIENT", "KILL", "ID", &b.to_string()]);
+    assert_eq!(result.to_string(), "1");
+    tokio::time::timeout(Duration::from_secs(1), b_kill.notified()).await.expect("kill signal");
+
+    let result = handle_client_command(&clients, a, &["CLIENT", "KILL", "ADDR", "10.0.0.1:1"]);
+    assert_eq!(result.to_string(), "0");
+
+    clients.unregister(b);
+    assert_eq!(clients.len(), 1);
+}
+
+#[tokio::test]
+async fn test_lcs() {
+    use kvstore::protocol::handle_command;
+    use kvstore::LcsOptions;
+
+    let store = Store::new(None);
+    store.set("a".to_string(), "ohmytext".to_string(), None);
+    store.set("b".to_string(), "mynewtext".to_string(), None);
+
+    assert_eq!(store.lcs("a", "b", LcsOptions::default()).to_string(), "mytext");
+    assert_eq!(handle_command(&store, "LCS a b LEN").to_string(), "6");
+    // ranges are [start, end] in each string, last match first
+    assert_eq!(handle_command(&store, "LCS a b IDX").to_string(), "matches 4 7 5 8 2 3 0 1 len 6");
+    assert_eq!(handle_command(&store, "LCS a b IDX MINMATCHLEN 4 WITHMATCHLEN").to_string(), "matches 4 7 5 8 4 len 6");
+    let both = LcsOptions { idx: true, with_match_len: true, ..LcsOptions::default() };
+    assert_eq!(store.lcs("a", "b", both).to_string(), "matches 4 7 5 8 4 2 3 0 1 2 len 6");
+
+    // missing keys are empty strings
+    assert_eq!(handle_command(&store, "LCS a missing").to_string(), "");
+    assert_eq!(handle_command(&store, "LCS missing b LEN").to_string(), "0");
+    assert_eq!(handle_command(&store, "LCS a missing IDX").to_string(), "matches (empty) len 0");
+
+    assert!(handle_command(&store, "LCS a b LEN IDX").to_string().contains("just use IDX"));
+    assert!(handle_command(&store, "LCS a b MINMATCHLEN x").to_string().contains("ERR"));
+    assert!(handle_command(&store, "LCS a b FOO").to_string().contains("syntax error"));
+    store.lpush("list", vec!["x".to_string()]);
+    assert!(handle_command(&store, "LCS a list").to_string().contains("WRONGTYPE"));
+}
+
+#[tokio::test]
+async fn test_bitpos() {
+    use kvstore::protocol::handle_command;
+    use kvstore::{BitRange, RangeUnit};
+
+    let store = Store::new(None);
+
+    // "\u{7f}" is 0x7f (first bit clear), "\0" is all clear
+    store.set("k".to_string(), "\0\u{7f}".to_string(), None);
+    assert_eq!(store.bitpos("k", true, None).to_string(), "9");
+    assert_eq!(store.bitpos("k", false, None).to_string(), "0");
+
+    let range = BitRange { start: 1, end: None, unit: RangeUnit::Byte };
+    assert_eq!(store.bitpos("k", false, Some(range)).to_string(), "8");
+
+    let range = BitRange { start: 10, end: Some(-1), unit: RangeUnit::Bit };
+    assert_eq!(store.bitpos("k", true, Some(range)).to_string(), "10");
+
+    let range = BitRange { start: -1, end: Some(-1), unit: RangeUnit::Byte };
+    assert_eq!(store.bitpos("k", true, Some(range)).to_string(), "9");
+
+    // looking for 0 in an all-ones string reports the bit just past the end,
+    // unless the caller pinned an explicit end
+    store.set("ones".to_string(), "\u{7f}".to_string(), None);
+    let range = BitRange { start: 1, end: None, unit: RangeUnit::Bit };
+    assert_eq!(store.bitpos("ones", false, Some(range)).to_string(), "8");
+    let range = BitRange { start: 1, end: Some(7), unit: RangeUnit::Bit };
+    assert_eq!(store.bitpos("ones", false, Some(range)).to_string(), "-1");
+
+    // missing keys behave as an empty string
+    assert_eq!(store.bitpos("missing", true, None).to_string(), "-1");
+    assert_eq!(store.bitpos("missing", false, None).to_string(), "0");
+
+    assert_eq!(handle_command(&store, "BITPOS k 1 0 -1 BIT").to_string(), "9");
+    assert_eq!(handle_command(&store, "BITPOS k 1 2").to_string(), "-1");
+    assert!(handle_command(&store, "BITPOS k 2").to_string().contains("ERR"));
+
+    store.lpush("list", vec!["a".to_string()]);
+    assert!(store.bitpos("list", true, None).to_string().contains("WRONGTYPE"));
+}
+
+fn temp_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("kvstore-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path.to_string_lossy().into_owned()
+}
+
+fn free_addr() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn test_aof_flush() {
+    use kvstore::aof::Aof;
+
+    let path = temp_path("flush.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    let store = Store::new(Some(aof.clone()));
+    store.set("a".to_string(), "1".to_string(), None);
+    store.set("b".to_string(), "2".to_string(), None);
+    aof.flush().await.unwrap();
+
+    let entries = Aof::replay(&path).unwrap().entries;
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[1].key, "b");
+}
+
+#[tokio::test]
+async fn test_expired_keys_stay_gone_after_replay() {
+    use kvstore::aof::Aof;
+
+    let path = temp_path("expired-dels.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    let store = Store::new(Some(aof.clone()));
+    tokio::spawn(store.clone().start_sweeper(3600));
+    for key in ["lazy", "swept"] {
+        store.set(key.to_string(), "v".to_string(), None);
+        store.pexpire(key, 20, Default::default());
+    }
+    store.lpush("list", vec!["x".to_string()]);
+    store.lpop("list");
+    store.sadd("set", vec!["m".to_string()]);
+    store.srem("set", vec!["m".to_string()]);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(matches!(store.get("lazy"), Response::Nil));
+    store.keys_with_prefix("");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    aof.flush().await.unwrap();
+
+    // replay with the clock wound back: every expiry is in the future again
+    let later = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as i64 + 3_600_000;
+    let mut entries = Aof::replay(&path).unwrap().entries;
+    for entry in &mut entries {
+        if entry.expires_at_ms.is_some() {
+            entry.expires_at_ms = Some(later);
+        }
+    }
+    let restarted = Store::new(None);
+    restarted.load_from_aof(entries.clone());
+    for key in ["lazy", "swept", "list", "set"] {
+        assert!(matches!(restarted.exists(key), Response::Integer(0)), "{key} came back");
+    }
+
+    // without the logged deletes the expired keys would have
+    let without_dels = Store::new(None);
+    without_dels.load_from_aof(entries.into_iter().filter(|e| e.op != "del"));
+    assert!(matches!(without_dels.exists("lazy"), Response::Integer(1)));
+}
+
+#[tokio::test]
+async fn test_aof_batches_queued_entries() {
+    use kvstore::aof::Aof;
+
+    let path = temp_path("batched.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    let store = Store::new(Some(aof.clone()));
+    // the writer can't run until this test yields, so everything queues up
+    for i in 0..5000 {
+        store.set(format!("k{i}"), i.to_string(), None);
+    }
+    assert_eq!(aof.stats().pending, 5000);
+    aof.flush().await.unwrap();
+    let stats = aof.stats();
+    assert_eq!((stats.pending, stats.pending_peak), (0, 5000));
+
+    let entries = Aof::replay(&path).unwrap().entries;
+    assert_eq!(entries.len(), 5000);
+    assert!(entries.iter().enumerate().all(|(i, e)| e.key == format!("k{i}")));
+}
+
+#[tokio::test]
+async fn test_durable_writes() {
+    use kvstore::aof::{segments, Aof, AppendFsync};
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    async fn send(conn: &mut BufReader<tokio::net::TcpStream>, cmd: &str) -> String {
+        conn.get_mut().write_all(format!("{cmd}\n").as_bytes()).await.unwrap();
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        line.trim_end().to_string()
+    }
+
+    // set_durable returns once the entry is fsynced, even with appendfsync no
+    let path = temp_path("durable.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    aof.set_fsync(AppendFsync::No);
+    let store = Store::new(Some(aof.clone()));
+    store.set("before".to_string(), "1".to_string(), None);
+    let resp = store.set_durable("idem:1".to_string(), "paid".to_string(), None).await;
+    assert!(matches!(resp, Response::SimpleString(_)));
+    let stats = aof.stats();
+    assert_eq!(stats.pending, 0);
+    assert!(stats.last_fsync > 0);
+    let keys: Vec<String> = Aof::replay(&path).unwrap().entries.into_iter().map(|e| e.key).collect();
+    assert_eq!(keys, ["before", "idem:1"]);
+    // the offset is what's fsynced, so it matches the segments once flushed
+    let on_disk = |path: &str| segments(path).iter().map(|f| std::fs::metadata(f).unwrap().len()).sum::<u64>();
+    let offset = aof.current_offset();
+    assert_eq!(offset, on_disk(&path));
+    let mut last = offset;
+    for i in 0..5 {
+        store.set(format!("k{i}"), "v".to_string(), None);
+        aof.flush().await.unwrap();
+        assert!(aof.current_offset() > last);
+        last = aof.current_offset();
+    }
+    assert!(matches!(Store::new(None).set_durable("k".to_string(), "v".to_string(), None).await, Response::SimpleString(_)));
+
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("waitaof.aof"),
+        ..ServerConfig::default()
+    };
+    let (addr, aof_path) = (config.addrs[0].clone(), config.aof_path.clone());
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+    let mut conn = BufReader::new(connect(&addr).await);
+
+    send(&mut conn, "SET idem:2 paid").await;
+    assert_eq!(send(&mut conn, "WAITAOF 1 1000").await, "1");
+    assert!(Aof::replay(&aof_path).unwrap().entries.iter().any(|e| e.key == "idem:2"));
+    assert_eq!(send(&mut conn, "WAITAOF 0 0").await, "0");
+    assert!(send(&mut conn, "WAITAOF 2 0").await.contains("0 or 1"));
+    assert!(send(&mut conn, "WAITAOF 1").await.starts_with("ERR"));
+
+    let offset: u64 = send(&mut conn, "AOFOFFSET").await.parse().unwrap();
+    assert_eq!(offset, on_disk(&aof_path));
+    send(&mut conn, "SET idem:3 paid").await;
+    let after: u64 = send(&mut conn, "AOFOFFSET").await.parse().unwrap();
+    assert!(after > offset);
+    assert_eq!(send(&mut conn, "AOFOFFSET").await, after.to_string());
+    assert!(send(&mut conn, "AOFOFFSET now").await.starts_with("ERR"));
+    shutdown.trigger();
+}
+
+#[test]
+fn test_store_for_each() {
+    use kvstore::protocol::handle_command;
+
+    let store = Store::new(None);
+    store.set("a".to_string(), "1".to_string(), None);
+    store.set("gone".to_string(), "1".to_string(), None);
+    store.lpush("l", vec!["x".to_string(), "y".to_string()]);
+    store.sadd("s", vec!["m".to_string()]);
+    store.expire_now("gone");
+
+    let mut count = 0;
+    let mut elements = 0;
+    store.for_each(|key, entry| {
+        assert_ne!(key, "gone");
+        count += 1;
+        if let kvstore::RedisValue::List(list) = &entry.value {
+            elements += list.len();
+        }
+    });
+    assert_eq!((count, elements), (3, 2));
+    assert_eq!(store.dbsize().to_string(), count.to_string());
+    assert_eq!(handle_command(&store, "DBSIZE").to_string(), "3");
+}
+
+#[tokio::test]
+async fn test_get_or_set() {
+    use kvstore::aof::Aof;
+    use kvstore::protocol::handle_command;
+    use std::sync::{Arc, Barrier};
+
+    let path = temp_path("getorset.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    let store = Store::new(Some(aof.clone()));
+    // two callers race on each key: both see the winner's value, and it is written once
+    for round in 0..50 {
+        let key = format!("k{round}");
+        let barrier = Arc::new(Barrier::new(2));
+        let racers: Vec<_> = ["first", "second"].into_iter()
+            .map(|default| {
+                let (store, barrier, key) = (store.clone(), barrier.clone(), key.clone());
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    store.get_or_set(&key, default.to_string(), None).to_string()
+                })
+            })
+            .collect();
+        let replies: Vec<String> = racers.into_iter().map(|r| r.join().unwrap()).collect();
+        assert_eq!(replies[0], replies[1]);
+    }
+    aof.flush().await.unwrap();
+    let entries = Aof::replay(&path).unwrap().entries;
+    assert_eq!(entries.len(), 50);
+    assert!(entries.iter().all(|e| e.op == "set"));
+
+    assert_eq!(handle_command(&store, "GETORSET k0 other").to_string(), store.get("k0").to_string());
+    assert_eq!(handle_command(&store, "GETORSET fresh v EX 100").to_string(), "v");
+    assert!(matches!(store.ttl("fresh"), Response::Integer(99..=100)));
+    store.lpush("list", vec!["x".to_string()]);
+    assert!(handle_command(&store, "GETORSET list v").to_string().contains("WRONGTYPE"));
+    assert!(handle_command(&store, "GETORSET k v PX 5").to_string().contains("Unsupported option"));
+    assert!(handle_command(&store, "GETORSET k").to_string().contains("wrong number"));
+}
+
+#[tokio::test]
+async fn test_aof_queue_limit() {
+    use kvstore::aof::{Aof, QueueFullPolicy};
+    use kvstore::protocol::handle_command;
+    use kvstore::ServerConfig;
+
+    let path = temp_path("queue-limit.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    aof.set_queue_limit(10, QueueFullPolicy::Error);
+    let store = Store::new(Some(aof.clone()));
+    // the writer can't run until this test yields, so the queue fills up
+    for i in 0..10 {
+        store.set(format!("k{i}"), "v".to_string(), None);
+    }
+    assert!(!aof.has_room());
+    assert!(handle_command(&store, "SET k v").to_string().starts_with("BUSY"));
+    assert_eq!(handle_command(&store, "GET k0").to_string(), "v");
+    aof.flush().await.unwrap();
+    assert_eq!(handle_command(&store, "SET k v").to_string(), "OK");
+
+    // under block, waiting for room lets the writer catch up
+    aof.set_queue_limit(5, QueueFullPolicy::Block);
+    for i in 0..5 {
+        store.set(format!("k{i}"), "w".to_string(), None);
+    }
+    assert!(!aof.has_room());
+    aof.wait_for_room().await;
+    assert!(aof.has_room());
+
+    let config = ServerConfig::load_from(["kvstore", "--aof-queue-capacity", "7", "--aof-queue-full-policy", "error"]).unwrap();
+    assert_eq!((config.aof_queue_capacity, config.aof_queue_full_policy), (7, QueueFullPolicy::Error));
+    assert!(ServerConfig::load_from(["kvstore", "--aof-queue-full-policy", "drop"]).is_err());
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_aof_write_failure() {
+    use kvstore::aof::Aof;
+    use kvstore::protocol::handle_command;
+
+    // the AOF's segment points at /dev/full, where every write fails with ENOSPC
+    let path = temp_path("failing.aof");
+    let segment = format!("{path}.1");
+    std::os::unix::fs::symlink("/dev/full", &segment).unwrap();
+    let aof = Aof::new(&path).await.unwrap();
+    let store = Store::new(Some(aof.clone()));
+    assert_eq!(handle_command(&store, "SET a 1").to_string(), "OK");
+    for _ in 0..100 {
+        if !aof.is_healthy() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(!aof.is_healthy());
+    assert!(!aof.stats().healthy);
+    assert!(handle_command(&store, "SET b 2").to_string().starts_with("MISCONF"));
+    assert_eq!(handle_command(&store, "GET a").to_string(), "1");
+
+    // once the file can be written again the writer reopens it and carries on
+    std::fs::remove_file(&segment).unwrap();
+    std::fs::write(&segment, "").unwrap();
+    for _ in 0..150 {
+        if aof.is_healthy() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(aof.is_healthy());
+    assert_eq!(handle_command(&store, "SET b 2").to_string(), "OK");
+    aof.flush().await.unwrap();
+    let keys: Vec<_> = Aof::replay(&path).unwrap().entries.into_iter().map(|e| e.key).collect();
+    assert_eq!(keys, ["a", "b"]);
+}
+
+async fn connect(addr: &str) -> tokio::net::TcpStream {
+    loop {
+        match tokio::net::TcpStream::connect(addr).await {
+            Ok(s) => break s,
+            Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_shutdown_command_flushes_aof() {
+    use kvstore::aof::Aof;
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("shutdown.aof"),
+        ..ServerConfig::default()
+    };
+    let (addr, path) = (config.addrs[0].clone(), config.aof_path.clone());
+    let server = tokio::spawn(serve(config, Shutdown::new()));
+
+    let mut stream = connect(&addr).await;
+    let (reader, mut writer) = stream.split();
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+
+    writer.write_all(b"SET durable yes\n").await.unwrap();
+    reader.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "OK\n");
+
+    writer.write_all(b"SHUTDOWN NOSAVE\n").await.unwrap();
+    line.clear();
+    assert_eq!(reader.read_line(&mut line).await.unwrap(), 0);
+
+    tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+    let entries = Aof::replay(&path).unwrap().entries;
+    assert!(entries.iter().any(|e| e.key == "durable"));
+}
+
+#[tokio::test]
+async fn test_shutdown_save_persists_before_closing() {
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("shutdown-save.aof"),
+        dbfilename: temp_path("shutdown-save.kvs"),
+        ..ServerConfig::default()
+    };
+    let (addr, path) = (config.addrs[0].clone(), config.dbfilename.clone());
+    let server = tokio::spawn(serve(config, Shutdown::new()));
+
+    let mut stream = connect(&addr).await;
+    stream.write_all(b"SET pending 1\nLPUSH l a\nSHUTDOWN SAVE\n").await.unwrap();
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await.unwrap();
+    assert_eq!(reply, "OK\n1\n");
+
+    // SAVE has already made the writes durable by the time the connection closes
+    let (_, entries) = kvstore::snapshot::decode(&std::fs::read(&path).unwrap()).unwrap();
+    assert!(entries.iter().any(|(key, _)| key == "pending"));
+
+    tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_idle_connections_are_closed() {
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    let mut config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("idle.aof"),
+        ..ServerConfig::default()
+    };
+    config.timeout = 1;
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+
+    let mut idle = connect(&addr).await;
+    let mut active = BufReader::new(connect(&addr).await);
+    let pinging = async {
+        // a command every half timeout keeps it open well past the timeout
+        for _ in 0..6 {
+            active.get_mut().write_all(b"PING\n").await.unwrap();
+            let mut line = String::new();
+            active.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "PONG\n");
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    };
+    let mut buf = [0u8; 16];
+    let (closed, ()) = tokio::join!(tokio::time::timeout(Duration::from_secs(3), idle.read(&mut buf)), pinging);
+    assert_eq!(closed.unwrap().unwrap(), 0, "idle socket should be closed by the server");
+    drop(active);
+
+    // the idle client is gone from the registry, only the observer remains
+    let mut observer = connect(&addr).await;
+    let (reader, mut writer) = observer.split();
+    let mut reader = BufReader::new(reader);
+    writer.write_all(b"CLIENT LIST\n").await.unwrap();
+    let mut line = String::new();
+    reader.read_line(&mut line).await.unwrap();
+    assert!(line.starts_with("id=3 "));
+    line.clear();
+    reader.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "\n");
+
+    // a connection inside MULTI is exempt
+    let mut queued = BufReader::new(connect(&addr).await);
+    for (cmd, expected) in [("MULTI", "OK\n"), ("SET m 1", "QUEUED\n")] {
+        queued.get_mut().write_all(format!("{cmd}\n").as_bytes()).await.unwrap();
+        line.clear();
+        queued.read_line(&mut line).await.unwrap();
+        assert_eq!(line, expected);
+    }
+    // the observer pings halfway so it stays open meanwhile
+    for _ in 0..2 {
+        tokio::time::sleep(Duration::from_millis(750)).await;
+        writer.write_all(b"PING\n").await.unwrap();
+        line.clear();
+        reader.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "PONG\n");
+    }
+    queued.get_mut().write_all(b"EXEC\nGET m\n").await.unwrap();
+    for expected in ["OK\n", "1\n"] {
+        line.clear();
+        queued.read_line(&mut line).await.unwrap();
+        assert_eq!(line, expected, "the MULTI connection should still be open");
+    }
+
+    // 0 turns the timeout off from the next request on
+    writer.write_all(b"CONFIG SET timeout 0\nCONFIG GET timeout\nCONFIG SET timeout soon\n").await.unwrap();
+    for expected in ["OK\n", "timeout 0\n", "ERR Invalid argument 'soon' for CONFIG SET 'timeout'\n"] {
+        line.clear();
+        reader.read_line(&mut line).await.unwrap();
+        assert_eq!(line, expected);
+    }
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    writer.write_all(b"PING\n").await.unwrap();
+    line.clear();
+    reader.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "PONG\n");
+
+    shutdown.trigger();
+}
+
+#[test]
+fn test_glob_matching() {
+    use kvstore::glob::matches;
+
+    assert!(matches("temp:*", "temp:1"));
+    assert!(matches("*", ""));
+    assert!(matches("h?llo", "hello"));
+    assert!(!matches("h?llo", "hllo"));
+    assert!(matches("h[ae]llo", "hallo"));
+    assert!(!matches("h[^e]llo", "hello"));
+    assert!(matches("h[a-c]llo", "hbllo"));
+    assert!(matches("a*b*c", "axxbyyc"));
+    assert!(!matches("a*b*c", "axxbyy"));
+    assert!(matches("x\\*", "x*"));
+    assert!(!matches("x\\*", "xy"));
+}
+
+#[test]
+fn test_delpattern() {
+    use kvstore::protocol::handle_command;
+
+    let store = Store::new(None);
+    for i in 0..5 {
+        store.set(format!("temp:{i}"), "v".to_string(), None);
+        store.set(format!("perm:{i}"), "v".to_string(), None);
+    }
+
+    let result = handle_command(&store, "DELPATTERN temp:*");
+    assert!(result.to_string().contains("CONFIRM"));
+    assert_eq!(store.exists("temp:0").to_string(), "1");
+
+    let result = handle_command(&store, "DELPATTERN temp:* CONFIRM");
+    assert_eq!(result.to_string(), "5");
+    for i in 0..5 {
+        assert_eq!(store.exists(&format!("temp:{i}")).to_string(), "0");
+        assert_eq!(store.exists(&format!("perm:{i}")).to_string(), "1");
+    }
+}
+
+#[tokio::test]
+async fn test_maxclients_rejects_extra_connections() {
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    let mut config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("maxclients.aof"),
+        ..ServerConfig::default()
+    };
+    config.maxclients = 1;
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+
+    let first = connect(&addr).await;
+    let (reader, mut writer) = first.into_split();
+    let mut reader = BufReader::new(reader);
+    writer.write_all(b"INFO clients\n").await.unwrap();
+    let mut info = String::new();
+    for _ in 0..3 {
+        reader.read_line(&mut info).await.unwrap();
+    }
+    assert!(info.contains("connected_clients:1"));
+    assert!(info.contains("maxclients:1"));
+
+    let mut rejected = connect(&addr).await;
+    let mut reply = String::new();
+    rejected.read_to_string(&mut reply).await.unwrap();
+    assert_eq!(reply, "-ERR max number of clients reached\r\n");
+
+    // the slot is released once the first client goes away
+    drop(writer);
+    drop(reader);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut next = connect(&addr).await;
+    next.write_all(b"PING\n").await.unwrap();
+    let mut buf = [0u8; 5];
+    next.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"PONG\n");
+
+    shutdown.trigger();
+}
+
+#[test]
+fn test_readonly_mode() {
+    use kvstore::protocol::handle_command;
+
+    let store = Store::new(None);
+    handle_command(&store, "SET k v");
+
+    store.set_readonly(true);
+    let result = handle_command(&store, "SET k other");
+    assert_eq!(result.to_string(), "READONLY You can't write against a read only server");
+    assert!(handle_command(&store, "lpush l a").to_string().starts_with("READONLY"));
+    assert_eq!(handle_command(&store, "GET k").to_string(), "v");
+    assert_eq!(handle_command(&store, "PING").to_string(), "PONG");
+
+    store.set_readonly(false);
+    assert_eq!(handle_command(&store, "SET k other").to_string(), "OK");
+}
+
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn test_tls_connections() {
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use std::sync::Arc;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio_rustls::rustls::{self, pki_types::{pem::PemObject, CertificateDer, ServerName}};
+
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let (cert_path, key_path) = (temp_path("tls.crt"), temp_path("tls.key"));
+    std::fs::write(&cert_path, generated.cert.pem()).unwrap();
+    std::fs::write(&key_path, generated.signing_key.serialize_pem()).unwrap();
+
+    let mut config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("tls.aof"),
+        ..ServerConfig::default()
+    };
+    config.tls_cert_file = Some(cert_path.clone());
+    config.tls_key_file = Some(key_path);
+    config.timeout = 1;
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+
+    // a plaintext client fails the handshake without taking the listener down
+    let mut plain = connect(&addr).await;
+    plain.write_all(b"PING\n").await.unwrap();
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(CertificateDer::from_pem_file(&cert_path).unwrap()).unwrap();
+    let client_config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+    let tcp = connect(&addr).await;
+    let stream = connector
+        .connect(ServerName::try_from("localhost").unwrap(), tcp)
+        .await
+        .unwrap();
+
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    writer.write_all(b"PING\n").await.unwrap();
+    let mut line = String::new();
+    reader.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "PONG\n");
+
+    // a client that never starts its handshake is dropped after the idle
+    // timeout instead of holding its maxclients slot
+    let mut stalled = connect(&addr).await;
+    let mut rest = Vec::new();
+    let closed = tokio::time::timeout(Duration::from_secs(5), stalled.read_to_end(&mut rest)).await;
+    assert!(matches!(closed, Ok(Ok(0))), "{closed:?}");
+
+    shutdown.trigger();
+}
+
+#[tokio::test]
+async fn test_smove() {
+    use kvstore::aof::Aof;
+
+    let path = temp_path("smove.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    let store = Store::new(Some(aof.clone()));
+
+    store.sadd("src", vec!["a".to_string(), "b".to_string()]);
+    assert_eq!(store.smove("src", "dst", "a").to_string(), "1");
+    assert_eq!(store.smove("src", "dst", "missing").to_string(), "0");
+    assert_eq!(store.scard("src").to_string(), "1");
+    assert_eq!(store.scard("dst").to_string(), "1");
+
+    // emptying the source deletes it
+    assert_eq!(store.smove("src", "dst", "b").to_string(), "1");
+    assert_eq!(store.exists("src").to_string(), "0");
+    assert_eq!(store.scard("dst").to_string(), "2");
+
+    store.set("str".to_string(), "v".to_string(), None);
+    assert!(store.smove("dst", "str", "a").to_string().contains("WRONGTYPE"));
+    assert!(store.smove("str", "dst", "a").to_string().contains("WRONGTYPE"));
+    assert_eq!(store.scard("dst").to_string(), "2");
+
+    aof.flush().await.unwrap();
+    let replayed = Store::new(None);
+    replayed.load_from_aof(Aof::replay(&path).unwrap().entries);
+    assert_eq!(replayed.exists("src").to_string(), "0");
+    assert_eq!(replayed.scard("dst").to_string(), "2");
+}
+
+#[tokio::test]
+async fn test_graceful_shutdown_keeps_writes_across_restart() {
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    let aof_path = temp_path("graceful.aof");
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: aof_path.clone(),
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    let server = tokio::spawn(serve(config, shutdown.clone()));
+
+    let mut idle = connect(&addr).await;
+    let mut writer = connect(&addr).await;
+    for i in 0..100 {
+        writer.write_all(format!("SET key:{i} value:{i}\n").as_bytes()).await.unwrap();
+    }
+    let mut reader = BufReader::new(&mut writer);
+    let mut line = String::new();
+    for _ in 0..100 {
+        line.clear();
+        reader.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "OK\n");
+    }
+
+    shutdown.trigger();
+    tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+    // open connections are closed as part of the drain
+    let mut buf = [0u8; 1];
+    assert_eq!(idle.read(&mut buf).await.unwrap(), 0);
+
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path,
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+
+    let mut stream = connect(&addr).await;
+    stream.write_all(b"GET key:99\n").await.unwrap();
+    let mut reader = BufReader::new(&mut stream);
+    line.clear();
+    reader.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "value:99\n");
+
+    shutdown.trigger();
+}
+
+#[tokio::test]
+async fn test_hrandfield() {
+    use std::collections::HashSet;
+
+    let store = Store::new(None);
+    let pairs = (0..5).map(|i| (format!("f{i}"), format!("v{i}"))).collect();
+    assert_eq!(store.hset("h", pairs).to_string(), "5");
+
+    let single = store.hrandfield("h", None, false).to_string();
+    assert!(single.starts_with('f'));
+
+    // positive counts give distinct fields, capped at the hash size
+    if let Response::Array(fields) = store.hrandfield("h", Some(10), false) {
+        let distinct: HashSet<String> = fields.iter().map(|f| f.to_string()).collect();
+        assert_eq!(fields.len(), 5);
+        assert_eq!(distinct.len(), 5);
+    } else {
+        panic!("Expected array");
+    }
+
+    // negative counts may repeat, and always return exactly |count| fields
+    if let Response::Array(fields) = store.hrandfield("h", Some(-20), false) {
+        assert_eq!(fields.len(), 20);
+        let distinct: HashSet<String> = fields.iter().map(|f| f.to_string()).collect();
+        assert!(distinct.len() <= 5);
+    } else {
+        panic!("Expected array");
+    }
+
+    if let Response::Array(flat) = store.hrandfield("h", Some(2), true) {
+        assert_eq!(flat.len(), 4);
+        assert_eq!(flat[0].to_string().replace('f', "v"), flat[1].to_string());
+    } else {
+        panic!("Expected array");
+    }
+
+    // a negative count the reply could never hold is refused rather than
+    // built, a positive one is already bounded by the hash
+    for huge in [i64::MIN, -(1 << 40)] {
+        assert_eq!(store.hrandfield("h", Some(huge), true).to_string(), "ERR value is out of range");
+    }
+    assert!(matches!(store.hrandfield("h", Some(i64::MAX), false), Response::Array(fields) if fields.len() == 5));
+
+    assert!(matches!(store.hrandfield("missing", None, false), Response::Nil));
+    assert!(matches!(store.hrandfield("missing", Some(3), false), Response::Array(ref a) if a.is_empty()));
+
+    store.set("str".to_string(), "v".to_string(), None);
+    assert!(store.hrandfield("str", None, false).to_string().contains("WRONGTYPE"));
+}
+
+#[test]
+fn test_inline_tokenizer() {
+    use kvstore::protocol::{handle_command, tokenize_inline};
+
+    assert_eq!(tokenize_inline("SET k v\r\n").unwrap(), vec!["SET", "k", "v"]);
+    assert_eq!(tokenize_inline("SET k \"hello world\"").unwrap(), vec!["SET", "k", "hello world"]);
+    assert_eq!(tokenize_inline(r#"SET k "say \"hi\"""#).unwrap(), vec!["SET", "k", "say \"hi\""]);
+    assert_eq!(tokenize_inline(r#"SET k "a\nb\x41""#).unwrap(), vec!["SET", "k", "a\nbA"]);
+    // escaped bytes are bytes, so an escaped UTF-8 sequence is one character
+    assert_eq!(tokenize_inline(r#"SET k "\xc3\xa9\xE2\x82\xAC""#).unwrap(), vec!["SET", "k", "é€"]);
+    assert_eq!(tokenize_inline(r"SET k 'it\'s here'").unwrap(), vec!["SET", "k", "it's here"]);
+    assert_eq!(tokenize_inline("SET k \"\"").unwrap(), vec!["SET", "k", ""]);
+    assert!(tokenize_inline("SET k \"open").is_err());
+    assert!(tokenize_inline("SET k \"a\"b").is_err());
+    assert!(tokenize_inline("   ").unwrap().is_empty());
+
+    let store = Store::new(None);
+    assert_eq!(handle_command(&store, "SET k \"hello   world\"").to_string(), "OK");
+    assert_eq!(store.get("k").to_string(), "hello   world");
+    assert!(handle_command(&store, "SET k \"oops").to_string().contains("Protocol error"));
+}
+
+#[tokio::test]
+async fn test_resp_and_inline_requests() {
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("resp.aof"),
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+
+    let mut stream = connect(&addr).await;
+    stream.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$11\r\nhello world\r\n").await.unwrap();
+    stream.write_all(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await.unwrap();
+    stream.write_all(b"*2\r\n$3\r\nGET\r\n$7\r\nmissing\r\n").await.unwrap();
+    // inline requests on the same connection get the plain text reply
+    stream.write_all(b"GET k\n").await.unwrap();
+    stream.write_all(b"*1\r\n$4\r\nQUIT\r\n").await.unwrap();
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await.unwrap();
+    assert_eq!(reply, "+OK\r\n$11\r\nhello world\r\n$-1\r\nhello world\n+OK\r\n");
+
+    // broken framing is reported in the protocol it came in and the connection closed
+    for (request, error) in [(&b"*1\r\n#oops\r\n"[..], "-ERR Protocol error"), (b"SET k \"open\n", "ERR Protocol error")] {
+        let mut stream = connect(&addr).await;
+        stream.write_all(request).await.unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await.unwrap();
+        assert!(reply.starts_with(error), "{reply:?}");
+        assert_eq!(reply.ends_with("\r\n"), error.starts_with('-'));
+    }
+
+    // a bulk length header alone doesn't get its size allocated, the
+    // connection just waits for the bytes
+    let mut stream = connect(&addr).await;
+    stream.write_all(b"*2\r\n$4\r\nECHO\r\n$536870912\r\nhi").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await.unwrap();
+    assert_eq!(reply, "");
+
+    // a request split across writes is put back together, wherever it's cut
+    let mut stream = connect(&addr).await;
+    for part in [&b"*3\r\n$3\r\nSET\r\n$5\r\nsp"[..], b"lit\r\n$4\r\nboth\r\n", b"GET sp", b"lit\n"] {
+        stream.write_all(part).await.unwrap();
+        stream.flush().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"+OK\r\nboth\n");
+
+    // hanging up mid-request ends the connection quietly and runs nothing
+    for partial in [&b"*3\r\n$3\r\nSET\r\n$3\r\ncut\r\n$1\r"[..], b"SET cut 1"] {
+        let mut stream = connect(&addr).await;
+        stream.write_all(partial).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await.unwrap();
+        assert_eq!(reply, "");
+    }
+    stream.write_all(b"EXISTS cut\n").await.unwrap();
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"0\n");
+
+    shutdown.trigger();
+}
+
+#[tokio::test]
+async fn test_replication() {
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    async fn send(conn: &mut BufReader<tokio::net::TcpStream>, cmd: &str) -> String {
+        conn.get_mut().write_all(format!("{cmd}\n").as_bytes()).await.unwrap();
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        line.trim_end().to_string()
+    }
+
+    // polls the replica until `cmd` returns `want`
+    async fn eventually(conn: &mut BufReader<tokio::net::TcpStream>, cmd: &str, want: &str) {
+        for _ in 0..100 {
+            if send(conn, cmd).await == want {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("replica never returned {want} for {cmd}");
+    }
+
+    let primary_config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("primary.aof"),
+        ..ServerConfig::default()
+    };
+    let primary_addr = primary_config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(primary_config, shutdown.clone()));
+
+    let mut primary = BufReader::new(connect(&primary_addr).await);
+    assert_eq!(send(&mut primary, "SET before snapshot").await, "OK");
+    assert_eq!(send(&mut primary, "SADD s a b").await, "2");
+    // nobody to acknowledge yet, so WAIT gives up after its timeout
+    assert_eq!(send(&mut primary, "WAIT 1 50").await, "0");
+
+    let mut replica_config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("replica.aof"),
+        ..ServerConfig::default()
+    };
+    replica_config.replicaof = Some(primary_addr.clone());
+    let replica_addr = replica_config.addrs[0].clone();
+    tokio::spawn(serve(replica_config, shutdown.clone()));
+
+    // the snapshot carries existing keys, the stream carries later writes
+    let mut replica = BufReader::new(connect(&replica_addr).await);
+    eventually(&mut replica, "GET before", "snapshot").await;
+    eventually(&mut replica, "SCARD s", "2").await;
+    assert_eq!(send(&mut primary, "SET after \"live write\"").await, "OK");
+    assert_eq!(send(&mut primary, "INCR n").await, "1");
+    eventually(&mut replica, "GET after", "live write").await;
+    eventually(&mut replica, "GET n", "1").await;
+    assert_eq!(send(&mut primary, "SET acked yes").await, "OK");
+    assert_eq!(send(&mut primary, "WAIT 1 0").await, "1");
+    // only one replica exists, so asking for two runs into the timeout
+    assert_eq!(send(&mut primary, "WAIT 2 100").await, "1");
+    assert_eq!(send(&mut replica, "GET acked").await, "yes");
+
+    // clients can't write to a replica
+    assert!(send(&mut replica, "SET x 1").await.starts_with("READONLY"));
+    replica.get_mut().write_all(b"INFO replication\n").await.unwrap();
+    // the inline reply is the section text followed by a blank line
+    let mut role = String::new();
+    while !role.ends_with("\n\n") {
+        replica.read_line(&mut role).await.unwrap();
+    }
+    assert!(role.contains("role:slave"));
+    assert!(role.contains(&format!("master_port:{}", primary_addr.rsplit(':').next().unwrap())));
+
+    // promoting keeps the data and accepts writes again
+    assert_eq!(send(&mut replica, "REPLICAOF NO ONE").await, "OK");
+    assert_eq!(send(&mut replica, "SET x 1").await, "OK");
+    assert_eq!(send(&mut replica, "GET after").await, "live write");
+    assert_eq!(send(&mut primary, "SET after changed").await, "OK");
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(send(&mut replica, "GET after").await, "live write");
+
+    shutdown.trigger();
+}
+
+#[test]
+fn test_key_and_value_size_limits() {
+    let store = Store::new(None);
+    store.set_max_key_len(8);
+    store.set_max_value_len(16);
+
+    let long_key = "k".repeat(9);
+    let big_value = "v".repeat(17);
+    assert!(store.set(long_key.clone(), "v".to_string(), None).to_string().contains("key is too long"));
+    assert!(store.set("k".to_string(), big_value.clone(), None).to_string().contains("value is too large"));
+    assert_eq!(store.exists(&long_key).to_string(), "0");
+    assert_eq!(store.exists("k").to_string(), "0");
+
+    assert!(store.lpush("l", vec!["a".to_string(), big_value.clone()]).to_string().contains("too large"));
+    assert!(store.sadd(&long_key, vec!["a".to_string()]).to_string().contains("too long"));
+    assert!(store.hset("h", vec![("f".to_string(), big_value)]).to_string().contains("too large"));
+    assert!(store.incr(&long_key).to_string().contains("too long"));
+    assert!(store.setrange("s", 10, "abcdefg").to_string().contains("maximum allowed size"));
+    assert_eq!(store.llen("l").to_string(), "0");
+
+    // values right at the limit are fine
+    assert_eq!(store.set("k".repeat(8), "v".repeat(16), None).to_string(), "OK");
+}
+
+#[test]
+fn test_collection_len_limit() {
+    let store = Store::new(None);
+    store.set_max_collection_len(3);
+    let strings = |items: &[&str]| items.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+    // a push that would pass the cap is rejected whole
+    assert_eq!(store.lpush("l", strings(&["a", "b"])).to_string(), "2");
+    assert!(store.lpush("l", strings(&["c", "d"])).to_string().contains("max-collection-len is 3"));
+    assert_eq!(store.llen("l").to_string(), "2");
+    assert_eq!(store.lpush("l", strings(&["c"])).to_string(), "3");
+
+    // nor is an empty collection left behind for a new key
+    assert!(store.lpush("new", strings(&["a", "b", "c", "d"])).to_string().contains("max-collection-len"));
+    assert_eq!(store.exists("new").to_string(), "0");
+
+    // members and fields already there don't count
+    assert_eq!(store.sadd("s", strings(&["1", "2", "3"])).to_string(), "3");
+    assert_eq!(store.sadd("s", strings(&["1", "2"])).to_string(), "0");
+    assert!(store.sadd("s", strings(&["3", "4"])).to_string().contains("max-collection-len"));
+    assert_eq!(store.scard("s").to_string(), "3");
+    assert_eq!(store.sadd("other", strings(&["4"])).to_string(), "1");
+    assert!(store.smove("other", "s", "4").to_string().contains("max-collection-len"));
+    assert_eq!(store.scard("other").to_string(), "1");
+
+    let pairs = |fields: &[&str]| fields.iter().map(|f| (f.to_string(), "v".to_string())).collect::<Vec<_>>();
+    assert_eq!(store.hset("h", pairs(&["a", "b", "c"])).to_string(), "3");
+    assert_eq!(store.hset("h", pairs(&["a"])).to_string(), "0");
+    assert!(store.hset("h", pairs(&["a", "d"])).to_string().contains("max-collection-len"));
+    assert!(matches!(store.hrandfield("h", Some(10), false), Response::Array(fields) if fields.len() == 3));
+
+    store.set_max_collection_len(0);
+    assert_eq!(store.lpush("l", strings(&["d", "e"])).to_string(), "5");
+}
+
+#[tokio::test]
+async fn test_save_updates_lastsave() {
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("lastsave.aof"),
+        dbfilename: temp_path("lastsave.kvs"),
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+
+    let mut conn = BufReader::new(connect(&addr).await);
+    let mut line = String::new();
+    conn.get_mut().write_all(b"LASTSAVE\n").await.unwrap();
+    conn.read_line(&mut line).await.unwrap();
+    let before: u64 = line.trim().parse().unwrap();
+
+    // LASTSAVE has second resolution
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    line.clear();
+    conn.get_mut().write_all(b"SAVE\nLASTSAVE\n").await.unwrap();
+    conn.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "OK\n");
+    line.clear();
+    conn.read_line(&mut line).await.unwrap();
+    let after: u64 = line.trim().parse().unwrap();
+    assert!(after > before);
+
+    line.clear();
+    conn.get_mut().write_all(b"INFO persistence\n").await.unwrap();
+    while !line.ends_with("\n\n") {
+        conn.read_line(&mut line).await.unwrap();
+    }
+    assert!(line.contains("aof_enabled:1"));
+    assert!(line.contains("aof_pending_entries:0"));
+    assert!(line.contains(&format!("rdb_last_save_time:{after}")));
+
+    shutdown.trigger();
+}
+
+#[test]
+fn test_slowlog() {
+    use kvstore::slowlog::{handle_slowlog_command, SlowLog};
+
+    let slowlog = SlowLog::new(1000, 2);
+    let args = |s: &str| s.split(' ').map(String::from).collect::<Vec<_>>();
+    let client = || ("127.0.0.1:5000".to_string(), "worker".to_string());
+
+    slowlog.record(Duration::from_micros(999), &args("GET fast"), client);
+    assert!(slowlog.is_empty());
+
+    slowlog.record(Duration::from_millis(5), &args("KEYS a"), client);
+    slowlog.record(Duration::from_millis(6), &args("KEYS b"), client);
+    slowlog.record(Duration::from_millis(7), &args("KEYS c"), client);
+    // bounded to slowlog-max-len, newest first
+    let entries = slowlog.get(None);
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].args, vec!["KEYS", "c"]);
+    assert_eq!(entries[0].duration_us, 7000);
+    assert_eq!(entries[0].client_name, "worker");
+    assert_eq!(entries[1].id + 1, entries[0].id);
+
+    // long argv and long arguments are truncated
+    slowlog.set_max_len(10);
+    let mut long = vec!["SADD".to_string(), "x".repeat(200)];
+    long.extend((0..40).map(|i| i.to_string()));
+    slowlog.record(Duration::from_millis(2), &long, client);
+    let logged = &slowlog.get(Some(1))[0].args;
+    assert_eq!(logged.len(), 32);
+    assert_eq!(logged[1], format!("{}... (72 more bytes)", "x".repeat(128)));
+    assert_eq!(logged[31], "... (11 more arguments)");
+
+    assert_eq!(handle_slowlog_command(&slowlog, &["SLOWLOG", "LEN"]).to_string(), "3");
+    match handle_slowlog_command(&slowlog, &["SLOWLOG", "GET", "1"]) {
+        Response::Array(items) => assert_eq!(items.len(), 1),
+        other => panic!("unexpected {other}"),
+    }
+    assert_eq!(handle_slowlog_command(&slowlog, &["SLOWLOG", "RESET"]).to_string(), "OK");
+    assert_eq!(handle_slowlog_command(&slowlog, &["SLOWLOG", "LEN"]).to_string(), "0");
+
+    // a negative threshold turns the slowlog off
+    slowlog.set_slower_than(-1);
+    slowlog.record(Duration::from_secs(1), &args("KEYS d"), client);
+    assert!(slowlog.is_empty());
+}
+
+#[test]
+fn test_replay_stats() {
+    use kvstore::aof::Aof;
+    use kvstore::ReplayStats;
+
+    let path = temp_path("replay-stats.aof");
+    let past = 1_000;
+    let future = i64::MAX / 2;
+    let lines = [
+        r#"{"op":"set","key":"a","value":"1","expires_at_ms":null}"#.to_string(),
+        r#"{"op":"set","key":"b","value":"2","expires_at_ms":null}"#.to_string(),
+        format!(r#"{{"op":"set","key":"b","value":"3","expires_at_ms":{past}}}"#),
+        format!(r#"{{"op":"set","key":"c","value":"4","expires_at_ms":{future}}}"#),
+        r#"{"op":"sset","key":"s","value":null,"expires_at_ms":null,"values":["x","y"]}"#.to_string(),
+        r#"{"op":"hset","key":"h","value":null,"expires_at_ms":null,"values":["f","v"]}"#.to_string(),
+        r#"{"op":"del","key":"a","value":null,"expires_at_ms":null}"#.to_string(),
+        r#"{"op":"rename","key":"c","value":"d","expires_at_ms":null}"#.to_string(),
+    ];
+    std::fs::write(&path, lines.join("\n")).unwrap();
+
+    let store = Store::new(None);
+    let stats = store.load_from_aof(Aof::replay(&path).unwrap().entries);
+    assert_eq!(stats, ReplayStats {
+        keys_loaded: 5,
+        deletes_applied: 1,
+        expired_skipped: 1,
+        unknown_ops: 1,
+    });
+    // the expired write removes the earlier value instead of resurrecting it
+    assert_eq!(store.exists("a").to_string(), "0");
+    assert_eq!(store.exists("b").to_string(), "0");
+    assert_eq!(store.get("c").to_string(), "4");
+    assert_eq!(store.scard("s").to_string(), "2");
+}
+
+#[tokio::test]
+async fn test_command_stats() {
+    use kvstore::protocol::{command_id, COMMANDS};
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::stats::{handle_latency_command, info_commandstats, CommandStats};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    // ids come from a binary search, so the registry has to stay sorted
+    assert!(COMMANDS.windows(2).all(|w| w[0].0 < w[1].0));
+    assert_eq!(command_id("GET").map(|id| COMMANDS[id].0), Some("GET"));
+    assert_eq!(command_id("NOPE"), None);
+
+    let stats = CommandStats::new();
+    stats.record("GET", Duration::from_micros(1));
+    stats.record("GET", Duration::from_micros(3));
+    stats.record("GET", Duration::from_micros(4));
+    stats.record("SET", Duration::from_secs(5));
+    stats.record("NOPE", Duration::from_micros(1));
+
+    let get = stats.get("get").unwrap();
+    assert_eq!((get.calls, get.usec), (3, 8));
+    assert_eq!(get.histogram, vec![(1, 1), (4, 3)]);
+    // anything past the last bucket lands in it
+    assert_eq!(stats.get("SET").unwrap().histogram, vec![(1 << 20, 1)]);
+    assert_eq!(stats.all().len(), 2);
+
+    let info = info_commandstats(&stats);
+    assert!(info.contains("cmdstat_get:calls=3,usec=8,usec_per_call=2.67\n"));
+
+    match handle_latency_command(&stats, &["LATENCY", "HISTOGRAM", "get", "ping"]) {
+        Response::Array(items) => {
+            assert_eq!(items.len(), 2);
+            assert_eq!(items[0].to_string(), "get");
+        }
+        other => panic!("unexpected {other}"),
+    }
+
+    stats.reset();
+    assert!(stats.all().is_empty());
+    assert_eq!(info_commandstats(&stats), "");
+
+    // calls shorter than a microsecond still add up
+    for _ in 0..3 {
+        stats.record("GET", Duration::from_nanos(400));
+    }
+    assert_eq!(stats.get("GET").unwrap().usec, 1);
+
+    // a blocking command is counted without a time or a histogram bucket
+    stats.count("BGET");
+    let bget = stats.get("BGET").unwrap();
+    assert_eq!((bget.calls, bget.usec, bget.histogram.len()), (1, 0, 0));
+
+    // the server times every command it dispatches
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("commandstats.aof"),
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+    let mut conn = BufReader::new(connect(&addr).await);
+    let mut line = String::new();
+    conn.get_mut().write_all(b"SET k v\n").await.unwrap();
+    conn.read_line(&mut line).await.unwrap();
+    for _ in 0..20 {
+        line.clear();
+        conn.get_mut().write_all(b"GET k\n").await.unwrap();
+        conn.read_line(&mut line).await.unwrap();
+    }
+    line.clear();
+    conn.get_mut().write_all(b"INFO commandstats\n").await.unwrap();
+    while !line.ends_with("\n\n") {
+        conn.read_line(&mut line).await.unwrap();
+    }
+    let get = line.lines().find_map(|l| l.strip_prefix("cmdstat_get:")).expect("no GET stats");
+    assert!(get.starts_with("calls=20,usec="), "{get}");
+    let usec: u64 = get["calls=20,usec=".len()..].split(',').next().unwrap().parse().unwrap();
+    assert!(usec > 0);
+    assert!(line.contains("cmdstat_set:calls=1,"));
+    shutdown.trigger();
+}
+
+#[tokio::test]
+async fn test_aof_rotation() {
+    use kvstore::aof::{segments, Aof};
+
+    let path = temp_path("rotate.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    aof.set_rotate_size(200);
+    let store = Store::new(Some(aof.clone()));
+    for i in 0..20 {
+        store.set(format!("key{i}"), format!("value{i}"), None);
+    }
+    store.set("key0".to_string(), "changed".to_string(), None);
+    store.del("key1");
+    aof.flush().await.unwrap();
+
+    let rotated = segments(&path);
+    assert!(rotated.len() >= 3, "expected several segments, got {rotated:?}");
+    let (live, closed) = rotated.split_last().unwrap();
+    for (n, segment) in closed.iter().enumerate() {
+        assert_eq!(*segment, format!("{path}.{}", n + 1));
+        assert!(std::fs::metadata(segment).unwrap().len() >= 200);
+    }
+    assert!(std::fs::metadata(live).unwrap().len() < 200);
+
+    // an explicit rotation starts a fresh segment, leaving the others as they were
+    aof.rotate().await.unwrap();
+    let after = segments(&path);
+    assert_eq!(after[..rotated.len()], rotated[..]);
+    assert_eq!(std::fs::metadata(after.last().unwrap()).unwrap().len(), 0);
+
+    let restored = Store::new(None);
+    restored.load_from_aof(Aof::replay(&path).unwrap().entries);
+    assert_eq!(restored.get("key0").to_string(), "changed");
+    assert_eq!(restored.exists("key1").to_string(), "0");
+    assert_eq!(restored.get("key19").to_string(), "value19");
+    match restored.keys_with_prefix("key") {
+        Response::Array(keys) => assert_eq!(keys.len(), 19),
+        other => panic!("unexpected {other}"),
+    }
+
+    for segment in segments(&path) {
+        let _ = std::fs::remove_file(segment);
+    }
+    let _ = std::fs::remove_file(kvstore::aof::manifest_path(&path));
+}
+
+#[tokio::test]
+async fn test_aof_manifest() {
+    use kvstore::aof::{manifest_path, segments, Aof};
+    use kvstore::ServerConfig;
+
+    let line = |value: &str| format!(r#"{{"op":"set","key":"a","value":"{value}","expires_at_ms":null}}"#) + "\n";
+    let values = |path: &str| -> Vec<String> {
+        Aof::replay(path).unwrap().entries.into_iter().filter_map(|e| e.value).collect()
+    };
+
+    // an AOF from before segments, with one rotated file, becomes segments 1 and 2
+    let path = temp_path("manifest.aof");
+    let manifest = manifest_path(&path);
+    std::fs::write(format!("{path}.1"), line("1")).unwrap();
+    std::fs::write(&path, line("2")).unwrap();
+    assert_eq!(values(&path), ["1", "2"]);
+    let aof = Aof::new(&path).await.unwrap();
+    assert!(!std::path::Path::new(&path).exists());
+    assert_eq!(segments(&path), [format!("{path}.1"), format!("{path}.2")]);
+    let name = std::path::Path::new(&path).file_name().unwrap().to_string_lossy().into_owned();
+    assert_eq!(
+        std::fs::read_to_string(&manifest).unwrap(),
+        format!("kvstore-aof-manifest 1\n{name}.1\n{name}.2\nend\n"),
+    );
+    aof.rotate().await.unwrap();
+    aof.log(kvstore::aof::LogEntry { op: "set".into(), key: "a".into(), value: Some("3".into()), expires_at_ms: None, values: None });
+    aof.flush().await.unwrap();
+    assert_eq!(values(&path), ["1", "2", "3"]);
+
+    // a manifest cut short, or missing, falls back to the segments on disk
+    let full = std::fs::read_to_string(&manifest).unwrap();
+    std::fs::write(&manifest, &full[..full.len() - "end\n".len()]).unwrap();
+    assert_eq!(values(&path), ["1", "2", "3"]);
+    std::fs::remove_file(&manifest).unwrap();
+    assert_eq!(values(&path), ["1", "2", "3"]);
+
+    // startup writes the manifest again, and drops segments it doesn't list
+    drop(Aof::new(&path).await.unwrap());
+    assert_eq!(std::fs::read_to_string(&manifest).unwrap(), full);
+    std::fs::write(format!("{path}.9"), line("stale")).unwrap();
+    drop(Aof::new(&path).await.unwrap());
+    assert!(!std::path::Path::new(&format!("{path}.9")).exists());
+    assert_eq!(values(&path), ["1", "2", "3"]);
+
+    let config = ServerConfig::load_from(["kvstore", "--aof-path", "/data/kv.aof", "--aof-dir", "/backup/aof"]).unwrap();
+    assert_eq!(config.aof_base(), "/backup/aof/kv.aof");
+    assert_eq!(ServerConfig::default().aof_base(), "kvstore.aof");
+}
+
+#[test]
+fn test_token_bucket() {
+    use kvstore::ratelimit::TokenBucket;
+    use std::time::Instant;
+
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(10, 3, start);
+    assert!(bucket.try_take(start));
+    assert!(bucket.try_take(start));
+    assert!(bucket.try_take(start));
+    assert!(!bucket.try_take(start));
+    // refills at 10 a second
+    assert!(bucket.try_take(start + Duration::from_millis(100)));
+    assert!(!bucket.try_take(start + Duration::from_millis(150)));
+
+    // reserve always takes a token and says how long it was owed
+    let mut bucket = TokenBucket::new(10, 1, start);
+    assert_eq!(bucket.reserve(start), Duration::ZERO);
+    assert_eq!(bucket.reserve(start), Duration::from_millis(100));
+    assert_eq!(bucket.reserve(start), Duration::from_millis(200));
+}
+
+#[tokio::test]
+async fn test_rate_limit_reject() {
+    use kvstore::ratelimit::RateLimitMode;
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let mut config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("ratelimit.aof"),
+        ..ServerConfig::default()
+    };
+    config.ratelimit_cps = 1;
+    config.ratelimit_burst = 2;
+    config.ratelimit_mode = RateLimitMode::Reject;
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+
+    let mut conn = BufReader::new(connect(&addr).await);
+    conn.get_mut().write_all(b"PING\nPING\nPING\n").await.unwrap();
+    let mut replies = String::new();
+    for _ in 0..3 {
+        conn.read_line(&mut replies).await.unwrap();
+    }
+    assert_eq!(replies, "PONG\nPONG\nBUSY rate limit exceeded\n");
+
+    // limits are per connection, so another client is unaffected
+    let mut other = BufReader::new(connect(&addr).await);
+    other.get_mut().write_all(b"INFO stats\n").await.unwrap();
+    let mut info = String::new();
+    while !info.ends_with("\n\n") {
+        other.read_line(&mut info).await.unwrap();
+    }
+    assert!(info.contains("throttled_commands:1"));
+
+    shutdown.trigger();
+}
+
+#[test]
+fn test_lpush_return() {
+    use kvstore::protocol::handle_command;
+
+    let store = Store::new(None);
+    match store.lpush_return("l", vec!["a".to_string(), "b".to_string()]) {
+        Response::Array(items) => {
+            assert_eq!(items[0].to_string(), "2");
+            assert_eq!(items[1].to_string(), "a");
+        }
+        other => panic!("unexpected {other}"),
+    }
+    // plain LPUSH still just returns the length
+    assert_eq!(store.lpush("l", vec!["c".to_string()]).to_string(), "3");
+
+    match handle_command(&store, "LPUSHRET l d") {
+        Response::Array(items) => {
+            assert_eq!(items[0].to_string(), "4");
+            assert_eq!(items[1].to_string(), "d");
+        }
+        other => panic!("unexpected {other}"),
+    }
+    assert!(handle_command(&store, "LPUSHRET l").to_string().contains("wrong number of arguments"));
+
+    store.set("s".to_string(), "v".to_string(), None);
+    assert!(store.lpush_return("s", vec!["x".to_string()]).to_string().contains("WRONGTYPE"));
+}
+
+#[test]
+fn test_randomkey() {
+    use kvstore::protocol::handle_command;
+    use std::collections::HashSet;
+
+    let store = Store::new(None);
+    let run = |cmd: &str| handle_command(&store, cmd).to_string();
+    let keys = |cmd: &str| -> Vec<String> {
+        match handle_command(&store, cmd) {
+            Response::Array(items) => items.iter().map(|k| k.to_string()).collect(),
+            other => panic!("{cmd}: {other}"),
+        }
+    };
+    assert_eq!(run("RANDOMKEY"), "(nil)");
+    assert_eq!(run("RANDOMKEY COUNT 5"), "(empty)");
+
+    for i in 0..5 {
+        run(&format!("LPUSH list:{i} a"));
+    }
+    for i in 0..20 {
+        run(&format!("SET string:{i} v"));
+    }
+    run("SADD set:0 m");
+    run("SET gone v");
+    store.pexpire("gone", 1, Default::default());
+    std::thread::sleep(Duration::from_millis(10));
+
+    // distinct keys of the type, never more than there are
+    let lists = keys("RANDOMKEY COUNT 10 TYPE list");
+    assert_eq!(lists.iter().collect::<HashSet<_>>().len(), 5);
+    assert!(lists.iter().all(|k| k.starts_with("list:")));
+    let strings = keys("RANDOMKEY TYPE STRING COUNT 3");
+    assert_eq!(strings.iter().collect::<HashSet<_>>().len(), 3);
+    assert!(strings.iter().all(|k| k.starts_with("string:")));
+    assert_eq!(keys("RANDOMKEY COUNT 100").len(), 26);
+    assert!(keys("RANDOMKEY COUNT 0").is_empty());
+    assert!(keys("RANDOMKEY COUNT 3 TYPE hash").is_empty());
+    assert_eq!(run("RANDOMKEY TYPE set"), "set:0");
+    assert_eq!(run("RANDOMKEY TYPE hash"), "(nil)");
+
+    // every match can come up
+    let seen: HashSet<String> = (0..500).map(|_| run("RANDOMKEY TYPE list")).collect();
+    assert_eq!(seen.len(), 5);
+
+    assert!(run("RANDOMKEY COUNT -1").contains("out of range"));
+    assert!(run("RANDOMKEY COUNT x").contains("not an integer"));
+    assert!(run("RANDOMKEY COUNT").contains("syntax error"));
+    assert!(run("RANDOMKEY LIMIT 3").contains("syntax error"));
+}
+
+#[test]
+fn test_sorted_members() {
+    use kvstore::protocol::handle_command;
+
+    let strings = |resp: Response| match resp {
+        Response::Array(items) => items.iter().map(|i| i.to_string()).collect::<Vec<_>>(),
+        other => panic!("unexpected {other}"),
+    };
+    // each store hashes with its own seed, so only sorted replies agree
+    let replies: Vec<_> = (0..3).map(|_| {
+        let store = Store::new(None);
+        for i in (0..50).rev() {
+            handle_command(&store, &format!("SADD s m{i}"));
+            handle_command(&store, &format!("HSET h f{i} v{i}"));
+        }
+        handle_command(&store, "SADD ints 10 9 -1");
+        let sorted = |cmd: &str| strings(handle_command(&store, cmd));
+        (sorted("SMEMBERS s SORTED"), sorted("HGETALL h sorted"), sorted("SMEMBERS ints SORTED"))
+    }).collect();
+    let (members, pairs, ints) = &replies[0];
+    assert!(replies.iter().all(|r| r == &replies[0]));
+    let mut expected: Vec<String> = (0..50).map(|i| format!("m{i}")).collect();
+    expected.sort();
+    assert_eq!(members, &expected);
+    assert_eq!(&pairs[..4], ["f0", "v0", "f1", "v1"]);
+    assert_eq!(pairs.len(), 100);
+    // bytewise, even for an intset
+    assert_eq!(ints, &["-1", "10", "9"]);
+
+    let store = Store::new(None);
+    handle_command(&store, "SADD s b a");
+    handle_command(&store, "SET str v");
+    let mut unsorted = strings(handle_command(&store, "SMEMBERS s"));
+    unsorted.sort();
+    assert_eq!(unsorted, ["a", "b"]);
+    assert!(strings(handle_command(&store, "HGETALL missing")).is_empty());
+    assert!(handle_command(&store, "SMEMBERS str").to_string().starts_with("WRONGTYPE"));
+    assert!(handle_command(&store, "SMEMBERS s REVERSED").to_string().starts_with("ERR"));
+    assert!(handle_command(&store, "HGETALL").to_string().starts_with("ERR"));
+}
+
+#[test]
+fn test_intset_encoding() {
+    use kvstore::protocol::handle_command;
+
+    let store = Store::new(None);
+    let run = |cmd: &str| handle_command(&store, cmd).to_string();
+    assert_eq!(run("SADD nums 3 1 2 -7 2"), "4");
+    assert_eq!(run("OBJECT ENCODING nums"), "intset");
+    assert_eq!(run("SCARD nums"), "4");
+    assert_eq!(run("SREM nums 1 5 x"), "1");
+    assert_eq!(run("OBJECT ENCODING nums"), "intset");
+    // not read back the same, so not an integer
+    assert_eq!(run("SADD padded 01"), "1");
+    assert_eq!(run("OBJECT ENCODING padded"), "hashtable");
+
+    // a string member converts the set, keeping what was there
+    assert_eq!(run("SADD nums hello"), "1");
+    assert_eq!(run("OBJECT ENCODING nums"), "hashtable");
+    assert_eq!(run("SADD nums 3 -7 2"), "0");
+    assert_eq!(run("SREM nums 3 hello"), "2");
+    assert_eq!(run("SCARD nums"), "2");
+    assert_eq!(run("OBJECT ENCODING nums"), "hashtable");
+
+    // too many members for an intset
+    for i in 0..kvstore::types::MAX_INTSET_ENTRIES {
+        run(&format!("SADD big {i}"));
+    }
+    assert_eq!(run("OBJECT ENCODING big"), "intset");
+    run("SADD big 100000");
+    assert_eq!(run("OBJECT ENCODING big"), "hashtable");
+
+    assert_eq!(run("SET s v"), "OK");
+    assert_eq!(run("OBJECT ENCODING s"), "raw");
+    assert_eq!(run("OBJECT ENCODING missing"), "(nil)");
+}
+
+#[test]
+fn test_lfu_eviction() {
+    use kvstore::{protocol::handle_command, MaxMemoryPolicy};
+
+    let store = Store::new(None);
+    for i in 0..10 {
+        store.set(format!("hot{i}"), "v".to_string(), None);
+        for _ in 0..100 {
+            store.get(&format!("hot{i}"));
+        }
+    }
+    for i in 0..50 {
+        store.set(format!("cold{i}"), "v".to_string(), None);
+    }
+    let freq = |key: &str| handle_command(&store, &format!("OBJECT FREQ {key}")).to_string().parse::<i64>().unwrap();
+    assert!(freq("hot0") > freq("cold0"));
+    assert_eq!(handle_command(&store, "OBJECT FREQ missing").to_string(), "(nil)");
+
+    // no eviction by default, writes fail once over the limit
+    store.set_maxmemory(store.used_memory());
+    handle_command(&store, "SET extra v");
+    assert!(handle_command(&store, "SET extra2 v").to_string().starts_with("OOM"));
+    assert_eq!(store.evicted_keys(), 0);
+    // reads still work
+    assert_eq!(handle_command(&store, "GET hot0").to_string(), "v");
+    handle_command(&store, "DEL extra");
+
+    store.set_maxmemory_policy(MaxMemoryPolicy::AllKeysLfu);
+    for i in 0..100 {
+        assert_eq!(handle_command(&store, &format!("SET new{i} v")).to_string(), "OK");
+    }
+    assert!(store.evicted_keys() >= 50);
+    for i in 0..10 {
+        assert_eq!(store.exists(&format!("hot{i}")).to_string(), "1", "hot{i} was evicted");
+    }
+    match handle_command(&store, "KEYS *") {
+        Response::Array(keys) => assert!(keys.len() <= 61),
+        other => panic!("unexpected {other}"),
+    }
+
+    // volatile-lfu keeps the keys without a TTL and the hot ones with one
+    let store = Store::new(None);
+    for i in 0..10 {
+        store.set(format!("hot{i}"), "v".to_string(), Some(100));
+        for _ in 0..100 {
+            store.get(&format!("hot{i}"));
+        }
+        store.set(format!("keep{i}"), "v".to_string(), None);
+    }
+    for i in 0..30 {
+        store.set(format!("cold{i}"), "v".to_string(), Some(100));
+    }
+    store.set_maxmemory(store.used_memory());
+    store.set_maxmemory_policy(MaxMemoryPolicy::VolatileLfu);
+    for i in 0..20 {
+        assert_eq!(handle_command(&store, &format!("SET new{i} v")).to_string(), "OK");
+    }
+    // keys differ in length, so it's about one eviction per write
+    assert!((15..=30).contains(&store.evicted_keys()), "{} evicted", store.evicted_keys());
+    for i in 0..10 {
+        assert_eq!(store.exists(&format!("hot{i}")).to_string(), "1", "hot{i} was evicted");
+        assert_eq!(store.exists(&format!("keep{i}")).to_string(), "1");
+    }
+}
+
+#[test]
+fn test_memory_accounting() {
+    use kvstore::protocol::handle_command;
+
+    let store = Store::new(None);
+    let counted = || {
+        let mut used = 0;
+        store.for_each(|key, entry| used += entry.usage(key));
+        used
+    };
+    for cmd in [
+        "SET s hello", "SET s a-longer-value", "INCR n", "INCR n", "SETRANGE s 30 tail", "GETORSET g v",
+        "LPUSH l a b c", "LPOP l", "LPUSH gone x", "LPOP gone", "HSET h f 1 g 2", "HSET h f a-longer-one",
+        "SADD ints 1 2 3", "SREM ints 2", "SMOVE ints other 3", "SADD words x y", "SREM words x y",
+        "BITOP OR dest s n", "BITOP AND dest missing", "EXPIRE g -1", "DEL n",
+    ] {
+        handle_command(&store, cmd);
+        assert_eq!(store.used_memory(), counted(), "after {cmd}");
+    }
+    // an intset growing past its limit turns into a hashtable
+    let many: Vec<String> = (0..600).map(|i| i.to_string()).collect();
+    store.sadd("ints", &many);
+    assert_eq!(store.used_memory(), counted());
+
+    // MEMORY USAGE and STATS report the same estimate maxmemory counts
+    let usage = |cmd: &str| handle_command(&store, cmd).to_string().parse::<usize>().unwrap();
+    assert_eq!(store.memory_usage("s"), Some(usage("MEMORY USAGE s")));
+    assert_eq!(store.memory_usage("ints"), Some(usage("MEMORY USAGE ints SAMPLES 0")));
+    assert_eq!(store.memory_usage("missing"), None);
+    assert_eq!(handle_command(&store, "MEMORY USAGE missing").to_string(), "(nil)");
+    // the members differ in length, so a sample's estimate is only close
+    let sampled = usage("MEMORY USAGE ints SAMPLES 10") as f64;
+    assert!((sampled / store.memory_usage("ints").unwrap() as f64 - 1.0).abs() < 0.2, "{sampled}");
+    assert!(handle_command(&store, "MEMORY USAGE s SAMPLES many").to_string().starts_with("ERR"));
+    assert!(handle_command(&store, "MEMORY USAGE s COUNT 5").to_string().starts_with("ERR"));
+    let stats = store.memory_stats();
+    assert_eq!(stats.total, store.used_memory());
+    assert!(stats.overhead > 0 && stats.overhead < stats.total);
+    match handle_command(&store, "MEMORY STATS") {
+        Response::Array(fields) => {
+            assert_eq!(fields[0].to_string(), "total.estimated");
+            assert_eq!(fields[1].to_string(), store.used_memory().to_string());
+        }
+        other => panic!("unexpected {other}"),
+    }
+    assert!(handle_command(&store, "MEMORY DOCTOR").to_string().starts_with("ERR"));
+    handle_command(&store, "DELPATTERN * CONFIRM");
+    assert_eq!(store.used_memory(), 0);
+}
+
+#[test]
+fn test_lru_eviction() {
+    use kvstore::{protocol::handle_command, MaxMemoryPolicy};
+
+    // one shard, so a sample of 5 sees every key and the oldest always goes
+    let store = Store::with_shards(None, 1);
+    for key in ["a", "b", "c", "d"] {
+        store.set(key.to_string(), "v".to_string(), None);
+    }
+    std::thread::sleep(Duration::from_millis(20));
+    for key in ["b", "c", "d"] {
+        store.get(key);
+    }
+    store.set_maxmemory(store.used_memory());
+    store.set_maxmemory_policy(MaxMemoryPolicy::AllKeysLru);
+    assert_eq!(handle_command(&store, "SET e v").to_string(), "OK");
+    assert_eq!(handle_command(&store, "SET f v").to_string(), "OK");
+    assert_eq!(store.evicted_keys(), 1);
+    assert_eq!(store.exists("a").to_string(), "0");
+    for key in ["b", "c", "d", "e", "f"] {
+        assert_eq!(store.exists(key).to_string(), "1", "{key} was evicted");
+    }
+
+    // volatile-lru only takes keys with a TTL, and fails writes once there are none
+    let store = Store::new(None);
+    for i in 0..20 {
+        store.set(format!("keep{i}"), "v".to_string(), None);
+        store.set(format!("temp{i}"), "v".to_string(), Some(100));
+    }
+    store.set_maxmemory(store.used_memory());
+    store.set_maxmemory_policy(MaxMemoryPolicy::VolatileLru);
+    let mut oom = false;
+    for i in 0..30 {
+        let reply = handle_command(&store, &format!("SET new{i} v")).to_string();
+        if reply.starts_with("OOM") {
+            oom = true;
+            break;
+        }
+        assert_eq!(reply, "OK");
+    }
+    assert!(oom, "writes should fail once no key with a TTL is left");
+    assert_eq!(store.evicted_keys(), 20);
+    for i in 0..20 {
+        assert_eq!(store.exists(&format!("keep{i}")).to_string(), "1");
+        assert_eq!(store.exists(&format!("temp{i}")).to_string(), "0");
+    }
+
+    // the one key still with a TTL is found even when nearly every entry in
+    // the expiry index is stale, from keys that were set again without one
+    let store = Store::with_shards(None, 1);
+    for i in 0..500 {
+        store.set(format!("k{i}"), "v".to_string(), Some(100));
+    }
+    for i in 1..500 {
+        store.set(format!("k{i}"), "v".to_string(), None);
+    }
+    store.set_maxmemory(store.used_memory());
+    store.set_maxmemory_policy(MaxMemoryPolicy::VolatileLru);
+    assert_eq!(handle_command(&store, "SET n1 v").to_string(), "OK");
+    assert_eq!(handle_command(&store, "SET n2 v").to_string(), "OK");
+    assert_eq!(store.evicted_keys(), 1);
+    assert_eq!(store.exists("k0").to_string(), "0");
+}
+
+#[tokio::test]
+async fn test_store_stats() {
+    use kvstore::protocol::handle_command;
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let store = Store::new(None);
+    tokio::spawn(store.clone().start_sweeper(3600));
+    for cmd in ["SET a 1", "GET a", "GET a", "GET missing", "LLEN missing", "PING", "NOPE", "SADD s x", "SCARD s"] {
+        handle_command(&store, cmd);
+    }
+    let stats = store.stats();
+    assert_eq!((stats.keyspace_hits, stats.keyspace_misses), (3, 2));
+    // PING is about the connection and NOPE isn't a command
+    assert_eq!((stats.read_commands, stats.write_commands), (5, 2));
+    assert_eq!(stats.expired_keys, 0);
+
+    // an expired key found by a read is a miss and an expiry right away, and
+    // isn't counted again by more reads or when the sweeper deletes it
+    store.expire_now("a");
+    assert_eq!(handle_command(&store, "GET a").to_string(), "(nil)");
+    let stats = store.stats();
+    assert_eq!((stats.keyspace_misses, stats.expired_keys), (3, 1));
+    handle_command(&store, "GET a");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(store.key_counts().0, 1, "the sweeper should have deleted it");
+    let stats = store.stats();
+    assert_eq!((stats.keyspace_misses, stats.expired_keys), (4, 1));
+    // one a write comes across first is counted by the write
+    store.set("b".to_string(), "1".to_string(), None);
+    store.expire_now("b");
+    handle_command(&store, "SETRANGE b 0 2");
+    assert_eq!(store.stats().expired_keys, 2);
+
+    store.reset_stats();
+    assert_eq!(store.stats(), kvstore::StatsSnapshot::default());
+
+    // INFO reads the same counters, and CONFIG RESETSTAT clears them
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("store-stats.aof"),
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+    let mut conn = BufReader::new(connect(&addr).await);
+    conn.get_mut().write_all(b"SET k v\nGET k\nGET nope\n").await.unwrap();
+    let mut line = String::new();
+    for _ in 0..3 {
+        conn.read_line(&mut line).await.unwrap();
+    }
+    async fn info_stats(conn: &mut BufReader<tokio::net::TcpStream>) -> String {
+        conn.get_mut().write_all(b"INFO stats\n").await.unwrap();
+        let mut info = String::new();
+        while !info.ends_with("\n\n") {
+            conn.read_line(&mut info).await.unwrap();
+        }
+        info
+    }
+    let stats = info_stats(&mut conn).await;
+    for field in ["keyspace_hits:1\n", "keyspace_misses:1\n", "expired_keys:0\n", "evicted_keys:0\n", "write_commands_processed:1\n"] {
+        assert!(stats.contains(field), "{field} missing from {stats}");
+    }
+    line.clear();
+    conn.get_mut().write_all(b"CONFIG RESETSTAT\n").await.unwrap();
+    conn.read_line(&mut line).await.unwrap();
+    assert!(info_stats(&mut conn).await.contains("keyspace_hits:0\n"));
+    shutdown.trigger();
+}
+
+#[test]
+fn test_config_sources() {
+    use kvstore::{MaxMemoryPolicy, ServerConfig};
+
+    let path = temp_path("kvstore.conf");
+    std::fs::write(&path, "# comment\n\ntimeout 30\nmaxmemory-policy allkeys-lfu\nrequirepass \"two words\"\n").unwrap();
+
+    // flags win over the file
+    let config = ServerConfig::load_from(["kvstore", "--config", &path, "--timeout", "5", "--addr", "127.0.0.1:7000, [::1]:7000"]).unwrap();
+    assert_eq!(config.timeout, 5);
+    assert_eq!(config.addrs, ["127.0.0.1:7000", "[::1]:7000"]);
+    assert_eq!(config.maxmemory_policy, MaxMemoryPolicy::AllKeysLfu);
+    assert_eq!(config.requirepass.as_deref(), Some("two words"));
+    assert_eq!(config.maxclients, ServerConfig::default().maxclients);
+
+    let err = ServerConfig::load_from(["kvstore", "--maxclients", "lots"]).unwrap_err();
+    assert!(err.to_string().contains("--maxclients"), "{err}");
+    let err = ServerConfig::load_from(["kvstore", "--tls-key-file", "key.pem"]).unwrap_err();
+    assert!(err.to_string().contains("tls-cert-file"), "{err}");
+    assert!(ServerConfig::load_from(["kvstore", "--no-such-flag", "1"]).is_err());
+
+    std::fs::write(&path, "timeout 30\nnot-a-setting 1\n").unwrap();
+    let err = ServerConfig::load_from(["kvstore", "--config", &path]).unwrap_err();
+    assert!(err.to_string().ends_with(":2: unknown setting 'not-a-setting'"), "{err}");
+}
+
+#[tokio::test]
+async fn test_requirepass() {
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let mut config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("requirepass.aof"),
+        ..ServerConfig::default()
+    };
+    config.requirepass = Some("sekrit".to_string());
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+
+    let mut conn = BufReader::new(connect(&addr).await);
+    conn.get_mut().write_all(b"GET k\nAUTH wrong\nAUTH sekrit\nSET k v\n").await.unwrap();
+    let mut replies = String::new();
+    for _ in 0..4 {
+        conn.read_line(&mut replies).await.unwrap();
+    }
+    assert_eq!(
+        replies,
+        "NOAUTH Authentication required.\n\
+         WRONGPASS invalid username-password pair or user is disabled.\n\
+         OK\n\
+         OK\n",
+    );
+
+    shutdown.trigger();
+}
+
+#[tokio::test]
+async fn test_acl_users() {
+    use kvstore::acl::Acl;
+    use kvstore::ratelimit::RateLimitMode;
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let users = "# readers\nuser reader >rpass +@read ~cache:*\nuser ops >opass +@all allkeys\n\
+                 user slow >spass +@all allkeys ratelimit-cps=1 ratelimit-burst=2\n";
+    let aclfile = temp_path("users.acl");
+    std::fs::write(&aclfile, users).unwrap();
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("acl_users.aof"),
+        aclfile: Some(aclfile.clone()),
+        // no limit for the server, only for `slow`
+        ratelimit_mode: RateLimitMode::Reject,
+        ..ServerConfig::default()
+    };
+    let acl = Acl::load(&config).unwrap();
+    let reader = acl.authenticate("reader", "rpass").unwrap();
+    assert!(acl.authenticate("reader", "opass").is_none());
+    assert!(reader.check("GET", &["get", "cache:a"]).is_ok());
+    assert_eq!(
+        reader.check("SET", &["set", "cache:a", "v"]).unwrap_err().to_string(),
+        "NOPERM User reader has no permissions to run the 'set' command",
+    );
+    assert_eq!(reader.check("GET", &["GET", "other"]).unwrap_err().to_string(), "NOPERM No permissions to access a key");
+    assert!(reader.check("LCS", &["LCS", "cache:a", "secret"]).is_err());
+    assert!(reader.check("CONFIG", &["CONFIG", "GET", "timeout"]).is_err());
+    assert_eq!(reader.rate_limit(&config), (0, 0));
+    assert_eq!(acl.user("slow").unwrap().rate_limit(&config), (1, 2));
+
+    let mut bad = ServerConfig { aclfile: Some(aclfile.clone()), ..ServerConfig::default() };
+    std::fs::write(&aclfile, "user reader +@read\n").unwrap();
+    assert!(Acl::load(&bad).unwrap_err().to_string().ends_with(":1: user 'reader' needs a >password or nopass"));
+    std::fs::write(&aclfile, "user slow nopass ratelimit-cps=fast\n").unwrap();
+    assert!(Acl::load(&bad).unwrap_err().to_string().ends_with(":1: invalid ratelimit-cps 'fast' for user 'slow'"));
+    bad.aclfile = Some(temp_path("missing.acl"));
+    assert!(Acl::load(&bad).is_err());
+    std::fs::write(&aclfile, users).unwrap();
+
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+
+    // without requirepass the default user needs no AUTH and may do anything
+    let mut conn = BufReader::new(connect(&addr).await);
+    conn.get_mut().write_all(b"SET cache:a 1\nSET other 2\n").await.unwrap();
+    let mut replies = String::new();
+    for _ in 0..2 {
+        conn.read_line(&mut replies).await.unwrap();
+    }
+    assert_eq!(replies, "OK\nOK\n");
+
+    let mut conn = BufReader::new(connect(&addr).await);
+    conn.get_mut()
+        .write_all(b"AUTH reader wrong\nAUTH reader rpass\nGET cache:a\nSET cache:a 2\nGET other\nMULTI\nSET cache:a 3\nEXEC\nAUTH ops opass\nSET other 3\n")
+        .await
+        .unwrap();
+    let mut replies = String::new();
+    for _ in 0..10 {
+        conn.read_line(&mut replies).await.unwrap();
+    }
+    assert_eq!(
+        replies,
+        "WRONGPASS invalid username-password pair or user is disabled.\n\
+         OK\n\
+         1\n\
+         NOPERM User reader has no permissions to run the 'set' command\n\
+         NOPERM No permissions to access a key\n\
+         OK\n\
+         NOPERM User reader has no permissions to run the 'set' command\n\
+         EXECABORT Transaction discarded because of previous errors.\n\
+         OK\n\
+         OK\n",
+    );
+
+    // AUTH as a user with a rate limit of their own starts it
+    conn.get_mut().write_all(b"AUTH slow spass\nPING\nPING\nPING\n").await.unwrap();
+    let mut replies = String::new();
+    for _ in 0..4 {
+        conn.read_line(&mut replies).await.unwrap();
+    }
+    assert_eq!(replies, "OK\nPONG\nPONG\nBUSY rate limit exceeded\n");
+
+    shutdown.trigger();
+}
+
+#[tokio::test]
+async fn test_peek() {
+    use kvstore::protocol::handle_command;
+
+    let store = Store::new(None);
+    store.set("k".to_string(), "v".to_string(), Some(1));
+    assert_eq!(handle_command(&store, "PEEK k").to_string(), "v");
+    assert_eq!(handle_command(&store, "PEEK missing").to_string(), "(nil)");
+    store.lpush("l", vec!["a".to_string()]);
+    assert!(handle_command(&store, "PEEK l").to_string().contains("WRONGTYPE"));
+
+    // its first sweep runs straight away, the next in an hour
+    tokio::spawn(store.clone().start_sweeper(3600));
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    store.expire_now("k");
+    let before = store.used_memory();
+    assert!(matches!(store.peek("k"), Response::Nil));
+    // still physically there until the sweeper removes it, which a GET asks for
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(store.used_memory(), before);
+    assert!(matches!(store.get("k"), Response::Nil));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(store.used_memory() < before);
+}
+
+#[tokio::test]
+async fn test_read_reply() {
+    use kvstore::{protocol::{encode_request, read_reply, read_request}, RedisError};
+
+    let reply = Response::Array(vec![
+        Response::Integer(3),
+        Response::BulkString(Some("a\r\nb".to_string())),
+        Response::Nil,
+        Response::Array(vec![Response::SimpleString("OK".to_string())]),
+        RedisError::ReadOnly.into(),
+    ]);
+    let wire = reply.to_resp();
+    let mut reader = &wire[..];
+    let parsed = read_reply(&mut reader).await.unwrap().unwrap();
+    assert_eq!(parsed.to_resp(), wire);
+    assert!(read_reply(&mut reader).await.unwrap().is_none());
+
+    // what a client sends is what the server reads
+    let args = vec!["SET".to_string(), "k".to_string(), "two words".to_string()];
+    let wire = encode_request(&args);
+    let request = read_request(&mut &wire[..]).await.unwrap().unwrap();
+    assert_eq!(request.args, args);
+}
+
+#[tokio::test]
+async fn test_borrowed_args() {
+    use kvstore::aof::Aof;
+    use kvstore::protocol::{encode_request, execute, read_request};
+
+    let path = temp_path("borrowed-args.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    let store = Store::new(Some(aof.clone()));
+    let run = |args: &[&str]| {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        execute(&store, &args).to_string()
+    };
+    assert_eq!(run(&["SADD", "s", "a", "b", "a", "7"]), "3");
+    assert_eq!(run(&["SADD", "s", "b", "c"]), "1");
+    assert_eq!(run(&["SREM", "s", "a", "x", "7"]), "2");
+    assert_eq!(run(&["LPUSH", "l", "x", "y"]), "2");
+    assert_eq!(run(&["LPUSHRET", "l", "z"]), "3 z");
+    assert!(run(&["SADD", "l", "m"]).starts_with("WRONGTYPE"));
+    // only canonical integers go in an intset
+    assert_eq!(run(&["SADD", "ints", "5", "-3"]), "2");
+    assert_eq!(run(&["OBJECT", "ENCODING", "ints"]), "intset");
+    assert_eq!(run(&["SADD", "ints", "+5"]), "1");
+    assert_eq!(run(&["OBJECT", "ENCODING", "ints"]), "hashtable");
+    assert_eq!(run(&["SADD", "zero", "-0"]), "1");
+    assert_eq!(run(&["OBJECT", "ENCODING", "zero"]), "hashtable");
+
+    // bulk arguments keep their bytes, invalid UTF-8 is replaced as before
+    let mut wire = encode_request(&["SADD".to_string(), "raw".to_string(), "caf\u{e9}".to_string()]);
+    wire.extend(b"*3\r\n$4\r\nSADD\r\n$3\r\nraw\r\n$2\r\n\xffa\r\n");
+    let mut reader = &wire[..];
+    for _ in 0..2 {
+        let request = read_request(&mut reader).await.unwrap().unwrap();
+        assert_eq!(execute(&store, &request.args).to_string(), "1");
+    }
+    assert_eq!(run(&["SADD", "raw", "caf\u{e9}", "\u{fffd}a"]), "0");
+
+    // the AOF gets the same entries, so replay gives back the same dataset
+    aof.flush().await.unwrap();
+    let restarted = Store::new(None);
+    restarted.load_from_aof(Aof::replay(&path).unwrap().entries);
+    let records = |store: &Store| {
+        let mut records = Vec::new();
+        store.for_each(|key, entry| records.push(kvstore::export::Record::from_entry(key, entry)));
+        records.sort_by(|a, b| a.key.cmp(&b.key));
+        records
+    };
+    assert_eq!(records(&restarted), records(&store));
+    assert_eq!(records(&store).len(), 5);
+}
+
+#[tokio::test]
+async fn test_exec_runs_past_failing_commands() {
+    use kvstore::protocol::{encode_request, read_reply};
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncWriteExt, BufReader};
+
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("exec_errors.aof"),
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+
+    let mut conn = BufReader::new(connect(&addr).await);
+    for cmd in ["MULTI", "SET k v", "LPUSH k x", "INCR k", "SET after 1", "EXEC"] {
+        let args: Vec<String> = cmd.split(' ').map(String::from).collect();
+        conn.get_mut().write_all(&encode_request(&args)).await.unwrap();
+    }
+    assert_eq!(read_reply(&mut conn).await.unwrap().unwrap().to_string(), "OK");
+    for _ in 0..4 {
+        assert_eq!(read_reply(&mut conn).await.unwrap().unwrap().to_string(), "QUEUED");
+    }
+    // runtime errors take their command's slot, the commands after them still run
+    let Response::Array(replies) = read_reply(&mut conn).await.unwrap().unwrap() else {
+        panic!("expected an array from EXEC");
+    };
+    assert_eq!(replies.len(), 4);
+    assert_eq!(replies[0].to_string(), "OK");
+    assert!(matches!(&replies[1], Response::Error(e) if e.to_string().contains("WRONGTYPE")), "{}", replies[1]);
+    assert!(matches!(&replies[2], Response::Error(_)));
+    assert_eq!(replies[3].to_string(), "OK");
+
+    conn.get_mut().write_all(&encode_request(&["GET".to_string(), "after".to_string()])).await.unwrap();
+    assert_eq!(read_reply(&mut conn).await.unwrap().unwrap().to_string(), "1");
+    shutdown.trigger();
+}
+
+#[tokio::test]
+async fn test_empty_commands() {
+    use kvstore::protocol::handle_command;
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let store = Store::new(None);
+    assert_eq!(handle_command(&store, "").to_string(), "ERR empty command");
+    assert_eq!(handle_command(&store, "  \t ").to_string(), "ERR empty command");
+
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("empty.aof"),
+        requirepass: Some("secret".to_string()),
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+
+    async fn reply(conn: &mut BufReader<tokio::net::TcpStream>) -> String {
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        line
+    }
+    // the same error before AUTH, for an empty array and blank inline lines
+    let mut conn = BufReader::new(connect(&addr).await);
+    conn.get_mut().write_all(b"*0\r\n\n   \r\nAUTH secret\n").await.unwrap();
+    assert_eq!(reply(&mut conn).await, "-ERR empty command\r\n");
+    assert_eq!(reply(&mut conn).await, "ERR empty command\n");
+    assert_eq!(reply(&mut conn).await, "ERR empty command\n");
+    assert_eq!(reply(&mut conn).await, "OK\n");
+
+    // inside a transaction they are neither queued nor abort it
+    conn.get_mut().write_all(b"MULTI\n*0\r\n\nSET k v\nEXEC\n").await.unwrap();
+    assert_eq!(reply(&mut conn).await, "OK\n");
+    assert_eq!(reply(&mut conn).await, "-ERR empty command\r\n");
+    assert_eq!(reply(&mut conn).await, "ERR empty command\n");
+    assert_eq!(reply(&mut conn).await, "QUEUED\n");
+    assert_eq!(reply(&mut conn).await, "OK\n");
+    shutdown.trigger();
+}
+
+#[tokio::test]
+async fn test_large_value_streaming() {
+    use kvstore::protocol::{encode_request, read_reply};
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("streaming.aof"),
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+
+    // not a repeated byte, so misplaced chunks would show
+    let value: String = (0..8 << 20).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+    let mut conn = BufReader::new(connect(&addr).await);
+    let set = ["SET".to_string(), "big".to_string(), value.clone()];
+    conn.get_mut().write_all(&encode_request(&set)).await.unwrap();
+    assert_eq!(read_reply(&mut conn).await.unwrap().unwrap().to_string(), "OK");
+
+    // a large bulk string inside an array, followed by more of the reply
+    let lpush = ["LPUSHRET".to_string(), "list".to_string(), value.clone()];
+    conn.get_mut().write_all(&encode_request(&lpush)).await.unwrap();
+    match read_reply(&mut conn).await.unwrap().unwrap() {
+        Response::Array(items) => {
+            assert_eq!(items[0].to_string(), "1");
+            assert!(matches!(&items[1], Response::BulkString(Some(s)) if *s == value));
+        }
+        other => panic!("unexpected {other}"),
+    }
+
+    conn.get_mut().write_all(&encode_request(&["GET".to_string(), "big".to_string()])).await.unwrap();
+    match read_reply(&mut conn).await.unwrap().unwrap() {
+        Response::BulkString(Some(s)) => assert!(s == value),
+        _ => panic!("expected the value back"),
+    }
+
+    // inline replies take the same shortcut
+    conn.get_mut().write_all(b"GET big\n").await.unwrap();
+    let mut inline = vec![0u8; value.len() + 1];
+    conn.read_exact(&mut inline).await.unwrap();
+    assert!(inline[..value.len()] == *value.as_bytes());
+    assert_eq!(inline[value.len()], b'\n');
+
+    shutdown.trigger();
+}
+
+#[tokio::test]
+async fn test_del_if_equal() {
+    use kvstore::{aof::Aof, protocol::handle_command};
+
+    let path = temp_path("deleq.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    let store = Store::new(Some(aof.clone()));
+
+    // the lock changed hands, so every stale owner must fail and exactly one
+    // of the current owner's racing releases may succeed
+    store.set("lock".to_string(), "owner-2".to_string(), None);
+    let handles: Vec<_> = (0..16)
+        .map(|i| {
+            let store = store.clone();
+            let token = if i % 2 == 0 { "owner-1" } else { "owner-2" };
+            std::thread::spawn(move || (token, store.del_if_equal("lock", token).to_string()))
+        })
+        .collect();
+    let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert!(results.iter().filter(|(token, _)| *token == "owner-1").all(|(_, r)| r == "0"));
+    assert_eq!(results.iter().filter(|(_, r)| r == "1").count(), 1);
+    assert_eq!(store.exists("lock").to_string(), "0");
+
+    store.set("lock".to_string(), "owner-3".to_string(), None);
+    assert_eq!(handle_command(&store, "DELEQ lock owner-1").to_string(), "0");
+    assert_eq!(handle_command(&store, "GET lock").to_string(), "owner-3");
+    assert_eq!(handle_command(&store, "DELEQ missing x").to_string(), "0");
+    store.lpush("list", vec!["x".to_string()]);
+    assert!(handle_command(&store, "DELEQ list x").to_string().contains("WRONGTYPE"));
+
+    // only the successful release was logged
+    aof.flush().await.unwrap();
+    let dels = Aof::replay(&path).unwrap().entries.into_iter().filter(|e| e.op == "del").count();
+    assert_eq!(dels, 1);
+}
+
+#[tokio::test]
+async fn test_multiple_binds() {
+    use kvstore::server::{Server, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let config = ServerConfig {
+        addrs: vec!["127.0.0.1:0".to_string(), "127.0.0.1:0".to_string()],
+        aof_path: temp_path("binds.aof"),
+        ..ServerConfig::default()
+    };
+    let shutdown = Shutdown::new();
+    let server = Server::bind(config, shutdown.clone()).await.unwrap();
+    let addrs = server.local_addrs().to_vec();
+    assert_eq!(addrs.len(), 2);
+    assert!(addrs.iter().all(|a| a.port() != 0));
+    assert_ne!(addrs[0], addrs[1]);
+    let running = tokio::spawn(server.run());
+
+    // both listeners serve the same dataset
+    let mut first = BufReader::new(connect(&addrs[0].to_string()).await);
+    first.get_mut().write_all(b"SET k v\n").await.unwrap();
+    let mut reply = String::new();
+    first.read_line(&mut reply).await.unwrap();
+    let mut second = BufReader::new(connect(&addrs[1].to_string()).await);
+    second.get_mut().write_all(b"GET k\n").await.unwrap();
+    second.read_line(&mut reply).await.unwrap();
+    assert_eq!(reply, "OK\nv\n");
+
+    // an address that is already taken fails startup and says which one
+    let taken = addrs[0].to_string();
+    let config = ServerConfig {
+        addrs: vec!["127.0.0.1:0".to_string(), taken.clone()],
+        aof_path: temp_path("binds-taken.aof"),
+        ..ServerConfig::default()
+    };
+    let err = Server::bind(config, Shutdown::new()).await.err().unwrap();
+    assert!(err.to_string().contains(&taken), "{err}");
+
+    shutdown.trigger();
+    running.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_pipelined_replies_flush() {
+    use kvstore::protocol::encode_request;
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("pipeline.aof"),
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+
+    // every reply batched ahead of QUIT still goes out before the close
+    let mut conn = connect(&addr).await;
+    conn.write_all(b"SET a 1\nGET a\nINCR a\nQUIT\n").await.unwrap();
+    let mut replies = String::new();
+    conn.read_to_string(&mut replies).await.unwrap();
+    assert_eq!(replies, "OK\n1\n2\nBye!!!\n");
+
+    // more replies than fit in one flush, over RESP
+    let mut conn = connect(&addr).await;
+    let mut batch = Vec::new();
+    for i in 0..5000 {
+        batch.extend(encode_request(&["SET".to_string(), format!("key:{i}"), "x".repeat(32)]));
+    }
+    batch.extend(encode_request(&["QUIT".to_string()]));
+    conn.write_all(&batch).await.unwrap();
+    let mut replies = String::new();
+    conn.read_to_string(&mut replies).await.unwrap();
+    assert_eq!(replies, "+OK\r\n".repeat(5001));
+
+    shutdown.trigger();
+}
+
+#[tokio::test]
+async fn test_expire_conditions() {
+    use kvstore::{aof::Aof, protocol::handle_command};
+
+    let path = temp_path("expire.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    let store = Store::new(Some(aof.clone()));
+    let cmd = |c: &str| handle_command(&store, c).to_string();
+    store.set("k".to_string(), "v".to_string(), None);
+
+    // XX needs an existing TTL, NX needs there to be none
+    assert_eq!(cmd("EXPIRE k 100 XX"), "0");
+    assert_eq!(cmd("TTL k"), "-1");
+    assert_eq!(cmd("EXPIRE k 100 NX"), "1");
+    assert_eq!(cmd("EXPIRE k 200 NX"), "0");
+    assert_eq!(cmd("TTL k"), "99");
+    assert_eq!(cmd("EXPIRE k 50 XX"), "1");
+    assert_eq!(cmd("TTL k"), "49");
+
+    // GT only extends and LT only shortens, so a renewal can't clobber a shorter TTL
+    assert_eq!(cmd("EXPIRE k 10 GT"), "0");
+    assert_eq!(cmd("EXPIRE k 300 GT"), "1");
+    assert_eq!(cmd("PEXPIRE k 400000 LT"), "0");
+    assert_eq!(cmd("PEXPIRE k 20000 LT"), "1");
+    assert_eq!(cmd("TTL k"), "19");
+
+    // no TTL counts as forever: GT never applies, LT always does
+    store.set("k2".to_string(), "v".to_string(), None);
+    assert_eq!(cmd("EXPIRE k2 100 GT"), "0");
+    assert_eq!(cmd("EXPIRE k2 100 LT"), "1");
+
+    assert_eq!(cmd("EXPIRE missing 10"), "0");
+    assert!(cmd("EXPIRE k 10 NX GT").contains("not compatible"));
+    assert!(cmd("EXPIRE k 10 GT LT").contains("not compatible"));
+    assert!(cmd("EXPIRE k 10 SOON").contains("Unsupported option"));
+    assert!(cmd("EXPIRE k soon").contains("not an integer"));
+
+    // a TTL that isn't positive deletes the key
+    assert_eq!(cmd("EXPIRE k2 0"), "1");
+    assert_eq!(cmd("EXISTS k2"), "0");
+
+    // replay restores the expiry on k, and k2 stays deleted
+    aof.flush().await.unwrap();
+    let replayed = Store::new(None);
+    replayed.load_from_aof(Aof::replay(&path).unwrap().entries);
+    assert_eq!(handle_command(&replayed, "TTL k").to_string(), "19");
+    assert_eq!(handle_command(&replayed, "EXISTS k2").to_string(), "0");
+}
+
+#[tokio::test]
+async fn test_readonly_multi() {
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("readonly_multi.aof"),
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+
+    async fn send(conn: &mut BufReader<tokio::net::TcpStream>, cmd: &str) -> String {
+        conn.get_mut().write_all(format!("{cmd}\n").as_bytes()).await.unwrap();
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        line.trim_end().to_string()
+    }
+    let mut conn = BufReader::new(connect(&addr).await);
+    let mut admin = BufReader::new(connect(&addr).await);
+
+    assert_eq!(send(&mut conn, "MULTI").await, "OK");
+    assert_eq!(send(&mut conn, "SET k v").await, "QUEUED");
+    assert_eq!(send(&mut conn, "INCR n").await, "QUEUED");
+    assert_eq!(send(&mut conn, "EXEC").await, "OK 1");
+
+    // the flag flips between queueing and EXEC, so the whole transaction is refused
+    assert_eq!(send(&mut conn, "MULTI").await, "OK");
+    assert_eq!(send(&mut conn, "SET k other").await, "QUEUED");
+    assert_eq!(send(&mut admin, "CONFIG SET readonly yes").await, "OK");
+    assert!(send(&mut conn, "EXEC").await.starts_with("READONLY"));
+    assert_eq!(send(&mut conn, "GET k").await, "v");
+
+    // reads, PING, INFO and CONFIG keep working, read-only transactions too
+    assert!(send(&mut conn, "SET k other").await.starts_with("READONLY"));
+    assert_eq!(send(&mut conn, "PING").await, "PONG");
+    assert_eq!(send(&mut conn, "CONFIG GET readonly").await, "readonly yes");
+    assert_eq!(send(&mut conn, "MULTI").await, "OK");
+    assert_eq!(send(&mut conn, "GET k").await, "QUEUED");
+    assert_eq!(send(&mut conn, "EXEC").await, "v");
+
+    // a command rejected while queueing aborts the transaction
+    assert_eq!(send(&mut admin, "CONFIG SET readonly no").await, "OK");
+    assert_eq!(send(&mut conn, "MULTI").await, "OK");
+    assert!(send(&mut conn, "NOSUCH x").await.starts_with("ERR unknown command"));
+    assert!(send(&mut conn, "EXEC").await.starts_with("EXECABORT"));
+    assert!(send(&mut conn, "EXEC").await.contains("EXEC without MULTI"));
+    assert_eq!(send(&mut conn, "MULTI").await, "OK");
+    assert_eq!(send(&mut conn, "SET k discarded").await, "QUEUED");
+    assert_eq!(send(&mut conn, "DISCARD").await, "OK");
+    assert_eq!(send(&mut conn, "GET k").await, "v");
+
+    shutdown.trigger();
+}
+
+#[tokio::test]
+async fn test_aof_pending_entries() {
+    use kvstore::aof::{Aof, LogEntry};
+
+    let path = temp_path("pending.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    assert_eq!(aof.pending(), 0);
+
+    // the writer task can't run until this test yields, so the whole flood is queued
+    for i in 0..10_000 {
+        aof.log(LogEntry {
+            op: "set".to_string(),
+            key: format!("k{i}"),
+            value: Some("v".to_string()),
+            expires_at_ms: None,
+            values: None,
+        });
+    }
+    assert_eq!(aof.pending(), 10_000);
+
+    aof.flush().await.unwrap();
+    assert_eq!(aof.pending(), 0);
+    assert_eq!(Aof::replay(&path).unwrap().entries.len(), 10_000);
+}
+
+#[tokio::test]
+async fn test_debug_reload() {
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    async fn send(conn: &mut BufReader<tokio::net::TcpStream>, cmd: &str) -> String {
+        conn.get_mut().write_all(format!("{cmd}\n").as_bytes()).await.unwrap();
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        line.trim_end().to_string()
+    }
+    async fn sorted(conn: &mut BufReader<tokio::net::TcpStream>, cmd: &str) -> Vec<String> {
+        let mut words: Vec<String> = send(conn, cmd).await.split(' ').map(str::to_string).collect();
+        words.sort();
+        words
+    }
+
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("reload.aof"),
+        dbfilename: temp_path("reload.kvs"),
+        enable_debug_command: true,
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+    let mut conn = BufReader::new(connect(&addr).await);
+
+    // one key of each type and encoding, some with a TTL
+    for cmd in [
+        "SET str hello EX 100", "SET plain world", "LPUSH list c b a", "PEXPIRE list 50000",
+        "SADD ints 1 2 3", "SADD words x y", "HSET hash f1 v1 f2 v2",
+    ] {
+        assert!(!send(&mut conn, cmd).await.starts_with("ERR"), "{cmd}");
+    }
+    let reads = [
+        "GET str", "GET plain", "DBSIZE", "LLEN list", "SCARD ints", "SCARD words",
+        "OBJECT ENCODING ints", "OBJECT ENCODING words", "TTL plain", "TTL ints",
+    ];
+    let mut before = Vec::new();
+    for cmd in reads {
+        before.push(send(&mut conn, cmd).await);
+    }
+    let hash = sorted(&mut conn, "HRANDFIELD hash 10 WITHVALUES").await;
+    let ttls: Vec<i64> = [send(&mut conn, "PTTL str").await, send(&mut conn, "PTTL list").await]
+        .iter().map(|t| t.parse().unwrap()).collect();
+
+    assert_eq!(send(&mut conn, "DEBUG RELOAD").await, "OK");
+    for (cmd, want) in reads.iter().zip(&before) {
+        assert_eq!(&send(&mut conn, cmd).await, want, "{cmd}");
+    }
+    assert_eq!(sorted(&mut conn, "HRANDFIELD hash 10 WITHVALUES").await, hash);
+    for (key, ttl) in ["str", "list"].iter().zip(ttls) {
+        let after: i64 = send(&mut conn, &format!("PTTL {key}")).await.parse().unwrap();
+        assert!(after <= ttl && after > ttl - 1000, "{key}: {ttl} then {after}");
+    }
+    // LPUSH keeps its arguments in order
+    for want in ["c", "b", "a"] {
+        assert_eq!(send(&mut conn, "LPOP list").await, want);
+    }
+    assert_eq!(send(&mut conn, "SREM ints 1 2 3").await, "3");
+    assert_eq!(send(&mut conn, "SREM words x y").await, "2");
+    assert!(send(&mut conn, "DEBUG RELOAD now").await.starts_with("ERR"));
+    shutdown.trigger();
+}
+
+#[tokio::test]
+async fn test_debug_command() {
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    async fn send(conn: &mut BufReader<tokio::net::TcpStream>, cmd: &str) -> String {
+        conn.get_mut().write_all(format!("{cmd}\n").as_bytes()).await.unwrap();
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        line.trim_end().to_string()
+    }
+
+    // off unless enabled
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("debug_off.aof"),
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+    let mut conn = BufReader::new(connect(&addr).await);
+    assert!(send(&mut conn, "DEBUG OBJECT-COUNT").await.contains("not allowed"));
+    shutdown.trigger();
+
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("debug.aof"),
+        enable_debug_command: true,
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+    let mut conn = BufReader::new(connect(&addr).await);
+
+    send(&mut conn, "SET a 1 EX 100").await;
+    send(&mut conn, "SET b 2").await;
+    // the AOF writer may not have caught up, so its count isn't checked
+    assert!(send(&mut conn, "DEBUG OBJECT-COUNT").await.starts_with("keys 2 expires 1 aof_pending_entries "));
+
+    // lazy expiry without waiting out a TTL
+    assert_eq!(send(&mut conn, "DEBUG EXPIRE-NOW b").await, "OK");
+    assert!(send(&mut conn, "DEBUG OBJECT-COUNT").await.starts_with("keys 2 expires 2 aof_pending_entries "));
+    assert_eq!(send(&mut conn, "GET b").await, "(nil)");
+    assert!(send(&mut conn, "DEBUG OBJECT-COUNT").await.starts_with("keys 1 expires 1 aof_pending_entries "));
+    assert!(send(&mut conn, "DEBUG EXPIRE-NOW missing").await.contains("no such key"));
+
+    // DEBUG SLEEP is slow on purpose, so it stays out of the slowlog
+    let started = std::time::Instant::now();
+    assert_eq!(send(&mut conn, "DEBUG SLEEP 0.05").await, "OK");
+    assert!(started.elapsed() >= Duration::from_millis(50));
+    assert_eq!(send(&mut conn, "SLOWLOG LEN").await, "0");
+    assert!(send(&mut conn, "DEBUG SLEEP soon").await.contains("invalid number"));
+    assert!(send(&mut conn, "DEBUG NOPE").await.starts_with("ERR"));
+
+    shutdown.trigger();
+}
+
+#[tokio::test]
+async fn test_binary_reply() {
+    use kvstore::protocol::read_reply;
+
+    // "é" is two bytes, so the first alone isn't valid utf-8
+    let store = Store::new(None);
+    store.set("k".to_string(), "é".to_string(), None);
+    let reply = store.getrange("k", 0, 0);
+    assert!(matches!(&reply, Response::BulkBytes(b) if b == &[0xc3]));
+    assert_eq!(reply.to_resp(), b"$1\r\n\xc3\r\n");
+    assert_eq!(reply.to_string(), "\u{fffd}");
+
+    // and a client reading it back gets the same byte, not a replacement character
+    let wire = reply.to_resp();
+    let parsed = read_reply(&mut &wire[..]).await.unwrap().unwrap();
+    assert!(matches!(parsed, Response::BulkBytes(b) if b == [0xc3]));
+}
+
+#[test]
+fn test_command_docs() {
+    use kvstore::protocol::{handle_command, COMMANDS};
+
+    let store = Store::new(None);
+    assert!(COMMANDS.iter().all(|(_, _, summary)| !summary.is_empty()));
+
+    let Response::Array(docs) = handle_command(&store, "COMMAND DOCS get nosuch") else { panic!("expected an array") };
+    assert_eq!(docs.len(), 2);
+    assert_eq!(docs[0].to_string(), "get");
+    assert_eq!(docs[1].to_string(), "summary Returns the string value of a key. arguments key");
+    assert_eq!(
+        handle_command(&store, "COMMAND COUNT").to_string(),
+        COMMANDS.len().to_string(),
+    );
+
+    let Response::Array(help) = handle_command(&store, "OBJECT HELP") else { panic!("expected an array") };
+    let help: Vec<String> = help.iter().map(|l| l.to_string()).collect();
+    assert!(help[0].starts_with("OBJECT <subcommand>"));
+    assert!(help.contains(&"FREQ <key>".to_string()));
+    assert_eq!(help.last().unwrap(), "    Print this help.");
+}
+
+#[tokio::test]
+async fn test_bget() {
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    async fn send(conn: &mut BufReader<tokio::net::TcpStream>, cmd: &str) -> String {
+        conn.get_mut().write_all(format!("{cmd}\n").as_bytes()).await.unwrap();
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        line.trim_end().to_string()
+    }
+
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("bget.aof"),
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+    let mut conn = BufReader::new(connect(&addr).await);
+
+    // an existing key returns straight away, a missing one times out as nil
+    send(&mut conn, "SET ready yes").await;
+    assert_eq!(send(&mut conn, "BGET ready 0").await, "yes");
+    let started = tokio::time::Instant::now();
+    assert_eq!(send(&mut conn, "BGET missing 0.1").await, "(nil)");
+    assert!(started.elapsed() >= Duration::from_millis(100));
+    assert!(send(&mut conn, "BGET missing soon").await.contains("timeout is not a float"));
+
+    // a set from another connection unblocks the waiter
+    let waiter = tokio::spawn(async move {
+        let answer = send(&mut conn, "BGET job 5").await;
+        (answer, started.elapsed())
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut other = BufReader::new(connect(&addr).await);
+    assert_eq!(send(&mut other, "SET job done").await, "OK");
+    let (answer, waited) = waiter.await.unwrap();
+    assert_eq!(answer, "done");
+    assert!(waited < Duration::from_secs(5));
+
+    // so do the other commands that write strings
+    for (key, write, value) in [
+        ("counter", "INCR counter", "1"),
+        ("padded", "SETRANGE padded 0 x", "x"),
+        ("combined", "BITOP OR combined ready", "yes"),
+    ] {
+        let addr = addr.clone();
+        let waiter = tokio::spawn(async move {
+            let started = tokio::time::Instant::now();
+            let answer = send(&mut BufReader::new(connect(&addr).await), &format!("BGET {key} 5")).await;
+            (answer, started.elapsed())
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        send(&mut other, write).await;
+        let (answer, waited) = waiter.await.unwrap();
+        assert_eq!(answer, value, "{write}");
+        assert!(waited < Duration::from_secs(5), "{write}");
+    }
+
+    // the waits were the point, so none of them is logged as slow
+    assert_eq!(send(&mut other, "SLOWLOG LEN").await, "0");
+    shutdown.trigger();
+}
+
+#[tokio::test]
+async fn test_panic_recovery() {
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    async fn send(conn: &mut BufReader<tokio::net::TcpStream>, cmd: &str) -> String {
+        conn.get_mut().write_all(format!("{cmd}\n").as_bytes()).await.unwrap();
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        line.trim_end().to_string()
+    }
+
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("panic.aof"),
+        enable_debug_command: true,
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+    let mut conn = BufReader::new(connect(&addr).await);
+
+    send(&mut conn, "SET before 1").await;
+    assert!(send(&mut conn, "DEBUG PANIC").await.contains("internal error"));
+    // the keyspace lock was held by the panic, yet this connection and others carry on
+    assert_eq!(send(&mut conn, "SET after 2").await, "OK");
+    let mut other = BufReader::new(connect(&addr).await);
+    assert_eq!(send(&mut other, "GET before").await, "1");
+    assert_eq!(send(&mut other, "GET after").await, "2");
+    shutdown.trigger();
+}
+
+#[tokio::test]
+async fn test_aof_collections_replay() {
+    use kvstore::{aof::Aof, protocol::handle_command};
+
+    let path = temp_path("collections.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    let store = Store::new(Some(aof.clone()));
+    for cmd in [
+        "LPUSH l a b c", "LPUSH l d", "LPOP l",
+        "LPUSH gone x", "LPOP gone",
+        "SADD s a b c", "SADD s c d", "SREM s a nosuch",
+        "SADD empty x", "SREM empty x",
+        "HSET h f1 v1 f2 v2", "HSET h f1 v3",
+        "EXPIRE l 100",
+    ] {
+        assert!(!matches!(handle_command(&store, cmd), Response::Error(_)), "{cmd}");
+    }
+    aof.flush().await.unwrap();
+
+    let replayed = Store::new(None);
+    replayed.load_from_aof(Aof::replay(&path).unwrap().entries);
+    assert_eq!(replayed.snapshot_commands().len(), store.snapshot_commands().len());
+    let sorted = |store: &Store, key: &str| {
+        let Response::Array(items) = handle_command(store, &format!("HRANDFIELD {key} 100 WITHVALUES")) else { unreachable!() };
+        let mut items: Vec<String> = items.iter().map(|i| i.to_string()).collect();
+        items.sort();
+        items
+    };
+    assert_eq!(sorted(&replayed, "h"), sorted(&store, "h"));
+    for cmd in ["LLEN l", "LPOP l", "LPOP l", "TTL l", "SCARD s", "EXISTS gone", "EXISTS empty"] {
+        assert_eq!(handle_command(&replayed, cmd).to_string(), handle_command(&store, cmd).to_string(), "{cmd}");
+    }
+    assert_eq!(handle_command(&replayed, "LLEN l").to_string(), "1");
+    for member in ["b", "c", "d"] {
+        assert_eq!(handle_command(&replayed, &format!("SREM s {member}")).to_string(), "1");
+    }
+    assert_eq!(handle_command(&replayed, "EXISTS s").to_string(), "0");
+}
+
+#[tokio::test]
+async fn test_aof_rewrite() {
+    use kvstore::{aof::{segments, Aof}, protocol::handle_command};
+
+    let path = temp_path("rewrite.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    aof.set_rotate_size(4096);
+    let store = Store::new(Some(aof.clone()));
+    for _ in 0..500 {
+        handle_command(&store, "INCR counter");
+    }
+    for cmd in ["LPUSH l a b c", "SADD s a b", "HSET h f v", "EXPIRE h 100", "SET gone x", "DEL gone"] {
+        handle_command(&store, cmd);
+    }
+    aof.flush().await.unwrap();
+    let rewritten = segments(&path);
+    assert!(rewritten.len() > 1);
+    let before = aof.current_offset();
+    let on_disk = |files: &[String]| files.iter().map(|f| std::fs::metadata(f).unwrap().len()).sum::<u64>();
+    assert_eq!(before, on_disk(&rewritten));
+
+    let snapshot = store.begin_aof_rewrite().unwrap();
+    assert!(store.begin_aof_rewrite().is_err(), "only one rewrite at a time");
+    // written after the snapshot, so only the carried over entries have them
+    handle_command(&store, "SET during 1");
+    handle_command(&store, "LPOP l");
+    aof.finish_rewrite(snapshot).await.unwrap();
+    handle_command(&store, "SET after 1");
+    aof.flush().await.unwrap();
+
+    let stats = aof.stats();
+    assert!(!stats.rewrite_in_progress && stats.last_rewrite_ok && stats.last_rewrite > 0);
+    // one base segment, numbered after the ones it replaced
+    let base = segments(&path);
+    assert_eq!(base, [format!("{path}.{}", rewritten.len() + 1)]);
+    // a rewrite is the only thing that moves the offset back
+    assert!(aof.current_offset() < before);
+    assert_eq!(aof.current_offset(), on_disk(&base));
+    assert!(rewritten.iter().all(|segment| !std::path::Path::new(segment).exists()));
+    let entries = Aof::replay(&path).unwrap().entries;
+    assert!(entries.len() < 20, "{} entries left", entries.len());
+
+    let replayed = Store::new(None);
+    replayed.load_from_aof(entries);
+    for cmd in ["GET counter", "LLEN l", "LPOP l", "LPOP l", "SCARD s", "HGET h f", "TTL h", "EXISTS gone", "GET during", "GET after"] {
+        assert_eq!(handle_command(&replayed, cmd).to_string(), handle_command(&store, cmd).to_string(), "{cmd}");
+    }
+    assert_eq!(handle_command(&replayed, "GET counter").to_string(), "500");
+}
+
+#[tokio::test]
+async fn test_auto_aof_rewrite() {
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    async fn send(conn: &mut BufReader<tokio::net::TcpStream>, cmd: &str) -> String {
+        conn.get_mut().write_all(format!("{cmd}\n").as_bytes()).await.unwrap();
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        line.trim_end().to_string()
+    }
+    async fn info(conn: &mut BufReader<tokio::net::TcpStream>) -> String {
+        conn.get_mut().write_all(b"INFO persistence\n").await.unwrap();
+        let mut text = String::new();
+        while !text.ends_with("\n\n") {
+            conn.read_line(&mut text).await.unwrap();
+        }
+        text
+    }
+
+    let config = ServerConfig::load_from(["kvstore", "--auto-aof-rewrite-min-size", "4096"]).unwrap();
+    assert_eq!((config.auto_aof_rewrite_percentage, config.auto_aof_rewrite_min_size), (100, 4096));
+    let path = temp_path("auto_rewrite.aof");
+    let config = ServerConfig { addrs: vec![free_addr()], aof_path: path.clone(), ..config };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+    let mut conn = BufReader::new(connect(&addr).await);
+
+    assert_eq!(send(&mut conn, "CONFIG GET auto-aof-rewrite-percentage").await, "auto-aof-rewrite-percentage 100");
+    // disabled, so growing past the minimum does nothing
+    assert_eq!(send(&mut conn, "CONFIG SET auto-aof-rewrite-percentage 0").await, "OK");
+    for _ in 0..200 {
+        send(&mut conn, "INCR counter").await;
+    }
+    let mut text = info(&mut conn).await;
+    while !text.contains("aof_pending_entries:0") {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        text = info(&mut conn).await;
+    }
+    assert!(text.contains("aof_last_rewrite_time:-1"), "{text}");
+    assert!(text.contains("aof_base_size:0"), "{text}");
+
+    assert_eq!(send(&mut conn, "CONFIG SET auto-aof-rewrite-percentage 100").await, "OK");
+    send(&mut conn, "INCR counter").await;
+    let mut text = String::new();
+    for _ in 0..100 {
+        text = info(&mut conn).await;
+        if !text.contains("aof_last_rewrite_time:-1") && text.contains("aof_rewrite_in_progress:0") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(text.contains("aof_last_bgrewrite_status:ok"), "{text}");
+    assert!(!text.contains("aof_base_size:0\n"), "{text}");
+    while !info(&mut conn).await.contains("aof_pending_entries:0") {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    let entries = kvstore::aof::Aof::replay(&path).unwrap().entries;
+    assert!(entries.len() < 10, "{} entries left", entries.len());
+    assert_eq!(send(&mut conn, "GET counter").await, "201");
+    shutdown.trigger();
+}
+
+#[tokio::test]
+async fn test_snapshot_save_and_restart() {
+    use kvstore::protocol::handle_command;
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    async fn send(conn: &mut BufReader<tokio::net::TcpStream>, cmd: &str) -> String {
+        conn.get_mut().write_all(format!("{cmd}\n").as_bytes()).await.unwrap();
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        line.trim_end().to_string()
+    }
+
+    // every type and TTLs survive a round trip through the format
+    let store = Store::new(None);
+    for cmd in ["SET s v", "EXPIRE s 100", "LPUSH l a b", "SADD ints 1 2 3", "SADD words x", "HSET h f v"] {
+        handle_command(&store, cmd);
+    }
+    let bytes = store.serialize_snapshot(7);
+    assert_eq!(kvstore::snapshot::id_of(&bytes).unwrap(), 7);
+    let loaded = Store::new(None);
+    assert_eq!(loaded.load_snapshot(&bytes).unwrap(), 7);
+    assert_eq!(handle_command(&loaded, "GET s").to_string(), "v");
+    assert!((99..=100).contains(&handle_command(&loaded, "TTL s").to_string().parse::<i64>().unwrap()));
+    assert_eq!(handle_command(&loaded, "LLEN l").to_string(), "2");
+    assert_eq!(handle_command(&loaded, "OBJECT ENCODING ints").to_string(), "intset");
+    assert_eq!(handle_command(&loaded, "SCARD words").to_string(), "1");
+    assert_eq!(handle_command(&loaded, "HRANDFIELD h 1 WITHVALUES").to_string(), handle_command(&store, "HRANDFIELD h 1 WITHVALUES").to_string());
+    assert!(loaded.load_snapshot(&bytes[..bytes.len() - 4]).is_err());
+
+    let (aof_path, dbfilename) = (temp_path("snapshot.aof"), temp_path("snapshot.kvs"));
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: aof_path.clone(),
+        dbfilename: dbfilename.clone(),
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    let server = tokio::spawn(serve(config, shutdown.clone()));
+    let mut conn = BufReader::new(connect(&addr).await);
+    send(&mut conn, "SET before 1").await;
+    send(&mut conn, "SADD set a b").await;
+    assert_eq!(send(&mut conn, "BGSAVE").await, "Background saving started");
+    let mut info = String::new();
+    for _ in 0..100 {
+        conn.get_mut().write_all(b"INFO persistence\n").await.unwrap();
+        info.clear();
+        while !info.ends_with("\n\n") {
+            conn.read_line(&mut info).await.unwrap();
+        }
+        if info.contains("rdb_bgsave_in_progress:0") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(info.contains("rdb_last_bgsave_status:ok"), "{info}");
+    // the AOF starts over from the snapshot, only later writes are appended
+    send(&mut conn, "SET after 2").await;
+    send(&mut conn, "DEL before").await;
+    shutdown.trigger();
+    tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+    let entries = kvstore::aof::Aof::replay(&aof_path).unwrap().entries;
+    assert!(entries.iter().all(|e| e.key != "set"), "{} entries", entries.len());
+
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path,
+        dbfilename,
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+    let mut conn = BufReader::new(connect(&addr).await);
+    assert_eq!(send(&mut conn, "SCARD set").await, "2");
+    assert_eq!(send(&mut conn, "GET after").await, "2");
+    assert_eq!(send(&mut conn, "EXISTS before").await, "0");
+    assert_eq!(send(&mut conn, "SAVE").await, "OK");
+    assert!(send(&mut conn, "CONFIG GET dbfilename").await.ends_with("snapshot.kvs"));
+
+    shutdown.trigger();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_concurrent_saves() {
+    use kvstore::protocol::handle_command;
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    async fn send(conn: &mut BufReader<tokio::net::TcpStream>, cmd: &str) -> String {
+        conn.get_mut().write_all(format!("{cmd}\n").as_bytes()).await.unwrap();
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        line.trim_end().to_string()
+    }
+
+    // without an AOF nothing else keeps saves apart, so they take turns at the
+    // snapshot's temp file rather than writing it at once
+    let dbfilename = temp_path("concurrent.kvs");
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        appendonly: false,
+        dbfilename: dbfilename.clone(),
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+    let mut conn = BufReader::new(connect(&addr).await);
+    let value = "x".repeat(4000);
+    let sets: String = (0..2000).map(|i| format!("SET key:{i} {value}\n")).collect();
+    conn.get_mut().write_all(sets.as_bytes()).await.unwrap();
+    for _ in 0..2000 {
+        conn.read_line(&mut String::new()).await.unwrap();
+    }
+    let saves: Vec<_> = (0..4)
+        .map(|_| {
+            let addr = addr.clone();
+            tokio::spawn(async move { send(&mut BufReader::new(connect(&addr).await), "SAVE").await })
+        })
+        .collect();
+    for save in saves {
+        assert_eq!(save.await.unwrap(), "OK");
+    }
+    let loaded = Store::new(None);
+    loaded.load_snapshot(&std::fs::read(&dbfilename).unwrap()).unwrap();
+    assert_eq!(handle_command(&loaded, "DBSIZE").to_string(), "2000");
+    shutdown.trigger();
+}
+
+#[tokio::test]
+async fn test_accepted_sockets_are_tuned() {
+    use kvstore::server::{tune_socket, Server, Shutdown};
+    use kvstore::ServerConfig;
+
+    let config = ServerConfig::load_from(["kvstore", "--tcp-backlog", "1024", "--tcp-keepalive", "60"]).unwrap();
+    assert_eq!((config.tcp_backlog, config.tcp_keepalive), (1024, 60));
+    assert!(ServerConfig::load_from(["kvstore", "--tcp-backlog", "0"]).is_err());
+    let config = ServerConfig { addrs: vec![free_addr()], aof_path: temp_path("tcp.aof"), ..config };
+    let server = Server::bind(config, Shutdown::new()).await.unwrap();
+    assert_eq!(server.local_addrs().len(), 1);
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let _client = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (socket, _) = listener.accept().await.unwrap();
+    assert!(!socket.nodelay().unwrap());
+    tune_socket(&socket, 60).unwrap();
+    assert!(socket.nodelay().unwrap());
+    assert!(socket2::SockRef::from(&socket).keepalive().unwrap());
+    #[cfg(target_os = "linux")]
+    assert_eq!(socket2::SockRef::from(&socket).tcp_keepalive_time().unwrap(), Duration::from_secs(60));
+}
+
+#[tokio::test]
+async fn test_aof_truncated_tail() {
+    use kvstore::aof::Aof;
+    use kvstore::server::{Server, Shutdown};
+    use kvstore::ServerConfig;
+
+    let good = r#"{"op":"set","key":"a","value":"1","expires_at_ms":null}"#;
+    let partial = r#"{"op":"set","key":"b","val"#;
+    let path = temp_path("truncated.aof");
+
+    // a half-written last line is left out, and repair cuts it off the file
+    std::fs::write(&path, format!("{good}\n{partial}")).unwrap();
+    let replay = Aof::replay(&path).unwrap();
+    assert_eq!((replay.entries.len(), replay.truncated_bytes), (1, partial.len() as u64));
+    replay.repair().unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), format!("{good}\n"));
+    assert_eq!(Aof::replay(&path).unwrap().truncated_bytes, 0);
+
+    // a bad line with entries after it is corruption, not a crash
+    std::fs::write(&path, format!("{good}\n{partial}\n{good}\n")).unwrap();
+    let err = Aof::replay(&path).unwrap_err().to_string();
+    assert!(err.contains("line 2"), "{err}");
+    let config = || ServerConfig { addrs: vec![free_addr()], aof_path: path.clone(), ..ServerConfig::default() };
+    assert!(Server::bind(config(), Shutdown::new()).await.is_err());
+
+    // startup drops the tail unless aof-load-truncated is off
+    std::fs::write(&path, format!("{good}\n{partial}\n")).unwrap();
+    let strict = ServerConfig { aof_load_truncated: false, ..config() };
+    assert!(Server::bind(strict, Shutdown::new()).await.is_err());
+    drop(Server::bind(config(), Shutdown::new()).await.unwrap());
+    assert_eq!(std::fs::read_to_string(format!("{path}.1")).unwrap(), format!("{good}\n"));
+}
+
+#[tokio::test]
+async fn test_aof_checksums() {
+    use kvstore::aof::{Aof, ChecksumPolicy, LogEntry};
+    use kvstore::server::{Server, Shutdown};
+    use kvstore::ServerConfig;
+
+    let entry = |key: &str| LogEntry {
+        op: "set".to_string(),
+        key: key.to_string(),
+        value: Some("1".to_string()),
+        expires_at_ms: None,
+        values: None,
+    };
+    // the CRC-32 of the JSON, as zlib computes it
+    let line = entry("a").to_line().unwrap();
+    assert_eq!(line, r#"16447d28 {"op":"set","key":"a","value":"1","expires_at_ms":null}"#);
+
+    // entries from before checksums still load, counted as unverified
+    let path = temp_path("checksums.aof");
+    let legacy = r#"{"op":"set","key":"old","value":"1","expires_at_ms":null}"#;
+    let corrupt = entry("b").to_line().unwrap().replace(r#""1""#, r#""2""#);
+    let lines = [legacy.to_string(), corrupt, entry("c").to_line().unwrap()];
+    std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+    let replay = Aof::replay(&path).unwrap();
+    let keys: Vec<_> = replay.entries.iter().map(|e| e.key.as_str()).collect();
+    assert_eq!(keys, ["old", "c"]);
+    assert_eq!((replay.checksum_failures, replay.unverified, replay.truncated_bytes), (1, 1, 0));
+
+    // startup refuses the file unless told to skip bad entries
+    let config = ServerConfig::load_from(["kvstore", "--aof-checksum-policy", "skip"]).unwrap();
+    assert_eq!(config.aof_checksum_policy, ChecksumPolicy::Skip);
+    assert_eq!(ServerConfig::default().aof_checksum_policy, ChecksumPolicy::Abort);
+    let abort = ServerConfig { addrs: vec![free_addr()], aof_path: path.clone(), ..ServerConfig::default() };
+    assert!(Server::bind(abort, Shutdown::new()).await.is_err());
+    let skip = ServerConfig { addrs: vec![free_addr()], aof_path: path.clone(), ..config };
+    drop(Server::bind(skip, Shutdown::new()).await.unwrap());
+
+    // the writer checksums what it logs
+    let path = temp_path("checksums-written.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    aof.log(entry("k"));
+    aof.flush().await.unwrap();
+    let written = std::fs::read_to_string(format!("{path}.1")).unwrap();
+    assert_eq!(written.lines().nth(1), Some(entry("k").to_line().unwrap().as_str()));
+    let replay = Aof::replay(&path).unwrap();
+    assert_eq!((replay.entries.len(), replay.unverified), (1, 0));
+}
+
+#[tokio::test]
+async fn test_aof_binary_format() {
+    use kvstore::aof::{Aof, AofFormat, LogEntry};
+    use kvstore::ServerConfig;
+
+    let entries = [
+        LogEntry { op: "set".into(), key: "a".into(), value: Some("1".into()), expires_at_ms: None, values: None },
+        LogEntry { op: "set".into(), key: "t".into(), value: Some("x".into()), expires_at_ms: Some(4_102_444_800_000), values: None },
+        LogEntry { op: "rpush".into(), key: "l".into(), value: None, expires_at_ms: None, values: Some(vec!["p".into(), "q".into()]) },
+    ];
+    let summary = |entries: &[LogEntry]| {
+        entries.iter().map(|e| format!("{} {} {:?} {:?} {:?}", e.op, e.key, e.value, e.expires_at_ms, e.values)).collect::<Vec<_>>()
+    };
+    for entry in &entries {
+        assert!(entry.to_frame().len() < entry.to_line().unwrap().len());
+    }
+
+    // a JSON file keeps its format, the next file is binary and both replay
+    let path = temp_path("binary.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    aof.log(entries[0].clone());
+    aof.flush().await.unwrap();
+    aof.set_format(AofFormat::Binary);
+    aof.log(entries[1].clone());
+    aof.rotate().await.unwrap();
+    aof.log(entries[1].clone());
+    aof.log(entries[2].clone());
+    aof.flush().await.unwrap();
+    let first = format!("{path}.1");
+    let live = format!("{path}.2");
+    assert!(std::fs::read_to_string(&first).unwrap().lines().nth(1).unwrap().starts_with("16447d28 "));
+    assert!(std::fs::read(&live).unwrap().starts_with(b"KVAOF\x01"));
+    let replay = Aof::replay(&path).unwrap();
+    assert_eq!(summary(&replay.entries), summary(&[entries[0].clone(), entries[1].clone(), entries[1].clone(), entries[2].clone()]));
+    assert_eq!(replay.unverified, 0);
+
+    // a frame cut short at the end is a truncated tail, a flipped byte a checksum failure
+    let full = std::fs::read(&live).unwrap();
+    std::fs::write(&live, &full[..full.len() - 3]).unwrap();
+    let replay = Aof::replay(&path).unwrap();
+    assert_eq!((replay.entries.len(), replay.truncated_bytes), (3, entries[2].to_frame().len() as u64 - 3));
+    replay.repair().unwrap();
+    assert_eq!(Aof::replay(&path).unwrap().truncated_bytes, 0);
+    let mut flipped = full.clone();
+    flipped[6 + 8] ^= 0xff;
+    std::fs::write(&live, &flipped).unwrap();
+    let replay = Aof::replay(&path).unwrap();
+    assert_eq!((replay.entries.len(), replay.checksum_failures), (3, 1));
+
+    // a version this build doesn't know is refused
+    let mut future = full;
+    future[5] = 2;
+    std::fs::write(&live, &future).unwrap();
+    assert!(Aof::replay(&path).unwrap_err().to_string().contains("version 2"));
+
+    let config = ServerConfig::load_from(["kvstore", "--aof-format", "binary"]).unwrap();
+    assert_eq!(config.aof_format, AofFormat::Binary);
+    assert_eq!(ServerConfig::default().aof_format, AofFormat::Json);
+    assert!(ServerConfig::load_from(["kvstore", "--aof-format", "xml"]).is_err());
+}
+
+#[tokio::test]
+async fn test_appendfsync() {
+    use kvstore::aof::{Aof, AppendFsync, LogEntry};
+    use kvstore::ServerConfig;
+
+    let entry = || LogEntry {
+        op: "set".to_string(),
+        key: "k".to_string(),
+        value: Some("v".to_string()),
+        expires_at_ms: None,
+        values: None,
+    };
+    async fn drained(aof: &Aof) {
+        while aof.pending() > 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    let config = ServerConfig::load_from(["kvstore", "--appendfsync", "always"]).unwrap();
+    assert_eq!(config.appendfsync, AppendFsync::Always);
+    assert_eq!(ServerConfig::default().appendfsync, AppendFsync::EverySec);
+    assert!(ServerConfig::load_from(["kvstore", "--appendfsync", "sometimes"]).is_err());
+
+    // always: synced before the entry stops counting as pending
+    let aof = Aof::new(&temp_path("fsync_always.aof")).await.unwrap();
+    aof.set_fsync(AppendFsync::Always);
+    aof.log(entry());
+    drained(&aof).await;
+    assert!(aof.stats().last_fsync > 0);
+
+    // no: written, but never synced by the writer
+    let aof = Aof::new(&temp_path("fsync_no.aof")).await.unwrap();
+    aof.set_fsync(AppendFsync::No);
+    aof.log(entry());
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+    let stats = aof.stats();
+    assert_eq!((stats.fsync, stats.last_fsync, stats.pending), (AppendFsync::No, 0, 0));
+
+    // everysec: the timer picks up what was written
+    aof.set_fsync(AppendFsync::EverySec);
+    aof.log(entry());
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+    assert!(aof.stats().last_fsync > 0);
+}
+
+#[tokio::test]
+async fn test_aof_write_stats() {
+    use kvstore::aof::{Aof, LogEntry};
+
+    let path = temp_path("write_stats.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    let stats = aof.stats();
+    assert_eq!((stats.entries_written, stats.bytes_written, stats.last_write), (0, 0, 0));
+    assert_eq!(stats.last_error, None);
+
+    for i in 0..3 {
+        aof.log(LogEntry { op: "set".to_string(), key: format!("k{i}"), value: Some("v".to_string()), expires_at_ms: None, values: None });
+    }
+    aof.flush().await.unwrap();
+    let stats = aof.stats();
+    assert_eq!(stats.entries_written, 3);
+    assert_eq!(stats.bytes_written, stats.current_size);
+    assert!(stats.last_write > 0 && stats.last_fsync > 0);
+
+    // INFO renders the same fields
+    let fields = stats.fields();
+    assert!(fields.contains(&("aof_entries_written", "3".to_string())));
+    assert!(fields.contains(&("aof_last_error", String::new())));
+}
+
+#[tokio::test]
+async fn test_key_version() {
+    use kvstore::{aof::Aof, protocol::handle_command};
+
+    let path = temp_path("version.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    let store = Store::new(Some(aof.clone()));
+    let version = |store: &Store, key: &str| match store.version_of(key) {
+        Response::Integer(v) => v,
+        other => panic!("expected an integer, got {other:?}"),
+    };
+
+    assert_eq!(version(&store, "k"), 0);
+    handle_command(&store, "SET k a");
+    let first = version(&store, "k");
+    handle_command(&store, "SET k b");
+    let second = version(&store, "k");
+    assert!(second > first);
+
+    // reads leave it alone, other writes bump it
+    handle_command(&store, "GET k");
+    assert_eq!(version(&store, "k"), second);
+    handle_command(&store, "EXPIRE k 100");
+    assert!(version(&store, "k") > second);
+    handle_command(&store, "SADD s a");
+    let set = version(&store, "s");
+    handle_command(&store, "SADD s a");
+    assert_eq!(version(&store, "s"), set);
+    handle_command(&store, "SREM s nosuch");
+    assert_eq!(version(&store, "s"), set);
+
+    // a deleted and recreated key never reuses an old version
+    let before = version(&store, "k");
+    handle_command(&store, "DEL k");
+    assert_eq!(handle_command(&store, "VERSION k").to_string(), "0");
+    handle_command(&store, "SET k c");
+    assert!(version(&store, "k") > before);
+
+    // replay never hands out a lower version than before the restart
+    let live = (version(&store, "k"), version(&store, "s"));
+    aof.flush().await.unwrap();
+    let replayed = Store::new(None);
+    replayed.load_from_aof(Aof::replay(&path).unwrap().entries);
+    assert!(version(&replayed, "k") >= live.0);
+    assert!(version(&replayed, "s") >= live.1);
+    assert!(version(&replayed, "k") > version(&replayed, "s"));
+}
+
+#[tokio::test]
+async fn test_dump_restore() {
+    use kvstore::{aof::Aof, protocol::handle_command};
+
+    let path = temp_path("dump_restore.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    let store = Store::new(Some(aof.clone()));
+    let run = |cmd: &str| handle_command(&store, cmd).to_string();
+    let dump = |key: &str| match store.dump(key) {
+        Response::BulkString(Some(payload)) => payload,
+        other => panic!("expected a payload, got {other:?}"),
+    };
+
+    run("SET s hello");
+    run("LPUSH l c b a");
+    run("SADD set 1 2 x");
+    run("HSET h f v");
+    assert!(matches!(store.dump("missing"), Response::Nil));
+
+    for key in ["s", "l", "set", "h"] {
+        assert_eq!(run(&format!("RESTORE {key}:copy 0 {}", dump(key))), "OK");
+    }
+    assert_eq!(run("GET s:copy"), "hello");
+    assert_eq!(run("LPOP l:copy"), "c");
+    assert_eq!(run("SCARD set:copy"), "3");
+    assert_eq!(run("OBJECT ENCODING set:copy"), "hashtable");
+    assert_eq!(dump("h:copy"), dump("h"));
+
+    // an existing key needs REPLACE
+    let payload = dump("s");
+    assert!(run(&format!("RESTORE l 0 {payload}")).starts_with("BUSYKEY"));
+    assert_eq!(run(&format!("RESTORE l 0 {payload} REPLACE")), "OK");
+    assert_eq!(run("GET l"), "hello");
+
+    // a damaged payload or another format version is refused
+    let flipped = format!("{}{}", if payload.starts_with('0') { "1" } else { "0" }, &payload[1..]);
+    assert!(run(&format!("RESTORE bad 0 {flipped}")).contains("version or checksum are wrong"));
+    assert!(run("RESTORE bad 0 nothex").contains("version or checksum are wrong"));
+    assert!(run(&format!("RESTORE bad -1 {payload}")).contains("Invalid TTL"));
+    assert_eq!(run("EXISTS bad"), "0");
+
+    assert_eq!(run(&format!("RESTORE ttl 100000 {payload}")), "OK");
+    assert!(matches!(store.ttl("ttl"), Response::Integer(t) if t > 0 && t <= 100));
+
+    // RESTORE is logged, so the keys come back after a restart
+    aof.flush().await.unwrap();
+    let replayed = Store::new(None);
+    replayed.load_from_aof(Aof::replay(&path).unwrap().entries);
+    assert_eq!(handle_command(&replayed, "GET l").to_string(), "hello");
+    assert_eq!(handle_command(&replayed, "LLEN l:copy").to_string(), "2");
+    assert_eq!(handle_command(&replayed, "SCARD set:copy").to_string(), "3");
+    assert_eq!(replayed.dump("h:copy").to_string(), dump("h"));
+}
+
+#[tokio::test]
+async fn test_exists_ttl_under_read_lock() {
+    use std::sync::mpsc;
+
+    let store = Store::new(None);
+    store.set("live".to_string(), "v".to_string(), None);
+    store.set("gone".to_string(), "v".to_string(), None);
+    store.pexpire("gone", 1, Default::default());
+    tokio::time::sleep(Duration::from_millis(20)).await;
+
+    // a reader holds the keyspace lock, parallel EXISTS and TTL still answer
+    let (held_tx, held_rx) = mpsc::channel();
+    let (release_tx, release_rx) = mpsc::channel::<()>();
+    let holder = {
+        let store = store.clone();
+        std::thread::spawn(move || store.for_each(|_, _| {
+            held_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        }))
+    };
+    held_rx.recv().unwrap();
+    let (done_tx, done_rx) = mpsc::channel();
+    for _ in 0..4 {
+        let (store, done_tx) = (store.clone(), done_tx.clone());
+        std::thread::spawn(move || {
+            for _ in 0..1000 {
+                assert_eq!(store.exists("live").to_string(), "1");
+                assert_eq!(store.exists("gone").to_string(), "0");
+                assert_eq!(store.pttl("gone").to_string(), "-2");
+                assert_eq!(store.ttl("live").to_string(), "-1");
+            }
+            done_tx.send(()).unwrap();
+        });
+    }
+    for _ in 0..4 {
+        done_rx.recv_timeout(Duration::from_secs(5)).expect("EXISTS waited on the reader");
+    }
+    release_tx.send(()).unwrap();
+    holder.join().unwrap();
+
+    // the expired key is only deleted by the sweeper
+    assert_eq!(store.key_counts().0, 2);
+    tokio::spawn(store.clone().start_sweeper(3600));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(store.key_counts().0, 1);
+
+    // which takes keys found expired as they come, not at the next sweep
+    store.set("later".to_string(), "v".to_string(), None);
+    store.pexpire("later", 1, Default::default());
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(store.exists("later").to_string(), "0");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(store.key_counts().0, 1);
+}
+
+#[tokio::test]
+async fn test_expiry_index() {
+    use kvstore::aof::LogEntry;
+    use kvstore::protocol::handle_command;
+
+    let store = Store::new(None);
+    let run = |cmd: &str| handle_command(&store, cmd).to_string();
+    for i in 0..5000 {
+        run(&format!("SET keep:{i} v"));
+    }
+    for i in 0..100 {
+        run(&format!("SET temp:{i} v PX 20"));
+    }
+    // indexed with a deadline that's gone stale by the time it passes
+    run("SET overwritten v PX 20");
+    run("SET overwritten v");
+    run("SET extended v PX 20");
+    run("PEXPIRE extended 100000");
+    run("SET recreated v PX 20");
+    run("DEL recreated");
+    run("LPUSH recreated a");
+    run("SET restored v PX 20");
+    let payload = run("DUMP restored");
+    run(&format!("RESTORE restored 0 {payload} REPLACE"));
+    run("SET now v");
+    store.expire_now("now");
+    tokio::time::sleep(Duration::from_millis(30)).await;
+
+    tokio::spawn(store.clone().start_sweeper(3600));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(store.key_counts(), (5004, 1));
+    assert_eq!(store.entry_count(), 5004);
+    for key in ["overwritten", "extended", "recreated", "restored"] {
+        assert_eq!(store.exists(key).to_string(), "1", "{key}");
+    }
+    assert_eq!(run("EXISTS now"), "0");
+
+    // keys loaded from the AOF are indexed too
+    let later = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as i64 + 20;
+    let loaded = Store::new(None);
+    loaded.load_from_aof((0..50).map(|i| LogEntry {
+        op: "set".into(),
+        key: format!("k{i}"),
+        value: Some("v".into()),
+        expires_at_ms: (i % 2 == 0).then_some(later),
+        values: None,
+    }));
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    tokio::spawn(loaded.clone().start_sweeper(3600));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(loaded.key_counts(), (25, 0));
+}
+
+#[tokio::test]
+async fn test_sharded_keyspace() {
+    use kvstore::protocol::handle_command;
+    use std::sync::mpsc;
+
+    let store = Store::new(None);
+    let run = |cmd: &str| handle_command(&store, cmd).to_string();
+    for i in 0..1000 {
+        run(&format!("SET key:{i} v"));
+    }
+    run("SADD a 1 2 3");
+    run("SET bits a");
+    assert_eq!(run("DBSIZE"), "1002");
+    assert_eq!(handle_command(&store, "KEYS key:").to_string().split(' ').count(), 1000);
+
+    // multi-key commands lock their shards in order, so they can't deadlock
+    // however the keys are ordered between threads
+    let (done_tx, done_rx) = mpsc::channel();
+    for t in 0..4 {
+        let (store, done_tx) = (store.clone(), done_tx.clone());
+        std::thread::spawn(move || {
+            let (src, dst) = if t % 2 == 0 { ("a", "b") } else { ("b", "a") };
+            for i in 0..2000 {
+                handle_command(&store, &format!("SMOVE {src} {dst} {}", i % 3 + 1));
+                handle_command(&store, &format!("BITOP OR key:{} bits key:{}", i % 1000, (i + 1) % 1000));
+                handle_command(&store, &format!("LCS key:{} bits", i % 1000));
+                handle_command(&store, &format!("SET key:{} v", i % 1000));
+            }
+            done_tx.send(()).unwrap();
+        });
+    }
+    for _ in 0..4 {
+        done_rx.recv_timeout(Duration::from_secs(30)).expect("multi-key commands deadlocked");
+    }
+    assert_eq!(handle_command(&store, "SCARD a").to_string().parse::<i64>().unwrap()
+        + handle_command(&store, "SCARD b").to_string().parse::<i64>().unwrap(), 3);
+
+    // the sweeper goes through every shard
+    for i in 0..500 {
+        store.pexpire(&format!("key:{i}"), 1, Default::default());
+    }
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    tokio::spawn(store.clone().start_sweeper(3600));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(store.key_counts(), (502, 0));
+    assert_eq!(run("DELPATTERN key:* CONFIRM"), "500");
+    assert_eq!(run("DBSIZE"), "2");
+
+    // a single shard behaves the same
+    let single = Store::with_shards(None, 1);
+    assert_eq!(handle_command(&single, "SADD a 1 2").to_string(), "2");
+    assert_eq!(handle_command(&single, "SMOVE a b 1").to_string(), "1");
+    assert_eq!(handle_command(&single, "KEYS \"\"").to_string().split(' ').count(), 2);
+}
+
+#[tokio::test]
+async fn test_reads_under_read_lock() {
+    use kvstore::protocol::handle_command;
+    use std::sync::mpsc;
+
+    let store = Store::new(None);
+    for cmd in [
+        "SET s hello", "LPUSH l a b", "SADD set x y", "HSET h f v",
+        "SET s:gone v", "LPUSH l:gone a", "SADD set:gone x", "HSET h:gone f v",
+    ] {
+        handle_command(&store, cmd);
+    }
+    for key in ["s:gone", "l:gone", "set:gone", "h:gone"] {
+        store.pexpire(key, 1, Default::default());
+    }
+    tokio::time::sleep(Duration::from_millis(20)).await;
+
+    // a reader holds the keyspace lock, every other read still answers
+    let (held_tx, held_rx) = mpsc::channel();
+    let (release_tx, release_rx) = mpsc::channel::<()>();
+    let holder = {
+        let store = store.clone();
+        let mut hold = Some((held_tx, release_rx));
+        std::thread::spawn(move || store.for_each(|_, _| {
+            if let Some((held_tx, release_rx)) = hold.take() {
+                held_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+            }
+        }))
+    };
+    held_rx.recv().unwrap();
+    let (done_tx, done_rx) = mpsc::channel();
+    {
+        let store = store.clone();
+        std::thread::spawn(move || {
+            let reads = [
+                ("GET s", "hello"), ("GETRANGE s 0 1", "he"), ("LLEN l", "2"), ("SCARD set", "2"),
+                ("HRANDFIELD h", "f"), ("BITPOS s 1", "1"), ("LCS s s", "hello"),
+                ("GET s:gone", "(nil)"), ("LLEN l:gone", "0"), ("SCARD set:gone", "0"),
+                ("HRANDFIELD h:gone", "(nil)"), ("DUMP s:gone", "(nil)"), ("KEYS s:", "(empty)"), ("KEYS set", "set"),
+            ];
+            for (cmd, want) in reads {
+                assert_eq!(handle_command(&store, cmd).to_string(), want, "{cmd}");
+            }
+            done_tx.send(()).unwrap();
+        });
+    }
+    done_rx.recv_timeout(Duration::from_secs(5)).expect("a read waited on the reader");
+    release_tx.send(()).unwrap();
+    holder.join().unwrap();
+
+    // the expired keys read as missing, and the sweeper deletes them as soon
+    // as it's told, not at its next sweep
+    assert_eq!(store.key_counts().0, 8);
+    tokio::spawn(store.clone().start_sweeper(3600));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(store.key_counts().0, 4);
+    handle_command(&store, "SET later v PX 1");
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(handle_command(&store, "GET later").to_string(), "(nil)");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(store.key_counts().0, 4);
+}
+
+#[tokio::test]
+async fn test_json_export_import() {
+    use kvstore::{aof::Aof, protocol::handle_command};
+
+    let store = Store::new(None);
+    for cmd in ["SET s hello", "EXPIRE s 1000", "LPUSH l c b a", "SADD ints 3 1 2", "SADD words b a", "HSET h f1 v1 f2 v2", "SET gone x"] {
+        handle_command(&store, cmd);
+    }
+    store.pexpire("gone", 1, Default::default());
+    tokio::time::sleep(Duration::from_millis(20)).await;
+
+    let mut exported = Vec::new();
+    assert_eq!(store.export_json(&mut exported).unwrap(), 5);
+    let text = String::from_utf8(exported.clone()).unwrap();
+    assert_eq!(text.lines().next().unwrap(), r#"{"key":"h","type":"hash","value":{"f1":"v1","f2":"v2"},"expires_at_ms":null}"#);
+    assert!(text.contains(r#"{"key":"ints","type":"set","value":["1","2","3"],"expires_at_ms":null}"#));
+    assert!(!text.contains("gone"));
+
+    // merging keeps keys that aren't in the import
+    let path = temp_path("json_import.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    let imported = Store::new(Some(aof.clone()));
+    handle_command(&imported, "SET other 1");
+    handle_command(&imported, "SET s old");
+    assert_eq!(imported.import_json(exported.as_slice(), false).unwrap(), 5);
+    assert_eq!(handle_command(&imported, "GET s").to_string(), "hello");
+    assert_eq!(handle_command(&imported, "GET other").to_string(), "1");
+    assert_eq!(handle_command(&imported, "OBJECT ENCODING ints").to_string(), "intset");
+
+    // replacing doesn't, and the round trip is exact
+    assert_eq!(imported.import_json(exported.as_slice(), true).unwrap(), 5);
+    assert_eq!(handle_command(&imported, "EXISTS other").to_string(), "0");
+    let mut again = Vec::new();
+    imported.export_json(&mut again).unwrap();
+    assert_eq!(again, exported);
+
+    // a bad line changes nothing
+    let bad = format!("{text}{{\"key\":\"x\",\"type\":\"zset\",\"value\":[]}}\n");
+    assert!(imported.import_json(bad.as_bytes(), true).unwrap_err().to_string().contains("line 6"));
+    assert_eq!(handle_command(&imported, "DBSIZE").to_string(), "5");
+
+    // imports are logged, so they persist
+    aof.flush().await.unwrap();
+    let replayed = Store::new(None);
+    replayed.load_from_aof(Aof::replay(&path).unwrap().entries);
+    let mut persisted = Vec::new();
+    replayed.export_json(&mut persisted).unwrap();
+    assert_eq!(persisted, exported);
+}
+
+#[test]
+fn test_expiretime() {
+    use kvstore::protocol::handle_command;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let store = Store::new(None);
+    let cmd = |c: &str| handle_command(&store, c).to_string();
+    store.set("k".to_string(), "v".to_string(), None);
+    assert_eq!(cmd("EXPIRETIME k"), "-1");
+    assert_eq!(cmd("PEXPIRETIME k"), "-1");
+    assert_eq!(cmd("EXPIRETIME missing"), "-2");
+
+    // EXPIREAT reads back exactly, with no jitter
+    store.set_ttl_jitter_pct(50);
+    let at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 1000;
+    assert_eq!(cmd(&format!("EXPIREAT k {at}")), "1");
+    assert_eq!(cmd("EXPIRETIME k"), at.to_string());
+    assert_eq!(cmd("PEXPIRETIME k"), (at * 1000).to_string());
+    assert_eq!(cmd(&format!("PEXPIREAT k {} GT", at * 1000 + 1500)), "1");
+    assert_eq!(cmd("PEXPIRETIME k"), (at * 1000 + 1500).to_string());
+    assert_eq!(cmd("EXPIRETIME k"), (at + 1).to_string());
+
+    // a time in the past deletes the key
+    assert_eq!(cmd("EXPIREAT k 1"), "1");
+    assert_eq!(cmd("EXISTS k"), "0");
+    assert_eq!(cmd("EXPIRETIME k"), "-2");
+}
+
+#[tokio::test]
+async fn test_config_set_appendonly() {
+    use kvstore::aof::Aof;
+    use kvstore::protocol::handle_command;
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    async fn send(conn: &mut BufReader<tokio::net::TcpStream>, cmd: &str) -> String {
+        conn.get_mut().write_all(format!("{cmd}\n").as_bytes()).await.unwrap();
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        line.trim_end().to_string()
+    }
+
+    let aof_path = temp_path("appendonly.aof");
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: aof_path.clone(),
+        appendonly: false,
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    let server = tokio::spawn(serve(config, shutdown.clone()));
+    let mut conn = BufReader::new(connect(&addr).await);
+
+    // off from the start: nothing is logged
+    send(&mut conn, "SET before 1").await;
+    send(&mut conn, "LPUSH l a b").await;
+    assert!(send(&mut conn, "CONFIG GET appendonly").await.ends_with("no"));
+    assert!(Aof::replay(&aof_path).unwrap().entries.is_empty());
+    assert!(send(&mut conn, "BGREWRITEAOF").await.contains("persistence is disabled"));
+    assert!(send(&mut conn, "CONFIG SET appendonly maybe").await.contains("Invalid argument"));
+
+    // turning it on rewrites the AOF from the dataset before replying
+    assert_eq!(send(&mut conn, "CONFIG SET appendonly yes").await, "OK");
+    assert!(send(&mut conn, "CONFIG GET appendonly").await.ends_with("yes"));
+    assert_eq!(send(&mut conn, "CONFIG SET appendonly yes").await, "OK");
+    send(&mut conn, "SET during 2").await;
+    let store = Store::new(None);
+    store.load_from_aof(Aof::replay(&aof_path).unwrap().entries);
+    assert_eq!(handle_command(&store, "GET before").to_string(), "1");
+    assert_eq!(handle_command(&store, "LLEN l").to_string(), "2");
+
+    // turning it off flushes what was logged, later writes stay out
+    assert_eq!(send(&mut conn, "CONFIG SET appendonly no").await, "OK");
+    let logged = Aof::replay(&aof_path).unwrap().entries;
+    assert!(logged.iter().any(|e| e.key == "during"));
+    send(&mut conn, "SET after 3").await;
+    shutdown.trigger();
+    tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+    let entries = Aof::replay(&aof_path).unwrap().entries;
+    assert_eq!(entries.len(), logged.len());
+    assert!(entries.iter().all(|e| e.key != "after"));
+}
+
+#[test]
+fn test_wrongtype_names_actual_type() {
+    use kvstore::protocol::handle_command;
+
+    let store = Store::new(None);
+    handle_command(&store, "SET str v");
+    handle_command(&store, "LPUSH list a");
+    handle_command(&store, "SADD set a");
+    handle_command(&store, "HSET hash f v");
+
+    let wrongtype = |cmd: &str, actual: &str| {
+        let reply = handle_command(&store, cmd).to_string();
+        assert!(reply.starts_with("WRONGTYPE Operation against a key holding the wrong kind of value"), "{cmd}: {reply}");
+        assert!(reply.ends_with(&format!("it holds a {actual}")), "{cmd}: {reply}");
+    };
+    wrongtype("INCR list", "list");
+    wrongtype("GET set", "set");
+    wrongtype("LPUSH hash x", "hash");
+    wrongtype("LPOP str", "string");
+    wrongtype("SADD list x", "list");
+    wrongtype("SCARD hash", "hash");
+    wrongtype("HSET set f v", "set");
+    wrongtype("HRANDFIELD str", "string");
+    wrongtype("SMOVE set list a", "list");
+
+    // nothing was changed by the rejected commands
+    assert_eq!(handle_command(&store, "LLEN list").to_string(), "1");
+    assert_eq!(handle_command(&store, "GET str").to_string(), "v");
+}
+
+#[test]
+fn test_aof_replay_iter() {
+    use kvstore::aof::{Aof, LogEntry, ReplayError};
+
+    let entry = |key: &str, value: &str| LogEntry {
+        op: "set".to_string(),
+        key: key.to_string(),
+        value: Some(value.to_string()),
+        expires_at_ms: None,
+        values: None,
+    };
+    let path = temp_path("replay-iter.aof");
+    let lines: Vec<String> = (0..5).map(|i| entry(&format!("k{}", i % 2), &i.to_string()).to_line().unwrap()).collect();
+    // a half-written tail is left out and counted, as with `replay`
+    let tail = r#"0000 {"op":"se"#;
+    let text = lines.join("\n") + "\n" + tail;
+    std::fs::write(&path, &text).unwrap();
+
+    // the store takes the entries as they're read
+    let mut replay = Aof::replay_iter(&path).log_every(2);
+    let store = Store::new(None);
+    let stats = store.load_from_aof(replay.by_ref().map(Result::unwrap));
+    assert_eq!(stats.keys_loaded, 5);
+    assert_eq!(kvstore::protocol::handle_command(&store, "GET k0").to_string(), "4");
+    assert_eq!(replay.entries_read(), 5);
+    assert_eq!(replay.bytes_read(), text.len() as u64);
+    assert_eq!(replay.summary().truncated_bytes, tail.len() as u64);
+    assert_eq!(Aof::replay(&path).unwrap().entries.len(), 5);
+
+    // a corrupt entry before the end stops it, after the entries ahead of it
+    let corrupt = format!("{}\n{{\"op\"\n{}\n", lines[0], lines[1]);
+    std::fs::write(&path, corrupt).unwrap();
+    let results: Vec<_> = Aof::replay_iter(&path).collect();
+    assert_eq!(results.len(), 2);
+    assert!(results[0].is_ok());
+    match &results[1] {
+        Err(e @ ReplayError::Corrupt(_)) => assert!(e.to_string().contains("line 2: corrupt AOF entry"), "{e}"),
+        other => panic!("expected a corrupt entry, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn test_aof_version_header() {
+    use kvstore::aof::{Aof, LogEntry, JSON_VERSION};
+
+    let entry = |key: &str| LogEntry {
+        op: "set".to_string(),
+        key: key.to_string(),
+        value: Some("1".to_string()),
+        expires_at_ms: None,
+        values: None,
+    };
+
+    // version 0: a JSON AOF from before the header still loads and is appended to
+    let path = temp_path("header-v0.aof");
+    std::fs::write(&path, entry("old").to_line().unwrap() + "\n").unwrap();
+    assert_eq!(Aof::replay(&path).unwrap().entries.len(), 1);
+    let aof = Aof::new(&path).await.unwrap();
+    aof.log(entry("new"));
+    aof.flush().await.unwrap();
+    let keys: Vec<_> = Aof::replay(&path).unwrap().entries.into_iter().map(|e| e.key).collect();
+    assert_eq!(keys, ["old", "new"]);
+
+    // version 1: a new file starts with the magic, the version and when it was created
+    let path = temp_path("header-v1.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    aof.log(entry("a"));
+    aof.flush().await.unwrap();
+    let text = std::fs::read_to_string(format!("{path}.1")).unwrap();
+    let header: Vec<_> = text.lines().next().unwrap().split(' ').collect();
+    assert_eq!(header[..2], ["#KVAOF-JSON", &JSON_VERSION.to_string()]);
+    assert!(header[2].parse::<u64>().unwrap() > 0);
+    let replay = Aof::replay(&path).unwrap();
+    assert_eq!((replay.entries.len(), replay.unverified, replay.truncated_bytes), (1, 0, 0));
+    // a rewrite starts its base with a header too
+    aof.begin_rewrite().unwrap();
+    aof.finish_rewrite(vec![entry("b")]).await.unwrap();
+    let base = kvstore::aof::segments(&path).pop().unwrap();
+    assert!(std::fs::read_to_string(base).unwrap().starts_with("#KVAOF-JSON 1 "));
+    assert_eq!(Aof::replay(&path).unwrap().entries.len(), 1);
+
+    // an unknown, newer version is neither replayed nor appended to
+    let path = temp_path("header-v9.aof");
+    std::fs::write(&path, format!("#KVAOF-JSON 9 0\n{}\n", entry("a").to_line().unwrap())).unwrap();
+    let err = Aof::replay(&path).err().unwrap();
+    assert!(err.to_string().contains("version 9 is newer"), "{err}");
+    let err = Aof::new(&path).await.err().unwrap();
+    assert!(err.to_string().contains("refusing to append"), "{err}");
+}
+
+#[test]
+fn test_keys_limit() {
+    use kvstore::protocol::handle_command;
+    use kvstore::KeysLimitPolicy;
+
+    let store = Store::new(None);
+    for i in 0..50 {
+        store.set(format!("user:{i}"), "x".to_string(), None);
+    }
+    store.set("other".to_string(), "x".to_string(), None);
+    let count = |resp: Response| match resp {
+        Response::Array(items) => items.len(),
+        other => panic!("unexpected {other}"),
+    };
+    assert_eq!(count(handle_command(&store, "KEYS \"\"")), 51);
+
+    // over the whole keyspace it's refused, a prefix still answers in full
+    store.set_keys_limit(10);
+    let refused = handle_command(&store, "KEYS \"\"").to_string();
+    assert!(refused.contains("would return 51 keys, more than keys-limit 10"), "{refused}");
+    assert_eq!(count(handle_command(&store, "KEYS user:")), 50);
+    assert_eq!(count(handle_command(&store, "KEYS oth")), 1);
+
+    store.set_keys_limit_policy(KeysLimitPolicy::Truncate);
+    assert_eq!(count(handle_command(&store, "KEYS \"\"")), 10);
+    assert_eq!(count(handle_command(&store, "KEYS user:")), 10);
+    assert_eq!(count(handle_command(&store, "KEYS oth")), 1);
+
+    store.set_keys_limit(0);
+    assert_eq!(count(handle_command(&store, "KEYS \"\"")), 51);
+}
+
+#[tokio::test]
+async fn test_keys_big_scan_slowlog() {
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::ServerConfig;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    async fn send(conn: &mut BufReader<tokio::net::TcpStream>, cmd: &str) -> String {
+        conn.get_mut().write_all(format!("{cmd}\n").as_bytes()).await.unwrap();
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        line.trim_end().to_string()
+    }
+
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("keys-slowlog.aof"),
+        slowlog_log_slower_than: 10_000_000,
+        slowlog_keys_scanned: 20,
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    let server = tokio::spawn(serve(config, shutdown.clone()));
+    let mut conn = BufReader::new(connect(&addr).await);
+
+    assert!(send(&mut conn, "CONFIG GET slowlog-keys-scanned").await.ends_with("20"));
+    for i in 0..20 {
+        send(&mut conn, &format!("SET k{i} v")).await;
+    }
+    // at the threshold nothing is logged, past it KEYS is however fast it ran
+    send(&mut conn, "KEYS nomatch").await;
+    assert_eq!(send(&mut conn, "SLOWLOG LEN").await, "0");
+    send(&mut conn, "SET k20 v").await;
+    send(&mut conn, "KEYS nomatch").await;
+    assert_eq!(send(&mut conn, "SLOWLOG LEN").await, "1");
+    send(&mut conn, "DBSIZE").await;
+    assert_eq!(send(&mut conn, "SLOWLOG LEN").await, "1");
+
+    assert_eq!(send(&mut conn, "CONFIG SET slowlog-keys-scanned 0").await, "OK");
+    send(&mut conn, "KEYS nomatch").await;
+    assert_eq!(send(&mut conn, "SLOWLOG LEN").await, "1");
+
+    assert_eq!(send(&mut conn, "CONFIG SET keys-limit 5").await, "OK");
+    assert!(send(&mut conn, "CONFIG GET keys-limit-policy").await.ends_with("require-prefix"));
+    assert!(send(&mut conn, "KEYS \"\"").await.contains("more than keys-limit 5"));
+    assert!(send(&mut conn, "CONFIG SET keys-limit-policy sometimes").await.contains("Invalid argument"));
+
+    shutdown.trigger();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_set_pxat_survives_replay() {
+    use kvstore::aof::Aof;
+    use kvstore::protocol::handle_command;
+    use kvstore::snapshot;
+
+    let path = temp_path("set-pxat.aof");
+    let aof = Aof::new(&path).await.unwrap();
+    let store = Store::new(Some(aof.clone()));
+    let now_ms = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as i64;
+    let at = now_ms + 60_000;
+    let pttl = |store: &Store, key: &str| match store.pttl(key) {
+        Response::Integer(ms) => ms,
+        other => panic!("unexpected {other}"),
+    };
+
+    assert_eq!(handle_command(&store, &format!("SET pxat v PXAT {at}")).to_string(), "OK");
+    assert_eq!(handle_command(&store, &format!("SET exat v EXAT {}", at / 1000)).to_string(), "OK");
+    assert_eq!(handle_command(&store, "SET px v PX 60000 NX").to_string(), "OK");
+    assert_eq!(handle_command(&store, "SET past v PXAT 1").to_string(), "OK");
+    assert_eq!(handle_command(&store, "PEXPIRETIME pxat").to_string(), at.to_string());
+    assert!(handle_command(&store, "SET bad v PXAT soon").to_string().contains("invalid PXAT ttl"));
+    // TTLs too long for the clock, and the largest PXAT, neither panic nor wrap
+    assert_eq!(handle_command(&store, &format!("SET forever v EX {}", u64::MAX)).to_string(), "OK");
+    assert_eq!(handle_command(&store, &format!("SET end v PXAT {}", u64::MAX)).to_string(), "OK");
+    assert!(pttl(&store, "forever") > 0);
+    assert_eq!(handle_command(&store, "PEXPIRETIME end").to_string(), i64::MAX.to_string());
+    aof.flush().await.unwrap();
+
+    let restarted = Store::new(None);
+    restarted.load_from_aof(Aof::replay(&path).unwrap().entries);
+    assert_eq!(handle_command(&restarted, "PEXPIRETIME pxat").to_string(), at.to_string());
+    for key in ["pxat", "px"] {
+        let left = pttl(&restarted, key);
+        assert!((59_000..=60_000).contains(&left), "{key}: {left}");
+    }
+    assert!((58_000..=60_000).contains(&pttl(&restarted, "exat")));
+    assert!(matches!(restarted.get("past"), Response::Nil));
+    assert!(pttl(&restarted, "forever") > 0);
+    assert_eq!(handle_command(&restarted, "PEXPIRETIME end").to_string(), i64::MAX.to_string());
+
+    // a snapshot keeps the same expiry, and one from before 1970 counts as past
+    let (_, entries) = snapshot::decode(&restarted.serialize_snapshot(1)).unwrap();
+    let (_, entry) = entries.iter().find(|(k, _)| k == "pxat").unwrap();
+    let ms = entry.expires_at.unwrap().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as i64;
+    assert_eq!(ms, at);
+    let record = kvstore::export::Record { expires_at_ms: Some(-5_000), ..kvstore::export::Record::from_entry("k", entry) };
+    assert!(!record.is_live());
+}
+
+#[tokio::test]
+async fn test_debug_reload_random_writes() {
+    use kvstore::export::Record;
+    use kvstore::server::{serve, Shutdown};
+    use kvstore::snapshot;
+    use kvstore::ServerConfig;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    async fn send(conn: &mut BufReader<tokio::net::TcpStream>, cmd: &str) -> String {
+        conn.get_mut().write_all(format!("{cmd}\n").as_bytes()).await.unwrap();
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        line.trim_end().to_string()
+    }
+    // every live key with its value and exact expiry
+    async fn dataset(conn: &mut BufReader<tokio::net::TcpStream>) -> Vec<(String, String)> {
+        let keys = send(conn, "KEYS \"\"").await;
+        let mut out = Vec::new();
+        for key in keys.split(' ').filter(|k| *k != "(empty)") {
+            let value = snapshot::restore(&send(conn, &format!("DUMP {key}")).await).unwrap();
+            let record = serde_json::to_string(&Record::new(key, &value, None)).unwrap();
+            out.push((record, send(conn, &format!("PEXPIRETIME {key}")).await));
+        }
+        out.sort();
+        out
+    }
+
+    let config = ServerConfig {
+        addrs: vec![free_addr()],
+        aof_path: temp_path("reload-random.aof"),
+        dbfilename: temp_path("reload-random.kvs"),
+        enable_debug_command: true,
+        ..ServerConfig::default()
+    };
+    let addr = config.addrs[0].clone();
+    let shutdown = Shutdown::new();
+    tokio::spawn(serve(config, shutdown.clone()));
+    let mut conn = BufReader::new(connect(&addr).await);
+
+    let seed = rand::random::<u64>();
+    let mut rng = StdRng::seed_from_u64(seed);
+    let later_ms = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() + 3_600_000;
+    for round in 0..8 {
+        for _ in 0..150 {
+            let k = format!("k{}", rng.random_range(0..12));
+            let k2 = format!("k{}", rng.random_range(0..12));
+            let v = format!("v{}", rng.random_range(0..20));
+            let n = rng.random_range(100..10_000);
+            let cmd = match rng.random_range(0..22) {
+                0 => format!("SET {k} {v}"),
+                1 => format!("SET {k} {v} EX {n}"),
+                2 => format!("SET {k} {v} PX {}", n * 1000),
+                3 => format!("SET {k} {v} NX GET"),
+                4 => format!("SET {k} {v} XX PXAT {}", later_ms + n),
+                5 => format!("GETORSET {k} {v}"),
+                6 => format!("DEL {k}"),
+                7 => format!("DELEQ {k} {v}"),
+                8 => format!("EXPIRE {k} {n} NX"),
+                9 => format!("PEXPIRE {k} {}", n * 1000),
+                10 => format!("PEXPIREAT {k} {}", later_ms + n),
+                11 => format!("INCR {k}"),
+                12 => format!("SETRANGE {k} {} {v}", n % 8),
+                13 => format!("BITOP OR {k} {k2} k0"),
+                14 => format!("LPUSH {k} {v} x{n}"),
+                15 => format!("LPOP {k}"),
+                16 => format!("SADD {k} {} {v}", n % 50),
+                17 => format!("SREM {k} {v} {}", n % 50),
+                18 => format!("SMOVE {k} {k2} {v}"),
+                19 => format!("HSET {k} f{} {v}", n % 5),
+                20 => format!("EXPIRE {k} -1"),
+                _ => "DELPATTERN k1* CONFIRM".to_string(),
+            };
+            send(&mut conn, &cmd).await;
+        }
+        let before = dataset(&mut conn).await;
+        assert_eq!(send(&mut conn, "DEBUG RELOAD").await, "OK");
+        assert_eq!(dataset(&mut conn).await, before, "seed {seed}, round {round}");
+    }
+}